@@ -0,0 +1,45 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Contains the error type returned by the APIs of the LSPS protocol handlers.
+
+use lightning::io;
+use lightning::util::errors::APIError;
+
+use core::fmt;
+
+/// An error returned by the APIs of the LSPS protocol handlers.
+#[derive(Debug)]
+pub enum LiquidityError {
+	/// The API was misused, e.g., by passing an unknown request id, or an underlying LDK API call
+	/// failed.
+	APIError(APIError),
+	/// Persisting the updated state of a protocol handler to its [`KVStore`] failed.
+	///
+	/// The in-memory state may already have been updated, and will be persisted again with the
+	/// next change to the state of the respective counterparty.
+	///
+	/// [`KVStore`]: lightning::util::persist::KVStore
+	PersistenceError(io::Error),
+}
+
+impl fmt::Display for LiquidityError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			LiquidityError::APIError(e) => write!(f, "{:?}", e),
+			LiquidityError::PersistenceError(e) => write!(f, "Failed to persist state: {}", e),
+		}
+	}
+}
+
+impl From<APIError> for LiquidityError {
+	fn from(e: APIError) -> Self {
+		LiquidityError::APIError(e)
+	}
+}
@@ -10,6 +10,9 @@
 
 //! # `lightning-liquidity`
 //! Types and primitives to integrate a spec-compliant LSP with an LDK-based node.
+//!
+//! Support for LSPS1 (Channel Request) is still experimental and needs to be enabled via the
+//! `lsps1` feature.
 #![deny(missing_docs)]
 #![deny(rustdoc::broken_intra_doc_links)]
 #![deny(rustdoc::private_intra_doc_links)]
@@ -38,16 +41,21 @@ mod prelude {
 	pub use alloc::string::ToString;
 }
 
+pub mod errors;
 pub mod events;
 pub mod lsps0;
-#[cfg(lsps1)]
+#[cfg(feature = "lsps1")]
 pub mod lsps1;
 pub mod lsps2;
 mod manager;
 pub mod message_queue;
+pub mod persist;
 mod sync;
 #[cfg(test)]
 mod tests;
 mod utils;
 
-pub use manager::{LiquidityClientConfig, LiquidityManager, LiquidityServiceConfig};
+pub use manager::{
+	LiquidityClientConfig, LiquidityManager, LiquidityServiceConfig,
+	DEFAULT_MAX_REQUESTS_PER_PEER_PER_TICK, DEFAULT_REQUEST_TIMEOUT_TICKS,
+};
@@ -8,10 +8,11 @@ use crate::events::{Event, EventQueue};
 use crate::lsps0::event::LSPS0ClientEvent;
 use crate::lsps0::msgs::{
 	LSPS0Message, LSPS0Request, LSPS0Response, ListProtocolsRequest, ListProtocolsResponse,
-	ProtocolMessageHandler, ResponseError,
+	ProtocolMessageHandler, RequestId, ResponseError,
 };
 use crate::message_queue::MessageQueue;
-use crate::sync::Arc;
+use crate::prelude::{HashMap, Vec};
+use crate::sync::{Arc, Mutex};
 use crate::utils;
 
 use lightning::ln::msgs::{ErrorAction, LightningError};
@@ -31,6 +32,9 @@ where
 	entropy_source: ES,
 	pending_messages: MQ,
 	pending_events: Arc<EventQueue>,
+	// The requests awaiting a response, mapped to the number of timer ticks they are pending.
+	pending_requests: Mutex<HashMap<(PublicKey, RequestId), u32>>,
+	request_timeout_ticks: u32,
 }
 
 impl<ES: Deref, MQ: Deref> LSPS0ClientHandler<ES, MQ>
@@ -41,8 +45,16 @@ where
 	/// Returns a new instance of [`LSPS0ClientHandler`].
 	pub(crate) fn new(
 		entropy_source: ES, pending_messages: MQ, pending_events: Arc<EventQueue>,
+		request_timeout_ticks: u32,
 	) -> Self {
-		Self { entropy_source, pending_messages, pending_events }
+		let pending_requests = Mutex::new(HashMap::new());
+		Self {
+			entropy_source,
+			pending_messages,
+			pending_events,
+			pending_requests,
+			request_timeout_ticks,
+		}
 	}
 
 	/// Calls LSPS0's `list_protocols`.
@@ -50,18 +62,86 @@ where
 	/// Please refer to the [LSPS0
 	/// specifcation](https://github.com/BitcoinAndLightningLayerSpecs/lsp/tree/main/LSPS0#lsps-specification-support-query)
 	/// for more information.
-	pub fn list_protocols(&self, counterparty_node_id: &PublicKey) {
+	///
+	/// Returns the id of the request, which identifies it in case it fails, e.g., in a
+	/// [`LSPS0ClientEvent::RequestTimedOut`] or [`LSPS0ClientEvent::PeerDisconnected`] event.
+	pub fn list_protocols(&self, counterparty_node_id: &PublicKey) -> RequestId {
+		let request_id = utils::generate_request_id(&self.entropy_source);
+		self.pending_requests
+			.lock()
+			.unwrap()
+			.insert((*counterparty_node_id, request_id.clone()), 0);
+
 		let msg = LSPS0Message::Request(
-			utils::generate_request_id(&self.entropy_source),
+			request_id.clone(),
 			LSPS0Request::ListProtocols(ListProtocolsRequest {}),
 		);
 
 		self.pending_messages.enqueue(counterparty_node_id, msg.into());
+		request_id
+	}
+
+	/// Times out requests that weren't answered within the configured number of timer ticks.
+	pub(crate) fn timer_tick_occurred(&self) {
+		let mut timed_out_requests = Vec::new();
+		self.pending_requests.lock().unwrap().retain(
+			|(counterparty_node_id, request_id), ticks| {
+				*ticks += 1;
+				if *ticks > self.request_timeout_ticks {
+					timed_out_requests.push((*counterparty_node_id, request_id.clone()));
+					false
+				} else {
+					true
+				}
+			},
+		);
+
+		for (counterparty_node_id, request_id) in timed_out_requests {
+			self.pending_events.enqueue(Event::LSPS0Client(LSPS0ClientEvent::RequestTimedOut {
+				counterparty_node_id,
+				request_id,
+			}));
+		}
+	}
+
+	pub(crate) fn peer_disconnected(&self, counterparty_node_id: &PublicKey) {
+		let mut dropped_requests = Vec::new();
+		self.pending_requests.lock().unwrap().retain(|(node_id, request_id), _| {
+			if node_id == counterparty_node_id {
+				dropped_requests.push(request_id.clone());
+				false
+			} else {
+				true
+			}
+		});
+
+		for request_id in dropped_requests {
+			self.pending_events.enqueue(Event::LSPS0Client(LSPS0ClientEvent::PeerDisconnected {
+				counterparty_node_id: *counterparty_node_id,
+				request_id,
+			}));
+		}
 	}
 
 	fn handle_response(
-		&self, response: LSPS0Response, counterparty_node_id: &PublicKey,
+		&self, request_id: RequestId, response: LSPS0Response, counterparty_node_id: &PublicKey,
 	) -> Result<(), LightningError> {
+		if self
+			.pending_requests
+			.lock()
+			.unwrap()
+			.remove(&(*counterparty_node_id, request_id.clone()))
+			.is_none()
+		{
+			return Err(LightningError {
+				err: format!(
+					"Received list_protocols response for an unknown request: {:?}",
+					request_id
+				),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			});
+		}
+
 		match response {
 			LSPS0Response::ListProtocols(ListProtocolsResponse { protocols }) => {
 				self.pending_events.enqueue(Event::LSPS0Client(
@@ -97,8 +177,8 @@ where
 		&self, message: Self::ProtocolMessage, counterparty_node_id: &PublicKey,
 	) -> Result<(), LightningError> {
 		match message {
-			LSPS0Message::Response(_, response) => {
-				self.handle_response(response, counterparty_node_id)
+			LSPS0Message::Response(request_id, response) => {
+				self.handle_response(request_id, response, counterparty_node_id)
 			}
 			LSPS0Message::Request(..) => {
 				debug_assert!(
@@ -132,6 +212,7 @@ mod tests {
 			entropy_source,
 			Arc::clone(&pending_messages),
 			event_queue,
+			1,
 		));
 
 		let counterparty_node_id = utils::parse_pubkey(
@@ -155,4 +236,91 @@ mod tests {
 			))
 		);
 	}
+
+	#[test]
+	fn unanswered_list_protocols_times_out() {
+		let pending_messages = Arc::new(TestMessageQueue::new());
+		let entropy_source = Arc::new(TestEntropy {});
+		let event_queue = Arc::new(EventQueue::new());
+
+		let lsps0_handler = LSPS0ClientHandler::new(
+			entropy_source,
+			Arc::clone(&pending_messages),
+			Arc::clone(&event_queue),
+			1,
+		);
+
+		let counterparty_node_id = utils::parse_pubkey(
+			"027100442c3b79f606f80f322d98d499eefcb060599efc5d4ecb00209c2cb54190",
+		)
+		.unwrap();
+
+		let request_id = lsps0_handler.list_protocols(&counterparty_node_id);
+		lsps0_handler.timer_tick_occurred();
+		assert!(event_queue.get_and_clear_pending_events().is_empty());
+
+		lsps0_handler.timer_tick_occurred();
+		assert_eq!(
+			event_queue.get_and_clear_pending_events(),
+			vec![Event::LSPS0Client(LSPS0ClientEvent::RequestTimedOut {
+				counterparty_node_id,
+				request_id: request_id.clone(),
+			})]
+		);
+
+		// A late response is no longer accepted.
+		let response = LSPS0Message::Response(
+			request_id,
+			LSPS0Response::ListProtocols(ListProtocolsResponse { protocols: vec![2] }),
+		);
+		assert!(lsps0_handler.handle_message(response, &counterparty_node_id).is_err());
+		assert!(event_queue.get_and_clear_pending_events().is_empty());
+	}
+
+	#[test]
+	fn disconnection_drops_pending_list_protocols() {
+		let pending_messages = Arc::new(TestMessageQueue::new());
+		let entropy_source = Arc::new(TestEntropy {});
+		let event_queue = Arc::new(EventQueue::new());
+
+		let lsps0_handler = LSPS0ClientHandler::new(
+			entropy_source,
+			Arc::clone(&pending_messages),
+			Arc::clone(&event_queue),
+			1,
+		);
+
+		let counterparty_node_id = utils::parse_pubkey(
+			"027100442c3b79f606f80f322d98d499eefcb060599efc5d4ecb00209c2cb54190",
+		)
+		.unwrap();
+		let other_node_id = utils::parse_pubkey(
+			"03dd3fd8d5e6b7a4c3f3e1a52f0e1e3a8f1d93e0a6cc2ed0b5c0b1e8f0a9d7a6b1",
+		)
+		.unwrap();
+
+		let request_id = lsps0_handler.list_protocols(&counterparty_node_id);
+		lsps0_handler.peer_disconnected(&other_node_id);
+		assert!(event_queue.get_and_clear_pending_events().is_empty());
+
+		lsps0_handler.peer_disconnected(&counterparty_node_id);
+		assert_eq!(
+			event_queue.get_and_clear_pending_events(),
+			vec![Event::LSPS0Client(LSPS0ClientEvent::PeerDisconnected {
+				counterparty_node_id,
+				request_id: request_id.clone(),
+			})]
+		);
+
+		// The dropped request neither times out nor accepts a late response.
+		lsps0_handler.timer_tick_occurred();
+		lsps0_handler.timer_tick_occurred();
+		assert!(event_queue.get_and_clear_pending_events().is_empty());
+
+		let response = LSPS0Message::Response(
+			request_id,
+			LSPS0Response::ListProtocols(ListProtocolsResponse { protocols: vec![2] }),
+		);
+		assert!(lsps0_handler.handle_message(response, &counterparty_node_id).is_err());
+	}
 }
@@ -9,6 +9,7 @@
 
 //! Contains LSPS0 event types
 
+use crate::lsps0::msgs::RequestId;
 use crate::prelude::Vec;
 use bitcoin::secp256k1::PublicKey;
 
@@ -22,4 +23,32 @@ pub enum LSPS0ClientEvent {
 		/// A list of supported protocols.
 		protocols: Vec<u16>,
 	},
+	/// A request made via [`LSPS0ClientHandler::list_protocols`] wasn't answered in time.
+	///
+	/// See [`LiquidityClientConfig::request_timeout_ticks`] for more information.
+	///
+	/// [`LSPS0ClientHandler::list_protocols`]: crate::lsps0::client::LSPS0ClientHandler::list_protocols
+	/// [`LiquidityClientConfig::request_timeout_ticks`]: crate::LiquidityClientConfig::request_timeout_ticks
+	RequestTimedOut {
+		/// The node id of the LSP that didn't answer.
+		counterparty_node_id: PublicKey,
+		/// The id of the request, as returned by [`LSPS0ClientHandler::list_protocols`].
+		///
+		/// [`LSPS0ClientHandler::list_protocols`]: crate::lsps0::client::LSPS0ClientHandler::list_protocols
+		request_id: RequestId,
+	},
+	/// A request made via [`LSPS0ClientHandler::list_protocols`] was dropped as we disconnected
+	/// from the LSP before it answered.
+	///
+	/// The request may be repeated once the connection was re-established.
+	///
+	/// [`LSPS0ClientHandler::list_protocols`]: crate::lsps0::client::LSPS0ClientHandler::list_protocols
+	PeerDisconnected {
+		/// The node id of the LSP we disconnected from.
+		counterparty_node_id: PublicKey,
+		/// The id of the request, as returned by [`LSPS0ClientHandler::list_protocols`].
+		///
+		/// [`LSPS0ClientHandler::list_protocols`]: crate::lsps0::client::LSPS0ClientHandler::list_protocols
+		request_id: RequestId,
+	},
 }
@@ -2,7 +2,7 @@
 //!
 //! Please refer to the [LSPS0 specification](https://github.com/BitcoinAndLightningLayerSpecs/lsp/tree/main/LSPS0) for more information.
 
-#[cfg(lsps1)]
+#[cfg(feature = "lsps1")]
 use crate::lsps1::msgs::{
 	LSPS1Message, LSPS1Request, LSPS1Response, LSPS1_CREATE_ORDER_METHOD_NAME,
 	LSPS1_GET_INFO_METHOD_NAME, LSPS1_GET_ORDER_METHOD_NAME,
@@ -40,6 +40,12 @@ const JSONRPC_INVALID_MESSAGE_ERROR_CODE: i32 = -32700;
 const JSONRPC_INVALID_MESSAGE_ERROR_MESSAGE: &str = "parse error";
 const LSPS0_LISTPROTOCOLS_METHOD_NAME: &str = "lsps0.list_protocols";
 
+// An implementation-defined server error, as the LSPS specifications don't define an error for
+// requests we refuse to process because the counterparty exceeded its limits.
+pub(crate) const JSONRPC_RATE_LIMITED_ERROR_CODE: i32 = -32000;
+// The JSON-RPC error for failures on our side, e.g., if we aren't configured to serve a request.
+pub(crate) const JSONRPC_INTERNAL_ERROR_CODE: i32 = -32603;
+
 /// The Lightning message type id for LSPS messages.
 pub const LSPS_MESSAGE_TYPE_ID: u16 = 37913;
 
@@ -159,7 +165,7 @@ impl TryFrom<LSPSMessage> for LSPS0Message {
 		match message {
 			LSPSMessage::Invalid => Err(()),
 			LSPSMessage::LSPS0(message) => Ok(message),
-			#[cfg(lsps1)]
+			#[cfg(feature = "lsps1")]
 			LSPSMessage::LSPS1(_) => Err(()),
 			LSPSMessage::LSPS2(_) => Err(()),
 		}
@@ -180,7 +186,7 @@ pub enum LSPSMessage {
 	/// An LSPS0 message.
 	LSPS0(LSPS0Message),
 	/// An LSPS1 message.
-	#[cfg(lsps1)]
+	#[cfg(feature = "lsps1")]
 	LSPS1(LSPS1Message),
 	/// An LSPS2 message.
 	LSPS2(LSPS2Message),
@@ -190,7 +196,7 @@ impl LSPSMessage {
 	/// A constructor returning an `LSPSMessage` from a raw JSON string.
 	///
 	/// The given `request_id_to_method` associates request ids with method names, as response objects
-	/// don't carry the latter.
+	/// don't carry the latter. The entry for a response's id is removed when parsing it.
 	pub fn from_str_with_id_map(
 		json_str: &str, request_id_to_method: &mut HashMap<String, String>,
 	) -> Result<Self, serde_json::Error> {
@@ -205,7 +211,7 @@ impl LSPSMessage {
 			LSPSMessage::LSPS0(LSPS0Message::Request(request_id, request)) => {
 				Some((request_id.0.clone(), request.method().to_string()))
 			}
-			#[cfg(lsps1)]
+			#[cfg(feature = "lsps1")]
 			LSPSMessage::LSPS1(LSPS1Message::Request(request_id, request)) => {
 				Some((request_id.0.clone(), request.method().to_string()))
 			}
@@ -215,6 +221,36 @@ impl LSPSMessage {
 			_ => None,
 		}
 	}
+
+	/// Returns an error response to this message, if it is a request.
+	pub(crate) fn error_response(&self, error: ResponseError) -> Option<LSPSMessage> {
+		match self {
+			LSPSMessage::LSPS0(LSPS0Message::Request(request_id, request)) => match request {
+				LSPS0Request::ListProtocols(_) => Some(LSPSMessage::LSPS0(LSPS0Message::Response(
+					request_id.clone(),
+					LSPS0Response::ListProtocolsError(error),
+				))),
+			},
+			#[cfg(feature = "lsps1")]
+			LSPSMessage::LSPS1(LSPS1Message::Request(request_id, request)) => {
+				let response = match request {
+					LSPS1Request::GetInfo(_) => LSPS1Response::GetInfoError(error),
+					LSPS1Request::CreateOrder(_) => LSPS1Response::CreateOrderError(error),
+					LSPS1Request::GetOrder(_) => LSPS1Response::GetOrderError(error),
+				};
+				Some(LSPSMessage::LSPS1(LSPS1Message::Response(request_id.clone(), response)))
+			}
+			LSPSMessage::LSPS2(LSPS2Message::Request(request_id, request)) => {
+				let response = match request {
+					LSPS2Request::GetVersions(_) => LSPS2Response::GetVersionsError(error),
+					LSPS2Request::GetInfo(_) => LSPS2Response::GetInfoError(error),
+					LSPS2Request::Buy(_) => LSPS2Response::BuyError(error),
+				};
+				Some(LSPSMessage::LSPS2(LSPS2Message::Response(request_id.clone(), response)))
+			}
+			_ => None,
+		}
+	}
 }
 
 impl Serialize for LSPSMessage {
@@ -250,7 +286,7 @@ impl Serialize for LSPSMessage {
 					}
 				}
 			}
-			#[cfg(lsps1)]
+			#[cfg(feature = "lsps1")]
 			LSPSMessage::LSPS1(LSPS1Message::Request(request_id, request)) => {
 				jsonrpc_object.serialize_field(JSONRPC_ID_FIELD_KEY, &request_id.0)?;
 				jsonrpc_object.serialize_field(JSONRPC_METHOD_FIELD_KEY, request.method())?;
@@ -267,7 +303,7 @@ impl Serialize for LSPSMessage {
 					}
 				}
 			}
-			#[cfg(lsps1)]
+			#[cfg(feature = "lsps1")]
 			LSPSMessage::LSPS1(LSPS1Message::Response(request_id, response)) => {
 				jsonrpc_object.serialize_field(JSONRPC_ID_FIELD_KEY, &request_id.0)?;
 
@@ -275,6 +311,9 @@ impl Serialize for LSPSMessage {
 					LSPS1Response::GetInfo(result) => {
 						jsonrpc_object.serialize_field(JSONRPC_RESULT_FIELD_KEY, result)?
 					}
+					LSPS1Response::GetInfoError(error) => {
+						jsonrpc_object.serialize_field(JSONRPC_ERROR_FIELD_KEY, error)?
+					}
 					LSPS1Response::CreateOrder(result) => {
 						jsonrpc_object.serialize_field(JSONRPC_ERROR_FIELD_KEY, result)?
 					}
@@ -312,6 +351,9 @@ impl Serialize for LSPSMessage {
 					LSPS2Response::GetVersions(result) => {
 						jsonrpc_object.serialize_field(JSONRPC_RESULT_FIELD_KEY, result)?
 					}
+					LSPS2Response::GetVersionsError(error) => {
+						jsonrpc_object.serialize_field(JSONRPC_ERROR_FIELD_KEY, error)?
+					}
 					LSPS2Response::GetInfo(result) => {
 						jsonrpc_object.serialize_field(JSONRPC_RESULT_FIELD_KEY, result)?
 					}
@@ -388,15 +430,11 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 
 		match (id, method) {
 			(Some(id), Some(method)) => match method {
-				LSPS0_LISTPROTOCOLS_METHOD_NAME => {
-					self.request_id_to_method.insert(id.clone(), method.to_string());
-
-					Ok(LSPSMessage::LSPS0(LSPS0Message::Request(
-						RequestId(id),
-						LSPS0Request::ListProtocols(ListProtocolsRequest {}),
-					)))
-				}
-				#[cfg(lsps1)]
+				LSPS0_LISTPROTOCOLS_METHOD_NAME => Ok(LSPSMessage::LSPS0(LSPS0Message::Request(
+					RequestId(id),
+					LSPS0Request::ListProtocols(ListProtocolsRequest {}),
+				))),
+				#[cfg(feature = "lsps1")]
 				LSPS1_GET_INFO_METHOD_NAME => {
 					let request = serde_json::from_value(params.unwrap_or(json!({})))
 						.map_err(de::Error::custom)?;
@@ -405,7 +443,7 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 						LSPS1Request::GetInfo(request),
 					)))
 				}
-				#[cfg(lsps1)]
+				#[cfg(feature = "lsps1")]
 				LSPS1_CREATE_ORDER_METHOD_NAME => {
 					let request = serde_json::from_value(params.unwrap_or(json!({})))
 						.map_err(de::Error::custom)?;
@@ -414,7 +452,7 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 						LSPS1Request::CreateOrder(request),
 					)))
 				}
-				#[cfg(lsps1)]
+				#[cfg(feature = "lsps1")]
 				LSPS1_GET_ORDER_METHOD_NAME => {
 					let request = serde_json::from_value(params.unwrap_or(json!({})))
 						.map_err(de::Error::custom)?;
@@ -452,7 +490,7 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 					method
 				))),
 			},
-			(Some(id), None) => match self.request_id_to_method.get(&id) {
+			(Some(id), None) => match self.request_id_to_method.remove(&id) {
 				Some(method) => match method.as_str() {
 					LSPS0_LISTPROTOCOLS_METHOD_NAME => {
 						if let Some(error) = error {
@@ -472,7 +510,12 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 						}
 					}
 					LSPS2_GET_VERSIONS_METHOD_NAME => {
-						if let Some(result) = result {
+						if let Some(error) = error {
+							Ok(LSPSMessage::LSPS2(LSPS2Message::Response(
+								RequestId(id),
+								LSPS2Response::GetVersionsError(error),
+							)))
+						} else if let Some(result) = result {
 							let response =
 								serde_json::from_value(result).map_err(de::Error::custom)?;
 							Ok(LSPSMessage::LSPS2(LSPS2Message::Response(
@@ -483,7 +526,25 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 							Err(de::Error::custom("Received invalid lsps2.get_versions response."))
 						}
 					}
-					#[cfg(lsps1)]
+					#[cfg(feature = "lsps1")]
+					LSPS1_GET_INFO_METHOD_NAME => {
+						if let Some(error) = error {
+							Ok(LSPSMessage::LSPS1(LSPS1Message::Response(
+								RequestId(id),
+								LSPS1Response::GetInfoError(error),
+							)))
+						} else if let Some(result) = result {
+							let response =
+								serde_json::from_value(result).map_err(de::Error::custom)?;
+							Ok(LSPSMessage::LSPS1(LSPS1Message::Response(
+								RequestId(id),
+								LSPS1Response::GetInfo(response),
+							)))
+						} else {
+							Err(de::Error::custom("Received invalid JSON-RPC object: one of method, result, or error required"))
+						}
+					}
+					#[cfg(feature = "lsps1")]
 					LSPS1_CREATE_ORDER_METHOD_NAME => {
 						if let Some(error) = error {
 							Ok(LSPSMessage::LSPS1(LSPS1Message::Response(
@@ -501,7 +562,7 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 							Err(de::Error::custom("Received invalid JSON-RPC object: one of method, result, or error required"))
 						}
 					}
-					#[cfg(lsps1)]
+					#[cfg(feature = "lsps1")]
 					LSPS1_GET_ORDER_METHOD_NAME => {
 						if let Some(error) = error {
 							Ok(LSPSMessage::LSPS1(LSPS1Message::Response(
@@ -577,6 +638,8 @@ impl<'de, 'a> Visitor<'de> for LSPSMessageVisitor<'a> {
 mod tests {
 	use super::*;
 
+	use crate::lsps2::msgs::GetVersionsRequest;
+
 	#[test]
 	fn deserializes_request() {
 		let json = r#"{
@@ -667,6 +730,62 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn deserializes_response_only_once() {
+		let json = r#"{
+	        "jsonrpc": "2.0",
+	        "id": "request:id:xyz123",
+	        "result": {
+	            "protocols": [1,2,3]
+	        }
+	    }"#;
+		let mut request_id_to_method_map = HashMap::new();
+		request_id_to_method_map
+			.insert("request:id:xyz123".to_string(), "lsps0.list_protocols".to_string());
+
+		assert!(LSPSMessage::from_str_with_id_map(json, &mut request_id_to_method_map).is_ok());
+		assert!(request_id_to_method_map.is_empty());
+		assert!(LSPSMessage::from_str_with_id_map(json, &mut request_id_to_method_map).is_err());
+	}
+
+	#[test]
+	fn builds_error_responses_to_requests() {
+		let error = ResponseError {
+			code: JSONRPC_RATE_LIMITED_ERROR_CODE,
+			message: "too many requests".to_string(),
+			data: None,
+		};
+		let request = LSPSMessage::LSPS0(LSPS0Message::Request(
+			RequestId("request:id:xyz123".to_string()),
+			LSPS0Request::ListProtocols(ListProtocolsRequest {}),
+		));
+		assert_eq!(
+			request.error_response(error.clone()),
+			Some(LSPSMessage::LSPS0(LSPS0Message::Response(
+				RequestId("request:id:xyz123".to_string()),
+				LSPS0Response::ListProtocolsError(error.clone()),
+			)))
+		);
+
+		let request = LSPSMessage::LSPS2(LSPS2Message::Request(
+			RequestId("request:id:xyz124".to_string()),
+			LSPS2Request::GetVersions(GetVersionsRequest {}),
+		));
+		assert_eq!(
+			request.error_response(error.clone()),
+			Some(LSPSMessage::LSPS2(LSPS2Message::Response(
+				RequestId("request:id:xyz124".to_string()),
+				LSPS2Response::GetVersionsError(error.clone()),
+			)))
+		);
+
+		let response = LSPSMessage::LSPS0(LSPS0Message::Response(
+			RequestId("request:id:xyz123".to_string()),
+			LSPS0Response::ListProtocols(ListProtocolsResponse { protocols: vec![1, 2, 3] }),
+		));
+		assert_eq!(response.error_response(error), None);
+	}
+
 	#[test]
 	fn deserialize_fails_with_unknown_request_id() {
 		let json = r#"{
@@ -17,19 +17,25 @@ use super::msgs::{
 };
 use super::utils::is_valid;
 use crate::message_queue::MessageQueue;
+use crate::persist::{
+	persist_peer_state, read_peer_states, PersistedPeerState,
+	LSPS1_CLIENT_PERSISTENCE_PRIMARY_NAMESPACE, LSPS1_CLIENT_PERSISTENCE_SECONDARY_NAMESPACE,
+};
 
+use crate::errors::LiquidityError;
 use crate::events::EventQueue;
 use crate::lsps0::msgs::{ProtocolMessageHandler, RequestId};
 use crate::prelude::{HashMap, String, ToString, Vec};
 use crate::sync::{Arc, Mutex, RwLock};
 use crate::{events::Event, lsps0::msgs::ResponseError};
 
-use lightning::chain::Filter;
-use lightning::ln::channelmanager::AChannelManager;
+use lightning::io;
 use lightning::ln::msgs::{ErrorAction, LightningError};
 use lightning::sign::EntropySource;
 use lightning::util::errors::APIError;
 use lightning::util::logger::Level;
+use lightning::util::persist::KVStore;
+use lightning::{impl_writeable_tlv_based, impl_writeable_tlv_based_enum};
 
 use bitcoin::secp256k1::PublicKey;
 
@@ -61,6 +67,25 @@ enum InboundRequestState {
 	AwaitingConfirmation { id: u128, order_id: OrderId },
 }
 
+impl_writeable_tlv_based_enum!(InboundRequestState,
+	(0, InfoRequested) => {},
+	(2, OptionsSupport) => {
+		(0, version, required),
+		(2, options_supported, required),
+	},
+	(4, OrderRequested) => {
+		(0, version, required),
+		(2, order, required),
+	},
+	(6, PendingPayment) => {
+		(0, order_id, required),
+	},
+	(8, AwaitingConfirmation) => {
+		(0, id, required),
+		(2, order_id, required),
+	};
+);
+
 impl InboundRequestState {
 	fn info_received(
 		&self, versions: Vec<u16>, options: OptionsSupported,
@@ -93,10 +118,10 @@ impl InboundRequestState {
 				if is_valid(&order, options_supported) {
 					Ok(InboundRequestState::OrderRequested { version: *version, order })
 				} else {
-					return Err(ChannelStateError(format!(
+					Err(ChannelStateError(format!(
 						"The order created does not match options supported by LSP. Options Supported by LSP are {:?}. The order created was {:?}",
 						options_supported, order
-					)));
+					)))
 				}
 			}
 			state => Err(ChannelStateError(format!(
@@ -110,7 +135,7 @@ impl InboundRequestState {
 		&self, response_order: &OrderParams, order_id: OrderId,
 	) -> Result<Self, ChannelStateError> {
 		match self {
-			InboundRequestState::OrderRequested { version, order } => {
+			InboundRequestState::OrderRequested { order, .. } => {
 				if response_order == order {
 					Ok(InboundRequestState::PendingPayment { order_id })
 				} else {
@@ -127,16 +152,26 @@ impl InboundRequestState {
 		}
 	}
 
-	fn pay_for_channel(&self, channel_id: u128) -> Result<Self, ChannelStateError> {
+	fn pay_for_channel(
+		&self, channel_id: u128, requested_order_id: &OrderId,
+	) -> Result<Self, ChannelStateError> {
 		match self {
-			InboundRequestState::PendingPayment { order_id } => {
+			InboundRequestState::PendingPayment { order_id }
+			| InboundRequestState::AwaitingConfirmation { order_id, .. } => {
+				if order_id != requested_order_id {
+					return Err(ChannelStateError(format!(
+						"Order {:?} does not belong to channel {}, which is tracking order {:?}",
+						requested_order_id, channel_id, order_id
+					)));
+				}
+
 				Ok(InboundRequestState::AwaitingConfirmation {
 					id: channel_id,
 					order_id: order_id.clone(),
 				})
 			}
 			state => Err(ChannelStateError(format!(
-				"Received unexpected response. Channel was in state: {:?}",
+				"Can't check the status of an order that wasn't created yet. Channel was in state: {:?}",
 				state
 			))),
 		}
@@ -148,6 +183,11 @@ struct InboundCRChannel {
 	state: InboundRequestState,
 }
 
+impl_writeable_tlv_based!(InboundCRChannel, {
+	(0, id, required),
+	(2, state, required),
+});
+
 impl InboundCRChannel {
 	fn new(id: u128) -> Self {
 		Self { id, state: InboundRequestState::InfoRequested }
@@ -172,12 +212,10 @@ impl InboundCRChannel {
 
 		match self.state {
 			InboundRequestState::OrderRequested { version, .. } => Ok(version),
-			_ => {
-				return Err(LightningError {
-					action: ErrorAction::IgnoreAndLog(Level::Error),
-					err: "impossible state transition".to_string(),
-				});
-			}
+			_ => Err(LightningError {
+				action: ErrorAction::IgnoreAndLog(Level::Error),
+				err: "impossible state transition".to_string(),
+			}),
 		}
 	}
 
@@ -188,8 +226,10 @@ impl InboundCRChannel {
 		Ok(())
 	}
 
-	fn pay_for_channel(&mut self, channel_id: u128) -> Result<(), LightningError> {
-		self.state = self.state.pay_for_channel(channel_id)?;
+	fn pay_for_channel(
+		&mut self, channel_id: u128, order_id: &OrderId,
+	) -> Result<(), LightningError> {
+		self.state = self.state.pay_for_channel(channel_id, order_id)?;
 		Ok(())
 	}
 }
@@ -198,6 +238,8 @@ impl InboundCRChannel {
 struct PeerState {
 	inbound_channels_by_id: HashMap<u128, InboundCRChannel>,
 	request_to_cid: HashMap<RequestId, u128>,
+	// The number of timer ticks each request in `request_to_cid` is awaiting a response.
+	request_ticks: HashMap<RequestId, u32>,
 	pending_requests: HashMap<RequestId, LSPS1Request>,
 }
 
@@ -207,84 +249,182 @@ impl PeerState {
 	}
 
 	fn insert_request(&mut self, request_id: RequestId, channel_id: u128) {
+		self.request_ticks.insert(request_id.clone(), 0);
 		self.request_to_cid.insert(request_id, channel_id);
 	}
 
+	fn remove_request(&mut self, request_id: &RequestId) -> Option<u128> {
+		self.request_ticks.remove(request_id);
+		self.request_to_cid.remove(request_id)
+	}
+
+	/// Drops the requests that weren't answered within `request_timeout_ticks` timer ticks,
+	/// returning the ids of the affected channel requests.
+	///
+	/// Channel requests are dropped along with their request, unless the LSP already created an
+	/// order we are awaiting confirmation for, whose status may still be polled again later.
+	fn timer_tick_occurred(&mut self, request_timeout_ticks: u32) -> Vec<u128> {
+		let mut timed_out_requests = Vec::new();
+		for (request_id, ticks) in self.request_ticks.iter_mut() {
+			*ticks += 1;
+			if *ticks > request_timeout_ticks {
+				timed_out_requests.push(request_id.clone());
+			}
+		}
+		self.drop_requests(timed_out_requests)
+	}
+
+	/// Drops all requests in flight, as they can't be answered after a disconnection, and returns
+	/// the ids of the affected channels.
+	fn peer_disconnected(&mut self) -> Vec<u128> {
+		let requests = self.request_to_cid.keys().cloned().collect();
+		self.drop_requests(requests)
+	}
+
+	/// Drops the given requests, along with their channel requests unless we already placed an
+	/// order, and returns the ids of the affected channels.
+	fn drop_requests(&mut self, request_ids: Vec<RequestId>) -> Vec<u128> {
+		let mut dropped_channels = Vec::new();
+		for request_id in request_ids {
+			if let Some(channel_id) = self.remove_request(&request_id) {
+				let awaiting_confirmation = matches!(
+					self.inbound_channels_by_id.get(&channel_id).map(|channel| &channel.state),
+					Some(InboundRequestState::AwaitingConfirmation { .. })
+				);
+				if !awaiting_confirmation {
+					self.remove_inbound_channel(channel_id);
+				}
+				dropped_channels.push(channel_id);
+			}
+		}
+		dropped_channels
+	}
+
 	fn remove_inbound_channel(&mut self, id: u128) {
 		self.inbound_channels_by_id.remove(&id);
 	}
 }
 
+impl_writeable_tlv_based!(PeerState, {
+	(0, inbound_channels_by_id, required),
+	(not_written, request_to_cid, (static_value, HashMap::new())),
+	(not_written, request_ticks, (static_value, HashMap::new())),
+	(not_written, pending_requests, (static_value, HashMap::new())),
+});
+
+impl PersistedPeerState for PeerState {
+	const PRIMARY_NAMESPACE: &'static str = LSPS1_CLIENT_PERSISTENCE_PRIMARY_NAMESPACE;
+	const SECONDARY_NAMESPACE: &'static str = LSPS1_CLIENT_PERSISTENCE_SECONDARY_NAMESPACE;
+
+	fn is_persistable(&self) -> bool {
+		!self.inbound_channels_by_id.is_empty()
+	}
+}
+
 /// The main object allowing to send and receive LSPS1 messages.
-pub struct LSPS1ClientHandler<ES: Deref, CM: Deref + Clone, MQ: Deref, C: Deref>
+///
+/// The state of all channel orders is persisted to the given [`KVStore`] on every change and
+/// reloaded from it upon construction.
+pub struct LSPS1ClientHandler<ES: Deref, MQ: Deref, K: Deref>
 where
 	ES::Target: EntropySource,
-	CM::Target: AChannelManager,
 	MQ::Target: MessageQueue,
-	C::Target: Filter,
+	K::Target: KVStore,
 {
 	entropy_source: ES,
-	channel_manager: CM,
-	chain_source: Option<C>,
 	pending_messages: MQ,
 	pending_events: Arc<EventQueue>,
 	per_peer_state: RwLock<HashMap<PublicKey, Mutex<PeerState>>>,
+	kv_store: K,
 	config: LSPS1ClientConfig,
+	request_timeout_ticks: u32,
 }
 
-impl<ES: Deref, CM: Deref + Clone, MQ: Deref, C: Deref> LSPS1ClientHandler<ES, CM, MQ, C>
+impl<ES: Deref, MQ: Deref, K: Deref> LSPS1ClientHandler<ES, MQ, K>
 where
 	ES::Target: EntropySource,
-	CM::Target: AChannelManager,
 	MQ::Target: MessageQueue,
-	C::Target: Filter,
 	ES::Target: EntropySource,
+	K::Target: KVStore,
 {
+	#[allow(clippy::too_many_arguments)]
 	pub(crate) fn new(
-		entropy_source: ES, pending_messages: MQ, pending_events: Arc<EventQueue>,
-		channel_manager: CM, chain_source: Option<C>, config: LSPS1ClientConfig,
-	) -> Self {
-		Self {
+		entropy_source: ES, pending_messages: MQ, pending_events: Arc<EventQueue>, kv_store: K,
+		config: LSPS1ClientConfig, request_timeout_ticks: u32,
+	) -> Result<Self, io::Error> {
+		let persisted_peer_states: HashMap<PublicKey, PeerState> = read_peer_states(&kv_store)?;
+		let per_peer_state = persisted_peer_states
+			.into_iter()
+			.map(|(counterparty_node_id, peer_state)| {
+				(counterparty_node_id, Mutex::new(peer_state))
+			})
+			.collect();
+
+		Ok(Self {
 			entropy_source,
-			channel_manager,
-			chain_source,
 			pending_messages,
 			pending_events,
-			per_peer_state: RwLock::new(HashMap::new()),
+			per_peer_state: RwLock::new(per_peer_state),
+			kv_store,
 			config,
-		}
+			request_timeout_ticks,
+		})
 	}
 
-	fn request_for_info(&self, counterparty_node_id: PublicKey, channel_id: u128) {
+	/// Requests the channel options supported by the LSP, starting a new channel request.
+	///
+	/// `counterparty_node_id` is the node_id of the LSP you would like to buy a channel from.
+	///
+	/// `channel_id` is an identifier of your choosing that is used to refer to this channel
+	/// request in subsequent calls and events. It is not related in any way to the eventual
+	/// lightning channel id, but must be unique among your requests to this LSP.
+	///
+	/// The LSP's answer will be surfaced via an [`LSPS1ClientEvent::GetInfoResponse`] event, in
+	/// response to which you may call [`LSPS1ClientHandler::place_order`].
+	pub fn request_for_info(
+		&self, counterparty_node_id: PublicKey, channel_id: u128,
+	) -> Result<(), LiquidityError> {
 		let channel = InboundCRChannel::new(channel_id);
 
 		let mut outer_state_lock = self.per_peer_state.write().unwrap();
-		let inner_state_lock = outer_state_lock
+		let inner_state_lock: &Mutex<PeerState> = outer_state_lock
 			.entry(counterparty_node_id)
 			.or_insert(Mutex::new(PeerState::default()));
 		let mut peer_state_lock = inner_state_lock.lock().unwrap();
+		if peer_state_lock.inbound_channels_by_id.contains_key(&channel_id) {
+			return Err(APIError::APIMisuseError {
+				err: format!(
+					"A channel request with id {} already exists for counterparty {}",
+					channel_id, counterparty_node_id
+				),
+			}
+			.into());
+		}
 		peer_state_lock.insert_inbound_channel(channel_id, channel);
 
 		let request_id = crate::utils::generate_request_id(&self.entropy_source);
 		peer_state_lock.insert_request(request_id.clone(), channel_id);
+		persist_peer_state(&self.kv_store, &counterparty_node_id, &*peer_state_lock)?;
 
 		self.pending_messages.enqueue(
 			&counterparty_node_id,
 			LSPS1Message::Request(request_id, LSPS1Request::GetInfo(GetInfoRequest {})).into(),
 		);
+
+		Ok(())
 	}
 
 	fn handle_get_info_response(
 		&self, request_id: RequestId, counterparty_node_id: &PublicKey, result: GetInfoResponse,
 	) -> Result<(), LightningError> {
-		let outer_state_lock = self.per_peer_state.write().unwrap();
+		let outer_state_lock = self.per_peer_state.read().unwrap();
 
 		match outer_state_lock.get(counterparty_node_id) {
 			Some(inner_state_lock) => {
 				let mut peer_state_lock = inner_state_lock.lock().unwrap();
 
 				let channel_id =
-					peer_state_lock.request_to_cid.remove(&request_id).ok_or(LightningError {
+					peer_state_lock.remove_request(&request_id).ok_or(LightningError {
 						err: format!(
 							"Received get_info response for an unknown request: {:?}",
 							request_id
@@ -335,12 +475,21 @@ where
 		Ok(())
 	}
 
-	fn place_order(
-		&self, channel_id: u128, counterparty_node_id: &PublicKey, order: OrderParams,
-	) -> Result<(), APIError> {
-		let outer_state_lock = self.per_peer_state.write().unwrap();
+	/// Places an order for a channel with the given parameters.
+	///
+	/// Should be called in response to receiving a [`LSPS1ClientEvent::GetInfoResponse`] event,
+	/// using the `channel_id` previously passed to [`LSPS1ClientHandler::request_for_info`]. The
+	/// `order` must be within the bounds of the [`OptionsSupported`] reported by the LSP.
+	///
+	/// Once the LSP created the order you will receive an [`LSPS1ClientEvent::DisplayOrder`]
+	/// event with the payment details, or an [`LSPS1ClientEvent::OrderRequestFailed`] event if
+	/// the LSP refused it.
+	pub fn place_order(
+		&self, counterparty_node_id: PublicKey, channel_id: u128, order: OrderParams,
+	) -> Result<(), LiquidityError> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
 
-		match outer_state_lock.get(counterparty_node_id) {
+		match outer_state_lock.get(&counterparty_node_id) {
 			Some(inner_state_lock) => {
 				let mut peer_state_lock = inner_state_lock.lock().unwrap();
 
@@ -351,19 +500,18 @@ where
 					err: format!("Channel with id {} not found", channel_id),
 				})?;
 
-				let version = match inbound_channel.order_requested(order.clone()) {
-					Ok(version) => version,
-					Err(e) => {
-						peer_state_lock.remove_inbound_channel(channel_id);
-						return Err(APIError::APIMisuseError { err: e.err });
-					}
-				};
+				// An order that doesn't fit the LSP's options leaves the request untouched, so it
+				// can be retried with corrected parameters.
+				let version = inbound_channel
+					.order_requested(order.clone())
+					.map_err(|e| APIError::APIMisuseError { err: e.err })?;
 
 				let request_id = crate::utils::generate_request_id(&self.entropy_source);
 				peer_state_lock.insert_request(request_id.clone(), channel_id);
+				persist_peer_state(&self.kv_store, &counterparty_node_id, &*peer_state_lock)?;
 
 				self.pending_messages.enqueue(
-					counterparty_node_id,
+					&counterparty_node_id,
 					LSPS1Message::Request(
 						request_id,
 						LSPS1Request::CreateOrder(CreateOrderRequest { order, version }),
@@ -374,7 +522,8 @@ where
 			None => {
 				return Err(APIError::APIMisuseError {
 					err: format!("No existing state with counterparty {}", counterparty_node_id),
-				})
+				}
+				.into())
 			}
 		}
 		Ok(())
@@ -385,12 +534,12 @@ where
 		response: CreateOrderResponse,
 	) -> Result<(), LightningError> {
 		let outer_state_lock = self.per_peer_state.read().unwrap();
-		match outer_state_lock.get(&counterparty_node_id) {
+		match outer_state_lock.get(counterparty_node_id) {
 			Some(inner_state_lock) => {
 				let mut peer_state_lock = inner_state_lock.lock().unwrap();
 
 				let channel_id =
-					peer_state_lock.request_to_cid.remove(&request_id).ok_or(LightningError {
+					peer_state_lock.remove_request(&request_id).ok_or(LightningError {
 						err: format!(
 							"Received create_order response for an unknown request: {:?}",
 							request_id
@@ -426,6 +575,7 @@ where
 						LSPS1ClientEvent::DisplayOrder {
 							id: channel_id,
 							counterparty_node_id: *counterparty_node_id,
+							order_id: response.order_id,
 							order: response.order,
 							payment: response.payment,
 							channel: response.channel,
@@ -453,16 +603,64 @@ where
 		Ok(())
 	}
 
+	fn handle_get_info_error(
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, error: ResponseError,
+	) -> Result<(), LightningError> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		match outer_state_lock.get(counterparty_node_id) {
+			Some(inner_state_lock) => {
+				let mut peer_state_lock = inner_state_lock.lock().unwrap();
+
+				let channel_id =
+					peer_state_lock.remove_request(&request_id).ok_or(LightningError {
+						err: format!(
+							"Received get_info error for an unknown request: {:?}",
+							request_id
+						),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					})?;
+
+				if !peer_state_lock.inbound_channels_by_id.contains_key(&channel_id) {
+					return Err(LightningError {
+						err: format!(
+							"Received get_info error for an unknown channel: {:?}",
+							channel_id
+						),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					});
+				}
+
+				peer_state_lock.remove_inbound_channel(channel_id);
+
+				self.pending_events.enqueue(Event::LSPS1Client(
+					LSPS1ClientEvent::GetInfoRequestFailed {
+						id: channel_id,
+						counterparty_node_id: *counterparty_node_id,
+						error,
+					},
+				));
+				Ok(())
+			}
+			None => Err(LightningError {
+				err: format!(
+					"Received error response for a get_info request from an unknown counterparty ({:?})",
+					counterparty_node_id
+				),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			}),
+		}
+	}
+
 	fn handle_create_order_error(
 		&self, request_id: RequestId, counterparty_node_id: &PublicKey, error: ResponseError,
 	) -> Result<(), LightningError> {
 		let outer_state_lock = self.per_peer_state.read().unwrap();
-		match outer_state_lock.get(&counterparty_node_id) {
+		match outer_state_lock.get(counterparty_node_id) {
 			Some(inner_state_lock) => {
 				let mut peer_state_lock = inner_state_lock.lock().unwrap();
 
 				let channel_id =
-					peer_state_lock.request_to_cid.remove(&request_id).ok_or(LightningError {
+					peer_state_lock.remove_request(&request_id).ok_or(LightningError {
 						err: format!(
 							"Received create order error for an unknown request: {:?}",
 							request_id
@@ -470,28 +668,44 @@ where
 						action: ErrorAction::IgnoreAndLog(Level::Info),
 					})?;
 
-				let inbound_channel = peer_state_lock
-					.inbound_channels_by_id
-					.get_mut(&channel_id)
-					.ok_or(LightningError {
-					err: format!(
-						"Received create order error for an unknown channel: {:?}",
-						channel_id
-					),
-					action: ErrorAction::IgnoreAndLog(Level::Info),
-				})?;
+				if !peer_state_lock.inbound_channels_by_id.contains_key(&channel_id) {
+					return Err(LightningError {
+						err: format!(
+							"Received create order error for an unknown channel: {:?}",
+							channel_id
+						),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					});
+				}
+
+				// The LSP didn't create the order, so there is nothing left to track.
+				peer_state_lock.remove_inbound_channel(channel_id);
+
+				self.pending_events.enqueue(Event::LSPS1Client(
+					LSPS1ClientEvent::OrderRequestFailed {
+						id: channel_id,
+						counterparty_node_id: *counterparty_node_id,
+						error,
+					},
+				));
 				Ok(())
 			}
-			None => {
-				return Err(LightningError { err: format!("Received error response for a create order request from an unknown counterparty ({:?})",counterparty_node_id), action: ErrorAction::IgnoreAndLog(Level::Info)});
-			}
+			None => Err(LightningError { err: format!("Received error response for a create order request from an unknown counterparty ({:?})",counterparty_node_id), action: ErrorAction::IgnoreAndLog(Level::Info)}),
 		}
 	}
 
-	fn check_order_status(
-		&self, counterparty_node_id: &PublicKey, order_id: OrderId, channel_id: u128,
-	) -> Result<(), APIError> {
-		let outer_state_lock = self.per_peer_state.write().unwrap();
+	/// Queries the LSP for the current status of the order with the given `order_id`.
+	///
+	/// Should be called after receiving a [`LSPS1ClientEvent::DisplayOrder`] event for the
+	/// request identified by `channel_id`. May be called repeatedly to poll the order, e.g., while
+	/// waiting for the payment to be confirmed or the channel to be opened.
+	///
+	/// The LSP's answer will be surfaced via an [`LSPS1ClientEvent::OrderStatus`] event, or an
+	/// [`LSPS1ClientEvent::OrderStatusRequestFailed`] event if the LSP returned an error.
+	pub fn check_order_status(
+		&self, counterparty_node_id: PublicKey, channel_id: u128, order_id: OrderId,
+	) -> Result<(), LiquidityError> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
 		match outer_state_lock.get(&counterparty_node_id) {
 			Some(inner_state_lock) => {
 				let mut peer_state_lock = inner_state_lock.lock().unwrap();
@@ -499,32 +713,34 @@ where
 				if let Some(inbound_channel) =
 					peer_state_lock.inbound_channels_by_id.get_mut(&channel_id)
 				{
-					if let Err(e) = inbound_channel.pay_for_channel(channel_id) {
-						peer_state_lock.remove_inbound_channel(channel_id);
-						return Err(APIError::APIMisuseError { err: e.err });
-					}
+					inbound_channel
+						.pay_for_channel(channel_id, &order_id)
+						.map_err(|e| APIError::APIMisuseError { err: e.err })?;
 
 					let request_id = crate::utils::generate_request_id(&self.entropy_source);
 					peer_state_lock.insert_request(request_id.clone(), channel_id);
+					persist_peer_state(&self.kv_store, &counterparty_node_id, &*peer_state_lock)?;
 
 					self.pending_messages.enqueue(
-						counterparty_node_id,
+						&counterparty_node_id,
 						LSPS1Message::Request(
 							request_id,
-							LSPS1Request::GetOrder(GetOrderRequest { order_id: order_id.clone() }),
+							LSPS1Request::GetOrder(GetOrderRequest { order_id }),
 						)
 						.into(),
 					);
 				} else {
 					return Err(APIError::APIMisuseError {
 						err: format!("Channel with id {} not found", channel_id),
-					});
+					}
+					.into());
 				}
 			}
 			None => {
 				return Err(APIError::APIMisuseError {
 					err: format!("No existing state with counterparty {}", counterparty_node_id),
-				})
+				}
+				.into())
 			}
 		}
 
@@ -535,29 +751,38 @@ where
 		&self, request_id: RequestId, counterparty_node_id: &PublicKey, params: GetOrderResponse,
 	) -> Result<(), LightningError> {
 		let outer_state_lock = self.per_peer_state.read().unwrap();
-		match outer_state_lock.get(&counterparty_node_id) {
+		match outer_state_lock.get(counterparty_node_id) {
 			Some(inner_state_lock) => {
 				let mut peer_state_lock = inner_state_lock.lock().unwrap();
 
 				let channel_id =
-					peer_state_lock.request_to_cid.remove(&request_id).ok_or(LightningError {
+					peer_state_lock.remove_request(&request_id).ok_or(LightningError {
 						err: format!(
-							"Received get_versions response for an unknown request: {:?}",
+							"Received get_order response for an unknown request: {:?}",
 							request_id
 						),
 						action: ErrorAction::IgnoreAndLog(Level::Info),
 					})?;
 
-				let inbound_channel = peer_state_lock
-					.inbound_channels_by_id
-					.get_mut(&channel_id)
-					.ok_or(LightningError {
-					err: format!(
-						"Received get_versions response for an unknown channel: {:?}",
-						channel_id
-					),
-					action: ErrorAction::IgnoreAndLog(Level::Info),
-				})?;
+				if !peer_state_lock.inbound_channels_by_id.contains_key(&channel_id) {
+					return Err(LightningError {
+						err: format!(
+							"Received get_order response for an unknown channel: {:?}",
+							channel_id
+						),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					});
+				}
+
+				let response = params.response;
+				self.pending_events.enqueue(Event::LSPS1Client(LSPS1ClientEvent::OrderStatus {
+					id: channel_id,
+					counterparty_node_id: *counterparty_node_id,
+					order_id: response.order_id,
+					order_state: response.order_state,
+					payment: response.payment,
+					channel: response.channel,
+				}));
 			}
 			None => {
 				return Err(LightningError {
@@ -574,15 +799,15 @@ where
 	}
 
 	fn handle_get_order_error(
-		&self, request_id: RequestId, counterparty_node_id: &PublicKey, params: ResponseError,
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, error: ResponseError,
 	) -> Result<(), LightningError> {
 		let outer_state_lock = self.per_peer_state.read().unwrap();
-		match outer_state_lock.get(&counterparty_node_id) {
+		match outer_state_lock.get(counterparty_node_id) {
 			Some(inner_state_lock) => {
 				let mut peer_state_lock = inner_state_lock.lock().unwrap();
 
 				let channel_id =
-					peer_state_lock.request_to_cid.remove(&request_id).ok_or(LightningError {
+					peer_state_lock.remove_request(&request_id).ok_or(LightningError {
 						err: format!(
 							"Received get_order error for an unknown request: {:?}",
 							request_id
@@ -590,9 +815,9 @@ where
 						action: ErrorAction::IgnoreAndLog(Level::Info),
 					})?;
 
-				let _inbound_channel = peer_state_lock
+				let inbound_channel = peer_state_lock
 					.inbound_channels_by_id
-					.get_mut(&channel_id)
+					.get(&channel_id)
 					.ok_or(LightningError {
 						err: format!(
 							"Received get_order error for an unknown channel: {:?}",
@@ -600,22 +825,88 @@ where
 						),
 						action: ErrorAction::IgnoreAndLog(Level::Info),
 					})?;
+
+				let order_id = match &inbound_channel.state {
+					InboundRequestState::AwaitingConfirmation { order_id, .. } => order_id.clone(),
+					state => {
+						return Err(LightningError {
+							err: format!(
+								"Received get_order error for a channel that is not awaiting confirmation. Channel was in state: {:?}",
+								state
+							),
+							action: ErrorAction::IgnoreAndLog(Level::Info),
+						})
+					}
+				};
+
+				// We keep the order around, as the status may still be polled again later.
+				self.pending_events.enqueue(Event::LSPS1Client(
+					LSPS1ClientEvent::OrderStatusRequestFailed {
+						id: channel_id,
+						counterparty_node_id: *counterparty_node_id,
+						order_id,
+						error,
+					},
+				));
 				Ok(())
 			}
-			None => {
-				return Err(LightningError { err: format!("Received get_order response for a create order request from an unknown counterparty ({:?})",counterparty_node_id), action: ErrorAction::IgnoreAndLog(Level::Info)});
+			None => Err(LightningError { err: format!("Received error response for a get_order request from an unknown counterparty ({:?})",counterparty_node_id), action: ErrorAction::IgnoreAndLog(Level::Info)}),
+		}
+	}
+
+	/// Times out the requests the LSP didn't answer in time, generating an
+	/// [`LSPS1ClientEvent::RequestTimedOut`] event for each of them.
+	pub(crate) fn timer_tick_occurred(&self) {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		for (counterparty_node_id, inner_state_lock) in outer_state_lock.iter() {
+			let mut peer_state_lock = inner_state_lock.lock().unwrap();
+			let timed_out_channels =
+				peer_state_lock.timer_tick_occurred(self.request_timeout_ticks);
+			if timed_out_channels.is_empty() {
+				continue;
+			}
+
+			for id in timed_out_channels {
+				self.pending_events.enqueue(Event::LSPS1Client(
+					LSPS1ClientEvent::RequestTimedOut {
+						id,
+						counterparty_node_id: *counterparty_node_id,
+					},
+				));
+			}
+			// We can't do much about persistence failures here, but will retry on the next change.
+			let _ = persist_peer_state(&self.kv_store, counterparty_node_id, &*peer_state_lock);
+		}
+	}
+
+	pub(crate) fn peer_disconnected(&self, counterparty_node_id: &PublicKey) {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		if let Some(inner_state_lock) = outer_state_lock.get(counterparty_node_id) {
+			let mut peer_state_lock = inner_state_lock.lock().unwrap();
+			let dropped_channels = peer_state_lock.peer_disconnected();
+			if dropped_channels.is_empty() {
+				return;
+			}
+
+			for id in dropped_channels {
+				self.pending_events.enqueue(Event::LSPS1Client(
+					LSPS1ClientEvent::PeerDisconnected {
+						id,
+						counterparty_node_id: *counterparty_node_id,
+					},
+				));
 			}
+			// We can't do much about persistence failures here, but will retry on the next change.
+			let _ = persist_peer_state(&self.kv_store, counterparty_node_id, &*peer_state_lock);
 		}
 	}
 }
 
-impl<ES: Deref, CM: Deref + Clone, MQ: Deref, C: Deref> ProtocolMessageHandler
-	for LSPS1ClientHandler<ES, CM, MQ, C>
+impl<ES: Deref, MQ: Deref, K: Deref> ProtocolMessageHandler for LSPS1ClientHandler<ES, MQ, K>
 where
 	ES::Target: EntropySource,
-	CM::Target: AChannelManager,
 	MQ::Target: MessageQueue,
-	C::Target: Filter,
+	K::Target: KVStore,
 {
 	type ProtocolMessage = LSPS1Message;
 	const PROTOCOL_NUMBER: Option<u16> = Some(1);
@@ -624,23 +915,42 @@ where
 		&self, message: Self::ProtocolMessage, counterparty_node_id: &PublicKey,
 	) -> Result<(), LightningError> {
 		match message {
-			LSPS1Message::Response(request_id, response) => match response {
-				LSPS1Response::GetInfo(params) => {
-					self.handle_get_info_response(request_id, counterparty_node_id, params)
-				}
-				LSPS1Response::CreateOrder(params) => {
-					self.handle_create_order_response(request_id, counterparty_node_id, params)
-				}
-				LSPS1Response::CreateOrderError(params) => {
-					self.handle_create_order_error(request_id, counterparty_node_id, params)
-				}
-				LSPS1Response::GetOrder(params) => {
-					self.handle_get_order_response(request_id, counterparty_node_id, params)
-				}
-				LSPS1Response::GetOrderError(error) => {
-					self.handle_get_order_error(request_id, counterparty_node_id, error)
+			LSPS1Message::Response(request_id, response) => {
+				let res = match response {
+					LSPS1Response::GetInfo(params) => {
+						self.handle_get_info_response(request_id, counterparty_node_id, params)
+					}
+					LSPS1Response::GetInfoError(error) => {
+						self.handle_get_info_error(request_id, counterparty_node_id, error)
+					}
+					LSPS1Response::CreateOrder(params) => {
+						self.handle_create_order_response(request_id, counterparty_node_id, params)
+					}
+					LSPS1Response::CreateOrderError(params) => {
+						self.handle_create_order_error(request_id, counterparty_node_id, params)
+					}
+					LSPS1Response::GetOrder(params) => {
+						self.handle_get_order_response(request_id, counterparty_node_id, params)
+					}
+					LSPS1Response::GetOrderError(error) => {
+						self.handle_get_order_error(request_id, counterparty_node_id, error)
+					}
+				};
+
+				// Responses may have advanced or removed an order either way, so we persist the
+				// resulting state before reporting back.
+				let outer_state_lock = self.per_peer_state.read().unwrap();
+				if let Some(inner_state_lock) = outer_state_lock.get(counterparty_node_id) {
+					let peer_state = inner_state_lock.lock().unwrap();
+					persist_peer_state(&self.kv_store, counterparty_node_id, &*peer_state)
+						.map_err(|e| LightningError {
+							err: format!("{:?}", e),
+							action: ErrorAction::IgnoreAndLog(Level::Error),
+						})?;
 				}
-			},
+
+				res
+			}
 			_ => {
 				debug_assert!(
 					false,
@@ -651,3 +961,109 @@ where
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn test_order() -> OrderParams {
+		OrderParams {
+			api_version: 1,
+			lsp_balance_sat: 100_000,
+			client_balance_sat: 0,
+			confirms_within_blocks: 6,
+			channel_expiry_blocks: 144,
+			token: "".to_string(),
+			refund_onchain_address: None,
+			announce_channel: false,
+		}
+	}
+
+	#[test]
+	fn order_status_can_be_polled_repeatedly() {
+		let order_id = OrderId("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb".to_string());
+		let mut channel = InboundCRChannel::new(42);
+		channel.state = InboundRequestState::OrderRequested { version: 1, order: test_order() };
+		channel.order_received(&test_order(), order_id.clone()).unwrap();
+
+		// Polling before the order was created, or for a different order, is refused.
+		let mut unordered_channel = InboundCRChannel::new(43);
+		assert!(unordered_channel.pay_for_channel(43, &order_id).is_err());
+		let other_order_id = OrderId("cccccccccccccccccccccccccccccccc".to_string());
+		assert!(channel.pay_for_channel(42, &other_order_id).is_err());
+		assert_eq!(
+			channel.state,
+			InboundRequestState::PendingPayment { order_id: order_id.clone() }
+		);
+
+		channel.pay_for_channel(42, &order_id).unwrap();
+		channel.pay_for_channel(42, &order_id).unwrap();
+		assert_eq!(
+			channel.state,
+			InboundRequestState::AwaitingConfirmation { id: 42, order_id: order_id.clone() }
+		);
+	}
+
+	#[test]
+	fn unanswered_requests_time_out() {
+		let order_id = OrderId("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb".to_string());
+		let mut peer_state = PeerState::default();
+
+		peer_state.insert_inbound_channel(42, InboundCRChannel::new(42));
+		peer_state.insert_request(RequestId("aaa".to_string()), 42);
+
+		let mut polled_channel = InboundCRChannel::new(43);
+		polled_channel.state =
+			InboundRequestState::AwaitingConfirmation { id: 43, order_id: order_id.clone() };
+		peer_state.insert_inbound_channel(43, polled_channel);
+		peer_state.insert_request(RequestId("bbb".to_string()), 43);
+
+		assert!(peer_state.timer_tick_occurred(1).is_empty());
+
+		// An answered request doesn't time out.
+		peer_state.insert_request(RequestId("ccc".to_string()), 42);
+		assert_eq!(peer_state.remove_request(&RequestId("ccc".to_string())), Some(42));
+
+		let mut timed_out_channels = peer_state.timer_tick_occurred(1);
+		timed_out_channels.sort();
+		assert_eq!(timed_out_channels, vec![42, 43]);
+		assert!(peer_state.request_to_cid.is_empty());
+		assert!(peer_state.request_ticks.is_empty());
+
+		// The order we are awaiting confirmation for is still tracked.
+		assert!(!peer_state.inbound_channels_by_id.contains_key(&42));
+		assert_eq!(
+			peer_state.inbound_channels_by_id.get(&43).unwrap().state,
+			InboundRequestState::AwaitingConfirmation { id: 43, order_id }
+		);
+	}
+
+	#[test]
+	fn disconnection_drops_requests_in_flight() {
+		let order_id = OrderId("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb".to_string());
+		let mut peer_state = PeerState::default();
+
+		peer_state.insert_inbound_channel(42, InboundCRChannel::new(42));
+		peer_state.insert_request(RequestId("aaa".to_string()), 42);
+
+		let mut polled_channel = InboundCRChannel::new(43);
+		polled_channel.state =
+			InboundRequestState::AwaitingConfirmation { id: 43, order_id: order_id.clone() };
+		peer_state.insert_inbound_channel(43, polled_channel);
+		peer_state.insert_request(RequestId("bbb".to_string()), 43);
+
+		let mut dropped_channels = peer_state.peer_disconnected();
+		dropped_channels.sort();
+		assert_eq!(dropped_channels, vec![42, 43]);
+		assert!(peer_state.request_to_cid.is_empty());
+		assert!(peer_state.request_ticks.is_empty());
+
+		// The order we are awaiting confirmation for is still tracked.
+		assert!(!peer_state.inbound_channels_by_id.contains_key(&42));
+		assert_eq!(
+			peer_state.inbound_channels_by_id.get(&43).unwrap().state,
+			InboundRequestState::AwaitingConfirmation { id: 43, order_id }
+		);
+		assert!(peer_state.peer_disconnected().is_empty());
+	}
+}
@@ -1,8 +1,10 @@
 //! Contains LSPS1 event types
 
-use super::msgs::{ChannelInfo, OptionsSupported, OrderId, OrderParams, OrderPayment};
+use super::msgs::{
+	ChannelInfo, OnchainPayment, OptionsSupported, OrderId, OrderParams, OrderPayment, OrderState,
+};
 
-use crate::lsps0::msgs::RequestId;
+use crate::lsps0::msgs::{RequestId, ResponseError};
 use crate::prelude::String;
 
 use bitcoin::secp256k1::PublicKey;
@@ -10,64 +12,254 @@ use bitcoin::secp256k1::PublicKey;
 /// An event which an LSPS1 client should take some action in response to.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum LSPS1ClientEvent {
-	/// TODO
+	/// Information from the LSP about the channel options it supports.
+	///
+	/// You may call [`LSPS1ClientHandler::place_order`] with an order within the bounds of the
+	/// given options if you wish to proceed buying a channel.
+	///
+	/// [`LSPS1ClientHandler::place_order`]: crate::lsps1::client::LSPS1ClientHandler::place_order
 	GetInfoResponse {
-		/// TODO
+		/// The `channel_id` value passed in to [`LSPS1ClientHandler::request_for_info`].
+		///
+		/// [`LSPS1ClientHandler::request_for_info`]: crate::lsps1::client::LSPS1ClientHandler::request_for_info
 		id: u128,
-		/// TODO
+		/// The identifier of the request this is a response to.
 		request_id: RequestId,
-		/// TODO
+		/// The node id of the LSP that provided this response.
 		counterparty_node_id: PublicKey,
-		/// TODO
+		/// The LSPS1 version that will be used for the channel request.
 		version: u16,
-		/// TODO
+		/// The website of the LSP.
 		website: String,
-		/// TODO
+		/// The channel options supported by the LSP.
 		options_supported: OptionsSupported,
 	},
-	/// TODO
+	/// The LSP returned an error when queried for the channel options it supports.
+	///
+	/// The channel request has been dropped. A new one may be started via
+	/// [`LSPS1ClientHandler::request_for_info`].
+	///
+	/// [`LSPS1ClientHandler::request_for_info`]: crate::lsps1::client::LSPS1ClientHandler::request_for_info
+	GetInfoRequestFailed {
+		/// The `channel_id` value passed in to [`LSPS1ClientHandler::request_for_info`].
+		///
+		/// [`LSPS1ClientHandler::request_for_info`]: crate::lsps1::client::LSPS1ClientHandler::request_for_info
+		id: u128,
+		/// The node id of the LSP that returned the error.
+		counterparty_node_id: PublicKey,
+		/// The error returned by the LSP.
+		error: ResponseError,
+	},
+	/// The LSP created the order and awaits its payment.
+	///
+	/// The payment details should be shown to the user, who may pay the order via the given
+	/// `payment` options. Afterwards, the order status may be polled via
+	/// [`LSPS1ClientHandler::check_order_status`].
+	///
+	/// [`LSPS1ClientHandler::check_order_status`]: crate::lsps1::client::LSPS1ClientHandler::check_order_status
 	DisplayOrder {
-		/// TODO
+		/// The `channel_id` value passed in to [`LSPS1ClientHandler::request_for_info`].
+		///
+		/// [`LSPS1ClientHandler::request_for_info`]: crate::lsps1::client::LSPS1ClientHandler::request_for_info
 		id: u128,
-		/// TODO
+		/// The node id of the LSP that created the order.
 		counterparty_node_id: PublicKey,
-		/// TODO
+		/// The id the LSP assigned to the order.
+		order_id: OrderId,
+		/// The parameters of the created order.
 		order: OrderParams,
-		/// TODO
+		/// Details on how to pay for the order.
+		payment: OrderPayment,
+		/// Information about the ordered channel, if it was already opened.
+		channel: Option<ChannelInfo>,
+	},
+	/// The LSP refused to create the order placed via [`LSPS1ClientHandler::place_order`].
+	///
+	/// The channel request has been dropped. A new one may be started via
+	/// [`LSPS1ClientHandler::request_for_info`].
+	///
+	/// [`LSPS1ClientHandler::place_order`]: crate::lsps1::client::LSPS1ClientHandler::place_order
+	/// [`LSPS1ClientHandler::request_for_info`]: crate::lsps1::client::LSPS1ClientHandler::request_for_info
+	OrderRequestFailed {
+		/// The `channel_id` value passed in to [`LSPS1ClientHandler::request_for_info`].
+		///
+		/// [`LSPS1ClientHandler::request_for_info`]: crate::lsps1::client::LSPS1ClientHandler::request_for_info
+		id: u128,
+		/// The node id of the LSP that refused the order.
+		counterparty_node_id: PublicKey,
+		/// The error returned by the LSP.
+		error: ResponseError,
+	},
+	/// The current status of an order, as queried via
+	/// [`LSPS1ClientHandler::check_order_status`].
+	///
+	/// [`LSPS1ClientHandler::check_order_status`]: crate::lsps1::client::LSPS1ClientHandler::check_order_status
+	OrderStatus {
+		/// The `channel_id` value passed in to [`LSPS1ClientHandler::request_for_info`].
+		///
+		/// [`LSPS1ClientHandler::request_for_info`]: crate::lsps1::client::LSPS1ClientHandler::request_for_info
+		id: u128,
+		/// The node id of the LSP that provided this response.
+		counterparty_node_id: PublicKey,
+		/// The id of the queried order.
+		order_id: OrderId,
+		/// The current state of the order.
+		order_state: OrderState,
+		/// The current payment state of the order.
 		payment: OrderPayment,
-		/// TODO
+		/// Information about the ordered channel, if it was already opened.
 		channel: Option<ChannelInfo>,
 	},
+	/// The LSP returned an error when queried via [`LSPS1ClientHandler::check_order_status`].
+	///
+	/// The order is still tracked and its status may be polled again.
+	///
+	/// [`LSPS1ClientHandler::check_order_status`]: crate::lsps1::client::LSPS1ClientHandler::check_order_status
+	OrderStatusRequestFailed {
+		/// The `channel_id` value passed in to [`LSPS1ClientHandler::request_for_info`].
+		///
+		/// [`LSPS1ClientHandler::request_for_info`]: crate::lsps1::client::LSPS1ClientHandler::request_for_info
+		id: u128,
+		/// The node id of the LSP that returned the error.
+		counterparty_node_id: PublicKey,
+		/// The id of the queried order.
+		order_id: OrderId,
+		/// The error returned by the LSP.
+		error: ResponseError,
+	},
+	/// The LSP didn't answer one of our requests in time.
+	///
+	/// If the request was made via [`LSPS1ClientHandler::check_order_status`], the order is still
+	/// tracked and its status may be polled again. Otherwise, the channel request has been dropped
+	/// and a new one may be started via [`LSPS1ClientHandler::request_for_info`]. See
+	/// [`LiquidityClientConfig::request_timeout_ticks`] for more information.
+	///
+	/// [`LSPS1ClientHandler::check_order_status`]: crate::lsps1::client::LSPS1ClientHandler::check_order_status
+	/// [`LSPS1ClientHandler::request_for_info`]: crate::lsps1::client::LSPS1ClientHandler::request_for_info
+	/// [`LiquidityClientConfig::request_timeout_ticks`]: crate::LiquidityClientConfig::request_timeout_ticks
+	RequestTimedOut {
+		/// The `channel_id` value passed in to [`LSPS1ClientHandler::request_for_info`].
+		///
+		/// [`LSPS1ClientHandler::request_for_info`]: crate::lsps1::client::LSPS1ClientHandler::request_for_info
+		id: u128,
+		/// The node id of the LSP that didn't answer.
+		counterparty_node_id: PublicKey,
+	},
+	/// One of our requests was dropped as we disconnected from the LSP before it answered.
+	///
+	/// As for [`LSPS1ClientEvent::RequestTimedOut`], orders we already placed are still tracked and
+	/// their status may be polled again via [`LSPS1ClientHandler::check_order_status`] once the
+	/// connection was re-established, while other channel requests have been dropped.
+	///
+	/// [`LSPS1ClientHandler::check_order_status`]: crate::lsps1::client::LSPS1ClientHandler::check_order_status
+	PeerDisconnected {
+		/// The `channel_id` value passed in to [`LSPS1ClientHandler::request_for_info`].
+		///
+		/// [`LSPS1ClientHandler::request_for_info`]: crate::lsps1::client::LSPS1ClientHandler::request_for_info
+		id: u128,
+		/// The node id of the LSP we disconnected from.
+		counterparty_node_id: PublicKey,
+	},
 }
 
 /// An event which an LSPS1 server should take some action in response to.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum LSPS1ServiceEvent {
-	/// TODO
+	/// A client placed an order for a channel.
+	///
+	/// You must generate the payment details for the order and pass them to
+	/// [`LSPS1ServiceHandler::send_invoice_for_order`] if you wish to accept it.
+	///
+	/// If an unrecognized or stale token is provided you can use
+	/// [`LSPS1ServiceHandler::invalid_token_provided`], or [`LSPS1ServiceHandler::client_rejected`]
+	/// to otherwise refuse the order.
+	///
+	/// [`LSPS1ServiceHandler::send_invoice_for_order`]: crate::lsps1::service::LSPS1ServiceHandler::send_invoice_for_order
+	/// [`LSPS1ServiceHandler::invalid_token_provided`]: crate::lsps1::service::LSPS1ServiceHandler::invalid_token_provided
+	/// [`LSPS1ServiceHandler::client_rejected`]: crate::lsps1::service::LSPS1ServiceHandler::client_rejected
 	CreateInvoice {
-		/// TODO
+		/// An identifier that must be passed to [`LSPS1ServiceHandler::send_invoice_for_order`].
+		///
+		/// [`LSPS1ServiceHandler::send_invoice_for_order`]: crate::lsps1::service::LSPS1ServiceHandler::send_invoice_for_order
 		request_id: RequestId,
-		/// TODO
+		/// The node id of the client making the order.
 		counterparty_node_id: PublicKey,
-		/// TODO
+		/// The channel parameters requested by the client.
 		order: OrderParams,
 	},
-	/// TODO
+	/// A client queried the status of one of its orders.
+	///
+	/// You must pass the current state of the order, including information on the channel if it
+	/// was already opened, to [`LSPS1ServiceHandler::update_order_status`].
+	///
+	/// [`LSPS1ServiceHandler::update_order_status`]: crate::lsps1::service::LSPS1ServiceHandler::update_order_status
 	CheckPaymentConfirmation {
-		/// TODO
+		/// An identifier that must be passed to [`LSPS1ServiceHandler::update_order_status`].
+		///
+		/// [`LSPS1ServiceHandler::update_order_status`]: crate::lsps1::service::LSPS1ServiceHandler::update_order_status
 		request_id: RequestId,
-		/// TODO
+		/// The node id of the client querying the order.
 		counterparty_node_id: PublicKey,
-		/// TODO
+		/// The id of the queried order.
 		order_id: OrderId,
 	},
-	/// TODO
+	/// An order has been paid, i.e., its on-chain payment is sufficiently confirmed or its
+	/// Lightning payment was claimed, and the requested channel should now be opened.
+	OpenChannel {
+		/// The node id of the client that placed the order.
+		counterparty_node_id: PublicKey,
+		/// The id of the paid order.
+		order_id: OrderId,
+		/// The parameters of the channel that was ordered.
+		order: OrderParams,
+	},
+	/// Opening the channel for a paid order automatically failed.
+	///
+	/// The channel should now be opened manually, as after a [`LSPS1ServiceEvent::OpenChannel`]
+	/// event. See [`LSPS1ServiceConfig::auto_open_channels`] for more information.
+	///
+	/// [`LSPS1ServiceConfig::auto_open_channels`]: crate::lsps1::service::LSPS1ServiceConfig::auto_open_channels
+	ChannelOpenFailed {
+		/// The node id of the client that placed the order.
+		counterparty_node_id: PublicKey,
+		/// The id of the paid order.
+		order_id: OrderId,
+		/// The parameters of the channel that was ordered.
+		order: OrderParams,
+		/// A description of why the channel couldn't be opened.
+		error: String,
+	},
+	/// The on-chain payment of an order reached the required number of confirmations, but
+	/// doesn't cover the order total.
+	///
+	/// The order remains unpaid. Only the first payment to the order's on-chain address is
+	/// tracked, so further payments topping it up are neither detected nor summed up. Once the
+	/// order expires, a [`LSPS1ServiceEvent::Refund`] event will be emitted for the payment.
+	Underpaid {
+		/// The node id of the client that placed the order.
+		counterparty_node_id: PublicKey,
+		/// The id of the underpaid order.
+		order_id: OrderId,
+		/// The insufficient on-chain payment.
+		onchain_payment: OnchainPayment,
+		/// The amount the order needs to be paid with, in satoshis.
+		order_total_sat: u64,
+	},
+	/// An order expired after the client already paid for it, so the payment should be refunded.
+	///
+	/// Once the payment was refunded, you should call
+	/// [`LSPS1ServiceHandler::payment_refunded`].
+	///
+	/// [`LSPS1ServiceHandler::payment_refunded`]: crate::lsps1::service::LSPS1ServiceHandler::payment_refunded
 	Refund {
-		/// TODO
-		request_id: RequestId,
-		/// TODO
+		/// The node id of the client that placed the order.
 		counterparty_node_id: PublicKey,
-		/// TODO
+		/// The id of the expired order.
 		order_id: OrderId,
+		/// The on-chain payment that should be refunded, or `None` if the held Lightning payment
+		/// of the order should be failed back.
+		onchain_payment: Option<OnchainPayment>,
+		/// The address the client asked refunds to be sent to, if any.
+		refund_onchain_address: Option<String>,
 	},
 }
@@ -3,6 +3,11 @@
 use crate::lsps0::msgs::{LSPSMessage, RequestId, ResponseError};
 use crate::prelude::{String, Vec};
 
+use lightning::io;
+use lightning::ln::msgs::DecodeError;
+use lightning::util::ser::{Readable, Writeable, Writer};
+use lightning::{impl_writeable_tlv_based, impl_writeable_tlv_based_enum};
+
 use serde::{Deserialize, Serialize};
 
 use chrono::Utc;
@@ -13,16 +18,29 @@ pub(crate) const LSPS1_GET_INFO_METHOD_NAME: &str = "lsps1.get_info";
 pub(crate) const LSPS1_CREATE_ORDER_METHOD_NAME: &str = "lsps1.create_order";
 pub(crate) const LSPS1_GET_ORDER_METHOD_NAME: &str = "lsps1.get_order";
 
-pub(crate) const LSPS1_CREATE_ORDER_REQUEST_INVALID_PARAMS_ERROR_CODE: i32 = -32602;
 pub(crate) const LSPS1_CREATE_ORDER_REQUEST_ORDER_MISMATCH_ERROR_CODE: i32 = 1000;
 pub(crate) const LSPS1_CREATE_ORDER_REQUEST_CLIENT_REJECTED_ERROR_CODE: i32 = 1001;
 pub(crate) const LSPS1_CREATE_ORDER_REQUEST_INVALID_VERSION_ERROR_CODE: i32 = 1;
 pub(crate) const LSPS1_CREATE_ORDER_REQUEST_INVALID_TOKEN_ERROR_CODE: i32 = 2;
 
+pub(crate) const LSPS1_GET_ORDER_REQUEST_ORDER_NOT_FOUND_ERROR_CODE: i32 = -32602;
+
 /// The identifier of an order.
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Hash)]
 pub struct OrderId(pub String);
 
+impl Writeable for OrderId {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		self.0.write(writer)
+	}
+}
+
+impl Readable for OrderId {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		Ok(Self(Readable::read(reader)?))
+	}
+}
+
 /// A request made to an LSP to retrieve the supported options.
 ///
 /// Please refer to the [LSPS1 specification](https://github.com/BitcoinAndLightningLayerSpecs/lsp/tree/main/LSPS1#1-lsps1info)
@@ -59,6 +77,20 @@ pub struct OptionsSupported {
 	pub max_channel_balance_sat: u64,
 }
 
+impl_writeable_tlv_based!(OptionsSupported, {
+	(0, minimum_channel_confirmations, required),
+	(2, minimum_onchain_payment_confirmations, required),
+	(4, supports_zero_channel_reserve, required),
+	(6, min_onchain_payment_size_sat, option),
+	(8, max_channel_expiry_blocks, required),
+	(10, min_initial_client_balance_sat, required),
+	(12, max_initial_client_balance_sat, required),
+	(14, min_initial_lsp_balance_sat, required),
+	(16, max_initial_lsp_balance_sat, required),
+	(18, min_channel_balance_sat, required),
+	(20, max_channel_balance_sat, required),
+});
+
 /// A response to an [`GetInfoRequest`].
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct GetInfoResponse {
@@ -106,6 +138,17 @@ pub struct OrderParams {
 	pub announce_channel: bool,
 }
 
+impl_writeable_tlv_based!(OrderParams, {
+	(0, api_version, required),
+	(2, lsp_balance_sat, required),
+	(4, client_balance_sat, required),
+	(6, confirms_within_blocks, required),
+	(8, channel_expiry_blocks, required),
+	(10, token, required),
+	(12, refund_onchain_address, option),
+	(14, announce_channel, required),
+});
+
 /// A response to a [`CreateOrderRequest`].
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct CreateOrderResponse {
@@ -138,6 +181,13 @@ pub enum OrderState {
 	Failed,
 }
 
+impl_writeable_tlv_based_enum!(OrderState,
+	(0, Requested) => {},
+	(2, Created) => {},
+	(4, Completed) => {},
+	(6, Failed) => {};
+);
+
 /// Details regarding how to pay for an order.
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct OrderPayment {
@@ -162,6 +212,17 @@ pub struct OrderPayment {
 	pub onchain_payment: OnchainPayment,
 }
 
+impl_writeable_tlv_based!(OrderPayment, {
+	(0, state, required),
+	(2, fee_total_sat, required),
+	(4, order_total_sat, required),
+	(6, bolt11_invoice, required),
+	(8, onchain_address, required),
+	(10, onchain_block_confirmations_required, required),
+	(12, minimum_fee_for_0conf, required),
+	(14, onchain_payment, required),
+});
+
 /// The state of an [`OrderPayment`].
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum PaymentState {
@@ -175,6 +236,13 @@ pub enum PaymentState {
 	Refunded,
 }
 
+impl_writeable_tlv_based_enum!(PaymentState,
+	(0, ExpectPayment) => {},
+	(2, Hold) => {},
+	(4, Paid) => {},
+	(6, Refunded) => {};
+);
+
 /// Details regarding a detected on-chain payment.
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct OnchainPayment {
@@ -186,6 +254,12 @@ pub struct OnchainPayment {
 	pub confirmed: bool,
 }
 
+impl_writeable_tlv_based!(OnchainPayment, {
+	(0, outpoint, required),
+	(2, sat, required),
+	(4, confirmed, required),
+});
+
 /// Details regarding the state of an ordered channel.
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct ChannelInfo {
@@ -205,6 +279,16 @@ pub struct ChannelInfo {
 	pub closed_at: Option<String>,
 }
 
+impl_writeable_tlv_based!(ChannelInfo, {
+	(0, state, required),
+	(2, funded_at, required),
+	(4, funding_outpoint, required),
+	(6, scid, option),
+	(8, expires_at, required),
+	(10, closing_transaction, option),
+	(12, closed_at, option),
+});
+
 /// The current state of an ordered channel.
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum ChannelState {
@@ -216,6 +300,12 @@ pub enum ChannelState {
 	Closed,
 }
 
+impl_writeable_tlv_based_enum!(ChannelState,
+	(0, Opening) => {},
+	(2, Opened) => {},
+	(4, Closed) => {};
+);
+
 /// A request made to an LSP to retrieve information about an previously made order.
 ///
 /// Please refer to the [LSPS1 specification](https://github.com/BitcoinAndLightningLayerSpecs/lsp/tree/main/LSPS1#21-lsps1get_order)
@@ -260,6 +350,8 @@ impl LSPS1Request {
 pub enum LSPS1Response {
 	/// A successful response to a [`GetInfoRequest`].
 	GetInfo(GetInfoResponse),
+	/// An error response to a [`GetInfoRequest`].
+	GetInfoError(ResponseError),
 	/// A successful response to a [`CreateOrderRequest`].
 	CreateOrder(CreateOrderResponse),
 	/// An error response to a [`CreateOrderRequest`].
@@ -11,44 +11,104 @@
 
 use super::event::LSPS1ServiceEvent;
 use super::msgs::{
-	ChannelInfo, CreateOrderRequest, CreateOrderResponse, GetInfoResponse, GetOrderRequest,
-	GetOrderResponse, LSPS1Message, LSPS1Request, LSPS1Response, OptionsSupported, OrderId,
-	OrderParams, OrderPayment, OrderState, LSPS1_CREATE_ORDER_REQUEST_INVALID_VERSION_ERROR_CODE,
+	ChannelInfo, ChannelState, CreateOrderRequest, CreateOrderResponse, GetInfoResponse,
+	GetOrderRequest, GetOrderResponse, LSPS1Message, LSPS1Request, LSPS1Response, OnchainPayment,
+	OptionsSupported, OrderId, OrderParams, OrderPayment, OrderState, PaymentState,
+	LSPS1_CREATE_ORDER_REQUEST_CLIENT_REJECTED_ERROR_CODE,
+	LSPS1_CREATE_ORDER_REQUEST_INVALID_TOKEN_ERROR_CODE,
+	LSPS1_CREATE_ORDER_REQUEST_INVALID_VERSION_ERROR_CODE,
 	LSPS1_CREATE_ORDER_REQUEST_ORDER_MISMATCH_ERROR_CODE,
+	LSPS1_GET_ORDER_REQUEST_ORDER_NOT_FOUND_ERROR_CODE,
 };
-use super::utils::is_valid;
+use super::utils::{is_valid, TrackedTransaction};
 use crate::message_queue::MessageQueue;
+use crate::persist::{
+	persist_peer_state, read_peer_states, PersistedPeerState,
+	LSPS1_SERVICE_PERSISTENCE_PRIMARY_NAMESPACE, LSPS1_SERVICE_PERSISTENCE_SECONDARY_NAMESPACE,
+};
 
+use crate::errors::LiquidityError;
 use crate::events::EventQueue;
-use crate::lsps0::msgs::{ProtocolMessageHandler, RequestId};
-use crate::prelude::{HashMap, String, ToString};
+use crate::lsps0::msgs::{
+	ProtocolMessageHandler, RequestId, JSONRPC_INTERNAL_ERROR_CODE, JSONRPC_RATE_LIMITED_ERROR_CODE,
+};
+use crate::prelude::{HashMap, String, ToString, Vec};
 use crate::sync::{Arc, Mutex, RwLock};
 use crate::utils;
 use crate::{events::Event, lsps0::msgs::ResponseError};
 
+use lightning::chain::transaction::TransactionData;
 use lightning::chain::Filter;
+use lightning::events::ClosureReason;
+use lightning::io;
 use lightning::ln::channelmanager::AChannelManager;
-use lightning::ln::msgs::{ErrorAction, LightningError};
+use lightning::ln::msgs::{DecodeError, ErrorAction, LightningError};
+use lightning::ln::ChannelId;
 use lightning::sign::EntropySource;
 use lightning::util::errors::APIError;
 use lightning::util::logger::Level;
+use lightning::util::persist::KVStore;
+use lightning::util::ser::{Readable, RequiredWrapper, Writeable, Writer};
+use lightning::{
+	impl_writeable_tlv_based, impl_writeable_tlv_based_enum, read_tlv_fields, write_tlv_fields,
+};
 
 use bitcoin::secp256k1::PublicKey;
+use bitcoin::{Address, BlockHash, BlockHeader, OutPoint, Script, Txid};
 
-use chrono::Utc;
+use chrono::{TimeZone, Utc};
 use core::ops::Deref;
+use core::str::FromStr;
+
+#[cfg(feature = "std")]
+use std::time::{SystemTime, UNIX_EPOCH};
 
 const SUPPORTED_SPEC_VERSIONS: [u16; 1] = [1];
 
+// The expected time between two blocks, used to derive the datetime until which a channel is
+// guaranteed to stay open from the ordered `channel_expiry_blocks`.
+const MINUTES_PER_BLOCK: i64 = 10;
+
+/// The recommended value for [`LSPS1ServiceConfig::max_pending_requests_per_peer`].
+pub const DEFAULT_MAX_PENDING_REQUESTS_PER_PEER: usize = 10;
+
 /// Server-side configuration options for LSPS1 channel requests.
 #[derive(Clone, Debug)]
 pub struct LSPS1ServiceConfig {
 	/// A token to be send with each channel request.
 	pub token: Option<String>,
 	/// The options supported by the LSP.
+	///
+	/// If unset, all `get_info` and `create_order` requests are answered with an error.
 	pub options_supported: Option<OptionsSupported>,
 	/// The LSP's website.
+	///
+	/// If unset, all `get_info` requests are answered with an error.
 	pub website: Option<String>,
+	/// Whether the channels for paid orders should be opened automatically.
+	///
+	/// If set, the channel will be opened via the [`AChannelManager`] with the ordered parameters
+	/// as soon as the order is paid, i.e., its on-chain payment is sufficiently confirmed or its
+	/// Lightning payment was claimed, rather than emitting a [`LSPS1ServiceEvent::OpenChannel`]
+	/// event. If opening the channel fails, a [`LSPS1ServiceEvent::ChannelOpenFailed`] event is
+	/// emitted instead, so that it can be handled manually.
+	///
+	/// In this mode, the [`Event::ChannelReady`] and [`Event::ChannelClosed`] event parameters need
+	/// to be forwarded to [`LSPS1ServiceHandler::channel_ready`] and
+	/// [`LSPS1ServiceHandler::channel_closed`] to have the order completed, or learn that the
+	/// channel couldn't be opened after all.
+	///
+	/// [`Event::ChannelReady`]: lightning::events::Event::ChannelReady
+	/// [`Event::ChannelClosed`]: lightning::events::Event::ChannelClosed
+	pub auto_open_channels: bool,
+	/// The maximum number of `create_order` requests of a single peer that may await our response
+	/// at any given time.
+	///
+	/// Further requests are rejected with an error response until we responded to some of the
+	/// pending ones.
+	///
+	/// Should usually be set to [`DEFAULT_MAX_PENDING_REQUESTS_PER_PEER`].
+	pub max_pending_requests_per_peer: usize,
 }
 
 struct ChannelStateError(String);
@@ -66,6 +126,16 @@ enum OutboundRequestState {
 	Ready,
 }
 
+impl_writeable_tlv_based_enum!(OutboundRequestState,
+	(0, OrderCreated) => {
+		(0, order_id, required),
+	},
+	(2, WaitingPayment) => {
+		(0, order_id, required),
+	},
+	(4, Ready) => {};
+);
+
 impl OutboundRequestState {
 	fn create_payment_invoice(&self) -> Result<Self, ChannelStateError> {
 		match self {
@@ -73,7 +143,7 @@ impl OutboundRequestState {
 				Ok(OutboundRequestState::WaitingPayment { order_id: order_id.clone() })
 			}
 			state => Err(ChannelStateError(format!(
-				"Received unexpected get_versions response. JIT Channel was in state: {:?}",
+				"Payment invoice created when order request was in state: {:?}",
 				state
 			))),
 		}
@@ -85,13 +155,77 @@ struct OutboundLSPS1Config {
 	created_at: chrono::DateTime<Utc>,
 	expires_at: chrono::DateTime<Utc>,
 	payment: OrderPayment,
+	order_state: OrderState,
+	channel: Option<ChannelInfo>,
+}
+
+impl Writeable for OutboundLSPS1Config {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		let created_at = self.created_at.to_rfc3339();
+		let expires_at = self.expires_at.to_rfc3339();
+		write_tlv_fields!(writer, {
+			(0, self.order, required),
+			(2, created_at, required),
+			(4, expires_at, required),
+			(6, self.payment, required),
+			(8, self.order_state, required),
+			(10, self.channel, option),
+		});
+		Ok(())
+	}
+}
+
+impl Readable for OutboundLSPS1Config {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let mut order = RequiredWrapper(None);
+		let mut created_at: RequiredWrapper<String> = RequiredWrapper(None);
+		let mut expires_at: RequiredWrapper<String> = RequiredWrapper(None);
+		let mut payment = RequiredWrapper(None);
+		let mut order_state = None;
+		let mut channel = None;
+		read_tlv_fields!(reader, {
+			(0, order, required),
+			(2, created_at, required),
+			(4, expires_at, required),
+			(6, payment, required),
+			(8, order_state, option),
+			(10, channel, option),
+		});
+
+		let parse_datetime = |s: String| {
+			chrono::DateTime::parse_from_rfc3339(&s)
+				.map(|dt| dt.with_timezone(&Utc))
+				.map_err(|_| DecodeError::InvalidValue)
+		};
+
+		Ok(Self {
+			order: order.0.unwrap(),
+			created_at: parse_datetime(created_at.0.unwrap())?,
+			expires_at: parse_datetime(expires_at.0.unwrap())?,
+			payment: payment.0.unwrap(),
+			order_state: order_state.unwrap_or(OrderState::Created),
+			channel,
+		})
+	}
 }
 
 struct OutboundCRChannel {
 	state: OutboundRequestState,
 	config: OutboundLSPS1Config,
+	tracked_txs: Vec<TrackedTransaction>,
+	user_channel_id: Option<u128>,
+	/// Whether the payment of the failed order still needs to be refunded.
+	refund_pending: bool,
 }
 
+impl_writeable_tlv_based!(OutboundCRChannel, {
+	(0, state, required),
+	(2, config, required),
+	(4, tracked_txs, optional_vec),
+	(6, user_channel_id, option),
+	(8, refund_pending, (default_value, false)),
+});
+
 impl OutboundCRChannel {
 	fn new(
 		order: OrderParams, created_at: chrono::DateTime<Utc>, expires_at: chrono::DateTime<Utc>,
@@ -99,7 +233,17 @@ impl OutboundCRChannel {
 	) -> Self {
 		Self {
 			state: OutboundRequestState::OrderCreated { order_id },
-			config: OutboundLSPS1Config { order, created_at, expires_at, payment },
+			config: OutboundLSPS1Config {
+				order,
+				created_at,
+				expires_at,
+				payment,
+				order_state: OrderState::Created,
+				channel: None,
+			},
+			tracked_txs: Vec::new(),
+			user_channel_id: None,
+			refund_pending: false,
 		}
 	}
 	fn create_payment_invoice(&mut self) -> Result<(), LightningError> {
@@ -107,17 +251,256 @@ impl OutboundCRChannel {
 		Ok(())
 	}
 
-	fn check_order_validity(&self, options_supported: &OptionsSupported) -> bool {
-		let order = &self.config.order;
+	fn transactions_confirmed(
+		&mut self, block_hash: &BlockHash, txdata: &TransactionData, height: u32,
+	) -> bool {
+		let mut updated = false;
+		for (_, tx) in txdata.iter() {
+			let txid = tx.txid();
+			for tracked_tx in self.tracked_txs.iter_mut().filter(|t| t.txid == txid) {
+				tracked_tx.confirmation = Some((*block_hash, height));
+				updated = true;
+			}
+		}
+		updated
+	}
+
+	fn transaction_unconfirmed(&mut self, txid: &Txid) -> bool {
+		let mut updated = false;
+		for tracked_tx in self.tracked_txs.iter_mut().filter(|t| t.txid == *txid) {
+			if tracked_tx.confirmation.take().is_some() {
+				updated = true;
+			}
+		}
+		updated
+	}
+
+	fn block_disconnected(&mut self, height: u32) -> bool {
+		let mut updated = false;
+		for tracked_tx in self.tracked_txs.iter_mut() {
+			if matches!(tracked_tx.confirmation, Some((_, conf_height)) if conf_height >= height) {
+				tracked_tx.confirmation = None;
+				updated = true;
+			}
+		}
+		updated
+	}
+
+	/// Returns the script of the on-chain address the order may be paid to, if any.
+	fn payment_script(&self) -> Option<Script> {
+		if self.config.payment.onchain_address.is_empty() {
+			return None;
+		}
+		Address::from_str(&self.config.payment.onchain_address)
+			.ok()
+			.map(|address| address.script_pubkey())
+	}
+
+	fn onchain_payment_txid(&self) -> Option<Txid> {
+		OutPoint::from_str(&self.config.payment.onchain_payment.outpoint)
+			.ok()
+			.map(|outpoint| outpoint.txid)
+	}
 
-		is_valid(order, options_supported)
+	/// Returns whether we're still waiting on an on-chain payment to be detected for this order.
+	///
+	/// Payments to the addresses of failed orders are still detected, so that they can be refunded.
+	fn awaits_onchain_payment(&self) -> bool {
+		self.config.order_state != OrderState::Completed
+			&& self.config.payment.state == PaymentState::ExpectPayment
+			&& self.onchain_payment_txid().is_none()
+	}
+
+	/// Looks for an output paying to the order's on-chain address in the given transactions and,
+	/// if found, records it as the order's on-chain payment.
+	///
+	/// Returns the paying transaction's id and output script, if any.
+	fn detect_onchain_payment(&mut self, txdata: &TransactionData) -> Option<(Txid, Script)> {
+		if !self.awaits_onchain_payment() {
+			return None;
+		}
+
+		let payment_script = self.payment_script()?;
+		for (_, tx) in txdata.iter() {
+			for (vout, output) in tx.output.iter().enumerate() {
+				if output.script_pubkey == payment_script {
+					let outpoint = OutPoint { txid: tx.txid(), vout: vout as u32 };
+					self.config.payment.onchain_payment = OnchainPayment {
+						outpoint: outpoint.to_string(),
+						sat: output.value,
+						confirmed: false,
+					};
+					return Some((outpoint.txid, payment_script));
+				}
+			}
+		}
+		None
+	}
+
+	/// Marks the order as paid if its on-chain payment reached the required number of
+	/// confirmations at the given height and covers the order total. If the order already failed,
+	/// the payment is marked to be refunded instead.
+	///
+	/// Returns `true` if the order was newly marked as paid.
+	fn check_onchain_payment_confirmed(&mut self, best_block_height: u32) -> bool {
+		if self.config.order_state == OrderState::Completed
+			|| self.config.payment.state != PaymentState::ExpectPayment
+			|| self.config.payment.onchain_payment.confirmed
+		{
+			return false;
+		}
+
+		let payment_txid = match self.onchain_payment_txid() {
+			Some(txid) => txid,
+			None => return false,
+		};
+		let confirmations = self
+			.tracked_txs
+			.iter()
+			.find(|t| t.txid == payment_txid)
+			.map_or(0, |t| t.confirmations(best_block_height));
+		let required_confirmations =
+			u32::from(self.config.payment.onchain_block_confirmations_required).max(1);
+
+		if confirmations < required_confirmations {
+			return false;
+		}
+
+		self.config.payment.onchain_payment.confirmed = true;
+		if self.config.order_state == OrderState::Failed {
+			self.refund_pending = true;
+			return false;
+		}
+		if self.config.payment.onchain_payment.sat < self.config.payment.order_total_sat {
+			return false;
+		}
+		self.config.payment.state = PaymentState::Paid;
+		true
+	}
+
+	/// Records the given state of the order's Lightning payment.
+	///
+	/// Expected payments may be put on hold or be marked as paid, while held payments may only be
+	/// marked as paid.
+	///
+	/// Returns `true` if the order was newly marked as paid.
+	fn lightning_payment_received(
+		&mut self, payment_state: PaymentState,
+	) -> Result<bool, ChannelStateError> {
+		if self.config.order_state != OrderState::Created {
+			return Err(ChannelStateError(format!(
+				"Received Lightning payment for order in state: {:?}",
+				self.config.order_state
+			)));
+		}
+
+		match (&self.config.payment.state, &payment_state) {
+			(PaymentState::ExpectPayment, PaymentState::Hold)
+			| (PaymentState::ExpectPayment, PaymentState::Paid)
+			| (PaymentState::Hold, PaymentState::Paid) => {}
+			(state, _) => {
+				return Err(ChannelStateError(format!(
+					"Invalid payment state transition from {:?} to {:?}",
+					state, payment_state
+				)))
+			}
+		}
+
+		let paid = payment_state == PaymentState::Paid;
+		self.config.payment.state = payment_state;
+		Ok(paid)
+	}
+
+	/// Forgets about an on-chain payment which hasn't yet been considered final but got
+	/// unconfirmed by a reorg. If it confirms again, it will be re-detected.
+	///
+	/// Returns `true` if the payment was forgotten.
+	fn drop_unconfirmed_onchain_payment(&mut self) -> bool {
+		if self.config.payment.state != PaymentState::ExpectPayment {
+			return false;
+		}
+
+		let payment_txid = match self.onchain_payment_txid() {
+			Some(txid) => txid,
+			None => return false,
+		};
+		if self.tracked_txs.iter().any(|t| t.txid == payment_txid && t.confirmation.is_some()) {
+			return false;
+		}
+
+		self.tracked_txs.retain(|t| t.txid != payment_txid);
+		self.config.payment.onchain_payment =
+			OnchainPayment { outpoint: String::new(), sat: 0, confirmed: false };
+		true
+	}
+
+	/// Fails the order if it wasn't paid before its expiry. Held Lightning payments and confirmed
+	/// on-chain payments are marked to be refunded, while on-chain payments that confirm later on
+	/// will be marked once they do.
+	///
+	/// Returns `true` if the order was newly failed.
+	fn check_expiry(&mut self, now: chrono::DateTime<Utc>) -> bool {
+		if self.config.order_state != OrderState::Created || now <= self.config.expires_at {
+			return false;
+		}
+
+		match self.config.payment.state {
+			PaymentState::ExpectPayment => {
+				self.refund_pending = self.config.payment.onchain_payment.confirmed;
+			}
+			PaymentState::Hold => self.refund_pending = true,
+			PaymentState::Paid | PaymentState::Refunded => return false,
+		}
+		self.config.order_state = OrderState::Failed;
+		true
+	}
+
+	/// Records that the payment of the failed order was refunded.
+	fn payment_refunded(&mut self) -> Result<(), ChannelStateError> {
+		if !self.refund_pending {
+			return Err(ChannelStateError(format!(
+				"No refund pending for order in state {:?} with payment state {:?}",
+				self.config.order_state, self.config.payment.state
+			)));
+		}
+
+		self.config.payment.state = PaymentState::Refunded;
+		self.refund_pending = false;
+		Ok(())
+	}
+
+	fn refund_event(&self, counterparty_node_id: &PublicKey, order_id: &OrderId) -> Event {
+		let onchain_payment = if self.config.payment.state == PaymentState::Hold {
+			None
+		} else {
+			Some(self.config.payment.onchain_payment.clone())
+		};
+		Event::LSPS1Service(LSPS1ServiceEvent::Refund {
+			counterparty_node_id: *counterparty_node_id,
+			order_id: order_id.clone(),
+			onchain_payment,
+			refund_onchain_address: self.config.order.refund_onchain_address.clone(),
+		})
+	}
+
+	fn order_response(
+		&self, order_id: OrderId, order_state: OrderState, channel: Option<ChannelInfo>,
+	) -> CreateOrderResponse {
+		CreateOrderResponse {
+			order_id,
+			order: self.config.order.clone(),
+			order_state,
+			created_at: self.config.created_at,
+			expires_at: self.config.expires_at,
+			payment: self.config.payment.clone(),
+			channel,
+		}
 	}
 }
 
 #[derive(Default)]
 struct PeerState {
 	outbound_channels_by_order_id: HashMap<OrderId, OutboundCRChannel>,
-	request_to_cid: HashMap<RequestId, u128>,
 	pending_requests: HashMap<RequestId, LSPS1Request>,
 }
 
@@ -125,23 +508,33 @@ impl PeerState {
 	fn insert_outbound_channel(&mut self, order_id: OrderId, channel: OutboundCRChannel) {
 		self.outbound_channels_by_order_id.insert(order_id, channel);
 	}
+}
 
-	fn insert_request(&mut self, request_id: RequestId, channel_id: u128) {
-		self.request_to_cid.insert(request_id, channel_id);
-	}
+impl_writeable_tlv_based!(PeerState, {
+	(0, outbound_channels_by_order_id, required),
+	(not_written, pending_requests, (static_value, HashMap::new())),
+});
+
+impl PersistedPeerState for PeerState {
+	const PRIMARY_NAMESPACE: &'static str = LSPS1_SERVICE_PERSISTENCE_PRIMARY_NAMESPACE;
+	const SECONDARY_NAMESPACE: &'static str = LSPS1_SERVICE_PERSISTENCE_SECONDARY_NAMESPACE;
 
-	fn remove_outbound_channel(&mut self, order_id: OrderId) {
-		self.outbound_channels_by_order_id.remove(&order_id);
+	fn is_persistable(&self) -> bool {
+		!self.outbound_channels_by_order_id.is_empty()
 	}
 }
 
 /// The main object allowing to send and receive LSPS1 messages.
-pub struct LSPS1ServiceHandler<ES: Deref, CM: Deref + Clone, MQ: Deref, C: Deref>
+///
+/// All orders are persisted to the given [`KVStore`] upon creation and on every state change, and
+/// are reloaded from it upon construction.
+pub struct LSPS1ServiceHandler<ES: Deref, CM: Deref + Clone, MQ: Deref, C: Deref, K: Deref>
 where
 	ES::Target: EntropySource,
 	CM::Target: AChannelManager,
 	MQ::Target: MessageQueue,
 	C::Target: Filter,
+	K::Target: KVStore,
 {
 	entropy_source: ES,
 	channel_manager: CM,
@@ -149,47 +542,90 @@ where
 	pending_messages: MQ,
 	pending_events: Arc<EventQueue>,
 	per_peer_state: RwLock<HashMap<PublicKey, Mutex<PeerState>>>,
+	best_block_height: RwLock<Option<u32>>,
+	latest_block_time: RwLock<Option<chrono::DateTime<Utc>>>,
+	kv_store: K,
 	config: LSPS1ServiceConfig,
 }
 
-impl<ES: Deref, CM: Deref + Clone, MQ: Deref, C: Deref> LSPS1ServiceHandler<ES, CM, MQ, C>
+impl<ES: Deref, CM: Deref + Clone, MQ: Deref, C: Deref, K: Deref>
+	LSPS1ServiceHandler<ES, CM, MQ, C, K>
 where
 	ES::Target: EntropySource,
 	CM::Target: AChannelManager,
 	MQ::Target: MessageQueue,
 	C::Target: Filter,
 	ES::Target: EntropySource,
+	K::Target: KVStore,
 {
+	#[allow(clippy::too_many_arguments)]
 	pub(crate) fn new(
 		entropy_source: ES, pending_messages: MQ, pending_events: Arc<EventQueue>,
-		channel_manager: CM, chain_source: Option<C>, config: LSPS1ServiceConfig,
-	) -> Self {
-		Self {
+		channel_manager: CM, chain_source: Option<C>, best_block_height: Option<u32>, kv_store: K,
+		config: LSPS1ServiceConfig,
+	) -> Result<Self, io::Error> {
+		let persisted_peer_states: HashMap<PublicKey, PeerState> = read_peer_states(&kv_store)?;
+		let per_peer_state = persisted_peer_states
+			.into_iter()
+			.map(|(counterparty_node_id, peer_state)| {
+				(counterparty_node_id, Mutex::new(peer_state))
+			})
+			.collect::<HashMap<_, _>>();
+
+		// Registrations with the chain source don't survive a restart, so we need to re-register
+		// all transactions we still track.
+		if let Some(chain_source) = chain_source.as_ref() {
+			for peer_state in per_peer_state.values() {
+				let peer_state = peer_state.lock().unwrap();
+				for channel in peer_state.outbound_channels_by_order_id.values() {
+					for tracked_tx in channel.tracked_txs.iter() {
+						chain_source.register_tx(&tracked_tx.txid, &tracked_tx.script_pubkey);
+					}
+				}
+			}
+		}
+
+		Ok(Self {
 			entropy_source,
 			channel_manager,
 			chain_source,
 			pending_messages,
 			pending_events,
-			per_peer_state: RwLock::new(HashMap::new()),
+			per_peer_state: RwLock::new(per_peer_state),
+			best_block_height: RwLock::new(best_block_height),
+			latest_block_time: RwLock::new(None),
+			kv_store,
 			config,
-		}
+		})
 	}
 
 	fn handle_get_info_request(
 		&self, request_id: RequestId, counterparty_node_id: &PublicKey,
 	) -> Result<(), LightningError> {
+		let (website, options) =
+			match (self.config.website.as_ref(), self.config.options_supported.as_ref()) {
+				(Some(website), Some(options)) => (website.clone(), options.clone()),
+				_ => {
+					self.enqueue_response(
+						counterparty_node_id,
+						request_id,
+						LSPS1Response::GetInfoError(ResponseError {
+							code: JSONRPC_INTERNAL_ERROR_CODE,
+							message: "the LSP doesn't offer any channel options".to_string(),
+							data: None,
+						}),
+					);
+					return Err(LightningError {
+						err: "LSPS1 website or supported options not configured".to_string(),
+						action: ErrorAction::IgnoreAndLog(Level::Error),
+					});
+				}
+			};
+
 		let response = GetInfoResponse {
 			supported_versions: SUPPORTED_SPEC_VERSIONS.to_vec(),
-			website: self.config.website.clone().unwrap().to_string(),
-			options: self
-				.config
-				.options_supported
-				.clone()
-				.ok_or(LightningError {
-					err: format!("Configuration for LSP server not set."),
-					action: ErrorAction::IgnoreAndLog(Level::Info),
-				})
-				.unwrap(),
+			website,
+			options,
 		};
 
 		self.enqueue_response(counterparty_node_id, request_id, LSPS1Response::GetInfo(response));
@@ -215,21 +651,40 @@ where
 			});
 		}
 
-		if !is_valid(&params.order, &self.config.options_supported.as_ref().unwrap()) {
+		let options_supported = match self.config.options_supported.as_ref() {
+			Some(options_supported) => options_supported,
+			None => {
+				self.enqueue_response(
+					counterparty_node_id,
+					request_id,
+					LSPS1Response::CreateOrderError(ResponseError {
+						code: JSONRPC_INTERNAL_ERROR_CODE,
+						message: "the LSP doesn't offer any channel options".to_string(),
+						data: None,
+					}),
+				);
+				return Err(LightningError {
+					err: "LSPS1 supported options not configured".to_string(),
+					action: ErrorAction::IgnoreAndLog(Level::Error),
+				});
+			}
+		};
+
+		if !is_valid(&params.order, options_supported) {
 			self.enqueue_response(
 				counterparty_node_id,
 				request_id,
 				LSPS1Response::CreateOrderError(ResponseError {
 					code: LSPS1_CREATE_ORDER_REQUEST_ORDER_MISMATCH_ERROR_CODE,
-					message: format!("Order does not match options supported by LSP server"),
-					data: Some(format!(
-						"Supported options are {:?}",
-						&self.config.options_supported.as_ref().unwrap()
-					)),
+					message: "Order does not match options supported by LSP server".to_string(),
+					data: Some(format!("Supported options are {:?}", options_supported)),
 				}),
 			);
 			return Err(LightningError {
-				err: format!("client requested unsupported version {}", params.version),
+				err: format!(
+					"client requested an order not matching our options: {:?}",
+					params.order
+				),
 				action: ErrorAction::IgnoreAndLog(Level::Info),
 			});
 		}
@@ -241,6 +696,22 @@ where
 			.or_insert(Mutex::new(PeerState::default()));
 		let mut peer_state_lock = inner_state_lock.lock().unwrap();
 
+		if peer_state_lock.pending_requests.len() >= self.config.max_pending_requests_per_peer {
+			self.enqueue_response(
+				counterparty_node_id,
+				request_id,
+				LSPS1Response::CreateOrderError(ResponseError {
+					code: JSONRPC_RATE_LIMITED_ERROR_CODE,
+					message: "too many pending requests".to_string(),
+					data: None,
+				}),
+			);
+			return Err(LightningError {
+				err: format!("peer {} has too many pending requests", counterparty_node_id),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			});
+		}
+
 		peer_state_lock
 			.pending_requests
 			.insert(request_id.clone(), LSPS1Request::CreateOrder(params.clone()));
@@ -254,10 +725,110 @@ where
 		Ok(())
 	}
 
-	fn send_invoice_for_order(
-		&self, request_id: RequestId, counterparty_node_id: &PublicKey, payment: OrderPayment,
+	/// Used by LSP to inform a client placing an order that the token they used is invalid.
+	///
+	/// Should be called in response to receiving a [`LSPS1ServiceEvent::CreateInvoice`] event.
+	pub fn invalid_token_provided(
+		&self, counterparty_node_id: &PublicKey, request_id: RequestId,
+	) -> Result<(), LiquidityError> {
+		self.reject_create_order_request(
+			counterparty_node_id,
+			request_id,
+			ResponseError {
+				code: LSPS1_CREATE_ORDER_REQUEST_INVALID_TOKEN_ERROR_CODE,
+				message: "an unrecognized or stale token was provided".to_string(),
+				data: None,
+			},
+		)
+	}
+
+	/// Used by LSP to refuse creating an order for a client, e.g., as the client is not served by
+	/// the LSP.
+	///
+	/// Should be called in response to receiving a [`LSPS1ServiceEvent::CreateInvoice`] event.
+	pub fn client_rejected(
+		&self, counterparty_node_id: &PublicKey, request_id: RequestId,
+	) -> Result<(), LiquidityError> {
+		self.reject_create_order_request(
+			counterparty_node_id,
+			request_id,
+			ResponseError {
+				code: LSPS1_CREATE_ORDER_REQUEST_CLIENT_REJECTED_ERROR_CODE,
+				message: "the LSP rejected the order".to_string(),
+				data: None,
+			},
+		)
+	}
+
+	fn reject_create_order_request(
+		&self, counterparty_node_id: &PublicKey, request_id: RequestId, error: ResponseError,
+	) -> Result<(), LiquidityError> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+
+		match outer_state_lock.get(counterparty_node_id) {
+			Some(inner_state_lock) => {
+				let mut peer_state_lock = inner_state_lock.lock().unwrap();
+
+				match peer_state_lock.pending_requests.remove(&request_id) {
+					Some(LSPS1Request::CreateOrder(_)) => {
+						self.enqueue_response(
+							counterparty_node_id,
+							request_id,
+							LSPS1Response::CreateOrderError(error),
+						);
+						Ok(())
+					}
+					Some(request) => {
+						peer_state_lock.pending_requests.insert(request_id.clone(), request);
+						Err(APIError::APIMisuseError {
+							err: format!(
+								"No pending create_order request for request_id: {:?}",
+								request_id
+							),
+						}
+						.into())
+					}
+					None => Err(APIError::APIMisuseError {
+						err: format!(
+							"No pending create_order request for request_id: {:?}",
+							request_id
+						),
+					}
+					.into()),
+				}
+			}
+			None => Err(APIError::APIMisuseError {
+				err: format!("No state for the counterparty exists: {:?}", counterparty_node_id),
+			}
+			.into()),
+		}
+	}
+
+	/// Used by LSP to create an order and provide the client with the details on how to pay for
+	/// it.
+	///
+	/// Should be called in response to receiving a [`LSPS1ServiceEvent::CreateInvoice`] event.
+	///
+	/// If `payment` includes an on-chain address, the order's on-chain payment will be detected
+	/// and tracked automatically. Once it is sufficiently confirmed, the order is treated as paid,
+	/// just as after a call to [`Self::lightning_payment_received`]. If the confirmed payment
+	/// doesn't cover the order total, a [`LSPS1ServiceEvent::Underpaid`] event will be emitted
+	/// instead. As only the first output paying to the address is tracked, such partial payments
+	/// can't be topped up and are only refunded once the order expires.
+	///
+	/// Note that the payment is detected in the transactions of the blocks connected via
+	/// [`Listen`] or [`Confirm::transactions_confirmed`]. As [`Filter`] offers no way to register
+	/// interest in payments to a script before the paying transaction is known, chain sources
+	/// which only deliver registered transactions won't report the payment on their own. Once it
+	/// has been detected, the paying transaction is registered with the chain source to track its
+	/// confirmations.
+	///
+	/// [`Listen`]: lightning::chain::Listen
+	/// [`Confirm::transactions_confirmed`]: lightning::chain::Confirm::transactions_confirmed
+	pub fn send_invoice_for_order(
+		&self, counterparty_node_id: &PublicKey, request_id: RequestId, payment: OrderPayment,
 		created_at: chrono::DateTime<Utc>, expires_at: chrono::DateTime<Utc>,
-	) -> Result<(), APIError> {
+	) -> Result<(), LiquidityError> {
 		let outer_state_lock = self.per_peer_state.read().unwrap();
 
 		match outer_state_lock.get(counterparty_node_id) {
@@ -266,16 +837,36 @@ where
 
 				match peer_state_lock.pending_requests.remove(&request_id) {
 					Some(LSPS1Request::CreateOrder(params)) => {
+						if !payment.onchain_address.is_empty()
+							&& Address::from_str(&payment.onchain_address).is_err()
+						{
+							peer_state_lock
+								.pending_requests
+								.insert(request_id, LSPS1Request::CreateOrder(params));
+							return Err(APIError::APIMisuseError {
+								err: format!(
+									"Invalid on-chain address: {}",
+									payment.onchain_address
+								),
+							}
+							.into());
+						}
+
 						let order_id = self.generate_order_id();
 						let channel = OutboundCRChannel::new(
 							params.order.clone(),
-							created_at.clone(),
-							expires_at.clone(),
+							created_at,
+							expires_at,
 							order_id.clone(),
 							payment.clone(),
 						);
 
 						peer_state_lock.insert_outbound_channel(order_id.clone(), channel);
+						persist_peer_state(
+							&self.kv_store,
+							counterparty_node_id,
+							&*peer_state_lock,
+						)?;
 
 						self.enqueue_response(
 							counterparty_node_id,
@@ -292,10 +883,24 @@ where
 						);
 					}
 
-					_ => {
+					Some(request) => {
+						peer_state_lock.pending_requests.insert(request_id.clone(), request);
 						return Err(APIError::APIMisuseError {
-							err: format!("No pending buy request for request_id: {:?}", request_id),
-						})
+							err: format!(
+								"No pending create_order request for request_id: {:?}",
+								request_id
+							),
+						}
+						.into());
+					}
+					None => {
+						return Err(APIError::APIMisuseError {
+							err: format!(
+								"No pending create_order request for request_id: {:?}",
+								request_id
+							),
+						}
+						.into())
 					}
 				}
 			}
@@ -305,7 +910,8 @@ where
 						"No state for the counterparty exists: {:?}",
 						counterparty_node_id
 					),
-				})
+				}
+				.into())
 			}
 		}
 
@@ -316,99 +922,226 @@ where
 		&self, request_id: RequestId, counterparty_node_id: &PublicKey, params: GetOrderRequest,
 	) -> Result<(), LightningError> {
 		let outer_state_lock = self.per_peer_state.read().unwrap();
-		match outer_state_lock.get(&counterparty_node_id) {
-			Some(inner_state_lock) => {
-				let mut peer_state_lock = inner_state_lock.lock().unwrap();
-
-				let outbound_channel = peer_state_lock
-					.outbound_channels_by_order_id
-					.get_mut(&params.order_id)
-					.ok_or(LightningError {
-						err: format!(
-							"Received get order request for unknown order id {:?}",
-							params.order_id
-						),
-						action: ErrorAction::IgnoreAndLog(Level::Info),
-					})?;
+		let inner_state_lock = match outer_state_lock.get(counterparty_node_id) {
+			Some(inner_state_lock) => inner_state_lock,
+			None => return self.order_not_found(counterparty_node_id, request_id, params.order_id),
+		};
+		let mut peer_state_lock = inner_state_lock.lock().unwrap();
 
-				if let Err(e) = outbound_channel.create_payment_invoice() {
-					peer_state_lock.outbound_channels_by_order_id.remove(&params.order_id);
-					self.pending_events.enqueue(Event::LSPS1Service(LSPS1ServiceEvent::Refund {
-						request_id,
-						counterparty_node_id: *counterparty_node_id,
-						order_id: params.order_id,
-					}));
-					return Err(e);
-				}
+		let outbound_channel = match peer_state_lock
+			.outbound_channels_by_order_id
+			.get_mut(&params.order_id)
+		{
+			Some(outbound_channel) => outbound_channel,
+			None => return self.order_not_found(counterparty_node_id, request_id, params.order_id),
+		};
 
-				peer_state_lock
-					.pending_requests
-					.insert(request_id.clone(), LSPS1Request::GetOrder(params.clone()));
+		// There is nothing left to decide about failed orders, so we answer right away.
+		if outbound_channel.config.order_state == OrderState::Failed {
+			let response =
+				outbound_channel.order_response(params.order_id, OrderState::Failed, None);
+			self.enqueue_response(
+				counterparty_node_id,
+				request_id,
+				LSPS1Response::GetOrder(GetOrderResponse { response }),
+			);
+			return Ok(());
+		}
 
-				self.pending_events.enqueue(Event::LSPS1Service(
-					LSPS1ServiceEvent::CheckPaymentConfirmation {
-						request_id,
-						counterparty_node_id: *counterparty_node_id,
-						order_id: params.order_id,
-					},
-				));
-			}
-			None => {
-				return Err(LightningError {
-					err: format!("Received error response for a create order request from an unknown counterparty ({:?})",counterparty_node_id),
-					action: ErrorAction::IgnoreAndLog(Level::Info),
-				});
-			}
+		// Orders may be polled repeatedly, but only the first request moves them on to waiting
+		// for the payment.
+		if matches!(outbound_channel.state, OutboundRequestState::OrderCreated { .. }) {
+			outbound_channel.create_payment_invoice()?;
+			persist_peer_state(&self.kv_store, counterparty_node_id, &*peer_state_lock).map_err(
+				|e| LightningError {
+					err: format!("{:?}", e),
+					action: ErrorAction::IgnoreAndLog(Level::Error),
+				},
+			)?;
 		}
 
+		peer_state_lock
+			.pending_requests
+			.insert(request_id.clone(), LSPS1Request::GetOrder(params.clone()));
+
+		self.pending_events.enqueue(Event::LSPS1Service(
+			LSPS1ServiceEvent::CheckPaymentConfirmation {
+				request_id,
+				counterparty_node_id: *counterparty_node_id,
+				order_id: params.order_id,
+			},
+		));
+
 		Ok(())
 	}
 
-	fn update_order_status(
-		&self, request_id: RequestId, counterparty_node_id: PublicKey, order_id: OrderId,
+	fn order_not_found(
+		&self, counterparty_node_id: &PublicKey, request_id: RequestId, order_id: OrderId,
+	) -> Result<(), LightningError> {
+		self.enqueue_response(
+			counterparty_node_id,
+			request_id,
+			LSPS1Response::GetOrderError(ResponseError {
+				code: LSPS1_GET_ORDER_REQUEST_ORDER_NOT_FOUND_ERROR_CODE,
+				message: "Order not found".to_string(),
+				data: Some(format!("Unknown order_id: {}", order_id.0)),
+			}),
+		);
+		Err(LightningError {
+			err: format!("Received get order request for unknown order id {:?}", order_id),
+			action: ErrorAction::IgnoreAndLog(Level::Info),
+		})
+	}
+
+	/// Used by LSP to provide a client with the current status of their order.
+	///
+	/// Should be called in response to receiving a [`LSPS1ServiceEvent::CheckPaymentConfirmation`]
+	/// event. The payment details of the order are taken from the tracked order state, i.e., the
+	/// detected on-chain payment and the state of the Lightning payment as reported via
+	/// [`Self::lightning_payment_received`]. The given `order_state` and `channel` information are
+	/// reported as is, unless the order already failed, e.g., as it expired, or was completed by
+	/// opening the channel automatically.
+	pub fn update_order_status(
+		&self, counterparty_node_id: &PublicKey, request_id: RequestId, order_id: OrderId,
 		order_state: OrderState, channel: Option<ChannelInfo>,
-	) -> Result<(), APIError> {
+	) -> Result<(), LiquidityError> {
 		let outer_state_lock = self.per_peer_state.read().unwrap();
 
-		match outer_state_lock.get(&counterparty_node_id) {
+		match outer_state_lock.get(counterparty_node_id) {
 			Some(inner_state_lock) => {
 				let mut peer_state_lock = inner_state_lock.lock().unwrap();
 
+				match peer_state_lock.pending_requests.remove(&request_id) {
+					Some(LSPS1Request::GetOrder(params)) if params.order_id == order_id => {}
+					Some(request) => {
+						peer_state_lock.pending_requests.insert(request_id.clone(), request);
+						return Err(APIError::APIMisuseError {
+							err: format!(
+								"No pending get_order request for order {} with request_id: {:?}",
+								order_id.0, request_id
+							),
+						}
+						.into());
+					}
+					None => {
+						return Err(APIError::APIMisuseError {
+							err: format!(
+								"No pending get_order request for request_id: {:?}",
+								request_id
+							),
+						}
+						.into())
+					}
+				}
+
 				if let Some(outbound_channel) =
 					peer_state_lock.outbound_channels_by_order_id.get_mut(&order_id)
 				{
-					let config = &outbound_channel.config;
+					// Failed and completed orders are final, e.g., we don't want to report an
+					// expired order as still being open. Likewise, we keep reporting the channel
+					// we opened automatically.
+					let order_state = match outbound_channel.config.order_state {
+						OrderState::Failed => OrderState::Failed,
+						OrderState::Completed => OrderState::Completed,
+						_ => order_state,
+					};
+					let channel = channel.or_else(|| outbound_channel.config.channel.clone());
+					let response =
+						outbound_channel.order_response(order_id, order_state.clone(), channel);
+
+					if outbound_channel.config.order_state != order_state {
+						outbound_channel.config.order_state = order_state;
+						persist_peer_state(
+							&self.kv_store,
+							counterparty_node_id,
+							&*peer_state_lock,
+						)?;
+					}
 
 					self.enqueue_response(
-						&counterparty_node_id,
+						counterparty_node_id,
 						request_id,
-						LSPS1Response::GetOrder(GetOrderResponse {
-							response: CreateOrderResponse {
-								order_id,
-								order: config.order.clone(),
-								order_state,
-								created_at: config.created_at,
-								expires_at: config.expires_at,
-								payment: config.payment.clone(),
-								channel,
-							},
-						}),
+						LSPS1Response::GetOrder(GetOrderResponse { response }),
 					)
 				} else {
 					return Err(APIError::APIMisuseError {
 						err: format!("Channel with order_id {} not found", order_id.0),
-					});
+					}
+					.into());
 				}
 			}
 			None => {
 				return Err(APIError::APIMisuseError {
 					err: format!("No existing state with counterparty {}", counterparty_node_id),
-				})
+				}
+				.into())
 			}
 		}
 		Ok(())
 	}
 
+	/// Used by LSP to record the arrival of the Lightning payment for an order.
+	///
+	/// Should be called with [`PaymentState::Hold`] once the HTLCs paying the order's
+	/// `bolt11_invoice` arrived but the preimage hasn't been released yet, and with
+	/// [`PaymentState::Paid`] once the payment was claimed. Once the order is paid, its channel
+	/// is opened automatically or a [`LSPS1ServiceEvent::OpenChannel`] event is emitted, depending
+	/// on [`LSPS1ServiceConfig::auto_open_channels`].
+	///
+	/// Returns an error if the order is unknown, no longer awaits a payment, or its payment can't
+	/// transition to the given state. In particular, payments for orders that already expired are
+	/// refused and should be failed back.
+	pub fn lightning_payment_received(
+		&self, counterparty_node_id: &PublicKey, order_id: &OrderId, payment_state: PaymentState,
+	) -> Result<(), LiquidityError> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		let inner_state_lock =
+			outer_state_lock.get(counterparty_node_id).ok_or(APIError::APIMisuseError {
+				err: format!("No existing state with counterparty {}", counterparty_node_id),
+			})?;
+		let mut peer_state_lock = inner_state_lock.lock().unwrap();
+
+		let channel = peer_state_lock.outbound_channels_by_order_id.get_mut(order_id).ok_or(
+			APIError::APIMisuseError {
+				err: format!("Channel with order_id {} not found", order_id.0),
+			},
+		)?;
+		let paid = channel
+			.lightning_payment_received(payment_state)
+			.map_err(|e| APIError::APIMisuseError { err: e.0 })?;
+
+		if paid {
+			self.order_paid(counterparty_node_id, order_id, channel);
+		}
+
+		persist_peer_state(&self.kv_store, counterparty_node_id, &*peer_state_lock)
+	}
+
+	/// Used by LSP to record that the payment of a failed order was refunded.
+	///
+	/// Should be called once the payment reported via a [`LSPS1ServiceEvent::Refund`] event was
+	/// refunded, i.e., the held Lightning payment was failed back or the on-chain payment was
+	/// returned to the client. Until then, the order's payment is reported in its previous state.
+	pub fn payment_refunded(
+		&self, counterparty_node_id: &PublicKey, order_id: &OrderId,
+	) -> Result<(), LiquidityError> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		let inner_state_lock =
+			outer_state_lock.get(counterparty_node_id).ok_or(APIError::APIMisuseError {
+				err: format!("No existing state with counterparty {}", counterparty_node_id),
+			})?;
+		let mut peer_state_lock = inner_state_lock.lock().unwrap();
+
+		let channel = peer_state_lock.outbound_channels_by_order_id.get_mut(order_id).ok_or(
+			APIError::APIMisuseError {
+				err: format!("Channel with order_id {} not found", order_id.0),
+			},
+		)?;
+		channel.payment_refunded().map_err(|e| APIError::APIMisuseError { err: e.0 })?;
+
+		persist_peer_state(&self.kv_store, counterparty_node_id, &*peer_state_lock)
+	}
+
 	fn enqueue_response(
 		&self, counterparty_node_id: &PublicKey, request_id: RequestId, response: LSPS1Response,
 	) {
@@ -416,19 +1149,376 @@ where
 			.enqueue(counterparty_node_id, LSPS1Message::Response(request_id, response).into());
 	}
 
+	/// Starts tracking the confirmation status of the given transaction for the given order and
+	/// registers it with the chain source, if any.
+	fn track_transaction(
+		&self, channel: &mut OutboundCRChannel, txid: Txid, script_pubkey: Script,
+	) {
+		if channel.tracked_txs.iter().any(|t| t.txid == txid) {
+			return;
+		}
+
+		if let Some(chain_source) = self.chain_source.as_ref() {
+			chain_source.register_tx(&txid, &script_pubkey);
+		}
+		channel.tracked_txs.push(TrackedTransaction::new(txid, script_pubkey));
+	}
+
+	pub(crate) fn transactions_confirmed(
+		&self, header: &BlockHeader, txdata: &TransactionData, height: u32,
+	) {
+		let block_hash = header.block_hash();
+		let best_block_height =
+			self.best_block_height.read().unwrap().map_or(height, |h| h.max(height));
+		self.update_orders(|counterparty_node_id, order_id, channel| {
+			let mut updated = false;
+			if let Some((txid, script_pubkey)) = channel.detect_onchain_payment(txdata) {
+				self.track_transaction(channel, txid, script_pubkey);
+				updated = true;
+			}
+			updated |= channel.transactions_confirmed(&block_hash, txdata, height);
+			updated |= self.check_onchain_payment_confirmed(
+				counterparty_node_id,
+				order_id,
+				channel,
+				best_block_height,
+			);
+			updated
+		});
+	}
+
+	pub(crate) fn transaction_unconfirmed(&self, txid: &Txid) {
+		self.update_orders(|_, _, channel| {
+			let updated = channel.transaction_unconfirmed(txid);
+			channel.drop_unconfirmed_onchain_payment() || updated
+		});
+	}
+
+	pub(crate) fn block_disconnected(&self, height: u32) {
+		*self.best_block_height.write().unwrap() = Some(height.saturating_sub(1));
+		self.update_orders(|_, _, channel| {
+			let updated = channel.block_disconnected(height);
+			channel.drop_unconfirmed_onchain_payment() || updated
+		});
+	}
+
+	pub(crate) fn best_block_updated(&self, header: &BlockHeader, height: u32) {
+		*self.best_block_height.write().unwrap() = Some(height);
+		self.update_orders(|counterparty_node_id, order_id, channel| {
+			self.check_onchain_payment_confirmed(counterparty_node_id, order_id, channel, height)
+		});
+
+		// Block timestamps may be off by a few hours, but they allow us to expire orders even in
+		// no-std environments.
+		if let Some(block_time) = Utc.timestamp_opt(i64::from(header.time), 0).single() {
+			*self.latest_block_time.write().unwrap() = Some(block_time);
+			self.expire_orders(block_time);
+		}
+	}
+
+	/// Fails all orders that weren't paid before their expiry.
+	///
+	/// Orders are also expired based on the block timestamps whenever a new best block is
+	/// connected. Calling this method regularly, e.g., once a minute, allows to enforce the
+	/// expiry more accurately.
+	///
+	/// For each expired order whose Lightning payment is held or whose on-chain payment is
+	/// confirmed, a [`LSPS1ServiceEvent::Refund`] event will be emitted. On-chain payments that
+	/// confirm only after the order expired will be reported likewise once they do.
+	#[cfg(feature = "std")]
+	pub fn process_timeouts(&self) {
+		if let Some(now) = wall_clock_time() {
+			self.expire_orders(now);
+		}
+	}
+
+	/// Returns the current time, falling back to the timestamp of the latest block if no clock is
+	/// available.
+	fn current_time(&self) -> Option<chrono::DateTime<Utc>> {
+		#[cfg(feature = "std")]
+		if let Some(now) = wall_clock_time() {
+			return Some(now);
+		}
+		*self.latest_block_time.read().unwrap()
+	}
+
+	fn expire_orders(&self, now: chrono::DateTime<Utc>) {
+		self.update_orders(|counterparty_node_id, order_id, channel| {
+			if !channel.check_expiry(now) {
+				return false;
+			}
+
+			if channel.refund_pending {
+				self.pending_events.enqueue(channel.refund_event(counterparty_node_id, order_id));
+			}
+			true
+		});
+	}
+
+	fn check_onchain_payment_confirmed(
+		&self, counterparty_node_id: &PublicKey, order_id: &OrderId,
+		channel: &mut OutboundCRChannel, best_block_height: u32,
+	) -> bool {
+		let was_confirmed = channel.config.payment.onchain_payment.confirmed;
+		if channel.check_onchain_payment_confirmed(best_block_height) {
+			self.order_paid(counterparty_node_id, order_id, channel);
+			return true;
+		}
+
+		if was_confirmed || !channel.config.payment.onchain_payment.confirmed {
+			return false;
+		}
+
+		if channel.refund_pending {
+			// The payment only confirmed after the order expired.
+			self.pending_events.enqueue(channel.refund_event(counterparty_node_id, order_id));
+			return true;
+		}
+
+		// The payment is final but doesn't cover the order total. As we don't sum up further
+		// payments to the order's address, the order remains unpaid and the payment will be
+		// refunded once the order expires.
+		self.pending_events.enqueue(Event::LSPS1Service(LSPS1ServiceEvent::Underpaid {
+			counterparty_node_id: *counterparty_node_id,
+			order_id: order_id.clone(),
+			onchain_payment: channel.config.payment.onchain_payment.clone(),
+			order_total_sat: channel.config.payment.order_total_sat,
+		}));
+		true
+	}
+
+	/// Opens the channel for a newly paid order if configured to do so, or asks the LSP to open it
+	/// otherwise.
+	fn order_paid(
+		&self, counterparty_node_id: &PublicKey, order_id: &OrderId,
+		channel: &mut OutboundCRChannel,
+	) {
+		if !self.config.auto_open_channels {
+			self.pending_events.enqueue(Event::LSPS1Service(LSPS1ServiceEvent::OpenChannel {
+				counterparty_node_id: *counterparty_node_id,
+				order_id: order_id.clone(),
+				order: channel.config.order.clone(),
+			}));
+			return;
+		}
+
+		if let Err(e) = self.open_channel(counterparty_node_id, channel) {
+			self.pending_events.enqueue(Event::LSPS1Service(
+				LSPS1ServiceEvent::ChannelOpenFailed {
+					counterparty_node_id: *counterparty_node_id,
+					order_id: order_id.clone(),
+					order: channel.config.order.clone(),
+					error: e.to_string(),
+				},
+			));
+		}
+	}
+
+	/// Opens the channel for a paid order with the ordered parameters.
+	fn open_channel(
+		&self, counterparty_node_id: &PublicKey, channel: &mut OutboundCRChannel,
+	) -> Result<(), LiquidityError> {
+		let order = &channel.config.order;
+		let channel_value_sat = order.lsp_balance_sat.checked_add(order.client_balance_sat).ok_or(
+			APIError::APIMisuseError {
+				err: format!("Invalid channel value for order: {:?}", order),
+			},
+		)?;
+		let push_msat =
+			order.client_balance_sat.checked_mul(1000).ok_or(APIError::APIMisuseError {
+				err: format!("Invalid client balance for order: {:?}", order),
+			})?;
+
+		let mut user_config = *self.channel_manager.get_cm().get_current_default_configuration();
+		user_config.channel_handshake_config.announced_channel = order.announce_channel;
+
+		let user_channel_id = self.generate_user_channel_id();
+		self.channel_manager.get_cm().create_channel(
+			*counterparty_node_id,
+			channel_value_sat,
+			push_msat,
+			user_channel_id,
+			Some(user_config),
+		)?;
+		channel.user_channel_id = Some(user_channel_id);
+		Ok(())
+	}
+
+	/// Forward [`Event::ChannelReady`] event parameters into this function.
+	///
+	/// Completes the order for which the channel was opened automatically, recording the details
+	/// of the channel in the order's [`ChannelInfo`]. See
+	/// [`LSPS1ServiceConfig::auto_open_channels`] for more information.
+	///
+	/// [`Event::ChannelReady`]: lightning::events::Event::ChannelReady
+	pub fn channel_ready(
+		&self, user_channel_id: u128, channel_id: &ChannelId, counterparty_node_id: &PublicKey,
+	) -> Result<(), LiquidityError> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		let inner_state_lock =
+			outer_state_lock.get(counterparty_node_id).ok_or(APIError::APIMisuseError {
+				err: format!("No state for the counterparty exists: {:?}", counterparty_node_id),
+			})?;
+		let mut peer_state_lock = inner_state_lock.lock().unwrap();
+
+		let channel = peer_state_lock
+			.outbound_channels_by_order_id
+			.values_mut()
+			.find(|channel| channel.user_channel_id == Some(user_channel_id))
+			.ok_or(APIError::APIMisuseError {
+				err: format!("Could not find an order with user_channel_id {}", user_channel_id),
+			})?;
+
+		let channel_details = self
+			.channel_manager
+			.get_cm()
+			.list_channels()
+			.into_iter()
+			.find(|details| details.channel_id == *channel_id)
+			.ok_or(APIError::ChannelUnavailable {
+				err: format!("Could not find channel {}", channel_id),
+			})?;
+
+		// Without any clock we might not have seen a block yet, in which case we fall back to
+		// the order's creation time.
+		let funded_at = self.current_time().unwrap_or(channel.config.created_at);
+		let expires_at = funded_at
+			+ chrono::Duration::minutes(
+				i64::from(channel.config.order.channel_expiry_blocks) * MINUTES_PER_BLOCK,
+			);
+		channel.config.channel = Some(ChannelInfo {
+			state: ChannelState::Opened,
+			funded_at: funded_at.to_rfc3339(),
+			funding_outpoint: channel_details
+				.funding_txo
+				.map(|txo| txo.into_bitcoin_outpoint().to_string())
+				.unwrap_or_default(),
+			scid: channel_details.short_channel_id.map(utils::scid_to_human_readable_string),
+			expires_at: expires_at.to_rfc3339(),
+			closing_transaction: None,
+			closed_at: None,
+		});
+		channel.config.order_state = OrderState::Completed;
+
+		persist_peer_state(&self.kv_store, counterparty_node_id, &*peer_state_lock)
+	}
+
+	/// Forward [`Event::ChannelClosed`] event parameters into this function.
+	///
+	/// If the channel we opened automatically for an order closed before it became ready, e.g., as
+	/// the counterparty rejected it or its funding failed, a
+	/// [`LSPS1ServiceEvent::ChannelOpenFailed`] event will be emitted. Closures of any other
+	/// channels are ignored.
+	///
+	/// [`Event::ChannelClosed`]: lightning::events::Event::ChannelClosed
+	pub fn channel_closed(
+		&self, user_channel_id: u128, counterparty_node_id: &PublicKey, reason: &ClosureReason,
+	) -> Result<(), LiquidityError> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		let inner_state_lock = match outer_state_lock.get(counterparty_node_id) {
+			Some(inner_state_lock) => inner_state_lock,
+			None => return Ok(()),
+		};
+		let mut peer_state_lock = inner_state_lock.lock().unwrap();
+
+		let (order_id, channel) =
+			match peer_state_lock.outbound_channels_by_order_id.iter_mut().find(|(_, channel)| {
+				channel.user_channel_id == Some(user_channel_id)
+					&& channel.config.order_state == OrderState::Created
+			}) {
+				Some(entry) => entry,
+				None => return Ok(()),
+			};
+
+		// The order may be completed by opening the channel manually from here on.
+		channel.user_channel_id = None;
+		self.pending_events.enqueue(Event::LSPS1Service(LSPS1ServiceEvent::ChannelOpenFailed {
+			counterparty_node_id: *counterparty_node_id,
+			order_id: order_id.clone(),
+			order: channel.config.order.clone(),
+			error: reason.to_string(),
+		}));
+
+		persist_peer_state(&self.kv_store, counterparty_node_id, &*peer_state_lock)
+	}
+
+	/// Forgets about the requests of the given counterparty we didn't answer yet, as we can't do so
+	/// anymore after it disconnected.
+	pub(crate) fn peer_disconnected(&self, counterparty_node_id: &PublicKey) {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		if let Some(inner_state_lock) = outer_state_lock.get(counterparty_node_id) {
+			inner_state_lock.lock().unwrap().pending_requests.clear();
+		}
+	}
+
+	pub(crate) fn get_relevant_txids(&self) -> Vec<(Txid, Option<BlockHash>)> {
+		let mut relevant_txids = Vec::new();
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		for inner_state_lock in outer_state_lock.values() {
+			let peer_state = inner_state_lock.lock().unwrap();
+			for channel in peer_state.outbound_channels_by_order_id.values() {
+				for tracked_tx in channel.tracked_txs.iter() {
+					if let Some((block_hash, _)) = tracked_tx.confirmation {
+						relevant_txids.push((tracked_tx.txid, Some(block_hash)));
+					}
+				}
+			}
+		}
+		relevant_txids
+	}
+
+	/// Applies the given update to all orders, persisting the state of every peer for which at
+	/// least one order was updated.
+	fn update_orders<F>(&self, mut update: F)
+	where
+		F: FnMut(&PublicKey, &OrderId, &mut OutboundCRChannel) -> bool,
+	{
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		for (counterparty_node_id, inner_state_lock) in outer_state_lock.iter() {
+			let mut peer_state = inner_state_lock.lock().unwrap();
+			let mut updated = false;
+			for (order_id, channel) in peer_state.outbound_channels_by_order_id.iter_mut() {
+				updated |= update(counterparty_node_id, order_id, channel);
+			}
+
+			if updated {
+				// There is no way to surface the error to the chain source, so the state will be
+				// persisted again with the next successful write for this peer.
+				let _ = persist_peer_state(&self.kv_store, counterparty_node_id, &*peer_state);
+			}
+		}
+	}
+
 	fn generate_order_id(&self) -> OrderId {
 		let bytes = self.entropy_source.get_secure_random_bytes();
 		OrderId(utils::hex_str(&bytes[0..16]))
 	}
+
+	fn generate_user_channel_id(&self) -> u128 {
+		let bytes = self.entropy_source.get_secure_random_bytes();
+		let mut user_channel_id_bytes = [0u8; 16];
+		user_channel_id_bytes.copy_from_slice(&bytes[0..16]);
+		u128::from_be_bytes(user_channel_id_bytes)
+	}
 }
 
-impl<ES: Deref, CM: Deref + Clone, MQ: Deref, C: Deref> ProtocolMessageHandler
-	for LSPS1ServiceHandler<ES, CM, MQ, C>
+#[cfg(feature = "std")]
+fn wall_clock_time() -> Option<chrono::DateTime<Utc>> {
+	let seconds_since_epoch = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.expect("system clock to be ahead of the unix epoch")
+		.as_secs();
+	i64::try_from(seconds_since_epoch).ok().and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+}
+
+impl<ES: Deref, CM: Deref + Clone, MQ: Deref, C: Deref, K: Deref> ProtocolMessageHandler
+	for LSPS1ServiceHandler<ES, CM, MQ, C, K>
 where
 	ES::Target: EntropySource,
 	CM::Target: AChannelManager,
 	MQ::Target: MessageQueue,
 	C::Target: Filter,
+	K::Target: KVStore,
 {
 	type ProtocolMessage = LSPS1Message;
 	const PROTOCOL_NUMBER: Option<u16> = Some(1);
@@ -458,3 +1548,671 @@ where
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::lsps1::msgs::{GetInfoRequest, OnchainPayment, PaymentState};
+	use crate::persist::memory_store::MemoryStore;
+	use crate::tests::utils::{
+		connect_peer, create_channel_manager, TestChannelManager, TestEntropy, TestMessageQueue,
+	};
+
+	use lightning::chain::WatchedOutput;
+
+	use bitcoin::hashes::Hash;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+	use bitcoin::{PackedLockTime, Transaction, TxOut};
+
+	use chrono::TimeZone;
+
+	type TestServiceHandler = LSPS1ServiceHandler<
+		Arc<TestEntropy>,
+		Arc<TestChannelManager>,
+		Arc<TestMessageQueue>,
+		Arc<TestFilter>,
+		Arc<MemoryStore>,
+	>;
+
+	fn test_order() -> OrderParams {
+		OrderParams {
+			api_version: 1,
+			lsp_balance_sat: 100_000,
+			client_balance_sat: 0,
+			confirms_within_blocks: 6,
+			channel_expiry_blocks: 144,
+			token: "".to_string(),
+			refund_onchain_address: None,
+			announce_channel: false,
+		}
+	}
+
+	fn test_payment() -> OrderPayment {
+		OrderPayment {
+			state: PaymentState::ExpectPayment,
+			fee_total_sat: 1_000,
+			order_total_sat: 101_000,
+			bolt11_invoice: "lnbc...".to_string(),
+			onchain_address: "bc1q...".to_string(),
+			onchain_block_confirmations_required: 1,
+			minimum_fee_for_0conf: 253,
+			onchain_payment: OnchainPayment { outpoint: "".to_string(), sat: 0, confirmed: false },
+		}
+	}
+
+	struct TestFilter {}
+	impl Filter for TestFilter {
+		fn register_tx(&self, _txid: &Txid, _script_pubkey: &Script) {}
+
+		fn register_output(&self, _output: WatchedOutput) {}
+	}
+
+	fn test_options() -> OptionsSupported {
+		OptionsSupported {
+			minimum_channel_confirmations: 0,
+			minimum_onchain_payment_confirmations: 1,
+			supports_zero_channel_reserve: false,
+			min_onchain_payment_size_sat: None,
+			max_channel_expiry_blocks: 1_000,
+			min_initial_client_balance_sat: 0,
+			max_initial_client_balance_sat: 100_000,
+			min_initial_lsp_balance_sat: 0,
+			max_initial_lsp_balance_sat: 1_000_000,
+			min_channel_balance_sat: 0,
+			max_channel_balance_sat: 1_100_000,
+		}
+	}
+
+	fn test_counterparty_node_id() -> PublicKey {
+		PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[1; 32]).unwrap())
+	}
+
+	fn create_handler(
+		channel_manager: Arc<TestChannelManager>, auto_open_channels: bool,
+	) -> (TestServiceHandler, Arc<EventQueue>) {
+		let pending_events = Arc::new(EventQueue::new());
+		let config = LSPS1ServiceConfig {
+			token: None,
+			options_supported: Some(test_options()),
+			website: Some("https://example.com".to_string()),
+			auto_open_channels,
+			max_pending_requests_per_peer: DEFAULT_MAX_PENDING_REQUESTS_PER_PEER,
+		};
+		let handler = LSPS1ServiceHandler::new(
+			Arc::new(TestEntropy {}),
+			Arc::new(TestMessageQueue::new()),
+			Arc::clone(&pending_events),
+			channel_manager,
+			Some(Arc::new(TestFilter {})),
+			None,
+			Arc::new(MemoryStore::new()),
+			config,
+		)
+		.unwrap();
+		(handler, pending_events)
+	}
+
+	/// Places an order on behalf of the given client and accepts it, returning the order's id.
+	fn create_order(
+		handler: &TestServiceHandler, pending_events: &EventQueue, counterparty_node_id: &PublicKey,
+	) -> OrderId {
+		let request_id = RequestId("00000000000000000000000000000000".to_string());
+		let request = CreateOrderRequest { version: 1, order: test_order() };
+		handler
+			.handle_message(
+				LSPS1Message::Request(request_id.clone(), LSPS1Request::CreateOrder(request)),
+				counterparty_node_id,
+			)
+			.unwrap();
+		assert!(matches!(
+			pending_events.get_and_clear_pending_events()[..],
+			[Event::LSPS1Service(LSPS1ServiceEvent::CreateInvoice { .. })]
+		));
+
+		let mut payment = test_payment();
+		payment.onchain_address = String::new();
+		let created_at = Utc.with_ymd_and_hms(2023, 11, 20, 13, 37, 0).unwrap();
+		let expires_at = Utc.with_ymd_and_hms(2023, 11, 21, 13, 37, 0).unwrap();
+		handler
+			.send_invoice_for_order(
+				counterparty_node_id,
+				request_id,
+				payment,
+				created_at,
+				expires_at,
+			)
+			.unwrap();
+
+		let outer_state_lock = handler.per_peer_state.read().unwrap();
+		let peer_state = outer_state_lock.get(counterparty_node_id).unwrap().lock().unwrap();
+		peer_state.outbound_channels_by_order_id.keys().next().unwrap().clone()
+	}
+
+	fn with_order<R>(
+		handler: &TestServiceHandler, counterparty_node_id: &PublicKey, order_id: &OrderId,
+		f: impl FnOnce(&OutboundCRChannel) -> R,
+	) -> R {
+		let outer_state_lock = handler.per_peer_state.read().unwrap();
+		let peer_state = outer_state_lock.get(counterparty_node_id).unwrap().lock().unwrap();
+		f(peer_state.outbound_channels_by_order_id.get(order_id).unwrap())
+	}
+
+	#[test]
+	fn requests_are_rejected_without_configured_options() {
+		let counterparty_node_id = test_counterparty_node_id();
+		let pending_messages = Arc::new(TestMessageQueue::new());
+		let config = LSPS1ServiceConfig {
+			token: None,
+			options_supported: None,
+			website: None,
+			auto_open_channels: false,
+			max_pending_requests_per_peer: DEFAULT_MAX_PENDING_REQUESTS_PER_PEER,
+		};
+		let handler: TestServiceHandler = LSPS1ServiceHandler::new(
+			Arc::new(TestEntropy {}),
+			Arc::clone(&pending_messages),
+			Arc::new(EventQueue::new()),
+			create_channel_manager(),
+			Some(Arc::new(TestFilter {})),
+			None,
+			Arc::new(MemoryStore::new()),
+			config,
+		)
+		.unwrap();
+		let error = ResponseError {
+			code: JSONRPC_INTERNAL_ERROR_CODE,
+			message: "the LSP doesn't offer any channel options".to_string(),
+			data: None,
+		};
+
+		let request_id = RequestId("get_info".to_string());
+		let request = LSPS1Request::GetInfo(GetInfoRequest {});
+		assert!(handler
+			.handle_message(
+				LSPS1Message::Request(request_id.clone(), request),
+				&counterparty_node_id
+			)
+			.is_err());
+		assert_eq!(
+			pending_messages.get_and_clear_pending_msgs(),
+			vec![(
+				counterparty_node_id,
+				LSPS1Message::Response(request_id, LSPS1Response::GetInfoError(error.clone()))
+					.into()
+			)]
+		);
+
+		let request_id = RequestId("create_order".to_string());
+		let request =
+			LSPS1Request::CreateOrder(CreateOrderRequest { version: 1, order: test_order() });
+		assert!(handler
+			.handle_message(
+				LSPS1Message::Request(request_id.clone(), request),
+				&counterparty_node_id
+			)
+			.is_err());
+		assert_eq!(
+			pending_messages.get_and_clear_pending_msgs(),
+			vec![(
+				counterparty_node_id,
+				LSPS1Message::Response(request_id, LSPS1Response::CreateOrderError(error)).into()
+			)]
+		);
+	}
+
+	#[test]
+	fn paid_orders_without_auto_open_ask_for_the_channel() {
+		let counterparty_node_id = test_counterparty_node_id();
+		let (handler, pending_events) = create_handler(create_channel_manager(), false);
+		let order_id = create_order(&handler, &pending_events, &counterparty_node_id);
+
+		handler
+			.lightning_payment_received(&counterparty_node_id, &order_id, PaymentState::Paid)
+			.unwrap();
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS1Service(LSPS1ServiceEvent::OpenChannel {
+				counterparty_node_id,
+				order_id: order_id.clone(),
+				order: test_order(),
+			})]
+		);
+		assert!(handler
+			.lightning_payment_received(&counterparty_node_id, &order_id, PaymentState::Paid)
+			.is_err());
+	}
+
+	#[test]
+	fn paid_orders_are_completed_once_the_channel_is_ready() {
+		let counterparty_node_id = test_counterparty_node_id();
+		let channel_manager = create_channel_manager();
+		connect_peer(&channel_manager, &counterparty_node_id);
+		let (handler, pending_events) = create_handler(Arc::clone(&channel_manager), true);
+		let order_id = create_order(&handler, &pending_events, &counterparty_node_id);
+
+		// Held payments don't open the channel yet.
+		handler
+			.lightning_payment_received(&counterparty_node_id, &order_id, PaymentState::Hold)
+			.unwrap();
+		assert!(channel_manager.list_channels().is_empty());
+
+		handler
+			.lightning_payment_received(&counterparty_node_id, &order_id, PaymentState::Paid)
+			.unwrap();
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+
+		let channels = channel_manager.list_channels();
+		assert_eq!(channels.len(), 1);
+		let channel_details = &channels[0];
+		assert_eq!(channel_details.counterparty.node_id, counterparty_node_id);
+		assert_eq!(channel_details.channel_value_satoshis, 100_000);
+		let user_channel_id = channel_details.user_channel_id;
+		assert_eq!(
+			with_order(&handler, &counterparty_node_id, &order_id, |c| c.user_channel_id),
+			Some(user_channel_id)
+		);
+
+		// Unknown channels can't complete any order.
+		assert!(handler
+			.channel_ready(user_channel_id + 1, &channel_details.channel_id, &counterparty_node_id)
+			.is_err());
+
+		handler
+			.channel_ready(user_channel_id, &channel_details.channel_id, &counterparty_node_id)
+			.unwrap();
+		with_order(&handler, &counterparty_node_id, &order_id, |channel| {
+			assert_eq!(channel.config.order_state, OrderState::Completed);
+			let channel_info = channel.config.channel.as_ref().unwrap();
+			assert_eq!(channel_info.state, ChannelState::Opened);
+		});
+
+		// Closing the channel after it was ready doesn't fail the order.
+		handler
+			.channel_closed(
+				user_channel_id,
+				&counterparty_node_id,
+				&ClosureReason::DisconnectedPeer,
+			)
+			.unwrap();
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+	}
+
+	#[test]
+	fn failed_channel_opens_are_reported() {
+		let counterparty_node_id = test_counterparty_node_id();
+		let channel_manager = create_channel_manager();
+		let (handler, pending_events) = create_handler(Arc::clone(&channel_manager), true);
+
+		// We can't open channels to peers we're not connected to.
+		let order_id = create_order(&handler, &pending_events, &counterparty_node_id);
+		handler
+			.lightning_payment_received(&counterparty_node_id, &order_id, PaymentState::Paid)
+			.unwrap();
+		let events = pending_events.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match &events[0] {
+			Event::LSPS1Service(LSPS1ServiceEvent::ChannelOpenFailed {
+				counterparty_node_id: node_id,
+				order_id: failed_order_id,
+				order,
+				error,
+			}) => {
+				assert_eq!(*node_id, counterparty_node_id);
+				assert_eq!(*failed_order_id, order_id);
+				assert_eq!(*order, test_order());
+				assert!(!error.is_empty());
+			}
+			event => panic!("Unexpected event: {:?}", event),
+		}
+		assert_eq!(
+			with_order(&handler, &counterparty_node_id, &order_id, |c| c.user_channel_id),
+			None
+		);
+
+		// Channels closing before they become ready are reported likewise.
+		connect_peer(&channel_manager, &counterparty_node_id);
+		let (handler, pending_events) = create_handler(Arc::clone(&channel_manager), true);
+		let order_id = create_order(&handler, &pending_events, &counterparty_node_id);
+		handler
+			.lightning_payment_received(&counterparty_node_id, &order_id, PaymentState::Paid)
+			.unwrap();
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+		let user_channel_id =
+			with_order(&handler, &counterparty_node_id, &order_id, |c| c.user_channel_id).unwrap();
+
+		// Closures of unrelated channels are ignored.
+		handler
+			.channel_closed(
+				user_channel_id + 1,
+				&counterparty_node_id,
+				&ClosureReason::DisconnectedPeer,
+			)
+			.unwrap();
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+
+		handler
+			.channel_closed(
+				user_channel_id,
+				&counterparty_node_id,
+				&ClosureReason::DisconnectedPeer,
+			)
+			.unwrap();
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS1Service(LSPS1ServiceEvent::ChannelOpenFailed {
+				counterparty_node_id,
+				order_id: order_id.clone(),
+				order: test_order(),
+				error: ClosureReason::DisconnectedPeer.to_string(),
+			})]
+		);
+		with_order(&handler, &counterparty_node_id, &order_id, |channel| {
+			assert_eq!(channel.user_channel_id, None);
+			assert_eq!(channel.config.order_state, OrderState::Created);
+			assert_eq!(channel.config.payment.state, PaymentState::Paid);
+		});
+	}
+
+	#[test]
+	fn peer_state_serialization_roundtrip() {
+		let order = test_order();
+		let payment = test_payment();
+		let created_at = Utc.with_ymd_and_hms(2023, 11, 20, 13, 37, 0).unwrap();
+		let expires_at = Utc.with_ymd_and_hms(2023, 11, 21, 13, 37, 0).unwrap();
+		let order_id = OrderId("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb".to_string());
+
+		let mut channel = OutboundCRChannel::new(
+			order.clone(),
+			created_at,
+			expires_at,
+			order_id.clone(),
+			payment.clone(),
+		);
+		channel.create_payment_invoice().unwrap();
+		let mut tracked_tx = TrackedTransaction::new(Txid::all_zeros(), Script::new());
+		tracked_tx.confirmation = Some((BlockHash::all_zeros(), 42));
+		channel.tracked_txs.push(tracked_tx.clone());
+		channel.user_channel_id = Some(1337);
+		channel.refund_pending = true;
+		let channel_info = ChannelInfo {
+			state: ChannelState::Opened,
+			funded_at: created_at.to_rfc3339(),
+			funding_outpoint: format!("{}:0", Txid::all_zeros()),
+			scid: Some("800000x42x0".to_string()),
+			expires_at: expires_at.to_rfc3339(),
+			closing_transaction: None,
+			closed_at: None,
+		};
+		channel.config.channel = Some(channel_info.clone());
+
+		let mut peer_state = PeerState::default();
+		peer_state.insert_outbound_channel(order_id.clone(), channel);
+		peer_state
+			.pending_requests
+			.insert(RequestId("xyz123".to_string()), LSPS1Request::GetInfo(GetInfoRequest {}));
+
+		let encoded = peer_state.encode();
+		let decoded = PeerState::read(&mut io::Cursor::new(&encoded)).unwrap();
+
+		assert!(decoded.pending_requests.is_empty());
+		let decoded_channel = decoded.outbound_channels_by_order_id.get(&order_id).unwrap();
+		assert_eq!(decoded_channel.state, OutboundRequestState::WaitingPayment { order_id });
+		assert_eq!(decoded_channel.config.order, order);
+		assert_eq!(decoded_channel.config.created_at, created_at);
+		assert_eq!(decoded_channel.config.expires_at, expires_at);
+		assert_eq!(decoded_channel.config.payment, payment);
+		assert_eq!(decoded_channel.config.order_state, OrderState::Created);
+		assert_eq!(decoded_channel.tracked_txs, vec![tracked_tx]);
+		assert_eq!(decoded_channel.user_channel_id, Some(1337));
+		assert!(decoded_channel.refund_pending);
+		assert_eq!(decoded_channel.config.channel, Some(channel_info));
+	}
+
+	#[test]
+	fn tracked_transactions_follow_reorgs() {
+		let tx = Transaction {
+			version: 2,
+			lock_time: PackedLockTime::ZERO,
+			input: vec![],
+			output: vec![TxOut { value: 101_000, script_pubkey: Script::new() }],
+		};
+		let txid = tx.txid();
+		let block_hash = BlockHash::all_zeros();
+
+		let now = Utc.with_ymd_and_hms(2023, 11, 20, 13, 37, 0).unwrap();
+		let mut channel = OutboundCRChannel::new(
+			test_order(),
+			now,
+			now,
+			OrderId("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb".to_string()),
+			test_payment(),
+		);
+		channel.tracked_txs.push(TrackedTransaction::new(txid, Script::new()));
+
+		assert!(!channel.transactions_confirmed(&block_hash, &[], 100));
+		assert!(channel.transactions_confirmed(&block_hash, &[(0, &tx)], 100));
+		assert_eq!(channel.tracked_txs[0].confirmations(100), 1);
+		assert_eq!(channel.tracked_txs[0].confirmations(105), 6);
+
+		// Disconnecting a block above the confirmation height doesn't affect the transaction.
+		assert!(!channel.block_disconnected(101));
+		assert_eq!(channel.tracked_txs[0].confirmations(100), 1);
+
+		assert!(channel.block_disconnected(100));
+		assert_eq!(channel.tracked_txs[0].confirmations(100), 0);
+
+		assert!(channel.transactions_confirmed(&block_hash, &[(0, &tx)], 101));
+		assert!(channel.transaction_unconfirmed(&txid));
+		assert!(!channel.transaction_unconfirmed(&txid));
+		assert_eq!(channel.tracked_txs[0].confirmation, None);
+	}
+
+	#[test]
+	fn onchain_payment_detection() {
+		let mut payment = test_payment();
+		payment.onchain_address = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4".to_string();
+		payment.onchain_block_confirmations_required = 3;
+		let payment_script = Address::from_str(&payment.onchain_address).unwrap().script_pubkey();
+
+		let now = Utc.with_ymd_and_hms(2023, 11, 20, 13, 37, 0).unwrap();
+		let mut channel = OutboundCRChannel::new(
+			test_order(),
+			now,
+			now,
+			OrderId("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb".to_string()),
+			payment,
+		);
+		assert_eq!(channel.payment_script(), Some(payment_script.clone()));
+
+		let unrelated_tx = Transaction {
+			version: 2,
+			lock_time: PackedLockTime::ZERO,
+			input: vec![],
+			output: vec![TxOut { value: 101_000, script_pubkey: Script::new() }],
+		};
+		assert_eq!(channel.detect_onchain_payment(&[(0, &unrelated_tx)]), None);
+
+		let payment_tx = Transaction {
+			version: 2,
+			lock_time: PackedLockTime::ZERO,
+			input: vec![],
+			output: vec![
+				TxOut { value: 5_000, script_pubkey: Script::new() },
+				TxOut { value: 101_000, script_pubkey: payment_script.clone() },
+			],
+		};
+		let payment_txid = payment_tx.txid();
+		let block_hash = BlockHash::all_zeros();
+		let txdata = [(0, &unrelated_tx), (1, &payment_tx)];
+		assert_eq!(
+			channel.detect_onchain_payment(&txdata),
+			Some((payment_txid, payment_script.clone()))
+		);
+		assert_eq!(
+			channel.config.payment.onchain_payment,
+			OnchainPayment {
+				outpoint: format!("{}:1", payment_txid),
+				sat: 101_000,
+				confirmed: false
+			}
+		);
+		// We only detect the first payment.
+		assert_eq!(channel.detect_onchain_payment(&txdata), None);
+
+		channel.tracked_txs.push(TrackedTransaction::new(payment_txid, payment_script));
+		assert!(channel.transactions_confirmed(&block_hash, &txdata, 100));
+		assert!(!channel.check_onchain_payment_confirmed(101));
+		assert!(!channel.config.payment.onchain_payment.confirmed);
+
+		// A reorg before the payment is final makes us forget about it.
+		assert!(channel.block_disconnected(100));
+		assert!(channel.drop_unconfirmed_onchain_payment());
+		assert!(channel.tracked_txs.is_empty());
+		assert!(channel.awaits_onchain_payment());
+
+		let (txid, script_pubkey) = channel.detect_onchain_payment(&txdata).unwrap();
+		channel.tracked_txs.push(TrackedTransaction::new(txid, script_pubkey));
+		assert!(channel.transactions_confirmed(&block_hash, &txdata, 101));
+		assert!(channel.check_onchain_payment_confirmed(103));
+		assert!(channel.config.payment.onchain_payment.confirmed);
+		assert_eq!(channel.config.payment.state, PaymentState::Paid);
+		assert!(!channel.check_onchain_payment_confirmed(104));
+		assert!(!channel.drop_unconfirmed_onchain_payment());
+	}
+
+	#[test]
+	fn disconnecting_the_genesis_block_does_not_underflow() {
+		let (handler, _) = create_handler(create_channel_manager(), false);
+		handler.block_disconnected(0);
+		assert_eq!(*handler.best_block_height.read().unwrap(), Some(0));
+	}
+
+	#[test]
+	fn lightning_payment_state_transitions() {
+		let now = Utc.with_ymd_and_hms(2023, 11, 20, 13, 37, 0).unwrap();
+		let new_channel = || {
+			OutboundCRChannel::new(
+				test_order(),
+				now,
+				now,
+				OrderId("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb".to_string()),
+				test_payment(),
+			)
+		};
+
+		// Payments may be put on hold before being claimed.
+		let mut channel = new_channel();
+		assert_eq!(channel.lightning_payment_received(PaymentState::Hold).ok(), Some(false));
+		assert_eq!(channel.config.payment.state, PaymentState::Hold);
+		assert!(channel.lightning_payment_received(PaymentState::Hold).is_err());
+		assert!(channel.lightning_payment_received(PaymentState::ExpectPayment).is_err());
+		assert_eq!(channel.lightning_payment_received(PaymentState::Paid).ok(), Some(true));
+		assert_eq!(channel.config.payment.state, PaymentState::Paid);
+
+		// Paid orders can't be paid again.
+		assert!(channel.lightning_payment_received(PaymentState::Paid).is_err());
+		assert!(channel.lightning_payment_received(PaymentState::Hold).is_err());
+		assert_eq!(channel.config.payment.state, PaymentState::Paid);
+
+		// Payments may also be claimed right away, but not be refunded this way.
+		let mut channel = new_channel();
+		assert!(channel.lightning_payment_received(PaymentState::Refunded).is_err());
+		assert_eq!(channel.lightning_payment_received(PaymentState::Paid).ok(), Some(true));
+
+		// Failed orders don't accept payments anymore.
+		let mut channel = new_channel();
+		channel.config.order_state = OrderState::Failed;
+		assert!(channel.lightning_payment_received(PaymentState::Paid).is_err());
+		assert_eq!(channel.config.payment.state, PaymentState::ExpectPayment);
+	}
+
+	#[test]
+	fn expired_orders_fail_and_refund_payments() {
+		let mut payment = test_payment();
+		payment.onchain_address = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4".to_string();
+		let payment_script = Address::from_str(&payment.onchain_address).unwrap().script_pubkey();
+		let created_at = Utc.with_ymd_and_hms(2023, 11, 20, 13, 37, 0).unwrap();
+		let expires_at = Utc.with_ymd_and_hms(2023, 11, 21, 13, 37, 0).unwrap();
+		let new_channel = |payment: OrderPayment| {
+			OutboundCRChannel::new(
+				test_order(),
+				created_at,
+				expires_at,
+				OrderId("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb".to_string()),
+				payment,
+			)
+		};
+		let payment_tx = Transaction {
+			version: 2,
+			lock_time: PackedLockTime::ZERO,
+			input: vec![],
+			output: vec![TxOut { value: 50_000, script_pubkey: payment_script }],
+		};
+		let txdata = [(0, &payment_tx)];
+
+		// Orders that expire without any payment fail, but payments that arrive later on are
+		// refunded once they confirm.
+		let mut channel = new_channel(payment.clone());
+		assert!(!channel.check_expiry(expires_at));
+		assert!(channel.check_expiry(expires_at + chrono::Duration::seconds(1)));
+		assert_eq!(channel.config.order_state, OrderState::Failed);
+		assert_eq!(channel.config.payment.state, PaymentState::ExpectPayment);
+		assert!(!channel.refund_pending);
+		assert!(!channel.check_expiry(expires_at + chrono::Duration::days(1)));
+		assert!(channel.awaits_onchain_payment());
+		let (txid, script_pubkey) = channel.detect_onchain_payment(&txdata).unwrap();
+		channel.tracked_txs.push(TrackedTransaction::new(txid, script_pubkey));
+		assert!(channel.transactions_confirmed(&BlockHash::all_zeros(), &txdata, 100));
+		assert!(!channel.check_onchain_payment_confirmed(100));
+		assert!(channel.refund_pending);
+		assert_eq!(channel.config.order_state, OrderState::Failed);
+
+		// Insufficient payments are refunded once the order expires.
+		let mut channel = new_channel(payment.clone());
+		let (txid, script_pubkey) = channel.detect_onchain_payment(&txdata).unwrap();
+		channel.tracked_txs.push(TrackedTransaction::new(txid, script_pubkey));
+		assert!(channel.transactions_confirmed(&BlockHash::all_zeros(), &txdata, 100));
+		assert!(!channel.check_onchain_payment_confirmed(100));
+		assert!(channel.config.payment.onchain_payment.confirmed);
+		assert_eq!(channel.config.payment.state, PaymentState::ExpectPayment);
+		assert!(channel.payment_refunded().is_err());
+		assert!(channel.check_expiry(expires_at + chrono::Duration::seconds(1)));
+		assert_eq!(channel.config.order_state, OrderState::Failed);
+		assert!(channel.refund_pending);
+
+		// The payment is only reported as refunded once we were told it was.
+		assert_eq!(channel.config.payment.state, PaymentState::ExpectPayment);
+		assert!(channel.payment_refunded().is_ok());
+		assert_eq!(channel.config.payment.state, PaymentState::Refunded);
+		assert!(!channel.refund_pending);
+		assert!(channel.payment_refunded().is_err());
+
+		// Unconfirmed payments are only refunded once they confirm.
+		let mut channel = new_channel(payment.clone());
+		let (txid, script_pubkey) = channel.detect_onchain_payment(&txdata).unwrap();
+		channel.tracked_txs.push(TrackedTransaction::new(txid, script_pubkey));
+		assert!(channel.check_expiry(expires_at + chrono::Duration::seconds(1)));
+		assert!(!channel.refund_pending);
+		assert!(channel.transactions_confirmed(&BlockHash::all_zeros(), &txdata, 100));
+		assert!(!channel.check_onchain_payment_confirmed(100));
+		assert!(channel.refund_pending);
+
+		// Held Lightning payments are refunded once the order expires.
+		let mut channel = new_channel(payment.clone());
+		assert_eq!(channel.lightning_payment_received(PaymentState::Hold).ok(), Some(false));
+		assert!(channel.check_expiry(expires_at + chrono::Duration::seconds(1)));
+		assert_eq!(channel.config.order_state, OrderState::Failed);
+		assert!(channel.refund_pending);
+		assert!(channel.lightning_payment_received(PaymentState::Paid).is_err());
+		assert!(channel.payment_refunded().is_ok());
+		assert_eq!(channel.config.payment.state, PaymentState::Refunded);
+
+		// Paid orders don't expire.
+		let mut paid_payment = payment;
+		paid_payment.state = PaymentState::Paid;
+		let mut channel = new_channel(paid_payment);
+		assert!(!channel.check_expiry(expires_at + chrono::Duration::days(1)));
+		assert_eq!(channel.config.order_state, OrderState::Created);
+	}
+}
@@ -1,5 +1,9 @@
 use super::msgs::{OptionsSupported, OrderParams};
 
+use lightning::impl_writeable_tlv_based;
+
+use bitcoin::{BlockHash, Script, Txid};
+
 pub fn check_range(min: u64, max: u64, value: u64) -> bool {
 	(value >= min) && (value <= max)
 }
@@ -21,3 +25,33 @@ pub fn is_valid(order: &OrderParams, options: &OptionsSupported) -> bool {
 
 	bool
 }
+
+/// A transaction relevant to an order whose confirmation status we track.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TrackedTransaction {
+	pub txid: Txid,
+	/// The output script we registered with the chain source for this transaction.
+	pub script_pubkey: Script,
+	/// The hash and height of the block the transaction was confirmed in, if any.
+	pub confirmation: Option<(BlockHash, u32)>,
+}
+
+impl TrackedTransaction {
+	pub fn new(txid: Txid, script_pubkey: Script) -> Self {
+		Self { txid, script_pubkey, confirmation: None }
+	}
+
+	/// Returns the number of confirmations the transaction has at the given best block height.
+	pub fn confirmations(&self, best_block_height: u32) -> u32 {
+		match self.confirmation {
+			Some((_, height)) => best_block_height.saturating_sub(height) + 1,
+			None => 0,
+		}
+	}
+}
+
+impl_writeable_tlv_based!(TrackedTransaction, {
+	(0, txid, required),
+	(2, script_pubkey, required),
+	(4, confirmation, option),
+});
@@ -9,21 +9,39 @@
 
 //! Contains the main LSPS2 client object, [`LSPS2ClientHandler`].
 
+use crate::errors::LiquidityError;
 use crate::events::{Event, EventQueue};
 use crate::lsps0::msgs::{ProtocolMessageHandler, RequestId, ResponseError};
 use crate::lsps2::event::LSPS2ClientEvent;
+use crate::lsps2::utils::{compute_opening_fee, is_expired_opening_fee_params};
 use crate::message_queue::MessageQueue;
+use crate::persist::{
+	persist_peer_state, read_peer_states, PersistedPeerState,
+	LSPS2_CLIENT_PERSISTENCE_PRIMARY_NAMESPACE, LSPS2_CLIENT_PERSISTENCE_SECONDARY_NAMESPACE,
+};
 use crate::prelude::{HashMap, String, ToString, Vec};
 use crate::sync::{Arc, Mutex, RwLock};
 
+use lightning::io;
+use lightning::ln::channelmanager::MIN_FINAL_CLTV_EXPIRY_DELTA;
 use lightning::ln::msgs::{ErrorAction, LightningError};
-use lightning::sign::EntropySource;
+use lightning::ln::{PaymentHash, PaymentSecret};
+use lightning::routing::gossip::RoutingFees;
+use lightning::routing::router::{RouteHint, RouteHintHop};
+use lightning::sign::{EntropySource, NodeSigner, Recipient};
 use lightning::util::errors::APIError;
 use lightning::util::logger::Level;
+use lightning::util::persist::KVStore;
+use lightning::{impl_writeable_tlv_based, impl_writeable_tlv_based_enum};
+use lightning_invoice::{Bolt11Invoice, Currency, InvoiceBuilder};
 
+use bitcoin::bech32::ToBase32;
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::hashes::Hash;
 use bitcoin::secp256k1::PublicKey;
 
 use core::ops::Deref;
+use core::time::Duration;
 
 use crate::lsps2::msgs::{
 	BuyRequest, BuyResponse, GetInfoRequest, GetInfoResponse, GetVersionsRequest,
@@ -31,12 +49,185 @@ use crate::lsps2::msgs::{
 	OpeningFeeParams,
 };
 
+/// The default value for [`LSPS2ClientConfig::max_lsp_cltv_expiry_delta`].
+pub const DEFAULT_MAX_LSP_CLTV_EXPIRY_DELTA: u32 = 288;
+
+/// The default value for [`LSPS2ClientConfig::max_opening_fee_min_fee_msat`].
+pub const DEFAULT_MAX_OPENING_FEE_MIN_FEE_MSAT: u64 = 10_000_000;
+
+/// The default value for [`LSPS2ClientConfig::max_opening_fee_proportional`].
+pub const DEFAULT_MAX_OPENING_FEE_PROPORTIONAL: u32 = 50_000;
+
 /// Client-side configuration options for JIT channels.
 #[derive(Clone, Debug, Copy)]
-pub struct LSPS2ClientConfig {}
+pub struct LSPS2ClientConfig {
+	/// The maximum `lsp_cltv_expiry_delta` we accept in a buy response.
+	///
+	/// As the payer has to account for this delta in the route to us, LSPs requiring a larger
+	/// delta are considered to have sent an invalid response.
+	///
+	/// Defaults to [`DEFAULT_MAX_LSP_CLTV_EXPIRY_DELTA`].
+	pub max_lsp_cltv_expiry_delta: u32,
+	/// The maximum `min_fee_msat` we accept in an entry of the opening fee parameters menu.
+	///
+	/// Entries exceeding it are dropped from the menu, and LSPs only offering such entries are
+	/// considered to have sent an invalid response.
+	///
+	/// Defaults to [`DEFAULT_MAX_OPENING_FEE_MIN_FEE_MSAT`].
+	pub max_opening_fee_min_fee_msat: u64,
+	/// The maximum `proportional` fee, in parts per million, we accept in an entry of the opening
+	/// fee parameters menu.
+	///
+	/// Entries exceeding it are dropped from the menu, and LSPs only offering such entries are
+	/// considered to have sent an invalid response.
+	///
+	/// Defaults to [`DEFAULT_MAX_OPENING_FEE_PROPORTIONAL`].
+	pub max_opening_fee_proportional: u32,
+	/// The policy used to automatically select an entry from the opening fee parameters menu
+	/// offered by the LSP.
+	///
+	/// If set, the client will proceed buying a JIT channel right away instead of generating a
+	/// [`LSPS2ClientEvent::GetInfoResponse`] event. If none of the offered parameters is
+	/// acceptable, a [`LSPS2ClientEvent::NoAcceptableOpeningFeeParams`] event is generated
+	/// instead.
+	///
+	/// [`LSPS2ClientEvent::GetInfoResponse`]: crate::lsps2::event::LSPS2ClientEvent::GetInfoResponse
+	/// [`LSPS2ClientEvent::NoAcceptableOpeningFeeParams`]: crate::lsps2::event::LSPS2ClientEvent::NoAcceptableOpeningFeeParams
+	pub opening_fee_params_selection_policy: Option<OpeningFeeParamsSelectionPolicy>,
+}
+
+impl Default for LSPS2ClientConfig {
+	fn default() -> Self {
+		Self {
+			max_lsp_cltv_expiry_delta: DEFAULT_MAX_LSP_CLTV_EXPIRY_DELTA,
+			max_opening_fee_min_fee_msat: DEFAULT_MAX_OPENING_FEE_MIN_FEE_MSAT,
+			max_opening_fee_proportional: DEFAULT_MAX_OPENING_FEE_PROPORTIONAL,
+			opening_fee_params_selection_policy: None,
+		}
+	}
+}
+
+/// A policy to automatically select the cheapest acceptable entry from the opening fee parameters
+/// menu offered by the LSP.
+///
+/// For JIT channels with a fixed payment size, the cheapest entry is the one with the lowest
+/// opening fee for that payment size. Otherwise, entries are compared by their `min_fee_msat`
+/// first and their `proportional` fee second.
+#[derive(Clone, Debug, Copy, Default)]
+pub struct OpeningFeeParamsSelectionPolicy {
+	/// The maximum opening fee we are willing to pay.
+	///
+	/// For JIT channels without a fixed payment size, this is checked against `min_fee_msat`.
+	pub max_opening_fee_msat: Option<u64>,
+	/// The minimum number of blocks we require the LSP to keep the channel open.
+	pub min_channel_lifetime: Option<u32>,
+	/// The maximum `max_client_to_self_delay` we accept.
+	pub max_client_to_self_delay: Option<u32>,
+}
+
+impl OpeningFeeParamsSelectionPolicy {
+	/// Returns the cheapest acceptable entry of the given menu, if any.
+	///
+	/// Expired entries are skipped, which is only possible in std builds.
+	fn select(
+		&self, opening_fee_params_menu: &[OpeningFeeParams], payment_size_msat: Option<u64>,
+	) -> Option<OpeningFeeParams> {
+		let opening_fee = |params: &OpeningFeeParams| match payment_size_msat {
+			Some(payment_size_msat) => compute_opening_fee(
+				payment_size_msat,
+				params.min_fee_msat,
+				params.proportional.into(),
+			),
+			None => Some(params.min_fee_msat),
+		};
+
+		opening_fee_params_menu
+			.iter()
+			.filter(|params| !is_expired_opening_fee_params(params))
+			.filter(|params| {
+				self.min_channel_lifetime.iter().all(|min| params.min_lifetime >= *min)
+			})
+			.filter(|params| {
+				self.max_client_to_self_delay
+					.iter()
+					.all(|max| params.max_client_to_self_delay <= *max)
+			})
+			.filter_map(|params| opening_fee(params).map(|fee| (fee, params)))
+			.filter(|(fee, _)| self.max_opening_fee_msat.iter().all(|max| fee <= max))
+			.min_by_key(|(fee, params)| (*fee, params.proportional))
+			.map(|(_, params)| params.clone())
+	}
+}
 
 const SUPPORTED_SPEC_VERSIONS: [u16; 1] = [1];
 
+/// Validates the given get_info response, dropping the entries of its menu that expired already
+/// or exceed the fee limits of the given config.
+///
+/// Note that expiry is not checked in no-std builds, where all entries within the fee limits are kept.
+fn validate_get_info_response(
+	response: &mut GetInfoResponse, payment_size_msat: Option<u64>, config: &LSPS2ClientConfig,
+) -> Result<(), String> {
+	if response.min_payment_size_msat > response.max_payment_size_msat {
+		return Err(format!(
+			"Minimum payment size {} exceeds maximum payment size {}",
+			response.min_payment_size_msat, response.max_payment_size_msat
+		));
+	}
+
+	if let Some(payment_size_msat) = payment_size_msat {
+		if payment_size_msat < response.min_payment_size_msat
+			|| payment_size_msat > response.max_payment_size_msat
+		{
+			return Err(format!(
+				"Payment size {} is outside of the allowed range [{}, {}]",
+				payment_size_msat, response.min_payment_size_msat, response.max_payment_size_msat
+			));
+		}
+	}
+
+	let offered_params_count = response.opening_fee_params_menu.len();
+	response.opening_fee_params_menu.retain(|params| !is_expired_opening_fee_params(params));
+	if offered_params_count > 0 && response.opening_fee_params_menu.is_empty() {
+		return Err(format!("All {} offered opening fee params expired", offered_params_count));
+	}
+
+	let unexpired_params_count = response.opening_fee_params_menu.len();
+	response.opening_fee_params_menu.retain(|params| {
+		params.min_fee_msat <= config.max_opening_fee_min_fee_msat
+			&& params.proportional <= config.max_opening_fee_proportional
+	});
+	if unexpired_params_count > 0 && response.opening_fee_params_menu.is_empty() {
+		return Err(format!(
+			"All {} unexpired opening fee params exceed our maximum min_fee_msat of {} or proportional fee of {}",
+			unexpired_params_count,
+			config.max_opening_fee_min_fee_msat,
+			config.max_opening_fee_proportional
+		));
+	}
+
+	Ok(())
+}
+
+/// Validates the given buy response, returning the parsed JIT channel scid.
+fn validate_buy_response(
+	response: &BuyResponse, config: &LSPS2ClientConfig,
+) -> Result<u64, String> {
+	let scid = response
+		.jit_channel_scid
+		.to_scid()
+		.map_err(|_| format!("Invalid JIT channel scid {:?}", response.jit_channel_scid))?;
+
+	if response.lsp_cltv_expiry_delta > config.max_lsp_cltv_expiry_delta {
+		return Err(format!(
+			"LSP CLTV expiry delta {} exceeds our maximum of {}",
+			response.lsp_cltv_expiry_delta, config.max_lsp_cltv_expiry_delta
+		));
+	}
+
+	Ok(scid)
+}
+
 struct ChannelStateError(String);
 
 impl From<ChannelStateError> for LightningError {
@@ -51,15 +242,53 @@ struct InboundJITChannelConfig {
 	pub payment_size_msat: Option<u64>,
 }
 
+impl_writeable_tlv_based!(InboundJITChannelConfig, {
+	(0, user_id, required),
+	(2, token, option),
+	(4, payment_size_msat, option),
+});
+
 #[derive(PartialEq, Debug)]
 enum InboundJITChannelState {
 	VersionsRequested,
-	MenuRequested { version: u16 },
-	PendingMenuSelection { version: u16 },
-	BuyRequested { version: u16 },
-	PendingPayment { client_trusts_lsp: bool, short_channel_id: JITChannelScid },
+	MenuRequested {
+		version: u16,
+	},
+	PendingMenuSelection {
+		version: u16,
+	},
+	BuyRequested {
+		version: u16,
+		opening_fee_params: OpeningFeeParams,
+	},
+	PendingPayment {
+		client_trusts_lsp: bool,
+		short_channel_id: JITChannelScid,
+		lsp_cltv_expiry_delta: u32,
+		opening_fee_params: OpeningFeeParams,
+	},
 }
 
+impl_writeable_tlv_based_enum!(InboundJITChannelState,
+	(0, VersionsRequested) => {},
+	(2, MenuRequested) => {
+		(0, version, required),
+	},
+	(4, PendingMenuSelection) => {
+		(0, version, required),
+	},
+	(6, BuyRequested) => {
+		(0, version, required),
+		(2, opening_fee_params, required),
+	},
+	(8, PendingPayment) => {
+		(0, client_trusts_lsp, required),
+		(2, short_channel_id, required),
+		(4, opening_fee_params, required),
+		(6, lsp_cltv_expiry_delta, required),
+	};
+);
+
 impl InboundJITChannelState {
 	fn versions_received(&self, versions: Vec<u16>) -> Result<Self, ChannelStateError> {
 		let max_shared_version = versions
@@ -95,10 +324,12 @@ impl InboundJITChannelState {
 		}
 	}
 
-	fn opening_fee_params_selected(&self) -> Result<Self, ChannelStateError> {
+	fn opening_fee_params_selected(
+		&self, opening_fee_params: OpeningFeeParams,
+	) -> Result<Self, ChannelStateError> {
 		match self {
 			InboundJITChannelState::PendingMenuSelection { version } => {
-				Ok(InboundJITChannelState::BuyRequested { version: *version })
+				Ok(InboundJITChannelState::BuyRequested { version: *version, opening_fee_params })
 			}
 			state => Err(ChannelStateError(format!(
 				"Opening fee params selected when JIT Channel was in state: {:?}",
@@ -109,10 +340,16 @@ impl InboundJITChannelState {
 
 	fn invoice_params_received(
 		&self, client_trusts_lsp: bool, short_channel_id: JITChannelScid,
+		lsp_cltv_expiry_delta: u32,
 	) -> Result<Self, ChannelStateError> {
 		match self {
-			InboundJITChannelState::BuyRequested { .. } => {
-				Ok(InboundJITChannelState::PendingPayment { client_trusts_lsp, short_channel_id })
+			InboundJITChannelState::BuyRequested { opening_fee_params, .. } => {
+				Ok(InboundJITChannelState::PendingPayment {
+					client_trusts_lsp,
+					short_channel_id,
+					lsp_cltv_expiry_delta,
+					opening_fee_params: opening_fee_params.clone(),
+				})
 			}
 			state => Err(ChannelStateError(format!(
 				"Invoice params received when JIT Channel was in state: {:?}",
@@ -120,6 +357,30 @@ impl InboundJITChannelState {
 			))),
 		}
 	}
+
+	fn resumed(&self) -> Self {
+		match self {
+			InboundJITChannelState::PendingMenuSelection { version }
+			| InboundJITChannelState::BuyRequested { version, .. } => {
+				InboundJITChannelState::MenuRequested { version: *version }
+			}
+			InboundJITChannelState::VersionsRequested => InboundJITChannelState::VersionsRequested,
+			InboundJITChannelState::MenuRequested { version } => {
+				InboundJITChannelState::MenuRequested { version: *version }
+			}
+			InboundJITChannelState::PendingPayment {
+				client_trusts_lsp,
+				short_channel_id,
+				lsp_cltv_expiry_delta,
+				opening_fee_params,
+			} => InboundJITChannelState::PendingPayment {
+				client_trusts_lsp: *client_trusts_lsp,
+				short_channel_id: short_channel_id.clone(),
+				lsp_cltv_expiry_delta: *lsp_cltv_expiry_delta,
+				opening_fee_params: opening_fee_params.clone(),
+			},
+		}
+	}
 }
 
 struct InboundJITChannel {
@@ -128,6 +389,12 @@ struct InboundJITChannel {
 	config: InboundJITChannelConfig,
 }
 
+impl_writeable_tlv_based!(InboundJITChannel, {
+	(0, id, required),
+	(2, state, required),
+	(4, config, required),
+});
+
 impl InboundJITChannel {
 	fn new(id: u128, user_id: u128, payment_size_msat: Option<u64>, token: Option<String>) -> Self {
 		Self {
@@ -154,11 +421,13 @@ impl InboundJITChannel {
 		Ok(())
 	}
 
-	fn opening_fee_params_selected(&mut self) -> Result<u16, LightningError> {
-		self.state = self.state.opening_fee_params_selected()?;
+	fn opening_fee_params_selected(
+		&mut self, opening_fee_params: OpeningFeeParams,
+	) -> Result<u16, LightningError> {
+		self.state = self.state.opening_fee_params_selected(opening_fee_params)?;
 
 		match self.state {
-			InboundJITChannelState::BuyRequested { version } => Ok(version),
+			InboundJITChannelState::BuyRequested { version, .. } => Ok(version),
 			_ => Err(LightningError {
 				action: ErrorAction::IgnoreAndLog(Level::Error),
 				err: "impossible state transition".to_string(),
@@ -168,23 +437,93 @@ impl InboundJITChannel {
 
 	fn invoice_params_received(
 		&mut self, client_trusts_lsp: bool, jit_channel_scid: JITChannelScid,
+		lsp_cltv_expiry_delta: u32,
 	) -> Result<(), LightningError> {
-		self.state = self.state.invoice_params_received(client_trusts_lsp, jit_channel_scid)?;
+		self.state = self.state.invoice_params_received(
+			client_trusts_lsp,
+			jit_channel_scid,
+			lsp_cltv_expiry_delta,
+		)?;
 		Ok(())
 	}
+
+	/// Returns whether the fee the LSP skimmed off a payment of `amount_msat` we received over the
+	/// JIT channel is within the agreed opening fee.
+	fn payment_claimable(
+		&self, amount_msat: u64, counterparty_skimmed_fee_msat: u64,
+	) -> Result<bool, LightningError> {
+		match &self.state {
+			InboundJITChannelState::PendingPayment { opening_fee_params, .. } => {
+				let payment_size_msat = self
+					.config
+					.payment_size_msat
+					.unwrap_or(amount_msat.saturating_add(counterparty_skimmed_fee_msat));
+				let max_fee_msat = compute_opening_fee(
+					payment_size_msat,
+					opening_fee_params.min_fee_msat,
+					opening_fee_params.proportional.into(),
+				)
+				.ok_or(LightningError {
+					err: format!(
+						"Could not compute opening fee for payment_size_msat = {}",
+						payment_size_msat
+					),
+					action: ErrorAction::IgnoreAndLog(Level::Info),
+				})?;
+				Ok(counterparty_skimmed_fee_msat <= max_fee_msat)
+			}
+			state => Err(LightningError {
+				err: format!("Payment received when JIT Channel was in state: {:?}", state),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			}),
+		}
+	}
+
+	/// Returns whether the flow waits for a payment that can't arrive anymore, as the opening fee
+	/// parameters the JIT channel was bought with expired.
+	fn is_expired_pending_payment(&self) -> bool {
+		match &self.state {
+			InboundJITChannelState::PendingPayment { opening_fee_params, .. } => {
+				is_expired_opening_fee_params(opening_fee_params)
+			}
+			_ => false,
+		}
+	}
+
+	/// Moves the flow back to a state from which it can be continued by re-sending the request
+	/// returned, if any.
+	fn resume(&mut self) -> Option<LSPS2Request> {
+		self.state = self.state.resumed();
+
+		match self.state {
+			InboundJITChannelState::VersionsRequested => {
+				Some(LSPS2Request::GetVersions(GetVersionsRequest {}))
+			}
+			InboundJITChannelState::MenuRequested { version } => {
+				Some(LSPS2Request::GetInfo(GetInfoRequest {
+					version,
+					token: self.config.token.clone(),
+				}))
+			}
+			_ => None,
+		}
+	}
 }
 
 /// The peer state for LSPS2
 pub struct PeerState {
 	inbound_channels_by_id: HashMap<u128, InboundJITChannel>,
 	request_to_cid: HashMap<RequestId, u128>,
+	// The number of timer ticks each request in `request_to_cid` is awaiting a response.
+	request_ticks: HashMap<RequestId, u32>,
 }
 
 impl PeerState {
 	fn new() -> Self {
 		let inbound_channels_by_id = HashMap::new();
 		let request_to_cid = HashMap::new();
-		Self { inbound_channels_by_id, request_to_cid }
+		let request_ticks = HashMap::new();
+		Self { inbound_channels_by_id, request_to_cid, request_ticks }
 	}
 
 	fn insert_inbound_channel(&mut self, jit_channel_id: u128, channel: InboundJITChannel) {
@@ -192,44 +531,124 @@ impl PeerState {
 	}
 
 	fn insert_request(&mut self, request_id: RequestId, jit_channel_id: u128) {
+		self.request_ticks.insert(request_id.clone(), 0);
 		self.request_to_cid.insert(request_id, jit_channel_id);
 	}
 
+	fn remove_request(&mut self, request_id: &RequestId) -> Option<u128> {
+		self.request_ticks.remove(request_id);
+		self.request_to_cid.remove(request_id)
+	}
+
+	/// Returns the requests that weren't answered within `request_timeout_ticks` timer ticks.
+	fn timer_tick_occurred(&mut self, request_timeout_ticks: u32) -> Vec<RequestId> {
+		let mut timed_out_requests = Vec::new();
+		for (request_id, ticks) in self.request_ticks.iter_mut() {
+			*ticks += 1;
+			if *ticks > request_timeout_ticks {
+				timed_out_requests.push(request_id.clone());
+			}
+		}
+		timed_out_requests
+	}
+
+	/// Forgets about all requests in flight, as they can't be answered after a disconnection.
+	///
+	/// The flows they belong to are kept, so that they can be resumed upon reconnection.
+	fn peer_disconnected(&mut self) {
+		self.request_to_cid.clear();
+		self.request_ticks.clear();
+	}
+
 	fn remove_inbound_channel(&mut self, jit_channel_id: u128) {
 		self.inbound_channels_by_id.remove(&jit_channel_id);
 	}
+
+	/// Removes the flows whose payment can't arrive anymore, returning their `user_channel_id`s.
+	fn prune_expired_flows(&mut self) -> Vec<u128> {
+		let mut expired_user_channel_ids = Vec::new();
+		self.inbound_channels_by_id.retain(|_, jit_channel| {
+			if jit_channel.is_expired_pending_payment() {
+				expired_user_channel_ids.push(jit_channel.config.user_id);
+				false
+			} else {
+				true
+			}
+		});
+		expired_user_channel_ids
+	}
+}
+
+// Requests in flight can't be answered after a restart or a disconnection, so we don't persist
+// `request_to_cid` and rather re-send them via `LSPS2ClientHandler::resume_pending_flows`.
+impl_writeable_tlv_based!(PeerState, {
+	(0, inbound_channels_by_id, required),
+	(not_written, request_to_cid, (static_value, HashMap::new())),
+	(not_written, request_ticks, (static_value, HashMap::new())),
+});
+
+impl PersistedPeerState for PeerState {
+	const PRIMARY_NAMESPACE: &'static str = LSPS2_CLIENT_PERSISTENCE_PRIMARY_NAMESPACE;
+	const SECONDARY_NAMESPACE: &'static str = LSPS2_CLIENT_PERSISTENCE_SECONDARY_NAMESPACE;
+
+	fn is_persistable(&self) -> bool {
+		!self.inbound_channels_by_id.is_empty()
+	}
 }
 
 /// The main object allowing to send and receive LSPS2 messages.
-pub struct LSPS2ClientHandler<ES: Deref, MQ: Deref>
+///
+/// The state of all JIT channel flows is persisted to the given [`KVStore`] on every change and
+/// reloaded from it upon construction. After a restart or a disconnection, flows that were still
+/// waiting on a response from the LSP are continued via
+/// [`LSPS2ClientHandler::resume_pending_flows`] once the LSP is connected again.
+pub struct LSPS2ClientHandler<ES: Deref, MQ: Deref, K: Deref>
 where
 	ES::Target: EntropySource,
 	MQ::Target: MessageQueue,
+	K::Target: KVStore,
 {
 	entropy_source: ES,
 	pending_messages: MQ,
 	pending_events: Arc<EventQueue>,
 	per_peer_state: RwLock<HashMap<PublicKey, Mutex<PeerState>>>,
-	_config: LSPS2ClientConfig,
+	kv_store: K,
+	config: LSPS2ClientConfig,
+	request_timeout_ticks: u32,
 }
 
-impl<ES: Deref, MQ: Deref> LSPS2ClientHandler<ES, MQ>
+impl<ES: Deref, MQ: Deref, K: Deref> LSPS2ClientHandler<ES, MQ, K>
 where
 	ES::Target: EntropySource,
 	MQ::Target: MessageQueue,
+	K::Target: KVStore,
 {
-	/// Constructs an `LSPS2ClientHandler`.
+	/// Constructs an `LSPS2ClientHandler`, reloading any previously persisted state from the
+	/// given `kv_store`.
+	///
+	/// Requests that aren't answered within `request_timeout_ticks` calls to
+	/// [`LSPS2ClientHandler::timer_tick_occurred`] are timed out.
 	pub fn new(
-		entropy_source: ES, pending_messages: MQ, pending_events: Arc<EventQueue>,
-		config: LSPS2ClientConfig,
-	) -> Self {
-		Self {
+		entropy_source: ES, pending_messages: MQ, pending_events: Arc<EventQueue>, kv_store: K,
+		config: LSPS2ClientConfig, request_timeout_ticks: u32,
+	) -> Result<Self, io::Error> {
+		let persisted_peer_states: HashMap<PublicKey, PeerState> = read_peer_states(&kv_store)?;
+		let per_peer_state = persisted_peer_states
+			.into_iter()
+			.map(|(counterparty_node_id, peer_state)| {
+				(counterparty_node_id, Mutex::new(peer_state))
+			})
+			.collect();
+
+		Ok(Self {
 			entropy_source,
 			pending_messages,
 			pending_events,
-			per_peer_state: RwLock::new(HashMap::new()),
-			_config: config,
-		}
+			per_peer_state: RwLock::new(per_peer_state),
+			kv_store,
+			config,
+			request_timeout_ticks,
+		})
 	}
 
 	/// Initiate the creation of an invoice that when paid will open a channel
@@ -248,7 +667,7 @@ where
 	pub fn create_invoice(
 		&self, counterparty_node_id: PublicKey, payment_size_msat: Option<u64>,
 		token: Option<String>, user_channel_id: u128,
-	) {
+	) -> Result<(), LiquidityError> {
 		let jit_channel_id = self.generate_jit_channel_id();
 		let channel =
 			InboundJITChannel::new(jit_channel_id, user_channel_id, payment_size_msat, token);
@@ -261,12 +680,192 @@ where
 
 		let request_id = crate::utils::generate_request_id(&self.entropy_source);
 		peer_state_lock.insert_request(request_id.clone(), jit_channel_id);
+		persist_peer_state(&self.kv_store, &counterparty_node_id, &*peer_state_lock)?;
 
 		self.pending_messages.enqueue(
 			&counterparty_node_id,
 			LSPS2Message::Request(request_id, LSPS2Request::GetVersions(GetVersionsRequest {}))
 				.into(),
 		);
+
+		Ok(())
+	}
+
+	/// Continues all JIT channel flows with the given LSP that were interrupted by a restart or a
+	/// disconnection.
+	///
+	/// Is called by [`LiquidityManager::peer_connected`], which should be called once the
+	/// connection to the LSP has been (re-)established. Flows for which no request is currently in flight are
+	/// restarted from the last step that doesn't require any input from you, i.e., you will
+	/// receive a new [`LSPS2ClientEvent::GetInfoResponse`] event if the opening fee parameters
+	/// weren't accepted by the LSP yet. Flows which already reached the
+	/// [`LSPS2ClientEvent::InvoiceGenerationReady`] stage are left untouched and can be queried
+	/// via [`LSPS2ClientHandler::jit_channel_scid`].
+	///
+	/// [`LiquidityManager::peer_connected`]: crate::LiquidityManager::peer_connected
+	/// [`LSPS2ClientEvent::GetInfoResponse`]: crate::lsps2::event::LSPS2ClientEvent::GetInfoResponse
+	/// [`LSPS2ClientEvent::InvoiceGenerationReady`]: crate::lsps2::event::LSPS2ClientEvent::InvoiceGenerationReady
+	pub fn resume_pending_flows(
+		&self, counterparty_node_id: &PublicKey,
+	) -> Result<(), LiquidityError> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		if let Some(inner_state_lock) = outer_state_lock.get(counterparty_node_id) {
+			let mut peer_state = inner_state_lock.lock().unwrap();
+			let peer_state = &mut *peer_state;
+
+			let mut requests = Vec::new();
+			for (jit_channel_id, jit_channel) in peer_state.inbound_channels_by_id.iter_mut() {
+				if peer_state.request_to_cid.values().any(|cid| cid == jit_channel_id) {
+					continue;
+				}
+
+				if let Some(request) = jit_channel.resume() {
+					let request_id = crate::utils::generate_request_id(&self.entropy_source);
+					requests.push((request_id, *jit_channel_id, request));
+				}
+			}
+
+			for (request_id, jit_channel_id, _) in requests.iter() {
+				peer_state.insert_request(request_id.clone(), *jit_channel_id);
+			}
+			persist_peer_state(&self.kv_store, counterparty_node_id, peer_state)?;
+
+			for (request_id, _, request) in requests {
+				self.pending_messages.enqueue(
+					counterparty_node_id,
+					LSPS2Message::Request(request_id, request).into(),
+				);
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Returns the scid the LSP assigned to the JIT channel flow started with the given
+	/// `user_channel_id`, if the flow already reached the
+	/// [`LSPS2ClientEvent::InvoiceGenerationReady`] stage.
+	///
+	/// This allows to find which invoices are backed by which JIT channel after a restart.
+	///
+	/// [`LSPS2ClientEvent::InvoiceGenerationReady`]: crate::lsps2::event::LSPS2ClientEvent::InvoiceGenerationReady
+	pub fn jit_channel_scid(
+		&self, counterparty_node_id: &PublicKey, user_channel_id: u128,
+	) -> Option<u64> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		let peer_state = outer_state_lock.get(counterparty_node_id)?.lock().unwrap();
+		peer_state.inbound_channels_by_id.values().find_map(|jit_channel| {
+			match &jit_channel.state {
+				InboundJITChannelState::PendingPayment { short_channel_id, .. }
+					if jit_channel.config.user_id == user_channel_id =>
+				{
+					short_channel_id.to_scid().ok()
+				}
+				_ => None,
+			}
+		})
+	}
+
+	/// Creates a BOLT11 invoice for the JIT channel flow started with the given `user_channel_id`,
+	/// routing the payment to us via the JIT channel the LSP will open.
+	///
+	/// Should be called in response to receiving a [`LSPS2ClientEvent::InvoiceGenerationReady`]
+	/// event. The `payment_hash` and `payment_secret` can be generated via
+	/// [`ChannelManager::create_inbound_payment`].
+	///
+	/// The invoice will be for the `payment_size_msat` given to
+	/// [`LSPS2ClientHandler::create_invoice`], if any, and otherwise leave it to the payer to choose
+	/// the amount. If `min_final_cltv_expiry_delta` is [`Option::None`],
+	/// [`MIN_FINAL_CLTV_EXPIRY_DELTA`] will be used, otherwise a buffer of 3 blocks is added to the
+	/// given value.
+	///
+	/// [`LSPS2ClientEvent::InvoiceGenerationReady`]: crate::lsps2::event::LSPS2ClientEvent::InvoiceGenerationReady
+	/// [`ChannelManager::create_inbound_payment`]: lightning::ln::channelmanager::ChannelManager::create_inbound_payment
+	#[allow(clippy::too_many_arguments)]
+	pub fn create_bolt11_invoice<NS: Deref>(
+		&self, counterparty_node_id: &PublicKey, user_channel_id: u128, payment_hash: PaymentHash,
+		payment_secret: PaymentSecret, description: String, invoice_expiry_delta_secs: u32,
+		min_final_cltv_expiry_delta: Option<u16>, currency: Currency,
+		duration_since_epoch: Duration, node_signer: NS,
+	) -> Result<Bolt11Invoice, LiquidityError>
+	where
+		NS::Target: NodeSigner,
+	{
+		let (scid, lsp_cltv_expiry_delta, payment_size_msat) = {
+			let outer_state_lock = self.per_peer_state.read().unwrap();
+			let peer_state = outer_state_lock
+				.get(counterparty_node_id)
+				.ok_or_else(|| APIError::APIMisuseError {
+					err: format!("No existing state with counterparty {}", counterparty_node_id),
+				})?
+				.lock()
+				.unwrap();
+			peer_state
+				.inbound_channels_by_id
+				.values()
+				.find_map(|jit_channel| match &jit_channel.state {
+					InboundJITChannelState::PendingPayment {
+						short_channel_id,
+						lsp_cltv_expiry_delta,
+						..
+					} if jit_channel.config.user_id == user_channel_id => Some((
+						short_channel_id.clone(),
+						*lsp_cltv_expiry_delta,
+						jit_channel.config.payment_size_msat,
+					)),
+					_ => None,
+				})
+				.ok_or_else(|| APIError::APIMisuseError {
+					err: format!("No pending payment for user_channel_id {}", user_channel_id),
+				})?
+		};
+
+		let short_channel_id = scid.to_scid().map_err(|_| APIError::APIMisuseError {
+			err: format!("Invalid scid {:?} for user_channel_id {}", scid, user_channel_id),
+		})?;
+		let cltv_expiry_delta =
+			lsp_cltv_expiry_delta.try_into().map_err(|_| APIError::APIMisuseError {
+				err: format!("Invalid cltv_expiry_delta {}", lsp_cltv_expiry_delta),
+			})?;
+		let route_hint = RouteHint(vec![RouteHintHop {
+			src_node_id: *counterparty_node_id,
+			short_channel_id,
+			fees: RoutingFees { base_msat: 0, proportional_millionths: 0 },
+			cltv_expiry_delta,
+			htlc_minimum_msat: None,
+			htlc_maximum_msat: None,
+		}]);
+
+		let mut invoice = InvoiceBuilder::new(currency)
+			.description(description)
+			.duration_since_epoch(duration_since_epoch)
+			.payment_hash(Sha256::from_inner(payment_hash.0))
+			.payment_secret(payment_secret)
+			.min_final_cltv_expiry_delta(
+				min_final_cltv_expiry_delta
+					.map(|delta| delta.saturating_add(3))
+					.unwrap_or(MIN_FINAL_CLTV_EXPIRY_DELTA)
+					.into(),
+			)
+			.expiry_time(Duration::from_secs(invoice_expiry_delta_secs.into()))
+			.private_route(route_hint);
+		// Only fixed-amount payments may be split, see `LSPS2ClientHandler::create_invoice`.
+		if let Some(payment_size_msat) = payment_size_msat {
+			invoice = invoice.amount_milli_satoshis(payment_size_msat).basic_mpp();
+		}
+
+		let raw_invoice = invoice.build_raw().map_err(|e| APIError::APIMisuseError {
+			err: format!("Failed to create invoice: {}", e),
+		})?;
+		let hrp = raw_invoice.hrp.to_string();
+		let data_without_signature = raw_invoice.data.to_base32();
+		let signed_raw_invoice = raw_invoice
+			.sign(|_| {
+				node_signer.sign_invoice(hrp.as_bytes(), &data_without_signature, Recipient::Node)
+			})
+			.map_err(|_| APIError::APIMisuseError { err: "Failed to sign invoice".to_string() })?;
+		Bolt11Invoice::from_signed(signed_raw_invoice).map_err(|e| {
+			APIError::APIMisuseError { err: format!("Failed to create invoice: {}", e) }.into()
+		})
 	}
 
 	/// Used by client to confirm which channel parameters to use for the JIT Channel buy request.
@@ -279,54 +878,189 @@ where
 	pub fn opening_fee_params_selected(
 		&self, counterparty_node_id: PublicKey, jit_channel_id: u128,
 		opening_fee_params: OpeningFeeParams,
-	) -> Result<(), APIError> {
+	) -> Result<(), LiquidityError> {
 		let outer_state_lock = self.per_peer_state.read().unwrap();
 		match outer_state_lock.get(&counterparty_node_id) {
 			Some(inner_state_lock) => {
 				let mut peer_state = inner_state_lock.lock().unwrap();
-				if let Some(jit_channel) =
-					peer_state.inbound_channels_by_id.get_mut(&jit_channel_id)
-				{
-					let version = match jit_channel.opening_fee_params_selected() {
-						Ok(version) => version,
-						Err(e) => {
-							peer_state.remove_inbound_channel(jit_channel_id);
-							return Err(APIError::APIMisuseError { err: e.err });
-						}
-					};
-
-					let request_id = crate::utils::generate_request_id(&self.entropy_source);
-					let payment_size_msat = jit_channel.config.payment_size_msat;
-					peer_state.insert_request(request_id.clone(), jit_channel_id);
+				if peer_state.inbound_channels_by_id.contains_key(&jit_channel_id) {
+					let res = self.buy(&mut peer_state, jit_channel_id, opening_fee_params);
+					persist_peer_state(&self.kv_store, &counterparty_node_id, &*peer_state)?;
+					let (request_id, request) =
+						res.map_err(|e| APIError::APIMisuseError { err: e.err })?;
 
 					self.pending_messages.enqueue(
 						&counterparty_node_id,
-						LSPS2Message::Request(
-							request_id,
-							LSPS2Request::Buy(BuyRequest {
-								version,
-								opening_fee_params,
-								payment_size_msat,
-							}),
-						)
-						.into(),
+						LSPS2Message::Request(request_id, request).into(),
 					);
 				} else {
 					return Err(APIError::APIMisuseError {
 						err: format!("Channel with id {} not found", jit_channel_id),
-					});
+					}
+					.into());
 				}
 			}
 			None => {
 				return Err(APIError::APIMisuseError {
 					err: format!("No existing state with counterparty {}", counterparty_node_id),
-				})
+				}
+				.into())
 			}
 		}
 
 		Ok(())
 	}
 
+	/// Verifies the fee the LSP skimmed off a payment received over a JIT channel.
+	///
+	/// Should be called for any [`Event::PaymentClaimable`] received via a channel opened by the
+	/// given LSP for the JIT channel flow started with the given `user_channel_id`, passing in the
+	/// event's `amount_msat` and `counterparty_skimmed_fee_msat`.
+	///
+	/// Returns `Ok(true)` if the skimmed fee doesn't exceed the opening fee agreed upon in
+	/// [`LSPS2ClientHandler::opening_fee_params_selected`], in which case the payment may be
+	/// claimed and the JIT channel flow is finished. Returns `Ok(false)` if the LSP skimmed more
+	/// than agreed, in which case the payment should be failed back via
+	/// [`ChannelManager::fail_htlc_backwards`].
+	///
+	/// [`Event::PaymentClaimable`]: lightning::events::Event::PaymentClaimable
+	/// [`ChannelManager::fail_htlc_backwards`]: lightning::ln::channelmanager::ChannelManager::fail_htlc_backwards
+	pub fn payment_claimable(
+		&self, counterparty_node_id: &PublicKey, user_channel_id: u128, amount_msat: u64,
+		counterparty_skimmed_fee_msat: u64,
+	) -> Result<bool, LiquidityError> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		let inner_state_lock =
+			outer_state_lock.get(counterparty_node_id).ok_or_else(|| APIError::APIMisuseError {
+				err: format!("No existing state with counterparty {}", counterparty_node_id),
+			})?;
+		let mut peer_state = inner_state_lock.lock().unwrap();
+		let (jit_channel_id, jit_channel) = peer_state
+			.inbound_channels_by_id
+			.iter()
+			.find(|(_, jit_channel)| {
+				jit_channel.config.user_id == user_channel_id
+					&& matches!(jit_channel.state, InboundJITChannelState::PendingPayment { .. })
+			})
+			.ok_or_else(|| APIError::APIMisuseError {
+				err: format!("No pending payment for user_channel_id {}", user_channel_id),
+			})?;
+
+		let is_valid_fee = jit_channel
+			.payment_claimable(amount_msat, counterparty_skimmed_fee_msat)
+			.map_err(|e| APIError::APIMisuseError { err: e.err })?;
+
+		if is_valid_fee {
+			let jit_channel_id = *jit_channel_id;
+			peer_state.remove_inbound_channel(jit_channel_id);
+			persist_peer_state(&self.kv_store, counterparty_node_id, &*peer_state)?;
+		}
+
+		Ok(is_valid_fee)
+	}
+
+	/// Aborts the JIT channel flows for which the LSP didn't answer our request within the
+	/// `request_timeout_ticks` given upon construction, generating an
+	/// [`LSPS2ClientEvent::RequestTimedOut`] event for each of them.
+	///
+	/// Also removes the flows still waiting for a payment once the opening fee parameters the JIT
+	/// channel was bought with expired, generating an [`LSPS2ClientEvent::PaymentExpired`] event
+	/// for each of them. Note that this never happens in `no-std` environments, as we can't tell
+	/// the current time there.
+	///
+	/// Is called by [`LiquidityManager::timer_tick_occurred`].
+	///
+	/// [`LSPS2ClientEvent::RequestTimedOut`]: crate::lsps2::event::LSPS2ClientEvent::RequestTimedOut
+	/// [`LSPS2ClientEvent::PaymentExpired`]: crate::lsps2::event::LSPS2ClientEvent::PaymentExpired
+	/// [`LiquidityManager::timer_tick_occurred`]: crate::LiquidityManager::timer_tick_occurred
+	pub fn timer_tick_occurred(&self) {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		for (counterparty_node_id, inner_state_lock) in outer_state_lock.iter() {
+			let mut peer_state = inner_state_lock.lock().unwrap();
+			let timed_out_requests = peer_state.timer_tick_occurred(self.request_timeout_ticks);
+			let expired_user_channel_ids = peer_state.prune_expired_flows();
+			if timed_out_requests.is_empty() && expired_user_channel_ids.is_empty() {
+				continue;
+			}
+
+			for user_channel_id in expired_user_channel_ids {
+				self.pending_events.enqueue(Event::LSPS2Client(LSPS2ClientEvent::PaymentExpired {
+					counterparty_node_id: *counterparty_node_id,
+					user_channel_id,
+				}));
+			}
+
+			for request_id in timed_out_requests {
+				let jit_channel =
+					peer_state.remove_request(&request_id).and_then(|jit_channel_id| {
+						peer_state.inbound_channels_by_id.remove(&jit_channel_id)
+					});
+				if let Some(jit_channel) = jit_channel {
+					self.pending_events.enqueue(Event::LSPS2Client(
+						LSPS2ClientEvent::RequestTimedOut {
+							counterparty_node_id: *counterparty_node_id,
+							user_channel_id: jit_channel.config.user_id,
+						},
+					));
+				}
+			}
+			// We can't do much about persistence failures here, but will retry on the next change.
+			let _ = persist_peer_state(&self.kv_store, counterparty_node_id, &*peer_state);
+		}
+	}
+
+	pub(crate) fn peer_disconnected(&self, counterparty_node_id: &PublicKey) {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		if let Some(inner_state_lock) = outer_state_lock.get(counterparty_node_id) {
+			inner_state_lock.lock().unwrap().peer_disconnected();
+		}
+	}
+
+	/// Advances the given flow to buying a JIT channel with the given parameters, returning the
+	/// buy request to send, or removes the flow if that fails.
+	fn buy(
+		&self, peer_state: &mut PeerState, jit_channel_id: u128,
+		opening_fee_params: OpeningFeeParams,
+	) -> Result<(RequestId, LSPS2Request), LightningError> {
+		let jit_channel =
+			peer_state.inbound_channels_by_id.get_mut(&jit_channel_id).ok_or(LightningError {
+				err: format!("Channel with id {} not found", jit_channel_id),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			})?;
+
+		let version = match jit_channel.opening_fee_params_selected(opening_fee_params.clone()) {
+			Ok(version) => version,
+			Err(e) => {
+				peer_state.remove_inbound_channel(jit_channel_id);
+				return Err(e);
+			}
+		};
+		let payment_size_msat = jit_channel.config.payment_size_msat;
+
+		let request_id = crate::utils::generate_request_id(&self.entropy_source);
+		peer_state.insert_request(request_id.clone(), jit_channel_id);
+		Ok((
+			request_id,
+			LSPS2Request::Buy(BuyRequest { version, opening_fee_params, payment_size_msat }),
+		))
+	}
+
+	/// Notifies the user about an invalid response, returning the error to report back.
+	fn invalid_response(
+		&self, counterparty_node_id: &PublicKey, user_channel_id: u128, reason: String,
+	) -> LightningError {
+		let err = format!(
+			"Received invalid response from counterparty {}: {}",
+			counterparty_node_id, reason
+		);
+		self.pending_events.enqueue(Event::LSPS2Client(LSPS2ClientEvent::InvalidLSPResponse {
+			counterparty_node_id: *counterparty_node_id,
+			reason,
+			user_channel_id,
+		}));
+		LightningError { err, action: ErrorAction::IgnoreAndLog(Level::Info) }
+	}
+
 	fn generate_jit_channel_id(&self) -> u128 {
 		let bytes = self.entropy_source.get_secure_random_bytes();
 		let mut id_bytes: [u8; 16] = [0; 16];
@@ -343,7 +1077,7 @@ where
 				let mut peer_state = inner_state_lock.lock().unwrap();
 
 				let jit_channel_id =
-					peer_state.request_to_cid.remove(&request_id).ok_or(LightningError {
+					peer_state.remove_request(&request_id).ok_or(LightningError {
 						err: format!(
 							"Received get_versions response for an unknown request: {:?}",
 							request_id
@@ -364,6 +1098,26 @@ where
 
 				let token = jit_channel.config.token.clone();
 
+				if !result.versions.iter().any(|version| SUPPORTED_SPEC_VERSIONS.contains(version))
+				{
+					let user_channel_id = jit_channel.config.user_id;
+					peer_state.remove_inbound_channel(jit_channel_id);
+					self.pending_events.enqueue(Event::LSPS2Client(
+						LSPS2ClientEvent::UnsupportedVersions {
+							counterparty_node_id: *counterparty_node_id,
+							versions: result.versions.clone(),
+							user_channel_id,
+						},
+					));
+					return Err(LightningError {
+						err: format!(
+							"LSP {} does not support any of our specification versions. ours = {:?}. theirs = {:?}",
+							counterparty_node_id, SUPPORTED_SPEC_VERSIONS, result.versions
+						),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					});
+				}
+
 				let version = match jit_channel.versions_received(result.versions) {
 					Ok(version) => version,
 					Err(e) => {
@@ -399,7 +1153,7 @@ where
 	}
 
 	fn handle_get_info_response(
-		&self, request_id: RequestId, counterparty_node_id: &PublicKey, result: GetInfoResponse,
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, mut result: GetInfoResponse,
 	) -> Result<(), LightningError> {
 		let outer_state_lock = self.per_peer_state.read().unwrap();
 		match outer_state_lock.get(counterparty_node_id) {
@@ -407,7 +1161,7 @@ where
 				let mut peer_state = inner_state_lock.lock().unwrap();
 
 				let jit_channel_id =
-					peer_state.request_to_cid.remove(&request_id).ok_or(LightningError {
+					peer_state.remove_request(&request_id).ok_or(LightningError {
 						err: format!(
 							"Received get_info response for an unknown request: {:?}",
 							request_id
@@ -431,6 +1185,49 @@ where
 					return Err(e);
 				}
 
+				let user_channel_id = jit_channel.config.user_id;
+				if let Err(reason) = validate_get_info_response(
+					&mut result,
+					jit_channel.config.payment_size_msat,
+					&self.config,
+				) {
+					peer_state.remove_inbound_channel(jit_channel_id);
+					return Err(self.invalid_response(
+						counterparty_node_id,
+						user_channel_id,
+						reason,
+					));
+				}
+
+				if let Some(policy) = self.config.opening_fee_params_selection_policy {
+					match policy.select(
+						&result.opening_fee_params_menu,
+						jit_channel.config.payment_size_msat,
+					) {
+						Some(opening_fee_params) => {
+							let (request_id, request) =
+								self.buy(&mut peer_state, jit_channel_id, opening_fee_params)?;
+							self.pending_messages.enqueue(
+								counterparty_node_id,
+								LSPS2Message::Request(request_id, request).into(),
+							);
+						}
+						None => {
+							self.pending_events.enqueue(Event::LSPS2Client(
+								LSPS2ClientEvent::NoAcceptableOpeningFeeParams {
+									counterparty_node_id: *counterparty_node_id,
+									opening_fee_params_menu: result.opening_fee_params_menu,
+									min_payment_size_msat: result.min_payment_size_msat,
+									max_payment_size_msat: result.max_payment_size_msat,
+									jit_channel_id,
+									user_channel_id,
+								},
+							));
+						}
+					}
+					return Ok(());
+				}
+
 				self.pending_events.enqueue(Event::LSPS2Client(
 					LSPS2ClientEvent::GetInfoResponse {
 						counterparty_node_id: *counterparty_node_id,
@@ -438,7 +1235,7 @@ where
 						min_payment_size_msat: result.min_payment_size_msat,
 						max_payment_size_msat: result.max_payment_size_msat,
 						jit_channel_id,
-						user_channel_id: jit_channel.config.user_id,
+						user_channel_id,
 					},
 				));
 			}
@@ -456,8 +1253,54 @@ where
 		Ok(())
 	}
 
+	fn handle_get_versions_error(
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, error: ResponseError,
+	) -> Result<(), LightningError> {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		match outer_state_lock.get(counterparty_node_id) {
+			Some(inner_state_lock) => {
+				let mut peer_state = inner_state_lock.lock().unwrap();
+
+				let jit_channel_id =
+					peer_state.remove_request(&request_id).ok_or(LightningError {
+						err: format!(
+							"Received get_versions error for an unknown request: {:?}",
+							request_id
+						),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					})?;
+
+				let jit_channel = peer_state.inbound_channels_by_id.remove(&jit_channel_id).ok_or(
+					LightningError {
+						err: format!(
+							"Received get_versions error for an unknown channel: {:?}",
+							jit_channel_id
+						),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					},
+				)?;
+
+				self.pending_events.enqueue(Event::LSPS2Client(
+					LSPS2ClientEvent::GetVersionsRequestFailed {
+						counterparty_node_id: *counterparty_node_id,
+						error,
+						user_channel_id: jit_channel.config.user_id,
+					},
+				));
+				Ok(())
+			}
+			None => Err(LightningError {
+				err: format!(
+					"Received error response for a get_versions request from an unknown counterparty ({:?})",
+					counterparty_node_id
+				),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			}),
+		}
+	}
+
 	fn handle_get_info_error(
-		&self, request_id: RequestId, counterparty_node_id: &PublicKey, _error: ResponseError,
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, error: ResponseError,
 	) -> Result<(), LightningError> {
 		let outer_state_lock = self.per_peer_state.read().unwrap();
 		match outer_state_lock.get(counterparty_node_id) {
@@ -465,7 +1308,7 @@ where
 				let mut peer_state = inner_state_lock.lock().unwrap();
 
 				let jit_channel_id =
-					peer_state.request_to_cid.remove(&request_id).ok_or(LightningError {
+					peer_state.remove_request(&request_id).ok_or(LightningError {
 						err: format!(
 							"Received get_info error for an unknown request: {:?}",
 							request_id
@@ -473,7 +1316,7 @@ where
 						action: ErrorAction::IgnoreAndLog(Level::Info),
 					})?;
 
-				peer_state.inbound_channels_by_id.remove(&jit_channel_id).ok_or(
+				let jit_channel = peer_state.inbound_channels_by_id.remove(&jit_channel_id).ok_or(
 					LightningError {
 						err: format!(
 							"Received get_info error for an unknown channel: {:?}",
@@ -482,11 +1325,23 @@ where
 						action: ErrorAction::IgnoreAndLog(Level::Info),
 					},
 				)?;
+
+				self.pending_events.enqueue(Event::LSPS2Client(
+					LSPS2ClientEvent::GetInfoRequestFailed {
+						counterparty_node_id: *counterparty_node_id,
+						error,
+						user_channel_id: jit_channel.config.user_id,
+					},
+				));
 				Ok(())
 			}
-			None => {
-				return Err(LightningError { err: format!("Received error response for a get_info request from an unknown counterparty ({:?})",counterparty_node_id), action: ErrorAction::IgnoreAndLog(Level::Info)});
-			}
+			None => Err(LightningError {
+				err: format!(
+					"Received error response for a get_info request from an unknown counterparty ({:?})",
+					counterparty_node_id
+				),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			}),
 		}
 	}
 
@@ -499,7 +1354,7 @@ where
 				let mut peer_state = inner_state_lock.lock().unwrap();
 
 				let jit_channel_id =
-					peer_state.request_to_cid.remove(&request_id).ok_or(LightningError {
+					peer_state.remove_request(&request_id).ok_or(LightningError {
 						err: format!(
 							"Received buy response for an unknown request: {:?}",
 							request_id
@@ -518,34 +1373,38 @@ where
 						action: ErrorAction::IgnoreAndLog(Level::Info),
 					})?;
 
+				let user_channel_id = jit_channel.config.user_id;
+				let scid = match validate_buy_response(&result, &self.config) {
+					Ok(scid) => scid,
+					Err(reason) => {
+						peer_state.remove_inbound_channel(jit_channel_id);
+						return Err(self.invalid_response(
+							counterparty_node_id,
+							user_channel_id,
+							reason,
+						));
+					}
+				};
+
 				if let Err(e) = jit_channel.invoice_params_received(
 					result.client_trusts_lsp,
 					result.jit_channel_scid.clone(),
+					result.lsp_cltv_expiry_delta,
 				) {
 					peer_state.remove_inbound_channel(jit_channel_id);
 					return Err(e);
 				}
 
-				if let Ok(scid) = result.jit_channel_scid.to_scid() {
-					self.pending_events.enqueue(Event::LSPS2Client(
-						LSPS2ClientEvent::InvoiceGenerationReady {
-							counterparty_node_id: *counterparty_node_id,
-							scid,
-							cltv_expiry_delta: result.lsp_cltv_expiry_delta,
-							payment_size_msat: jit_channel.config.payment_size_msat,
-							client_trusts_lsp: result.client_trusts_lsp,
-							user_channel_id: jit_channel.config.user_id,
-						},
-					));
-				} else {
-					return Err(LightningError {
-						err: format!(
-							"Received buy response with an invalid scid {:?}",
-							result.jit_channel_scid
-						),
-						action: ErrorAction::IgnoreAndLog(Level::Info),
-					});
-				}
+				self.pending_events.enqueue(Event::LSPS2Client(
+					LSPS2ClientEvent::InvoiceGenerationReady {
+						counterparty_node_id: *counterparty_node_id,
+						scid,
+						cltv_expiry_delta: result.lsp_cltv_expiry_delta,
+						payment_size_msat: jit_channel.config.payment_size_msat,
+						client_trusts_lsp: result.client_trusts_lsp,
+						user_channel_id,
+					},
+				));
 			}
 			None => {
 				return Err(LightningError {
@@ -561,7 +1420,7 @@ where
 	}
 
 	fn handle_buy_error(
-		&self, request_id: RequestId, counterparty_node_id: &PublicKey, _error: ResponseError,
+		&self, request_id: RequestId, counterparty_node_id: &PublicKey, error: ResponseError,
 	) -> Result<(), LightningError> {
 		let outer_state_lock = self.per_peer_state.read().unwrap();
 		match outer_state_lock.get(counterparty_node_id) {
@@ -569,34 +1428,46 @@ where
 				let mut peer_state = inner_state_lock.lock().unwrap();
 
 				let jit_channel_id =
-					peer_state.request_to_cid.remove(&request_id).ok_or(LightningError {
+					peer_state.remove_request(&request_id).ok_or(LightningError {
 						err: format!("Received buy error for an unknown request: {:?}", request_id),
 						action: ErrorAction::IgnoreAndLog(Level::Info),
 					})?;
 
-				let _jit_channel = peer_state
-					.inbound_channels_by_id
-					.remove(&jit_channel_id)
-					.ok_or(LightningError {
+				let jit_channel = peer_state.inbound_channels_by_id.remove(&jit_channel_id).ok_or(
+					LightningError {
 						err: format!(
 							"Received buy error for an unknown channel: {:?}",
 							jit_channel_id
 						),
 						action: ErrorAction::IgnoreAndLog(Level::Info),
-					})?;
+					},
+				)?;
+
+				self.pending_events.enqueue(Event::LSPS2Client(
+					LSPS2ClientEvent::BuyRequestFailed {
+						counterparty_node_id: *counterparty_node_id,
+						error,
+						user_channel_id: jit_channel.config.user_id,
+					},
+				));
 				Ok(())
 			}
-			None => {
-				return Err(LightningError { err: format!("Received error response for a buy request from an unknown counterparty ({:?})",counterparty_node_id), action: ErrorAction::IgnoreAndLog(Level::Info)});
-			}
+			None => Err(LightningError {
+				err: format!(
+					"Received error response for a buy request from an unknown counterparty ({:?})",
+					counterparty_node_id
+				),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			}),
 		}
 	}
 }
 
-impl<ES: Deref, MQ: Deref> ProtocolMessageHandler for LSPS2ClientHandler<ES, MQ>
+impl<ES: Deref, MQ: Deref, K: Deref> ProtocolMessageHandler for LSPS2ClientHandler<ES, MQ, K>
 where
 	ES::Target: EntropySource,
 	MQ::Target: MessageQueue,
+	K::Target: KVStore,
 {
 	type ProtocolMessage = LSPS2Message;
 	const PROTOCOL_NUMBER: Option<u16> = Some(2);
@@ -605,23 +1476,42 @@ where
 		&self, message: Self::ProtocolMessage, counterparty_node_id: &PublicKey,
 	) -> Result<(), LightningError> {
 		match message {
-			LSPS2Message::Response(request_id, response) => match response {
-				LSPS2Response::GetVersions(result) => {
-					self.handle_get_versions_response(request_id, counterparty_node_id, result)
-				}
-				LSPS2Response::GetInfo(result) => {
-					self.handle_get_info_response(request_id, counterparty_node_id, result)
-				}
-				LSPS2Response::GetInfoError(error) => {
-					self.handle_get_info_error(request_id, counterparty_node_id, error)
-				}
-				LSPS2Response::Buy(result) => {
-					self.handle_buy_response(request_id, counterparty_node_id, result)
-				}
-				LSPS2Response::BuyError(error) => {
-					self.handle_buy_error(request_id, counterparty_node_id, error)
+			LSPS2Message::Response(request_id, response) => {
+				let res = match response {
+					LSPS2Response::GetVersions(result) => {
+						self.handle_get_versions_response(request_id, counterparty_node_id, result)
+					}
+					LSPS2Response::GetVersionsError(error) => {
+						self.handle_get_versions_error(request_id, counterparty_node_id, error)
+					}
+					LSPS2Response::GetInfo(result) => {
+						self.handle_get_info_response(request_id, counterparty_node_id, result)
+					}
+					LSPS2Response::GetInfoError(error) => {
+						self.handle_get_info_error(request_id, counterparty_node_id, error)
+					}
+					LSPS2Response::Buy(result) => {
+						self.handle_buy_response(request_id, counterparty_node_id, result)
+					}
+					LSPS2Response::BuyError(error) => {
+						self.handle_buy_error(request_id, counterparty_node_id, error)
+					}
+				};
+
+				// Responses may have advanced or removed a flow either way, so we persist the
+				// resulting state before reporting back.
+				let outer_state_lock = self.per_peer_state.read().unwrap();
+				if let Some(inner_state_lock) = outer_state_lock.get(counterparty_node_id) {
+					let peer_state = inner_state_lock.lock().unwrap();
+					persist_peer_state(&self.kv_store, counterparty_node_id, &*peer_state)
+						.map_err(|e| LightningError {
+							err: format!("{:?}", e),
+							action: ErrorAction::IgnoreAndLog(Level::Error),
+						})?;
 				}
-			},
+
+				res
+			}
 			_ => {
 				debug_assert!(
 					false,
@@ -634,4 +1524,792 @@ where
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+	use super::*;
+
+	use crate::persist::memory_store::MemoryStore;
+	use crate::tests::utils::{TestEntropy, TestMessageQueue};
+
+	use crate::lsps2::msgs::RawOpeningFeeParams;
+
+	use lightning::sign::KeysManager;
+	use lightning::util::ser::{Readable, Writeable};
+
+	type TestClientHandler =
+		LSPS2ClientHandler<Arc<TestEntropy>, Arc<TestMessageQueue>, Arc<MemoryStore>>;
+
+	fn test_counterparty_node_id() -> PublicKey {
+		crate::utils::parse_pubkey(
+			"027100442c3b79f606f80f322d98d499eefcb060599efc5d4ecb00209c2cb54190",
+		)
+		.unwrap()
+	}
+
+	/// The id of every request, as [`TestEntropy`] always yields the same bytes.
+	fn test_request_id() -> RequestId {
+		RequestId("00000000000000000000000000000000".to_string())
+	}
+
+	fn create_handler(
+		kv_store: Arc<MemoryStore>, config: LSPS2ClientConfig, request_timeout_ticks: u32,
+	) -> (TestClientHandler, Arc<TestMessageQueue>, Arc<EventQueue>) {
+		let pending_messages = Arc::new(TestMessageQueue::new());
+		let pending_events = Arc::new(EventQueue::new());
+		let handler = LSPS2ClientHandler::new(
+			Arc::new(TestEntropy {}),
+			Arc::clone(&pending_messages),
+			Arc::clone(&pending_events),
+			kv_store,
+			config,
+			request_timeout_ticks,
+		)
+		.unwrap();
+		(handler, pending_messages, pending_events)
+	}
+
+	fn versions_response(versions: Vec<u16>) -> LSPS2Message {
+		LSPS2Message::Response(
+			test_request_id(),
+			LSPS2Response::GetVersions(GetVersionsResponse { versions }),
+		)
+	}
+
+	fn get_info_response(
+		opening_fee_params_menu: Vec<OpeningFeeParams>, min_payment_size_msat: u64,
+	) -> LSPS2Message {
+		LSPS2Message::Response(
+			test_request_id(),
+			LSPS2Response::GetInfo(GetInfoResponse {
+				opening_fee_params_menu,
+				min_payment_size_msat,
+				max_payment_size_msat: 100_000_000,
+			}),
+		)
+	}
+
+	fn buy_response(jit_channel_scid: JITChannelScid, lsp_cltv_expiry_delta: u32) -> LSPS2Message {
+		LSPS2Message::Response(
+			test_request_id(),
+			LSPS2Response::Buy(BuyResponse {
+				jit_channel_scid,
+				lsp_cltv_expiry_delta,
+				client_trusts_lsp: false,
+			}),
+		)
+	}
+
+	/// Starts a flow to buy a JIT channel, answering the `get_versions` request so that the menu
+	/// is requested next.
+	fn request_menu(
+		handler: &TestClientHandler, payment_size_msat: Option<u64>, user_channel_id: u128,
+	) {
+		let counterparty_node_id = test_counterparty_node_id();
+		handler
+			.create_invoice(counterparty_node_id, payment_size_msat, None, user_channel_id)
+			.unwrap();
+		handler.handle_message(versions_response(vec![1]), &counterparty_node_id).unwrap();
+	}
+
+	fn test_opening_fee_params() -> OpeningFeeParams {
+		RawOpeningFeeParams {
+			min_fee_msat: 100,
+			proportional: 21,
+			valid_until: chrono::DateTime::parse_from_rfc3339("2035-05-20T08:30:45Z")
+				.unwrap()
+				.into(),
+			min_lifetime: 144,
+			max_client_to_self_delay: 128,
+		}
+		.into_opening_fee_params(&[42; 32])
+	}
+
+	#[test]
+	fn peer_state_serialization_roundtrip() {
+		let mut peer_state = PeerState::new();
+		let mut pending_channel = InboundJITChannel::new(1, 42, Some(500_000), None);
+		pending_channel.versions_received(vec![1]).unwrap();
+		pending_channel.info_received().unwrap();
+		pending_channel.opening_fee_params_selected(test_opening_fee_params()).unwrap();
+		pending_channel
+			.invoice_params_received(true, JITChannelScid::from(123_456_789), 144)
+			.unwrap();
+		peer_state.insert_inbound_channel(1, pending_channel);
+
+		let menu_channel = InboundJITChannel::new(2, 43, None, Some("sometoken".to_string()));
+		peer_state.insert_inbound_channel(2, menu_channel);
+		peer_state.insert_request(RequestId("xyz123".to_string()), 2);
+
+		let encoded = peer_state.encode();
+		let mut decoded = PeerState::read(&mut io::Cursor::new(&encoded)).unwrap();
+
+		// Requests in flight are not persisted.
+		assert!(decoded.request_to_cid.is_empty());
+		assert_eq!(decoded.inbound_channels_by_id.len(), 2);
+
+		let decoded_pending = decoded.inbound_channels_by_id.get_mut(&1).unwrap();
+		assert_eq!(decoded_pending.config.user_id, 42);
+		assert_eq!(decoded_pending.config.payment_size_msat, Some(500_000));
+		assert_eq!(
+			decoded_pending.state,
+			InboundJITChannelState::PendingPayment {
+				client_trusts_lsp: true,
+				short_channel_id: JITChannelScid::from(123_456_789),
+				lsp_cltv_expiry_delta: 144,
+				opening_fee_params: test_opening_fee_params(),
+			}
+		);
+		assert_eq!(decoded_pending.resume(), None);
+
+		let decoded_menu = decoded.inbound_channels_by_id.get_mut(&2).unwrap();
+		assert_eq!(decoded_menu.config.token, Some("sometoken".to_string()));
+		assert_eq!(decoded_menu.state, InboundJITChannelState::VersionsRequested);
+		assert_eq!(decoded_menu.resume(), Some(LSPS2Request::GetVersions(GetVersionsRequest {})));
+	}
+
+	#[test]
+	fn handler_state_is_reloaded_from_store() {
+		let kv_store = Arc::new(MemoryStore::new());
+		let counterparty_node_id = test_counterparty_node_id();
+
+		{
+			let (handler, pending_messages, _) =
+				create_handler(Arc::clone(&kv_store), LSPS2ClientConfig::default(), 1);
+			handler.create_invoice(counterparty_node_id, None, None, 42).unwrap();
+			assert_eq!(pending_messages.get_and_clear_pending_msgs().len(), 1);
+		}
+
+		let (handler, pending_messages, _) =
+			create_handler(Arc::clone(&kv_store), LSPS2ClientConfig::default(), 1);
+		assert_eq!(handler.jit_channel_scid(&counterparty_node_id, 42), None);
+
+		handler.resume_pending_flows(&counterparty_node_id).unwrap();
+		let msgs = pending_messages.get_and_clear_pending_msgs();
+		assert_eq!(msgs.len(), 1);
+		assert_eq!(
+			msgs[0],
+			(
+				counterparty_node_id,
+				LSPS2Message::Request(
+					test_request_id(),
+					LSPS2Request::GetVersions(GetVersionsRequest {})
+				)
+				.into()
+			)
+		);
+
+		// The request is in flight now, so resuming again doesn't re-send it.
+		handler.resume_pending_flows(&counterparty_node_id).unwrap();
+		assert!(pending_messages.get_and_clear_pending_msgs().is_empty());
+	}
+
+	#[test]
+	fn disconnected_flows_are_resumed() {
+		let counterparty_node_id = test_counterparty_node_id();
+		let (handler, pending_messages, pending_events) =
+			create_handler(Arc::new(MemoryStore::new()), LSPS2ClientConfig::default(), 1);
+
+		handler.create_invoice(counterparty_node_id, None, None, 42).unwrap();
+		assert_eq!(pending_messages.get_and_clear_pending_msgs().len(), 1);
+
+		handler.peer_disconnected(&counterparty_node_id);
+		{
+			let outer_state_lock = handler.per_peer_state.read().unwrap();
+			let peer_state = outer_state_lock.get(&counterparty_node_id).unwrap().lock().unwrap();
+			assert_eq!(peer_state.inbound_channels_by_id.len(), 1);
+			assert!(peer_state.request_to_cid.is_empty());
+			assert!(peer_state.request_ticks.is_empty());
+		}
+
+		// The flow doesn't time out while we're disconnected.
+		handler.timer_tick_occurred();
+		handler.timer_tick_occurred();
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+
+		handler.resume_pending_flows(&counterparty_node_id).unwrap();
+		assert_eq!(
+			pending_messages.get_and_clear_pending_msgs(),
+			vec![(
+				counterparty_node_id,
+				LSPS2Message::Request(
+					test_request_id(),
+					LSPS2Request::GetVersions(GetVersionsRequest {})
+				)
+				.into()
+			)]
+		);
+	}
+
+	#[test]
+	fn resume_restarts_from_menu_request() {
+		let mut jit_channel = InboundJITChannel::new(1, 42, None, Some("sometoken".to_string()));
+		jit_channel.versions_received(vec![1]).unwrap();
+		jit_channel.info_received().unwrap();
+		jit_channel.opening_fee_params_selected(test_opening_fee_params()).unwrap();
+
+		assert_eq!(
+			jit_channel.resume(),
+			Some(LSPS2Request::GetInfo(GetInfoRequest {
+				version: 1,
+				token: Some("sometoken".to_string()),
+			}))
+		);
+		assert_eq!(jit_channel.state, InboundJITChannelState::MenuRequested { version: 1 });
+	}
+
+	#[test]
+	fn skimmed_fee_is_verified() {
+		let mut jit_channel = InboundJITChannel::new(1, 42, Some(500_000), None);
+		jit_channel.versions_received(vec![1]).unwrap();
+		jit_channel.info_received().unwrap();
+		assert!(jit_channel.payment_claimable(499_900, 100).is_err());
+		jit_channel.opening_fee_params_selected(test_opening_fee_params()).unwrap();
+		jit_channel.invoice_params_received(false, JITChannelScid::from(123_456_789), 144).unwrap();
+
+		assert!(jit_channel.payment_claimable(499_900, 100).unwrap());
+		assert!(!jit_channel.payment_claimable(499_899, 101).unwrap());
+
+		// Without a fixed payment size the fee is based on what the LSP received.
+		let mut jit_channel = InboundJITChannel::new(2, 43, None, None);
+		jit_channel.versions_received(vec![1]).unwrap();
+		jit_channel.info_received().unwrap();
+		jit_channel.opening_fee_params_selected(test_opening_fee_params()).unwrap();
+		jit_channel.invoice_params_received(false, JITChannelScid::from(123_456_789), 144).unwrap();
+
+		assert!(jit_channel.payment_claimable(9_999_790, 210).unwrap());
+		assert!(!jit_channel.payment_claimable(9_999_789, 211).unwrap());
+	}
+
+	#[test]
+	fn bolt11_invoice_is_routed_via_lsp() {
+		let counterparty_node_id = test_counterparty_node_id();
+		let (handler, _, _) =
+			create_handler(Arc::new(MemoryStore::new()), LSPS2ClientConfig::default(), 1);
+
+		request_menu(&handler, Some(500_000), 42);
+		handler
+			.handle_message(
+				get_info_response(vec![test_opening_fee_params()], 1),
+				&counterparty_node_id,
+			)
+			.unwrap();
+		handler
+			.opening_fee_params_selected(counterparty_node_id, 0, test_opening_fee_params())
+			.unwrap();
+		handler
+			.handle_message(
+				buy_response(JITChannelScid::from(123_456_789), 144),
+				&counterparty_node_id,
+			)
+			.unwrap();
+
+		let keys_manager = Arc::new(KeysManager::new(&[42; 32], 42, 42));
+		let invoice = handler
+			.create_bolt11_invoice(
+				&counterparty_node_id,
+				42,
+				PaymentHash([1; 32]),
+				PaymentSecret([2; 32]),
+				"JIT channel".to_string(),
+				3600,
+				None,
+				Currency::Regtest,
+				Duration::from_secs(1_700_000_000),
+				Arc::clone(&keys_manager),
+			)
+			.unwrap();
+
+		assert_eq!(invoice.amount_milli_satoshis(), Some(500_000));
+		assert_eq!(invoice.payment_hash(), &Sha256::from_inner([1; 32]));
+		assert_eq!(invoice.min_final_cltv_expiry_delta(), MIN_FINAL_CLTV_EXPIRY_DELTA as u64);
+		assert_eq!(
+			invoice.recover_payee_pub_key(),
+			keys_manager.get_node_id(Recipient::Node).unwrap()
+		);
+		let route_hints = invoice.route_hints();
+		assert_eq!(route_hints.len(), 1);
+		assert_eq!(route_hints[0].0.len(), 1);
+		assert_eq!(route_hints[0].0[0].src_node_id, counterparty_node_id);
+		assert_eq!(route_hints[0].0[0].short_channel_id, 123_456_789);
+		assert_eq!(route_hints[0].0[0].cltv_expiry_delta, 144);
+
+		assert!(handler
+			.create_bolt11_invoice(
+				&counterparty_node_id,
+				43,
+				PaymentHash([1; 32]),
+				PaymentSecret([2; 32]),
+				"JIT channel".to_string(),
+				3600,
+				None,
+				Currency::Regtest,
+				Duration::from_secs(1_700_000_000),
+				keys_manager,
+			)
+			.is_err());
+	}
+
+	#[test]
+	fn selection_policy_picks_cheapest_acceptable_params() {
+		let raw_params = |min_fee_msat, proportional, min_lifetime, valid_until: &str| {
+			RawOpeningFeeParams {
+				min_fee_msat,
+				proportional,
+				valid_until: chrono::DateTime::parse_from_rfc3339(valid_until).unwrap().into(),
+				min_lifetime,
+				max_client_to_self_delay: 128,
+			}
+			.into_opening_fee_params(&[42; 32])
+		};
+		#[allow(unused_mut)]
+		let mut menu = vec![
+			raw_params(1_000, 1_000, 1_000, "2035-05-20T08:30:45Z"),
+			raw_params(2_000, 100, 1_000, "2035-05-20T08:30:45Z"),
+			raw_params(500, 1_000, 100, "2035-05-20T08:30:45Z"),
+		];
+		// Expiry is only checked in std builds, where expired entries are never selected, even if
+		// they were valid until before the unix epoch.
+		#[cfg(feature = "std")]
+		{
+			menu.push(raw_params(0, 0, 1_000, "2023-05-20T08:30:45Z"));
+			menu.push(raw_params(0, 0, 1_000, "1969-07-20T20:17:40Z"));
+		}
+
+		let policy = OpeningFeeParamsSelectionPolicy::default();
+		assert_eq!(policy.select(&menu, None), Some(menu[2].clone()));
+		assert_eq!(policy.select(&menu, Some(10_000_000)), Some(menu[1].clone()));
+
+		let policy = OpeningFeeParamsSelectionPolicy {
+			min_channel_lifetime: Some(1_000),
+			..Default::default()
+		};
+		assert_eq!(policy.select(&menu, None), Some(menu[0].clone()));
+		assert_eq!(policy.select(&menu, Some(1_000_000)), Some(menu[0].clone()));
+
+		let policy = OpeningFeeParamsSelectionPolicy {
+			max_opening_fee_msat: Some(1_500),
+			max_client_to_self_delay: Some(128),
+			..Default::default()
+		};
+		assert_eq!(policy.select(&menu, Some(400_000)), Some(menu[2].clone()));
+		assert_eq!(policy.select(&menu, Some(10_000_000)), None);
+
+		let policy = OpeningFeeParamsSelectionPolicy {
+			max_client_to_self_delay: Some(100),
+			..Default::default()
+		};
+		assert_eq!(policy.select(&menu, None), None);
+	}
+
+	#[test]
+	fn opening_fee_params_are_selected_automatically() {
+		let counterparty_node_id = test_counterparty_node_id();
+		let config = LSPS2ClientConfig {
+			opening_fee_params_selection_policy: Some(OpeningFeeParamsSelectionPolicy {
+				max_opening_fee_msat: Some(1_000),
+				..Default::default()
+			}),
+			..Default::default()
+		};
+		let (handler, pending_messages, pending_events) =
+			create_handler(Arc::new(MemoryStore::new()), config, 1);
+
+		for (user_channel_id, payment_size_msat) in [(42, Some(500_000)), (43, Some(50_000_000))] {
+			request_menu(&handler, payment_size_msat, user_channel_id);
+			pending_messages.get_and_clear_pending_msgs();
+			handler
+				.handle_message(
+					get_info_response(vec![test_opening_fee_params()], 1),
+					&counterparty_node_id,
+				)
+				.unwrap();
+
+			if user_channel_id == 42 {
+				// We proceed buying the channel right away.
+				assert!(pending_events.get_and_clear_pending_events().is_empty());
+				assert_eq!(
+					pending_messages.get_and_clear_pending_msgs(),
+					vec![(
+						counterparty_node_id,
+						LSPS2Message::Request(
+							test_request_id(),
+							LSPS2Request::Buy(BuyRequest {
+								version: 1,
+								opening_fee_params: test_opening_fee_params(),
+								payment_size_msat,
+							})
+						)
+						.into()
+					)]
+				);
+			} else {
+				// The opening fee of 1050 sat exceeds our limit.
+				assert!(pending_messages.get_and_clear_pending_msgs().is_empty());
+				assert_eq!(
+					pending_events.get_and_clear_pending_events(),
+					vec![Event::LSPS2Client(LSPS2ClientEvent::NoAcceptableOpeningFeeParams {
+						jit_channel_id: 0,
+						counterparty_node_id,
+						opening_fee_params_menu: vec![test_opening_fee_params()],
+						min_payment_size_msat: 1,
+						max_payment_size_msat: 100_000_000,
+						user_channel_id: 43,
+					})]
+				);
+			}
+		}
+	}
+
+	#[test]
+	fn invalid_lsp_responses_abort_the_flow() {
+		let counterparty_node_id = test_counterparty_node_id();
+		let (handler, _, pending_events) =
+			create_handler(Arc::new(MemoryStore::new()), LSPS2ClientConfig::default(), 1);
+
+		let assert_flow_aborted = |user_channel_id, reason: &str| {
+			assert_eq!(
+				pending_events.get_and_clear_pending_events(),
+				vec![Event::LSPS2Client(LSPS2ClientEvent::InvalidLSPResponse {
+					counterparty_node_id,
+					reason: reason.to_string(),
+					user_channel_id,
+				})]
+			);
+			let outer_state_lock = handler.per_peer_state.read().unwrap();
+			let peer_state = outer_state_lock.get(&counterparty_node_id).unwrap().lock().unwrap();
+			assert!(peer_state.inbound_channels_by_id.is_empty());
+			assert!(peer_state.request_to_cid.is_empty());
+		};
+
+		// The requested payment size is below the LSP's minimum.
+		request_menu(&handler, Some(500_000), 42);
+		assert!(handler
+			.handle_message(
+				get_info_response(vec![test_opening_fee_params()], 1_000_000),
+				&counterparty_node_id
+			)
+			.is_err());
+		assert_flow_aborted(
+			42,
+			"Payment size 500000 is outside of the allowed range [1000000, 100000000]",
+		);
+
+		// The LSP requires an excessive CLTV expiry delta.
+		request_menu(&handler, Some(500_000), 43);
+		handler
+			.handle_message(
+				get_info_response(vec![test_opening_fee_params()], 1),
+				&counterparty_node_id,
+			)
+			.unwrap();
+		assert_eq!(pending_events.get_and_clear_pending_events().len(), 1);
+		handler
+			.opening_fee_params_selected(counterparty_node_id, 0, test_opening_fee_params())
+			.unwrap();
+		let buy_response = buy_response(42.into(), DEFAULT_MAX_LSP_CLTV_EXPIRY_DELTA + 1);
+		assert!(handler.handle_message(buy_response, &counterparty_node_id).is_err());
+		assert_flow_aborted(43, "LSP CLTV expiry delta 289 exceeds our maximum of 288");
+
+		// The LSP only offers excessive fees, while acceptable entries are kept otherwise.
+		let excessive_params = |min_fee_msat, proportional| {
+			RawOpeningFeeParams {
+				min_fee_msat,
+				proportional,
+				valid_until: chrono::DateTime::parse_from_rfc3339("2035-05-20T08:30:45Z")
+					.unwrap()
+					.into(),
+				min_lifetime: 144,
+				max_client_to_self_delay: 128,
+			}
+			.into_opening_fee_params(&[42; 32])
+		};
+		let excessive_min_fee_params =
+			excessive_params(DEFAULT_MAX_OPENING_FEE_MIN_FEE_MSAT + 1, 21);
+		let excessive_proportional_params =
+			excessive_params(100, DEFAULT_MAX_OPENING_FEE_PROPORTIONAL + 1);
+
+		request_menu(&handler, Some(500_000), 44);
+		handler
+			.handle_message(
+				get_info_response(
+					vec![excessive_min_fee_params.clone(), test_opening_fee_params()],
+					1,
+				),
+				&counterparty_node_id,
+			)
+			.unwrap();
+		match pending_events.get_and_clear_pending_events().as_slice() {
+			[Event::LSPS2Client(LSPS2ClientEvent::GetInfoResponse {
+				opening_fee_params_menu,
+				..
+			})] => assert_eq!(opening_fee_params_menu, &vec![test_opening_fee_params()]),
+			events => panic!("Unexpected events: {:?}", events),
+		}
+
+		request_menu(&handler, Some(500_000), 45);
+		assert!(handler
+			.handle_message(
+				get_info_response(vec![excessive_min_fee_params, excessive_proportional_params], 1),
+				&counterparty_node_id
+			)
+			.is_err());
+		assert_flow_aborted(
+			45,
+			"All 2 unexpired opening fee params exceed our maximum min_fee_msat of 10000000 or proportional fee of 50000",
+		);
+	}
+
+	#[test]
+	#[cfg(feature = "std")]
+	fn expired_opening_fee_params_are_dropped_from_the_menu() {
+		let counterparty_node_id = test_counterparty_node_id();
+		let (handler, _, pending_events) =
+			create_handler(Arc::new(MemoryStore::new()), LSPS2ClientConfig::default(), 1);
+		let opening_fee_params = |valid_until| {
+			RawOpeningFeeParams {
+				min_fee_msat: 100,
+				proportional: 21,
+				valid_until: chrono::DateTime::parse_from_rfc3339(valid_until).unwrap().into(),
+				min_lifetime: 144,
+				max_client_to_self_delay: 128,
+			}
+			.into_opening_fee_params(&[42; 32])
+		};
+		let expired_params = opening_fee_params("2023-05-20T08:30:45Z");
+		// Params valid until before the unix epoch are considered expired, too.
+		let pre_epoch_params = opening_fee_params("1969-07-20T20:17:40Z");
+
+		// The menu is only rejected if all of its entries expired.
+		request_menu(&handler, Some(500_000), 42);
+		assert!(handler
+			.handle_message(
+				get_info_response(vec![expired_params.clone(), pre_epoch_params], 1),
+				&counterparty_node_id
+			)
+			.is_err());
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS2Client(LSPS2ClientEvent::InvalidLSPResponse {
+				counterparty_node_id,
+				reason: "All 2 offered opening fee params expired".to_string(),
+				user_channel_id: 42,
+			})]
+		);
+
+		request_menu(&handler, Some(500_000), 43);
+		handler
+			.handle_message(
+				get_info_response(vec![expired_params, test_opening_fee_params()], 1),
+				&counterparty_node_id,
+			)
+			.unwrap();
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS2Client(LSPS2ClientEvent::GetInfoResponse {
+				jit_channel_id: 0,
+				counterparty_node_id,
+				opening_fee_params_menu: vec![test_opening_fee_params()],
+				min_payment_size_msat: 1,
+				max_payment_size_msat: 100_000_000,
+				user_channel_id: 43,
+			})]
+		);
+	}
+
+	#[test]
+	fn failed_requests_are_reported() {
+		let counterparty_node_id = test_counterparty_node_id();
+		let request_id = test_request_id();
+		let (handler, _, pending_events) =
+			create_handler(Arc::new(MemoryStore::new()), LSPS2ClientConfig::default(), 1);
+
+		let error = ResponseError {
+			code: 200,
+			message: "Unrecognized token".to_string(),
+			data: Some("\"coupon\"".to_string()),
+		};
+
+		handler.create_invoice(counterparty_node_id, None, None, 42).unwrap();
+		assert!(handler.handle_message(versions_response(vec![2]), &counterparty_node_id).is_err());
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS2Client(LSPS2ClientEvent::UnsupportedVersions {
+				counterparty_node_id,
+				versions: vec![2],
+				user_channel_id: 42,
+			})]
+		);
+
+		request_menu(&handler, None, 43);
+		handler
+			.handle_message(
+				LSPS2Message::Response(
+					request_id.clone(),
+					LSPS2Response::GetInfoError(error.clone()),
+				),
+				&counterparty_node_id,
+			)
+			.unwrap();
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS2Client(LSPS2ClientEvent::GetInfoRequestFailed {
+				counterparty_node_id,
+				error: error.clone(),
+				user_channel_id: 43,
+			})]
+		);
+
+		for user_channel_id in [44, 45] {
+			request_menu(&handler, None, user_channel_id);
+			handler
+				.handle_message(
+					get_info_response(vec![test_opening_fee_params()], 1),
+					&counterparty_node_id,
+				)
+				.unwrap();
+			pending_events.get_and_clear_pending_events();
+			handler
+				.opening_fee_params_selected(counterparty_node_id, 0, test_opening_fee_params())
+				.unwrap();
+
+			if user_channel_id == 44 {
+				handler
+					.handle_message(
+						LSPS2Message::Response(
+							request_id.clone(),
+							LSPS2Response::BuyError(error.clone()),
+						),
+						&counterparty_node_id,
+					)
+					.unwrap();
+				assert_eq!(
+					pending_events.get_and_clear_pending_events(),
+					vec![Event::LSPS2Client(LSPS2ClientEvent::BuyRequestFailed {
+						counterparty_node_id,
+						error: error.clone(),
+						user_channel_id,
+					})]
+				);
+			} else {
+				let buy_response = buy_response(serde_json::from_str("\"invalid\"").unwrap(), 144);
+				assert!(handler.handle_message(buy_response, &counterparty_node_id).is_err());
+				assert_eq!(
+					pending_events.get_and_clear_pending_events(),
+					vec![Event::LSPS2Client(LSPS2ClientEvent::InvalidLSPResponse {
+						counterparty_node_id,
+						reason: "Invalid JIT channel scid JITChannelScid(\"invalid\")".to_string(),
+						user_channel_id,
+					})]
+				);
+			}
+		}
+	}
+
+	#[test]
+	fn unanswered_requests_time_out() {
+		let counterparty_node_id = test_counterparty_node_id();
+		let kv_store = Arc::new(MemoryStore::new());
+		let (handler, _, pending_events) =
+			create_handler(Arc::clone(&kv_store), LSPS2ClientConfig::default(), 2);
+
+		handler.create_invoice(counterparty_node_id, None, None, 42).unwrap();
+		handler.timer_tick_occurred();
+		handler.timer_tick_occurred();
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+
+		// Answering the request restarts the clock for the next one.
+		handler.handle_message(versions_response(vec![1]), &counterparty_node_id).unwrap();
+		handler.timer_tick_occurred();
+		handler.timer_tick_occurred();
+		assert!(pending_events.get_and_clear_pending_events().is_empty());
+
+		handler.timer_tick_occurred();
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS2Client(LSPS2ClientEvent::RequestTimedOut {
+				counterparty_node_id,
+				user_channel_id: 42,
+			})]
+		);
+		{
+			let outer_state_lock = handler.per_peer_state.read().unwrap();
+			let peer_state = outer_state_lock.get(&counterparty_node_id).unwrap().lock().unwrap();
+			assert!(peer_state.inbound_channels_by_id.is_empty());
+			assert!(peer_state.request_to_cid.is_empty());
+			assert!(peer_state.request_ticks.is_empty());
+		}
+
+		// The aborted flow was removed from the store, too.
+		let (reloaded_handler, _, _) = create_handler(kv_store, LSPS2ClientConfig::default(), 2);
+		assert!(reloaded_handler.per_peer_state.read().unwrap().is_empty());
+	}
+
+	#[test]
+	#[cfg(feature = "std")]
+	fn flows_are_pruned_once_their_payment_expired() {
+		let counterparty_node_id = test_counterparty_node_id();
+		let kv_store = Arc::new(MemoryStore::new());
+		let (handler, _, pending_events) =
+			create_handler(Arc::clone(&kv_store), LSPS2ClientConfig::default(), 1);
+
+		request_menu(&handler, Some(500_000), 42);
+		handler
+			.handle_message(
+				get_info_response(vec![test_opening_fee_params()], 1),
+				&counterparty_node_id,
+			)
+			.unwrap();
+		handler
+			.opening_fee_params_selected(counterparty_node_id, 0, test_opening_fee_params())
+			.unwrap();
+		handler
+			.handle_message(
+				buy_response(JITChannelScid::from(123_456_789), 144),
+				&counterparty_node_id,
+			)
+			.unwrap();
+
+		let expired_params = RawOpeningFeeParams {
+			min_fee_msat: 100,
+			proportional: 21,
+			valid_until: chrono::DateTime::parse_from_rfc3339("2023-05-20T08:30:45Z")
+				.unwrap()
+				.into(),
+			min_lifetime: 144,
+			max_client_to_self_delay: 128,
+		}
+		.into_opening_fee_params(&[42; 32]);
+		let mut expired_channel = InboundJITChannel::new(1, 43, Some(500_000), None);
+		expired_channel.versions_received(vec![1]).unwrap();
+		expired_channel.info_received().unwrap();
+		expired_channel.opening_fee_params_selected(expired_params).unwrap();
+		expired_channel
+			.invoice_params_received(false, JITChannelScid::from(987_654_321), 144)
+			.unwrap();
+		handler
+			.per_peer_state
+			.read()
+			.unwrap()
+			.get(&counterparty_node_id)
+			.unwrap()
+			.lock()
+			.unwrap()
+			.insert_inbound_channel(1, expired_channel);
+		assert_eq!(pending_events.get_and_clear_pending_events().len(), 2);
+
+		handler.timer_tick_occurred();
+		assert_eq!(
+			pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS2Client(LSPS2ClientEvent::PaymentExpired {
+				counterparty_node_id,
+				user_channel_id: 43,
+			})]
+		);
+		assert_eq!(handler.jit_channel_scid(&counterparty_node_id, 42), Some(123_456_789));
+		assert_eq!(handler.jit_channel_scid(&counterparty_node_id, 43), None);
+
+		// The expired flow was removed from the store, too.
+		let (reloaded_handler, _, _) = create_handler(kv_store, LSPS2ClientConfig::default(), 1);
+		assert_eq!(reloaded_handler.jit_channel_scid(&counterparty_node_id, 42), Some(123_456_789));
+		assert_eq!(reloaded_handler.jit_channel_scid(&counterparty_node_id, 43), None);
+	}
+}
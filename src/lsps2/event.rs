@@ -10,10 +10,11 @@
 //! Contains LSPS2 event types
 
 use super::msgs::OpeningFeeParams;
-use crate::lsps0::msgs::RequestId;
+use crate::lsps0::msgs::{RequestId, ResponseError};
 use crate::prelude::{String, Vec};
 
 use bitcoin::secp256k1::PublicKey;
+use bitcoin::Transaction;
 
 /// An event which an LSPS2 client should take some action in response to.
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -33,7 +34,8 @@ pub enum LSPS2ClientEvent {
 		jit_channel_id: u128,
 		/// The node id of the LSP that provided this response.
 		counterparty_node_id: PublicKey,
-		/// The menu of fee parameters the LSP is offering at this time.
+		/// The menu of fee parameters the LSP is offering at this time, without the entries that
+		/// expired already.
 		/// You must select one of these if you wish to proceed.
 		opening_fee_params_menu: Vec<OpeningFeeParams>,
 		/// The min payment size allowed when opening the channel.
@@ -45,6 +47,33 @@ pub enum LSPS2ClientEvent {
 		/// [`LSPS2ClientHandler::create_invoice`]: crate::lsps2::client::LSPS2ClientHandler::create_invoice
 		user_channel_id: u128,
 	},
+	/// None of the opening fee parameters offered by the LSP is acceptable according to the
+	/// configured [`OpeningFeeParamsSelectionPolicy`].
+	///
+	/// You may still call [`LSPS2ClientHandler::opening_fee_params_selected`] with any of the
+	/// offered parameters if you wish to proceed opening a channel.
+	///
+	/// [`OpeningFeeParamsSelectionPolicy`]: crate::lsps2::client::OpeningFeeParamsSelectionPolicy
+	/// [`LSPS2ClientHandler::opening_fee_params_selected`]: crate::lsps2::client::LSPS2ClientHandler::opening_fee_params_selected
+	NoAcceptableOpeningFeeParams {
+		/// The identifier used to track the JIT channel state, which needs to be passed to
+		/// [`LSPS2ClientHandler::opening_fee_params_selected`].
+		///
+		/// [`LSPS2ClientHandler::opening_fee_params_selected`]: crate::lsps2::client::LSPS2ClientHandler::opening_fee_params_selected
+		jit_channel_id: u128,
+		/// The node id of the LSP that provided this response.
+		counterparty_node_id: PublicKey,
+		/// The menu of fee parameters the LSP is offering at this time.
+		opening_fee_params_menu: Vec<OpeningFeeParams>,
+		/// The min payment size allowed when opening the channel.
+		min_payment_size_msat: u64,
+		/// The max payment size allowed when opening the channel.
+		max_payment_size_msat: u64,
+		/// The user_channel_id value passed in to [`LSPS2ClientHandler::create_invoice`].
+		///
+		/// [`LSPS2ClientHandler::create_invoice`]: crate::lsps2::client::LSPS2ClientHandler::create_invoice
+		user_channel_id: u128,
+	},
 	/// Use the provided fields to generate an invoice and give to payer.
 	///
 	/// When the invoice is paid the LSP will open a channel to you
@@ -65,6 +94,106 @@ pub enum LSPS2ClientEvent {
 		/// [`LSPS2ClientHandler::create_invoice`]: crate::lsps2::client::LSPS2ClientHandler::create_invoice
 		user_channel_id: u128,
 	},
+	/// The LSP doesn't support any of the protocol versions we support.
+	///
+	/// The JIT channel flow has been aborted.
+	UnsupportedVersions {
+		/// The node id of the LSP.
+		counterparty_node_id: PublicKey,
+		/// The protocol versions supported by the LSP.
+		versions: Vec<u16>,
+		/// The `user_channel_id` value passed in to [`LSPS2ClientHandler::create_invoice`].
+		///
+		/// [`LSPS2ClientHandler::create_invoice`]: crate::lsps2::client::LSPS2ClientHandler::create_invoice
+		user_channel_id: u128,
+	},
+	/// The LSP returned an error when queried for the protocol versions it supports.
+	///
+	/// The JIT channel flow has been aborted.
+	GetVersionsRequestFailed {
+		/// The node id of the LSP that returned the error.
+		counterparty_node_id: PublicKey,
+		/// The error returned by the LSP.
+		error: ResponseError,
+		/// The `user_channel_id` value passed in to [`LSPS2ClientHandler::create_invoice`].
+		///
+		/// [`LSPS2ClientHandler::create_invoice`]: crate::lsps2::client::LSPS2ClientHandler::create_invoice
+		user_channel_id: u128,
+	},
+	/// The LSP returned an error when queried for its opening fee parameters, e.g., because the
+	/// provided token was unrecognized.
+	///
+	/// The JIT channel flow has been aborted.
+	GetInfoRequestFailed {
+		/// The node id of the LSP that returned the error.
+		counterparty_node_id: PublicKey,
+		/// The error returned by the LSP.
+		error: ResponseError,
+		/// The `user_channel_id` value passed in to [`LSPS2ClientHandler::create_invoice`].
+		///
+		/// [`LSPS2ClientHandler::create_invoice`]: crate::lsps2::client::LSPS2ClientHandler::create_invoice
+		user_channel_id: u128,
+	},
+	/// The LSP refused to sell us a JIT channel with the selected opening fee parameters.
+	///
+	/// The JIT channel flow has been aborted.
+	BuyRequestFailed {
+		/// The node id of the LSP that returned the error.
+		counterparty_node_id: PublicKey,
+		/// The error returned by the LSP.
+		error: ResponseError,
+		/// The `user_channel_id` value passed in to [`LSPS2ClientHandler::create_invoice`].
+		///
+		/// [`LSPS2ClientHandler::create_invoice`]: crate::lsps2::client::LSPS2ClientHandler::create_invoice
+		user_channel_id: u128,
+	},
+	/// The LSP didn't answer one of our requests in time.
+	///
+	/// The JIT channel flow has been aborted. See [`LiquidityClientConfig::request_timeout_ticks`]
+	/// for more information.
+	///
+	/// [`LiquidityClientConfig::request_timeout_ticks`]: crate::LiquidityClientConfig::request_timeout_ticks
+	RequestTimedOut {
+		/// The node id of the LSP that didn't answer.
+		counterparty_node_id: PublicKey,
+		/// The `user_channel_id` value passed in to [`LSPS2ClientHandler::create_invoice`].
+		///
+		/// [`LSPS2ClientHandler::create_invoice`]: crate::lsps2::client::LSPS2ClientHandler::create_invoice
+		user_channel_id: u128,
+	},
+	/// The opening fee parameters of a JIT channel flow that reached the
+	/// [`LSPS2ClientEvent::InvoiceGenerationReady`] stage expired before the payment arrived.
+	///
+	/// The JIT channel flow has been removed, and invoices generated for it can't be paid anymore.
+	PaymentExpired {
+		/// The node id of the LSP we bought the JIT channel from.
+		counterparty_node_id: PublicKey,
+		/// The `user_channel_id` value passed in to [`LSPS2ClientHandler::create_invoice`].
+		///
+		/// [`LSPS2ClientHandler::create_invoice`]: crate::lsps2::client::LSPS2ClientHandler::create_invoice
+		user_channel_id: u128,
+	},
+	/// The LSP sent a response that failed validation, e.g., because it only offered expired
+	/// opening fee parameters or ones exceeding [`LSPS2ClientConfig::max_opening_fee_min_fee_msat`]
+	/// or [`LSPS2ClientConfig::max_opening_fee_proportional`], a payment size range not covering
+	/// the requested payment size, an invalid JIT channel scid, or an `lsp_cltv_expiry_delta`
+	/// exceeding [`LSPS2ClientConfig::max_lsp_cltv_expiry_delta`].
+	///
+	/// The JIT channel flow has been aborted.
+	///
+	/// [`LSPS2ClientConfig::max_opening_fee_min_fee_msat`]: crate::lsps2::client::LSPS2ClientConfig::max_opening_fee_min_fee_msat
+	/// [`LSPS2ClientConfig::max_opening_fee_proportional`]: crate::lsps2::client::LSPS2ClientConfig::max_opening_fee_proportional
+	/// [`LSPS2ClientConfig::max_lsp_cltv_expiry_delta`]: crate::lsps2::client::LSPS2ClientConfig::max_lsp_cltv_expiry_delta
+	InvalidLSPResponse {
+		/// The node id of the LSP that sent the invalid response.
+		counterparty_node_id: PublicKey,
+		/// The reason the response was deemed invalid.
+		reason: String,
+		/// The `user_channel_id` value passed in to [`LSPS2ClientHandler::create_invoice`].
+		///
+		/// [`LSPS2ClientHandler::create_invoice`]: crate::lsps2::client::LSPS2ClientHandler::create_invoice
+		user_channel_id: u128,
+	},
 }
 
 /// An event which an LSPS2 server should take some action in response to.
@@ -118,7 +247,11 @@ pub enum LSPS2ServiceEvent {
 	},
 	/// You should open a channel using [`ChannelManager::create_channel`].
 	///
+	/// If the channel fails to open or is closed before it becomes ready, you must call
+	/// [`LSPS2ServiceHandler::channel_open_failed`] to fail back the held HTLCs.
+	///
 	/// [`ChannelManager::create_channel`]: lightning::ln::channelmanager::ChannelManager::create_channel
+	/// [`LSPS2ServiceHandler::channel_open_failed`]: crate::lsps2::service::LSPS2ServiceHandler::channel_open_failed
 	OpenChannel {
 		/// The node to open channel with.
 		their_network_key: PublicKey,
@@ -129,4 +262,41 @@ pub enum LSPS2ServiceEvent {
 		/// An internal id used to track channel open.
 		user_channel_id: u128,
 	},
+	/// The channel opened in response to an [`LSPS2ServiceEvent::OpenChannel`] event failed to
+	/// open.
+	///
+	/// The HTLCs of the payment have been failed back and the JIT channel awaits another payment.
+	ChannelOpenFailed {
+		/// The node the channel was opened with.
+		counterparty_node_id: PublicKey,
+		/// The `user_channel_id` of the failed channel.
+		user_channel_id: u128,
+	},
+	/// The client claimed the payment forwarded over a JIT channel it doesn't trust us with, so
+	/// the channel's funding transaction may now be broadcast.
+	///
+	/// You should broadcast `funding_tx`, e.g., via your [`BroadcasterInterface`].
+	///
+	/// [`BroadcasterInterface`]: lightning::chain::chaininterface::BroadcasterInterface
+	BroadcastFundingTransaction {
+		/// The node the channel was opened with.
+		counterparty_node_id: PublicKey,
+		/// The `user_channel_id` of the channel.
+		user_channel_id: u128,
+		/// The funding transaction previously passed to
+		/// [`LSPS2ServiceHandler::store_funding_transaction`].
+		///
+		/// [`LSPS2ServiceHandler::store_funding_transaction`]: crate::lsps2::service::LSPS2ServiceHandler::store_funding_transaction
+		funding_tx: Transaction,
+	},
+	/// All HTLCs forwarded over a JIT channel were claimed by the client, i.e., the opening fee
+	/// was collected.
+	OpeningFeeCollected {
+		/// The node the channel was opened with.
+		counterparty_node_id: PublicKey,
+		/// The `user_channel_id` of the channel.
+		user_channel_id: u128,
+		/// The total fee we skimmed off the HTLCs forwarded over the channel.
+		skimmed_fee_msat: u64,
+	},
 }
@@ -6,6 +6,10 @@ use bitcoin::hashes::hmac::{Hmac, HmacEngine};
 use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::hashes::{Hash, HashEngine};
 use chrono::Utc;
+use lightning::io;
+use lightning::ln::msgs::DecodeError;
+use lightning::util::ser::{Readable, RequiredWrapper, Writeable, Writer};
+use lightning::{read_tlv_fields, write_tlv_fields};
 use serde::{Deserialize, Serialize};
 
 use crate::lsps0::msgs::{LSPSMessage, RequestId, ResponseError};
@@ -103,6 +107,53 @@ pub struct OpeningFeeParams {
 	pub promise: String,
 }
 
+impl Writeable for OpeningFeeParams {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		let valid_until = self.valid_until.to_rfc3339();
+		write_tlv_fields!(writer, {
+			(0, self.min_fee_msat, required),
+			(2, self.proportional, required),
+			(4, valid_until, required),
+			(6, self.min_lifetime, required),
+			(8, self.max_client_to_self_delay, required),
+			(10, self.promise, required),
+		});
+		Ok(())
+	}
+}
+
+impl Readable for OpeningFeeParams {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let mut min_fee_msat = RequiredWrapper(None);
+		let mut proportional = RequiredWrapper(None);
+		let mut valid_until: RequiredWrapper<String> = RequiredWrapper(None);
+		let mut min_lifetime = RequiredWrapper(None);
+		let mut max_client_to_self_delay = RequiredWrapper(None);
+		let mut promise = RequiredWrapper(None);
+		read_tlv_fields!(reader, {
+			(0, min_fee_msat, required),
+			(2, proportional, required),
+			(4, valid_until, required),
+			(6, min_lifetime, required),
+			(8, max_client_to_self_delay, required),
+			(10, promise, required),
+		});
+
+		let valid_until = chrono::DateTime::parse_from_rfc3339(&valid_until.0.unwrap())
+			.map(|dt| dt.with_timezone(&Utc))
+			.map_err(|_| DecodeError::InvalidValue)?;
+
+		Ok(Self {
+			min_fee_msat: min_fee_msat.0.unwrap(),
+			proportional: proportional.0.unwrap(),
+			valid_until,
+			min_lifetime: min_lifetime.0.unwrap(),
+			max_client_to_self_delay: max_client_to_self_delay.0.unwrap(),
+			promise: promise.0.unwrap(),
+		})
+	}
+}
+
 /// A response to a [`GetInfoRequest`]
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct GetInfoResponse {
@@ -132,11 +183,19 @@ pub struct JITChannelScid(String);
 
 impl From<u64> for JITChannelScid {
 	fn from(scid: u64) -> Self {
-		let block = utils::block_from_scid(&scid);
-		let tx_index = utils::tx_index_from_scid(&scid);
-		let vout = utils::vout_from_scid(&scid);
+		Self(utils::scid_to_human_readable_string(scid))
+	}
+}
+
+impl Writeable for JITChannelScid {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		self.0.write(writer)
+	}
+}
 
-		Self(format!("{}x{}x{}", block, tx_index, vout))
+impl Readable for JITChannelScid {
+	fn read<R: io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		Ok(Self(Readable::read(reader)?))
 	}
 }
 
@@ -188,6 +247,8 @@ impl LSPS2Request {
 pub enum LSPS2Response {
 	/// A successful response to a [`LSPS2Request::GetVersions`] request.
 	GetVersions(GetVersionsResponse),
+	/// An error response to a [`LSPS2Request::GetVersions`] request.
+	GetVersionsError(ResponseError),
 	/// A successful response to a [`LSPS2Request::GetInfo`] request.
 	GetInfo(GetInfoResponse),
 	/// An error response to a [`LSPS2Request::GetInfo`] request.
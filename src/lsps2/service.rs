@@ -9,22 +9,33 @@
 
 //! Contains the main LSPS2 server-side object, [`LSPS2ServiceHandler`].
 
+use crate::errors::LiquidityError;
 use crate::events::EventQueue;
-use crate::lsps0::msgs::{ProtocolMessageHandler, RequestId};
+use crate::lsps0::msgs::{ProtocolMessageHandler, RequestId, JSONRPC_RATE_LIMITED_ERROR_CODE};
 use crate::lsps2::event::LSPS2ServiceEvent;
-use crate::lsps2::utils::{compute_opening_fee, is_valid_opening_fee_params};
+use crate::lsps2::utils::{
+	compute_opening_fee, is_expired_opening_fee_params, is_valid_opening_fee_params,
+};
 use crate::message_queue::MessageQueue;
+use crate::persist::{
+	persist_peer_state, read_peer_states, PersistedPeerState,
+	LSPS2_SERVICE_PERSISTENCE_PRIMARY_NAMESPACE, LSPS2_SERVICE_PERSISTENCE_SECONDARY_NAMESPACE,
+};
 use crate::prelude::{HashMap, String, ToString, Vec};
 use crate::sync::{Arc, Mutex, RwLock};
 use crate::{events::Event, lsps0::msgs::ResponseError};
 
+use lightning::io;
 use lightning::ln::channelmanager::{AChannelManager, InterceptId};
 use lightning::ln::msgs::{ErrorAction, LightningError};
 use lightning::ln::ChannelId;
 use lightning::util::errors::APIError;
 use lightning::util::logger::Level;
+use lightning::util::persist::KVStore;
+use lightning::{impl_writeable_tlv_based, impl_writeable_tlv_based_enum};
 
 use bitcoin::secp256k1::PublicKey;
+use bitcoin::Transaction;
 
 use core::convert::TryInto;
 use core::ops::Deref;
@@ -40,7 +51,17 @@ use crate::lsps2::msgs::{
 	LSPS2_GET_INFO_REQUEST_UNRECOGNIZED_OR_STALE_TOKEN_ERROR_CODE,
 };
 
+/// The recommended value for [`LSPS2ServiceConfig::max_pending_requests_per_peer`].
+pub const DEFAULT_MAX_PENDING_REQUESTS_PER_PEER: usize = 10;
+
+/// The recommended value for [`LSPS2ServiceConfig::max_jit_channels_per_peer`].
+pub const DEFAULT_MAX_JIT_CHANNELS_PER_PEER: usize = 10;
+
 /// Server-side configuration options for JIT channels.
+///
+/// As the `promise_secret` needs to be chosen by the LSP, there is no `Default` implementation,
+/// but the recommended values for the other limits are given by the `DEFAULT_*` constants of this
+/// module.
 #[derive(Clone, Debug)]
 pub struct LSPS2ServiceConfig {
 	/// Used to calculate the promise for channel parameters supplied to clients.
@@ -51,6 +72,29 @@ pub struct LSPS2ServiceConfig {
 	pub min_payment_size_msat: u64,
 	/// The maximum payment size you are willing to accept.
 	pub max_payment_size_msat: u64,
+	/// The number of calls to [`LSPS2ServiceHandler::timer_tick_occurred`] after which the HTLCs
+	/// of a payment that didn't fully arrive yet are failed back to the payer.
+	///
+	/// This should leave ample time for the remaining parts of a multi-part payment to arrive,
+	/// while staying well below the CLTV expiry of the held HTLCs. Must be at least 1, as the held
+	/// HTLCs would otherwise be failed back with the next tick already, and constructing the
+	/// handler fails otherwise.
+	pub htlc_hold_timeout_ticks: u32,
+	/// The maximum number of `get_info` and `buy` requests of a single peer that may await our
+	/// response at any given time.
+	///
+	/// Further requests are rejected with an error response until we responded to some of the
+	/// pending ones.
+	///
+	/// Should usually be set to [`DEFAULT_MAX_PENDING_REQUESTS_PER_PEER`].
+	pub max_pending_requests_per_peer: usize,
+	/// The maximum number of JIT channels a single peer may have bought and not yet completed,
+	/// including the ones for which a `buy` request is still pending.
+	///
+	/// Further `buy` requests are rejected with an error response.
+	///
+	/// Should usually be set to [`DEFAULT_MAX_JIT_CHANNELS_PER_PEER`].
+	pub max_jit_channels_per_peer: usize,
 }
 
 const SUPPORTED_SPEC_VERSIONS: [u16; 1] = [1];
@@ -61,6 +105,11 @@ struct InterceptedHTLC {
 	expected_outbound_amount_msat: u64,
 }
 
+impl_writeable_tlv_based!(InterceptedHTLC, {
+	(0, intercept_id, required),
+	(2, expected_outbound_amount_msat, required),
+});
+
 struct ChannelStateError(String);
 
 impl From<ChannelStateError> for LightningError {
@@ -72,8 +121,7 @@ impl From<ChannelStateError> for LightningError {
 #[derive(PartialEq, Debug)]
 enum OutboundJITChannelState {
 	AwaitingPayment {
-		min_fee_msat: u64,
-		proportional_fee: u32,
+		opening_fee_params: OpeningFeeParams,
 		htlcs: Vec<InterceptedHTLC>,
 		payment_size_msat: Option<u64>,
 	},
@@ -81,18 +129,60 @@ enum OutboundJITChannelState {
 		htlcs: Vec<InterceptedHTLC>,
 		opening_fee_msat: u64,
 		amt_to_forward_msat: u64,
+		opening_fee_params: OpeningFeeParams,
+		payment_size_msat: Option<u64>,
+		/// HTLCs that arrived after the payment was complete, which are forwarded at their full
+		/// amount once all HTLCs of the payment were claimed.
+		late_htlcs: Vec<InterceptedHTLC>,
 	},
 	ChannelReady {
 		htlcs: Vec<InterceptedHTLC>,
 		amt_to_forward_msat: u64,
+		channel_id: ChannelId,
+		claimed_htlcs: Vec<InterceptId>,
+		skimmed_fee_msat: u64,
+		/// HTLCs that arrived after the payment was complete, which are held until all HTLCs of the
+		/// payment were claimed, as we couldn't tell their claims apart otherwise.
+		late_htlcs: Vec<InterceptedHTLC>,
+	},
+	Completed {
+		channel_id: ChannelId,
+		skimmed_fee_msat: u64,
 	},
 }
 
+impl_writeable_tlv_based_enum!(OutboundJITChannelState,
+	(0, AwaitingPayment) => {
+		(0, opening_fee_params, required),
+		(4, htlcs, required_vec),
+		(6, payment_size_msat, option),
+	},
+	(2, PendingChannelOpen) => {
+		(0, htlcs, required_vec),
+		(2, opening_fee_msat, required),
+		(4, amt_to_forward_msat, required),
+		(6, opening_fee_params, required),
+		(8, payment_size_msat, option),
+		(10, late_htlcs, optional_vec),
+	},
+	(4, ChannelReady) => {
+		(0, htlcs, required_vec),
+		(2, amt_to_forward_msat, required),
+		(4, channel_id, required),
+		(6, claimed_htlcs, optional_vec),
+		(8, skimmed_fee_msat, (default_value, 0)),
+		(10, late_htlcs, optional_vec),
+	},
+	(6, Completed) => {
+		(0, channel_id, required),
+		(2, skimmed_fee_msat, required),
+	};
+);
+
 impl OutboundJITChannelState {
 	fn new(payment_size_msat: Option<u64>, opening_fee_params: OpeningFeeParams) -> Self {
 		OutboundJITChannelState::AwaitingPayment {
-			min_fee_msat: opening_fee_params.min_fee_msat,
-			proportional_fee: opening_fee_params.proportional,
+			opening_fee_params,
 			htlcs: vec![],
 			payment_size_msat,
 		}
@@ -101,11 +191,19 @@ impl OutboundJITChannelState {
 	fn htlc_intercepted(&self, htlc: InterceptedHTLC) -> Result<Self, ChannelStateError> {
 		match self {
 			OutboundJITChannelState::AwaitingPayment {
+				opening_fee_params,
 				htlcs,
 				payment_size_msat,
-				min_fee_msat,
-				proportional_fee,
 			} => {
+				// Parts of a multi-part payment are accepted as long as its first part arrived
+				// before the parameters expired.
+				if htlcs.is_empty() && is_expired_opening_fee_params(opening_fee_params) {
+					return Err(ChannelStateError(format!(
+						"Opening fee params expired at {}",
+						opening_fee_params.valid_until
+					)));
+				}
+
 				let mut htlcs = htlcs.clone();
 				htlcs.push(htlc);
 
@@ -117,12 +215,12 @@ impl OutboundJITChannelState {
 
 				let opening_fee_msat = compute_opening_fee(
 					expected_payment_size_msat,
-					*min_fee_msat,
-					(*proportional_fee).into(),
+					opening_fee_params.min_fee_msat,
+					opening_fee_params.proportional.into(),
 				).ok_or(ChannelStateError(
-					format!("Could not compute valid opening fee with min_fee_msat = {}, proportional = {}, and total_expected_outbound_amount_msat = {}", 
-						min_fee_msat,
-						proportional_fee,
+					format!("Could not compute valid opening fee with min_fee_msat = {}, proportional = {}, and total_expected_outbound_amount_msat = {}",
+						opening_fee_params.min_fee_msat,
+						opening_fee_params.proportional,
 						total_expected_outbound_amount_msat
 					)
 				))?;
@@ -137,13 +235,15 @@ impl OutboundJITChannelState {
 						htlcs,
 						opening_fee_msat,
 						amt_to_forward_msat,
+						opening_fee_params: opening_fee_params.clone(),
+						payment_size_msat: *payment_size_msat,
+						late_htlcs: vec![],
 					})
 				} else {
 					// payment size being specified means MPP is supported
 					if payment_size_msat.is_some() {
 						Ok(OutboundJITChannelState::AwaitingPayment {
-							min_fee_msat: *min_fee_msat,
-							proportional_fee: *proportional_fee,
+							opening_fee_params: opening_fee_params.clone(),
 							htlcs,
 							payment_size_msat: *payment_size_msat,
 						})
@@ -152,36 +252,186 @@ impl OutboundJITChannelState {
 					}
 				}
 			}
+			// HTLCs arriving once the payment is complete, i.e., late parts or further payments,
+			// are held until the payment's HTLCs were claimed and then forwarded at their full
+			// amount without charging another opening fee.
+			OutboundJITChannelState::PendingChannelOpen {
+				htlcs,
+				opening_fee_msat,
+				amt_to_forward_msat,
+				opening_fee_params,
+				payment_size_msat,
+				late_htlcs,
+			} => {
+				let mut late_htlcs = late_htlcs.clone();
+				late_htlcs.push(htlc);
+				Ok(OutboundJITChannelState::PendingChannelOpen {
+					htlcs: htlcs.clone(),
+					opening_fee_msat: *opening_fee_msat,
+					amt_to_forward_msat: *amt_to_forward_msat,
+					opening_fee_params: opening_fee_params.clone(),
+					payment_size_msat: *payment_size_msat,
+					late_htlcs,
+				})
+			}
+			OutboundJITChannelState::ChannelReady {
+				htlcs,
+				amt_to_forward_msat,
+				channel_id,
+				claimed_htlcs,
+				skimmed_fee_msat,
+				late_htlcs,
+			} => {
+				let mut late_htlcs = late_htlcs.clone();
+				late_htlcs.push(htlc);
+				Ok(OutboundJITChannelState::ChannelReady {
+					htlcs: htlcs.clone(),
+					amt_to_forward_msat: *amt_to_forward_msat,
+					channel_id: *channel_id,
+					claimed_htlcs: claimed_htlcs.clone(),
+					skimmed_fee_msat: *skimmed_fee_msat,
+					late_htlcs,
+				})
+			}
+			OutboundJITChannelState::Completed { channel_id, skimmed_fee_msat } => {
+				Ok(OutboundJITChannelState::Completed {
+					channel_id: *channel_id,
+					skimmed_fee_msat: *skimmed_fee_msat,
+				})
+			}
+		}
+	}
+
+	fn channel_ready(&self, channel_id: ChannelId) -> Result<Self, ChannelStateError> {
+		match self {
+			OutboundJITChannelState::PendingChannelOpen {
+				htlcs,
+				amt_to_forward_msat,
+				late_htlcs,
+				..
+			} => Ok(OutboundJITChannelState::ChannelReady {
+				htlcs: htlcs.clone(),
+				amt_to_forward_msat: *amt_to_forward_msat,
+				channel_id,
+				claimed_htlcs: vec![],
+				skimmed_fee_msat: 0,
+				late_htlcs: late_htlcs.clone(),
+			}),
 			state => Err(ChannelStateError(format!(
-				"Invoice params received when JIT Channel was in state: {:?}",
+				"Channel ready received when JIT Channel was in state: {:?}",
 				state
 			))),
 		}
 	}
 
-	fn channel_ready(&self) -> Result<Self, ChannelStateError> {
+	fn payment_forwarded(
+		&self, outbound_amount_forwarded_msat: Option<u64>,
+	) -> Result<Self, ChannelStateError> {
 		match self {
-			OutboundJITChannelState::PendingChannelOpen { htlcs, amt_to_forward_msat, .. } => {
-				Ok(OutboundJITChannelState::ChannelReady {
-					htlcs: htlcs.clone(),
-					amt_to_forward_msat: *amt_to_forward_msat,
-				})
+			OutboundJITChannelState::ChannelReady {
+				htlcs,
+				amt_to_forward_msat,
+				channel_id,
+				claimed_htlcs,
+				skimmed_fee_msat,
+				late_htlcs,
+			} => {
+				// We can't tell which of the HTLCs was claimed, so we match on the forwarded amount
+				// and fall back to the next unclaimed HTLC if that fails.
+				let forwards = calculate_amount_to_forward_per_htlc(htlcs, *amt_to_forward_msat);
+				let mut unclaimed = htlcs
+					.iter()
+					.zip(forwards.iter())
+					.filter(|(htlc, _)| !claimed_htlcs.contains(&htlc.intercept_id));
+				let matching_htlc = unclaimed.clone().find(|(_, (_, amount_msat))| {
+					Some(*amount_msat) == outbound_amount_forwarded_msat
+				});
+
+				let (htlc, (_, planned_amount_msat)) =
+					matching_htlc.or_else(|| unclaimed.next()).ok_or(ChannelStateError(
+						"Payment forwarded after all HTLCs were claimed".to_string(),
+					))?;
+
+				let forwarded_amount_msat =
+					outbound_amount_forwarded_msat.unwrap_or(*planned_amount_msat);
+				let skimmed_fee_msat = skimmed_fee_msat
+					+ htlc.expected_outbound_amount_msat.saturating_sub(forwarded_amount_msat);
+				let mut claimed_htlcs = claimed_htlcs.clone();
+				claimed_htlcs.push(htlc.intercept_id);
+
+				if claimed_htlcs.len() == htlcs.len() {
+					Ok(OutboundJITChannelState::Completed {
+						channel_id: *channel_id,
+						skimmed_fee_msat,
+					})
+				} else {
+					Ok(OutboundJITChannelState::ChannelReady {
+						htlcs: htlcs.clone(),
+						amt_to_forward_msat: *amt_to_forward_msat,
+						channel_id: *channel_id,
+						claimed_htlcs,
+						skimmed_fee_msat,
+						late_htlcs: late_htlcs.clone(),
+					})
+				}
 			}
 			state => Err(ChannelStateError(format!(
-				"Channel ready received when JIT Channel was in state: {:?}",
+				"Payment forwarded when JIT Channel was in state: {:?}",
+				state
+			))),
+		}
+	}
+
+	fn channel_open_failed(&self) -> Result<Self, ChannelStateError> {
+		match self {
+			OutboundJITChannelState::PendingChannelOpen {
+				opening_fee_params,
+				payment_size_msat,
+				..
+			} => Ok(OutboundJITChannelState::AwaitingPayment {
+				opening_fee_params: opening_fee_params.clone(),
+				htlcs: vec![],
+				payment_size_msat: *payment_size_msat,
+			}),
+			state => Err(ChannelStateError(format!(
+				"Channel open failure received when JIT Channel was in state: {:?}",
 				state
 			))),
 		}
 	}
 }
 
+/// What needs to happen in response to an intercepted HTLC.
+#[derive(Debug, PartialEq, Eq)]
+enum HTLCInterceptedAction {
+	/// The payment is complete and the channel needs to be opened.
+	OpenChannel { opening_fee_msat: u64, amt_to_forward_msat: u64 },
+	/// The channel is already open and the HTLC can be forwarded over it right away.
+	ForwardHTLC { channel_id: ChannelId, amt_to_forward_msat: u64 },
+}
+
 struct OutboundJITChannel {
 	state: OutboundJITChannelState,
 	scid: u64,
 	cltv_expiry_delta: u32,
 	client_trusts_lsp: bool,
+	htlc_hold_ticks: u32,
+	/// The funding transaction we withhold until the client claimed the payment, if the client
+	/// doesn't trust us.
+	funding_tx: Option<Transaction>,
+	payment_claimed: bool,
 }
 
+impl_writeable_tlv_based!(OutboundJITChannel, {
+	(0, state, required),
+	(2, scid, required),
+	(4, cltv_expiry_delta, required),
+	(6, client_trusts_lsp, required),
+	(8, funding_tx, option),
+	(10, payment_claimed, (default_value, false)),
+	(not_written, htlc_hold_ticks, (static_value, 0)),
+});
+
 impl OutboundJITChannel {
 	fn new(
 		scid: u64, cltv_expiry_delta: u32, client_trusts_lsp: bool, payment_size_msat: Option<u64>,
@@ -192,16 +442,21 @@ impl OutboundJITChannel {
 			cltv_expiry_delta,
 			client_trusts_lsp,
 			state: OutboundJITChannelState::new(payment_size_msat, opening_fee_params),
+			htlc_hold_ticks: 0,
+			funding_tx: None,
+			payment_claimed: false,
 		}
 	}
 
 	fn htlc_intercepted(
 		&mut self, htlc: InterceptedHTLC,
-	) -> Result<Option<(u64, u64)>, LightningError> {
+	) -> Result<Option<HTLCInterceptedAction>, LightningError> {
+		let was_awaiting_payment =
+			matches!(self.state, OutboundJITChannelState::AwaitingPayment { .. });
 		self.state = self.state.htlc_intercepted(htlc)?;
 
 		match &self.state {
-			OutboundJITChannelState::AwaitingPayment { htlcs, payment_size_msat, .. } => {
+			OutboundJITChannelState::AwaitingPayment { .. } => {
 				// TODO: log that we received an htlc but are still awaiting payment
 				Ok(None)
 			}
@@ -209,23 +464,69 @@ impl OutboundJITChannel {
 				opening_fee_msat,
 				amt_to_forward_msat,
 				..
-			} => Ok(Some((*opening_fee_msat, *amt_to_forward_msat))),
-			impossible_state => Err(LightningError {
-				err: format!(
-					"Impossible state transition during htlc_intercepted to {:?}",
-					impossible_state
-				),
-				action: ErrorAction::IgnoreAndLog(Level::Info),
-			}),
+			} => {
+				if was_awaiting_payment {
+					Ok(Some(HTLCInterceptedAction::OpenChannel {
+						opening_fee_msat: *opening_fee_msat,
+						amt_to_forward_msat: *amt_to_forward_msat,
+					}))
+				} else {
+					// The HTLC will be forwarded once the channel is ready.
+					Ok(None)
+				}
+			}
+			OutboundJITChannelState::ChannelReady { .. } => {
+				// The HTLC will be forwarded once all HTLCs of the payment were claimed.
+				Ok(None)
+			}
+			OutboundJITChannelState::Completed { channel_id, .. } => {
+				Ok(Some(HTLCInterceptedAction::ForwardHTLC {
+					channel_id: *channel_id,
+					amt_to_forward_msat: htlc.expected_outbound_amount_msat,
+				}))
+			}
 		}
 	}
 
-	fn channel_ready(&mut self) -> Result<(Vec<InterceptedHTLC>, u64), LightningError> {
-		self.state = self.state.channel_ready()?;
+	/// Counts how long we've been holding the HTLCs of a payment that didn't fully arrive yet.
+	///
+	/// Returns the held HTLCs, which need to be failed back, once the timeout is reached, leaving
+	/// the channel waiting for the payer to retry.
+	fn timer_tick_occurred(&mut self, htlc_hold_timeout_ticks: u32) -> Vec<InterceptedHTLC> {
+		match &mut self.state {
+			OutboundJITChannelState::AwaitingPayment { htlcs, .. } if !htlcs.is_empty() => {
+				self.htlc_hold_ticks += 1;
+				if self.htlc_hold_ticks < htlc_hold_timeout_ticks {
+					return Vec::new();
+				}
+
+				self.htlc_hold_ticks = 0;
+				core::mem::take(htlcs)
+			}
+			_ => Vec::new(),
+		}
+	}
 
+	/// Returns whether the sold offer expired without any payment arriving, i.e., whether the scid
+	/// can't be used anymore.
+	fn is_expired_offer(&self) -> bool {
 		match &self.state {
-			OutboundJITChannelState::ChannelReady { htlcs, amt_to_forward_msat } => {
-				Ok((htlcs.clone(), *amt_to_forward_msat))
+			OutboundJITChannelState::AwaitingPayment { opening_fee_params, htlcs, .. } => {
+				htlcs.is_empty() && is_expired_opening_fee_params(opening_fee_params)
+			}
+			_ => false,
+		}
+	}
+
+	/// Returns the held HTLCs along with the amounts they need to be forwarded with.
+	fn channel_ready(
+		&mut self, channel_id: ChannelId,
+	) -> Result<Vec<(InterceptId, u64)>, LightningError> {
+		self.state = self.state.channel_ready(channel_id)?;
+
+		match &self.state {
+			OutboundJITChannelState::ChannelReady { htlcs, amt_to_forward_msat, .. } => {
+				Ok(calculate_amount_to_forward_per_htlc(htlcs, *amt_to_forward_msat))
 			}
 			impossible_state => Err(LightningError {
 				err: format!(
@@ -236,61 +537,213 @@ impl OutboundJITChannel {
 			}),
 		}
 	}
+
+	fn channel_id(&self) -> Option<ChannelId> {
+		match &self.state {
+			OutboundJITChannelState::ChannelReady { channel_id, .. }
+			| OutboundJITChannelState::Completed { channel_id, .. } => Some(*channel_id),
+			_ => None,
+		}
+	}
+
+	fn is_completed(&self) -> bool {
+		matches!(self.state, OutboundJITChannelState::Completed { .. })
+	}
+
+	/// Returns the total fee we skimmed off the forwarded HTLCs once all of them were claimed,
+	/// along with the late HTLCs that can now be forwarded at their full amount.
+	fn payment_forwarded(
+		&mut self, outbound_amount_forwarded_msat: Option<u64>,
+	) -> Result<Option<(u64, Vec<InterceptedHTLC>)>, LightningError> {
+		let late_htlcs = self.late_htlcs();
+		self.state = self.state.payment_forwarded(outbound_amount_forwarded_msat)?;
+
+		match &self.state {
+			OutboundJITChannelState::Completed { skimmed_fee_msat, .. } => {
+				Ok(Some((*skimmed_fee_msat, late_htlcs)))
+			}
+			_ => Ok(None),
+		}
+	}
+
+	/// Returns the HTLCs that arrived after the payment was complete and are still held.
+	fn late_htlcs(&self) -> Vec<InterceptedHTLC> {
+		match &self.state {
+			OutboundJITChannelState::PendingChannelOpen { late_htlcs, .. }
+			| OutboundJITChannelState::ChannelReady { late_htlcs, .. } => late_htlcs.clone(),
+			_ => Vec::new(),
+		}
+	}
+
+	fn store_funding_transaction(&mut self, funding_tx: Transaction) -> Option<Transaction> {
+		self.funding_tx = Some(funding_tx);
+		self.funding_tx_to_broadcast()
+	}
+
+	fn payment_claimed(&mut self) -> Option<Transaction> {
+		self.payment_claimed = true;
+		self.funding_tx_to_broadcast()
+	}
+
+	/// Returns the funding transaction once it's safe to broadcast it, i.e., if the client trusts
+	/// us or claimed the payment we forwarded to it.
+	fn funding_tx_to_broadcast(&mut self) -> Option<Transaction> {
+		if self.client_trusts_lsp || self.payment_claimed {
+			self.funding_tx.take()
+		} else {
+			None
+		}
+	}
+
+	/// Returns the HTLCs that need to be failed back, leaving the channel waiting for the payer to
+	/// retry.
+	fn channel_open_failed(&mut self) -> Result<Vec<InterceptedHTLC>, LightningError> {
+		let htlcs = match &self.state {
+			OutboundJITChannelState::PendingChannelOpen { htlcs, late_htlcs, .. } => {
+				htlcs.iter().chain(late_htlcs.iter()).copied().collect()
+			}
+			_ => Vec::new(),
+		};
+		self.state = self.state.channel_open_failed()?;
+		self.htlc_hold_ticks = 0;
+		Ok(htlcs)
+	}
 }
 
 struct PeerState {
 	outbound_channels_by_scid: HashMap<u64, OutboundJITChannel>,
+	/// The channel ids of the JIT channels whose opening fee was collected, by their scid.
+	completed_channels_by_scid: HashMap<u64, ChannelId>,
 	pending_requests: HashMap<RequestId, LSPS2Request>,
 }
 
 impl PeerState {
 	fn new() -> Self {
 		let outbound_channels_by_scid = HashMap::new();
+		let completed_channels_by_scid = HashMap::new();
 		let pending_requests = HashMap::new();
-		Self { outbound_channels_by_scid, pending_requests }
+		Self { outbound_channels_by_scid, completed_channels_by_scid, pending_requests }
 	}
 
 	fn insert_outbound_channel(&mut self, scid: u64, channel: OutboundJITChannel) {
 		self.outbound_channels_by_scid.insert(scid, channel);
 	}
 
-	fn remove_outbound_channel(&mut self, scid: u64) {
-		self.outbound_channels_by_scid.remove(&scid);
+	fn remove_outbound_channel(&mut self, scid: u64) -> Option<OutboundJITChannel> {
+		self.outbound_channels_by_scid.remove(&scid)
+	}
+
+	/// Replaces the JIT channel with the given scid by its channel id if it completed, as we don't
+	/// need to keep the rest of its state around anymore.
+	fn archive_completed_channel(&mut self, scid: u64) {
+		let channel_id = match self.outbound_channels_by_scid.get(&scid) {
+			Some(jit_channel) if jit_channel.is_completed() => jit_channel.channel_id(),
+			_ => None,
+		};
+		if let Some(channel_id) = channel_id {
+			self.outbound_channels_by_scid.remove(&scid);
+			self.completed_channels_by_scid.insert(scid, channel_id);
+		}
+	}
+
+	/// Returns the number of JIT channels that were bought or are being bought but didn't complete
+	/// yet.
+	fn open_jit_channel_count(&self) -> usize {
+		let pending_buys = self
+			.pending_requests
+			.values()
+			.filter(|request| matches!(request, LSPS2Request::Buy(_)))
+			.count();
+		pending_buys + self.outbound_channels_by_scid.len()
+	}
+}
+
+impl_writeable_tlv_based!(PeerState, {
+	(0, outbound_channels_by_scid, required),
+	(2, completed_channels_by_scid, (default_value, HashMap::new())),
+	(not_written, pending_requests, (static_value, HashMap::new())),
+});
+
+impl PersistedPeerState for PeerState {
+	const PRIMARY_NAMESPACE: &'static str = LSPS2_SERVICE_PERSISTENCE_PRIMARY_NAMESPACE;
+	const SECONDARY_NAMESPACE: &'static str = LSPS2_SERVICE_PERSISTENCE_SECONDARY_NAMESPACE;
+
+	fn is_persistable(&self) -> bool {
+		!self.outbound_channels_by_scid.is_empty() || !self.completed_channels_by_scid.is_empty()
 	}
 }
 
 /// The main object allowing to send and receive LSPS2 messages.
-pub struct LSPS2ServiceHandler<CM: Deref + Clone, MQ: Deref>
+///
+/// The state of all JIT channels sold is persisted to the given [`KVStore`] on every change and
+/// reloaded from it upon construction, so that previously issued scids keep working across
+/// restarts.
+pub struct LSPS2ServiceHandler<CM: Deref + Clone, MQ: Deref, K: Deref>
 where
 	CM::Target: AChannelManager,
 	MQ::Target: MessageQueue,
+	K::Target: KVStore,
 {
 	channel_manager: CM,
 	pending_messages: MQ,
 	pending_events: Arc<EventQueue>,
 	per_peer_state: RwLock<HashMap<PublicKey, Mutex<PeerState>>>,
 	peer_by_scid: RwLock<HashMap<u64, PublicKey>>,
+	peer_by_channel_id: RwLock<HashMap<ChannelId, PublicKey>>,
+	kv_store: K,
 	config: LSPS2ServiceConfig,
 }
 
-impl<CM: Deref + Clone, MQ: Deref> LSPS2ServiceHandler<CM, MQ>
+impl<CM: Deref + Clone, MQ: Deref, K: Deref> LSPS2ServiceHandler<CM, MQ, K>
 where
 	CM::Target: AChannelManager,
 	MQ::Target: MessageQueue,
+	K::Target: KVStore,
 {
-	/// Constructs a `LSPS2ServiceHandler`.
+	/// Constructs a `LSPS2ServiceHandler`, reloading any previously persisted state from the
+	/// given `kv_store`.
+	///
+	/// Returns an error if the given `config` is invalid.
 	pub(crate) fn new(
-		pending_messages: MQ, pending_events: Arc<EventQueue>, channel_manager: CM,
+		pending_messages: MQ, pending_events: Arc<EventQueue>, channel_manager: CM, kv_store: K,
 		config: LSPS2ServiceConfig,
-	) -> Self {
-		Self {
+	) -> Result<Self, io::Error> {
+		if config.htlc_hold_timeout_ticks == 0 {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				"The HTLC hold timeout must be at least one tick",
+			));
+		}
+
+		let persisted_peer_states: HashMap<PublicKey, PeerState> = read_peer_states(&kv_store)?;
+
+		let mut per_peer_state = HashMap::new();
+		let mut peer_by_scid = HashMap::new();
+		let mut peer_by_channel_id = HashMap::new();
+		for (counterparty_node_id, peer_state) in persisted_peer_states {
+			for (scid, jit_channel) in peer_state.outbound_channels_by_scid.iter() {
+				peer_by_scid.insert(*scid, counterparty_node_id);
+				if let Some(channel_id) = jit_channel.channel_id() {
+					peer_by_channel_id.insert(channel_id, counterparty_node_id);
+				}
+			}
+			for (scid, channel_id) in peer_state.completed_channels_by_scid.iter() {
+				peer_by_scid.insert(*scid, counterparty_node_id);
+				peer_by_channel_id.insert(*channel_id, counterparty_node_id);
+			}
+			per_peer_state.insert(counterparty_node_id, Mutex::new(peer_state));
+		}
+
+		Ok(Self {
 			pending_messages,
 			pending_events,
-			per_peer_state: RwLock::new(HashMap::new()),
-			peer_by_scid: RwLock::new(HashMap::new()),
+			per_peer_state: RwLock::new(per_peer_state),
+			peer_by_scid: RwLock::new(peer_by_scid),
+			peer_by_channel_id: RwLock::new(peer_by_channel_id),
 			channel_manager,
+			kv_store,
 			config,
-		}
+		})
 	}
 
 	/// Used by LSP to inform a client requesting a JIT Channel the token they used is invalid.
@@ -300,7 +753,7 @@ where
 	/// [`LSPS2ServiceEvent::GetInfo`]: crate::lsps2::event::LSPS2ServiceEvent::GetInfo
 	pub fn invalid_token_provided(
 		&self, counterparty_node_id: &PublicKey, request_id: RequestId,
-	) -> Result<(), APIError> {
+	) -> Result<(), LiquidityError> {
 		let outer_state_lock = self.per_peer_state.read().unwrap();
 
 		match outer_state_lock.get(counterparty_node_id) {
@@ -322,12 +775,14 @@ where
 							"No pending get_info request for request_id: {:?}",
 							request_id
 						),
-					}),
+					}
+					.into()),
 				}
 			}
 			None => Err(APIError::APIMisuseError {
 				err: format!("No state for the counterparty exists: {:?}", counterparty_node_id),
-			}),
+			}
+			.into()),
 		}
 	}
 
@@ -339,7 +794,7 @@ where
 	pub fn opening_fee_params_generated(
 		&self, counterparty_node_id: &PublicKey, request_id: RequestId,
 		opening_fee_params_menu: Vec<RawOpeningFeeParams>,
-	) -> Result<(), APIError> {
+	) -> Result<(), LiquidityError> {
 		let outer_state_lock = self.per_peer_state.read().unwrap();
 
 		match outer_state_lock.get(counterparty_node_id) {
@@ -366,12 +821,14 @@ where
 							"No pending get_info request for request_id: {:?}",
 							request_id
 						),
-					}),
+					}
+					.into()),
 				}
 			}
 			None => Err(APIError::APIMisuseError {
 				err: format!("No state for the counterparty exists: {:?}", counterparty_node_id),
-			}),
+			}
+			.into()),
 		}
 	}
 
@@ -379,11 +836,15 @@ where
 	///
 	/// Should be called in response to receiving a [`LSPS2ServiceEvent::BuyRequest`] event.
 	///
+	/// If `client_trusts_lsp` is `false`, the funding transaction of the JIT channel must be
+	/// withheld until the client claimed the payment, see
+	/// [`LSPS2ServiceHandler::store_funding_transaction`].
+	///
 	/// [`LSPS2ServiceEvent::BuyRequest`]: crate::lsps2::event::LSPS2ServiceEvent::BuyRequest
 	pub fn invoice_parameters_generated(
 		&self, counterparty_node_id: &PublicKey, request_id: RequestId, scid: u64,
 		cltv_expiry_delta: u32, client_trusts_lsp: bool,
-	) -> Result<(), APIError> {
+	) -> Result<(), LiquidityError> {
 		let outer_state_lock = self.per_peer_state.read().unwrap();
 
 		match outer_state_lock.get(counterparty_node_id) {
@@ -406,6 +867,7 @@ where
 						);
 
 						peer_state.insert_outbound_channel(scid, outbound_jit_channel);
+						persist_peer_state(&self.kv_store, counterparty_node_id, &*peer_state)?;
 
 						self.enqueue_response(
 							counterparty_node_id,
@@ -421,12 +883,14 @@ where
 					}
 					_ => Err(APIError::APIMisuseError {
 						err: format!("No pending buy request for request_id: {:?}", request_id),
-					}),
+					}
+					.into()),
 				}
 			}
 			None => Err(APIError::APIMisuseError {
 				err: format!("No state for the counterparty exists: {:?}", counterparty_node_id),
-			}),
+			}
+			.into()),
 		}
 	}
 
@@ -438,53 +902,77 @@ where
 	/// Will generate a [`LSPS2ServiceEvent::OpenChannel`] event if the scid matches a payment we are expected
 	/// and the payment amount is correct and the offer has not expired.
 	///
-	/// Will do nothing if the scid does not match any of the ones we gave out.
+	/// Will forward the intercepted HTLC at its full amount over the JIT channel, i.e., without
+	/// charging another opening fee, if the payment already arrived. As we can't tell its claim
+	/// apart from the ones of the payment's HTLCs, this happens only once the opening fee was
+	/// collected, or right away if it was already. This also holds for further payments to the scid.
+	///
+	/// Will do nothing if the scid does not match any of the ones we gave out, so that HTLCs
+	/// intercepted for other purposes are left alone.
 	///
 	/// [`Event::HTLCIntercepted`]: lightning::events::Event::HTLCIntercepted
 	/// [`LSPS2ServiceEvent::OpenChannel`]: crate::lsps2::event::LSPS2ServiceEvent::OpenChannel
 	pub fn htlc_intercepted(
 		&self, scid: u64, intercept_id: InterceptId, expected_outbound_amount_msat: u64,
-	) -> Result<(), APIError> {
-		let peer_by_scid = self.peer_by_scid.read().unwrap();
-		if let Some(counterparty_node_id) = peer_by_scid.get(&scid) {
-			let outer_state_lock = self.per_peer_state.read().unwrap();
-			match outer_state_lock.get(counterparty_node_id) {
-				Some(inner_state_lock) => {
-					let mut peer_state = inner_state_lock.lock().unwrap();
-					if let Some(jit_channel) = peer_state.outbound_channels_by_scid.get_mut(&scid) {
-						let htlc = InterceptedHTLC { intercept_id, expected_outbound_amount_msat };
-						match jit_channel.htlc_intercepted(htlc) {
-							Ok(Some((opening_fee_msat, amt_to_forward_msat))) => {
-								self.enqueue_event(Event::LSPS2Service(
-									LSPS2ServiceEvent::OpenChannel {
-										their_network_key: counterparty_node_id.clone(),
-										amt_to_forward_msat,
-										opening_fee_msat,
-										user_channel_id: scid as u128,
-									},
-								));
-							}
-							Ok(None) => {}
-							Err(e) => {
-								self.channel_manager
-									.get_cm()
-									.fail_intercepted_htlc(intercept_id)?;
-								peer_state.outbound_channels_by_scid.remove(&scid);
-								// TODO: cleanup peer_by_scid
-								return Err(APIError::APIMisuseError { err: e.err });
-							}
-						}
-					}
-				}
-				None => {
-					return Err(APIError::APIMisuseError {
-						err: format!("No counterparty found for scid: {}", scid),
-					});
-				}
-			}
+	) -> Result<(), LiquidityError> {
+		let counterparty_node_id = match self.peer_by_scid.read().unwrap().get(&scid) {
+			Some(counterparty_node_id) => *counterparty_node_id,
+			None => return Ok(()),
+		};
+
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		let inner_state_lock = match outer_state_lock.get(&counterparty_node_id) {
+			Some(inner_state_lock) => inner_state_lock,
+			None => return Ok(()),
+		};
+		let mut peer_state = inner_state_lock.lock().unwrap();
+
+		if let Some(channel_id) = peer_state.completed_channels_by_scid.get(&scid) {
+			self.channel_manager.get_cm().forward_intercepted_htlc(
+				intercept_id,
+				channel_id,
+				counterparty_node_id,
+				expected_outbound_amount_msat,
+			)?;
+			return Ok(());
 		}
 
-		Ok(())
+		let jit_channel = match peer_state.outbound_channels_by_scid.get_mut(&scid) {
+			Some(jit_channel) => jit_channel,
+			None => return Ok(()),
+		};
+		let htlc = InterceptedHTLC { intercept_id, expected_outbound_amount_msat };
+		match jit_channel.htlc_intercepted(htlc) {
+			Ok(Some(HTLCInterceptedAction::OpenChannel {
+				opening_fee_msat,
+				amt_to_forward_msat,
+			})) => {
+				self.enqueue_event(Event::LSPS2Service(LSPS2ServiceEvent::OpenChannel {
+					their_network_key: counterparty_node_id,
+					amt_to_forward_msat,
+					opening_fee_msat,
+					user_channel_id: scid as u128,
+				}));
+			}
+			Ok(Some(HTLCInterceptedAction::ForwardHTLC { channel_id, amt_to_forward_msat })) => {
+				self.channel_manager.get_cm().forward_intercepted_htlc(
+					intercept_id,
+					&channel_id,
+					counterparty_node_id,
+					amt_to_forward_msat,
+				)?;
+			}
+			Ok(None) => {}
+			Err(e) => {
+				// The HTLC might already have been failed back upstream in the meantime, in which
+				// case there is nothing left to do.
+				let _ = self.channel_manager.get_cm().fail_intercepted_htlc(intercept_id);
+				self.remove_jit_channel(&mut peer_state, scid);
+				persist_peer_state(&self.kv_store, &counterparty_node_id, &*peer_state)?;
+				return Err(APIError::APIMisuseError { err: e.err }.into());
+			}
+		}
+		persist_peer_state(&self.kv_store, &counterparty_node_id, &*peer_state)
 	}
 
 	/// Forward [`Event::ChannelReady`] event parameters into this function.
@@ -495,30 +983,44 @@ where
 	/// [`Event::ChannelReady`]: lightning::events::Event::ChannelReady
 	pub fn channel_ready(
 		&self, user_channel_id: u128, channel_id: &ChannelId, counterparty_node_id: &PublicKey,
-	) -> Result<(), APIError> {
+	) -> Result<(), LiquidityError> {
 		if let Ok(scid) = user_channel_id.try_into() {
 			let outer_state_lock = self.per_peer_state.read().unwrap();
 			match outer_state_lock.get(counterparty_node_id) {
 				Some(inner_state_lock) => {
 					let mut peer_state = inner_state_lock.lock().unwrap();
 					if let Some(jit_channel) = peer_state.outbound_channels_by_scid.get_mut(&scid) {
-						match jit_channel.channel_ready() {
-							Ok((htlcs, total_amt_to_forward_msat)) => {
-								let amounts_to_forward_msat = calculate_amount_to_forward_per_htlc(
-									&htlcs,
-									total_amt_to_forward_msat,
-								);
-
+						match jit_channel.channel_ready(*channel_id) {
+							Ok(amounts_to_forward_msat) => {
+								// We try to forward all HTLCs and only report the first failure once
+								// we recorded the channel, so that a single failed forward doesn't
+								// leave the others stuck.
+								let mut forward_result = Ok(());
 								for (intercept_id, amount_to_forward_msat) in
 									amounts_to_forward_msat
 								{
-									self.channel_manager.get_cm().forward_intercepted_htlc(
-										intercept_id,
-										channel_id,
-										*counterparty_node_id,
-										amount_to_forward_msat,
-									)?;
+									let res =
+										self.channel_manager.get_cm().forward_intercepted_htlc(
+											intercept_id,
+											channel_id,
+											*counterparty_node_id,
+											amount_to_forward_msat,
+										);
+									if forward_result.is_ok() {
+										forward_result = res;
+									}
 								}
+
+								self.peer_by_channel_id
+									.write()
+									.unwrap()
+									.insert(*channel_id, *counterparty_node_id);
+								persist_peer_state(
+									&self.kv_store,
+									counterparty_node_id,
+									&*peer_state,
+								)?;
+								forward_result?;
 							}
 							Err(e) => {
 								return Err(APIError::APIMisuseError {
@@ -526,7 +1028,8 @@ where
 										"Failed to transition to channel ready: {}",
 										e.err
 									),
-								})
+								}
+								.into())
 							}
 						}
 					} else {
@@ -535,13 +1038,15 @@ where
 								"Could not find a channel with user_channel_id {}",
 								user_channel_id
 							),
-						});
+						}
+						.into());
 					}
 				}
 				None => {
 					return Err(APIError::APIMisuseError {
 						err: format!("No counterparty state for: {}", counterparty_node_id),
-					});
+					}
+					.into());
 				}
 			}
 		}
@@ -549,6 +1054,329 @@ where
 		Ok(())
 	}
 
+	/// Used by LSP to hand us the funding transaction of a JIT channel.
+	///
+	/// If the client doesn't trust us, i.e., `client_trusts_lsp` was set to `false` in
+	/// [`LSPS2ServiceHandler::invoice_parameters_generated`], the funding transaction must not be
+	/// broadcast before the client claimed the payment we forwarded. In this case you need to keep
+	/// the [`BroadcasterInterface`] given to the [`ChannelManager`] from broadcasting the
+	/// transaction passed to [`ChannelManager::funding_transaction_generated`] and pass it here
+	/// instead. Once it is safe to broadcast the transaction, a
+	/// [`LSPS2ServiceEvent::BroadcastFundingTransaction`] event will be generated, which will
+	/// happen immediately if the client trusts us.
+	///
+	/// [`BroadcasterInterface`]: lightning::chain::chaininterface::BroadcasterInterface
+	/// [`ChannelManager`]: lightning::ln::channelmanager::ChannelManager
+	/// [`ChannelManager::funding_transaction_generated`]: lightning::ln::channelmanager::ChannelManager::funding_transaction_generated
+	/// [`LSPS2ServiceEvent::BroadcastFundingTransaction`]: crate::lsps2::event::LSPS2ServiceEvent::BroadcastFundingTransaction
+	pub fn store_funding_transaction(
+		&self, counterparty_node_id: &PublicKey, user_channel_id: u128, funding_tx: Transaction,
+	) -> Result<(), LiquidityError> {
+		let scid: u64 = user_channel_id.try_into().map_err(|_| APIError::APIMisuseError {
+			err: format!("Could not find a channel with user_channel_id {}", user_channel_id),
+		})?;
+
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		let inner_state_lock =
+			outer_state_lock.get(counterparty_node_id).ok_or_else(|| APIError::APIMisuseError {
+				err: format!("No counterparty state for: {}", counterparty_node_id),
+			})?;
+		let mut peer_state = inner_state_lock.lock().unwrap();
+		let jit_channel = peer_state.outbound_channels_by_scid.get_mut(&scid).ok_or_else(|| {
+			APIError::APIMisuseError {
+				err: format!("Could not find a channel with user_channel_id {}", user_channel_id),
+			}
+		})?;
+
+		let funding_tx_to_broadcast = jit_channel.store_funding_transaction(funding_tx);
+		persist_peer_state(&self.kv_store, counterparty_node_id, &*peer_state)?;
+
+		if let Some(funding_tx) = funding_tx_to_broadcast {
+			self.enqueue_event(Event::LSPS2Service(
+				LSPS2ServiceEvent::BroadcastFundingTransaction {
+					counterparty_node_id: *counterparty_node_id,
+					user_channel_id,
+					funding_tx,
+				},
+			));
+		}
+
+		Ok(())
+	}
+
+	/// Forward [`Event::PaymentForwarded`] event parameters into this function.
+	///
+	/// Will record the opening fee skimmed off the HTLC that was claimed and generate a
+	/// [`LSPS2ServiceEvent::OpeningFeeCollected`] event once all HTLCs forwarded over the JIT
+	/// channel were claimed. At that point, the HTLCs that arrived after the payment was complete
+	/// are forwarded at their full amount. Afterwards, only the scid and channel id of the completed
+	/// JIT channel are kept, so that further payments to the scid can be forwarded over the channel
+	/// until it is passed to [`LSPS2ServiceHandler::channel_closed`].
+	///
+	/// Will generate a [`LSPS2ServiceEvent::BroadcastFundingTransaction`] event if the payment
+	/// was forwarded over a JIT channel whose funding transaction we withheld.
+	///
+	/// Will do nothing if the payment wasn't forwarded over a JIT channel.
+	///
+	/// [`Event::PaymentForwarded`]: lightning::events::Event::PaymentForwarded
+	/// [`LSPS2ServiceEvent::OpeningFeeCollected`]: crate::lsps2::event::LSPS2ServiceEvent::OpeningFeeCollected
+	/// [`LSPS2ServiceEvent::BroadcastFundingTransaction`]: crate::lsps2::event::LSPS2ServiceEvent::BroadcastFundingTransaction
+	pub fn payment_forwarded(
+		&self, next_channel_id: ChannelId, outbound_amount_forwarded_msat: Option<u64>,
+	) -> Result<(), LiquidityError> {
+		let counterparty_node_id =
+			match self.peer_by_channel_id.read().unwrap().get(&next_channel_id) {
+				Some(counterparty_node_id) => *counterparty_node_id,
+				None => return Ok(()),
+			};
+
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		let inner_state_lock = outer_state_lock.get(&counterparty_node_id).ok_or_else(|| {
+			APIError::APIMisuseError {
+				err: format!("No counterparty state for: {}", counterparty_node_id),
+			}
+		})?;
+		let mut peer_state = inner_state_lock.lock().unwrap();
+		let jit_channel = match peer_state
+			.outbound_channels_by_scid
+			.values_mut()
+			.find(|jit_channel| jit_channel.channel_id() == Some(next_channel_id))
+		{
+			Some(jit_channel) => jit_channel,
+			None => return Ok(()),
+		};
+
+		let scid = jit_channel.scid;
+		let user_channel_id = scid as u128;
+		let completion = jit_channel
+			.payment_forwarded(outbound_amount_forwarded_msat)
+			.map_err(|e| APIError::APIMisuseError { err: e.err })?;
+		let funding_tx_to_broadcast = jit_channel.payment_claimed();
+
+		let mut skimmed_fee_msat = None;
+		let mut forward_result = Ok(());
+		if let Some((fee_msat, late_htlcs)) = completion {
+			skimmed_fee_msat = Some(fee_msat);
+			peer_state.archive_completed_channel(scid);
+
+			// As with the payment's HTLCs, we try to forward all late HTLCs and only report the
+			// first failure once we recorded the completion.
+			for htlc in late_htlcs {
+				let res = self.channel_manager.get_cm().forward_intercepted_htlc(
+					htlc.intercept_id,
+					&next_channel_id,
+					counterparty_node_id,
+					htlc.expected_outbound_amount_msat,
+				);
+				if forward_result.is_ok() {
+					forward_result = res;
+				}
+			}
+		}
+		persist_peer_state(&self.kv_store, &counterparty_node_id, &*peer_state)?;
+
+		if let Some(funding_tx) = funding_tx_to_broadcast {
+			self.enqueue_event(Event::LSPS2Service(
+				LSPS2ServiceEvent::BroadcastFundingTransaction {
+					counterparty_node_id,
+					user_channel_id,
+					funding_tx,
+				},
+			));
+		}
+
+		if let Some(skimmed_fee_msat) = skimmed_fee_msat {
+			self.enqueue_event(Event::LSPS2Service(LSPS2ServiceEvent::OpeningFeeCollected {
+				counterparty_node_id,
+				user_channel_id,
+				skimmed_fee_msat,
+			}));
+		}
+
+		forward_result?;
+		Ok(())
+	}
+
+	/// Forward [`Event::ChannelClosed`] event parameters into this function.
+	///
+	/// Will forget about the JIT channel with the given `channel_id`, whether its opening fee was
+	/// collected already or not, after which HTLCs to its scid are left alone. HTLCs that were held
+	/// until the opening fee was collected are failed back. Closures of any other channels are
+	/// ignored.
+	///
+	/// [`Event::ChannelClosed`]: lightning::events::Event::ChannelClosed
+	pub fn channel_closed(&self, channel_id: &ChannelId) -> Result<(), LiquidityError> {
+		let counterparty_node_id = match self.peer_by_channel_id.read().unwrap().get(channel_id) {
+			Some(counterparty_node_id) => *counterparty_node_id,
+			None => return Ok(()),
+		};
+
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		let inner_state_lock = match outer_state_lock.get(&counterparty_node_id) {
+			Some(inner_state_lock) => inner_state_lock,
+			None => return Ok(()),
+		};
+		let mut peer_state = inner_state_lock.lock().unwrap();
+
+		let completed_scid = peer_state
+			.completed_channels_by_scid
+			.iter()
+			.find(|(_, completed_channel_id)| *completed_channel_id == channel_id)
+			.map(|(scid, _)| *scid);
+		if let Some(scid) = completed_scid {
+			peer_state.completed_channels_by_scid.remove(&scid);
+			self.peer_by_scid.write().unwrap().remove(&scid);
+		}
+
+		let open_scid = peer_state
+			.outbound_channels_by_scid
+			.values()
+			.find(|jit_channel| jit_channel.channel_id() == Some(*channel_id))
+			.map(|jit_channel| jit_channel.scid);
+		if let Some(scid) = open_scid {
+			if let Some(jit_channel) = peer_state.outbound_channels_by_scid.get(&scid) {
+				for htlc in jit_channel.late_htlcs() {
+					// The HTLC might already have been failed back upstream in the meantime, in
+					// which case there is nothing left to do.
+					let _ = self.channel_manager.get_cm().fail_intercepted_htlc(htlc.intercept_id);
+				}
+			}
+			self.remove_jit_channel(&mut peer_state, scid);
+		}
+
+		self.peer_by_channel_id.write().unwrap().remove(channel_id);
+		persist_peer_state(&self.kv_store, &counterparty_node_id, &*peer_state)
+	}
+
+	/// Used by LSP to inform us that the channel opened in response to a
+	/// [`LSPS2ServiceEvent::OpenChannel`] event failed to open, e.g., because the peer
+	/// disconnected, funding failed, or the channel was closed before it became ready.
+	///
+	/// Will fail back the HTLCs held for the payment and generate a
+	/// [`LSPS2ServiceEvent::ChannelOpenFailed`] event. The JIT channel's scid remains valid so that
+	/// the payer can retry the payment.
+	///
+	/// [`LSPS2ServiceEvent::OpenChannel`]: crate::lsps2::event::LSPS2ServiceEvent::OpenChannel
+	/// [`LSPS2ServiceEvent::ChannelOpenFailed`]: crate::lsps2::event::LSPS2ServiceEvent::ChannelOpenFailed
+	pub fn channel_open_failed(
+		&self, counterparty_node_id: &PublicKey, user_channel_id: u128,
+	) -> Result<(), LiquidityError> {
+		let scid: u64 = user_channel_id.try_into().map_err(|_| APIError::APIMisuseError {
+			err: format!("Could not find a channel with user_channel_id {}", user_channel_id),
+		})?;
+
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		match outer_state_lock.get(counterparty_node_id) {
+			Some(inner_state_lock) => {
+				let mut peer_state = inner_state_lock.lock().unwrap();
+				let jit_channel =
+					peer_state.outbound_channels_by_scid.get_mut(&scid).ok_or_else(|| {
+						APIError::APIMisuseError {
+							err: format!(
+								"Could not find a channel with user_channel_id {}",
+								user_channel_id
+							),
+						}
+					})?;
+
+				let htlcs =
+					jit_channel.channel_open_failed().map_err(|e| APIError::APIMisuseError {
+						err: format!("Failed to transition to awaiting payment: {}", e.err),
+					})?;
+
+				for htlc in htlcs {
+					// The HTLC might already have been failed back upstream in the meantime, in
+					// which case there is nothing left to do.
+					let _ = self.channel_manager.get_cm().fail_intercepted_htlc(htlc.intercept_id);
+				}
+
+				persist_peer_state(&self.kv_store, counterparty_node_id, &*peer_state)?;
+
+				self.enqueue_event(Event::LSPS2Service(LSPS2ServiceEvent::ChannelOpenFailed {
+					counterparty_node_id: *counterparty_node_id,
+					user_channel_id,
+				}));
+
+				Ok(())
+			}
+			None => Err(APIError::APIMisuseError {
+				err: format!("No counterparty state for: {}", counterparty_node_id),
+			}
+			.into()),
+		}
+	}
+
+	/// Fails back the HTLCs of payments that didn't fully arrive within
+	/// [`LSPS2ServiceConfig::htlc_hold_timeout_ticks`] and forgets about the scids of offers that
+	/// expired before any payment arrived.
+	///
+	/// Is called by [`LiquidityManager::timer_tick_occurred`], which should be called roughly once
+	/// a minute.
+	///
+	/// Note that the expiry of offers can't be checked in no-std builds, so scids of offers that
+	/// were never paid are not forgotten there.
+	///
+	/// [`LiquidityManager::timer_tick_occurred`]: crate::LiquidityManager::timer_tick_occurred
+	pub fn timer_tick_occurred(&self) {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		for (counterparty_node_id, inner_state_lock) in outer_state_lock.iter() {
+			let mut peer_state = inner_state_lock.lock().unwrap();
+			let mut updated = false;
+			for jit_channel in peer_state.outbound_channels_by_scid.values_mut() {
+				let expired_htlcs =
+					jit_channel.timer_tick_occurred(self.config.htlc_hold_timeout_ticks);
+				for htlc in expired_htlcs.iter() {
+					// The HTLC might already have been failed back upstream in the meantime, in
+					// which case there is nothing left to do.
+					let _ = self.channel_manager.get_cm().fail_intercepted_htlc(htlc.intercept_id);
+				}
+				updated |= !expired_htlcs.is_empty();
+			}
+
+			let expired_scids: Vec<u64> = peer_state
+				.outbound_channels_by_scid
+				.iter()
+				.filter(|(_, jit_channel)| jit_channel.is_expired_offer())
+				.map(|(scid, _)| *scid)
+				.collect();
+			for scid in expired_scids.iter() {
+				self.remove_jit_channel(&mut peer_state, *scid);
+			}
+			updated |= !expired_scids.is_empty();
+
+			if updated {
+				// There is no caller to report the error to, so the state will be persisted again
+				// with the next successful write for this peer.
+				let _ = persist_peer_state(&self.kv_store, counterparty_node_id, &*peer_state);
+			}
+		}
+	}
+
+	/// Removes the JIT channel with the given scid from the given peer's state, and forgets about
+	/// its scid and channel id.
+	///
+	/// Must be used for every removal of a JIT channel, as we otherwise keep mapping its scid to
+	/// the peer.
+	fn remove_jit_channel(&self, peer_state: &mut PeerState, scid: u64) {
+		if let Some(jit_channel) = peer_state.remove_outbound_channel(scid) {
+			if let Some(channel_id) = jit_channel.channel_id() {
+				self.peer_by_channel_id.write().unwrap().remove(&channel_id);
+			}
+		}
+		self.peer_by_scid.write().unwrap().remove(&scid);
+	}
+
+	/// Forgets about the requests of the given counterparty we didn't answer yet, as we can't do so
+	/// anymore after it disconnected.
+	///
+	/// JIT channels we already sold to the counterparty are unaffected.
+	pub(crate) fn peer_disconnected(&self, counterparty_node_id: &PublicKey) {
+		let outer_state_lock = self.per_peer_state.read().unwrap();
+		if let Some(inner_state_lock) = outer_state_lock.get(counterparty_node_id) {
+			inner_state_lock.lock().unwrap().pending_requests.clear();
+		}
+	}
+
 	fn enqueue_response(
 		&self, counterparty_node_id: &PublicKey, request_id: RequestId, response: LSPS2Response,
 	) {
@@ -596,6 +1424,22 @@ where
 		let inner_state_lock: &mut Mutex<PeerState> =
 			outer_state_lock.entry(*counterparty_node_id).or_insert(Mutex::new(PeerState::new()));
 		let mut peer_state_lock = inner_state_lock.lock().unwrap();
+		if peer_state_lock.pending_requests.len() >= self.config.max_pending_requests_per_peer {
+			self.enqueue_response(
+				counterparty_node_id,
+				request_id,
+				LSPS2Response::GetInfoError(ResponseError {
+					code: JSONRPC_RATE_LIMITED_ERROR_CODE,
+					message: "too many pending requests".to_string(),
+					data: None,
+				}),
+			);
+			return Err(LightningError {
+				err: format!("peer {} has too many pending requests", counterparty_node_id),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			});
+		}
+
 		peer_state_lock
 			.pending_requests
 			.insert(request_id.clone(), LSPS2Request::GetInfo(params.clone()));
@@ -726,6 +1570,38 @@ where
 		let inner_state_lock =
 			outer_state_lock.entry(*counterparty_node_id).or_insert(Mutex::new(PeerState::new()));
 		let mut peer_state_lock = inner_state_lock.lock().unwrap();
+		if peer_state_lock.pending_requests.len() >= self.config.max_pending_requests_per_peer {
+			self.enqueue_response(
+				counterparty_node_id,
+				request_id,
+				LSPS2Response::BuyError(ResponseError {
+					code: JSONRPC_RATE_LIMITED_ERROR_CODE,
+					message: "too many pending requests".to_string(),
+					data: None,
+				}),
+			);
+			return Err(LightningError {
+				err: format!("peer {} has too many pending requests", counterparty_node_id),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			});
+		}
+
+		if peer_state_lock.open_jit_channel_count() >= self.config.max_jit_channels_per_peer {
+			self.enqueue_response(
+				counterparty_node_id,
+				request_id,
+				LSPS2Response::BuyError(ResponseError {
+					code: JSONRPC_RATE_LIMITED_ERROR_CODE,
+					message: "too many open JIT channels".to_string(),
+					data: None,
+				}),
+			);
+			return Err(LightningError {
+				err: format!("peer {} has too many open JIT channels", counterparty_node_id),
+				action: ErrorAction::IgnoreAndLog(Level::Info),
+			});
+		}
+
 		peer_state_lock
 			.pending_requests
 			.insert(request_id.clone(), LSPS2Request::Buy(params.clone()));
@@ -742,10 +1618,12 @@ where
 	}
 }
 
-impl<CM: Deref + Clone, MQ: Deref> ProtocolMessageHandler for LSPS2ServiceHandler<CM, MQ>
+impl<CM: Deref + Clone, MQ: Deref, K: Deref> ProtocolMessageHandler
+	for LSPS2ServiceHandler<CM, MQ, K>
 where
 	CM::Target: AChannelManager,
 	MQ::Target: MessageQueue,
+	K::Target: KVStore,
 {
 	type ProtocolMessage = LSPS2Message;
 	const PROTOCOL_NUMBER: Option<u16> = Some(2);
@@ -811,6 +1689,84 @@ mod tests {
 
 	use super::*;
 
+	use crate::persist::memory_store::MemoryStore;
+	use crate::tests::utils::{
+		connect_peer, create_channel_manager, TestChannelManager, TestMessageQueue,
+	};
+
+	use lightning::util::ser::{Readable, Writeable};
+
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+
+	type TestServiceHandler =
+		LSPS2ServiceHandler<Arc<TestChannelManager>, Arc<TestMessageQueue>, Arc<MemoryStore>>;
+
+	fn test_config() -> LSPS2ServiceConfig {
+		LSPS2ServiceConfig {
+			promise_secret: [42; 32],
+			min_payment_size_msat: 1_000,
+			max_payment_size_msat: 1_000_000_000,
+			htlc_hold_timeout_ticks: 2,
+			max_pending_requests_per_peer: DEFAULT_MAX_PENDING_REQUESTS_PER_PEER,
+			max_jit_channels_per_peer: DEFAULT_MAX_JIT_CHANNELS_PER_PEER,
+		}
+	}
+
+	fn test_counterparty_node_id() -> PublicKey {
+		PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[1; 32]).unwrap())
+	}
+
+	fn create_handler(config: LSPS2ServiceConfig) -> Result<TestServiceHandler, io::Error> {
+		LSPS2ServiceHandler::new(
+			Arc::new(TestMessageQueue::new()),
+			Arc::new(EventQueue::new()),
+			create_channel_manager(),
+			Arc::new(MemoryStore::new()),
+			config,
+		)
+	}
+
+	/// Adds the given JIT channel to the handler's state as if it had been sold to the given
+	/// counterparty.
+	fn insert_jit_channel(
+		handler: &TestServiceHandler, counterparty_node_id: PublicKey,
+		jit_channel: OutboundJITChannel,
+	) {
+		let scid = jit_channel.scid;
+		if let Some(channel_id) = jit_channel.channel_id() {
+			handler.peer_by_channel_id.write().unwrap().insert(channel_id, counterparty_node_id);
+		}
+		handler.peer_by_scid.write().unwrap().insert(scid, counterparty_node_id);
+		let mut outer_state_lock = handler.per_peer_state.write().unwrap();
+		outer_state_lock.entry(counterparty_node_id).or_insert(Mutex::new(PeerState::new()));
+		let inner_state_lock = outer_state_lock.get(&counterparty_node_id).unwrap();
+		inner_state_lock.lock().unwrap().insert_outbound_channel(scid, jit_channel);
+	}
+
+	fn test_opening_fee_params(valid_until: &str) -> OpeningFeeParams {
+		RawOpeningFeeParams {
+			min_fee_msat: 100,
+			proportional: 21,
+			valid_until: chrono::DateTime::parse_from_rfc3339(valid_until).unwrap().into(),
+			min_lifetime: 144,
+			max_client_to_self_delay: 128,
+		}
+		.into_opening_fee_params(&[42; 32])
+	}
+
+	/// A JIT channel with scid 42 that was just sold to the counterparty.
+	fn test_jit_channel(
+		client_trusts_lsp: bool, payment_size_msat: Option<u64>,
+	) -> OutboundJITChannel {
+		OutboundJITChannel::new(
+			42,
+			144,
+			client_trusts_lsp,
+			payment_size_msat,
+			test_opening_fee_params("2035-05-20T08:30:45Z"),
+		)
+	}
+
 	#[test]
 	fn test_calculate_amount_to_forward() {
 		// TODO: Use proptest to generate random allocations
@@ -842,4 +1798,543 @@ mod tests {
 		assert_eq!(result[2].0, htlcs[2].intercept_id);
 		assert_eq!(result[2].1, 2499);
 	}
+
+	#[test]
+	fn peer_state_serialization_roundtrip() {
+		let mut peer_state = PeerState::new();
+		let mut jit_channel = test_jit_channel(false, Some(500_000));
+		let htlc = InterceptedHTLC {
+			intercept_id: InterceptId([7; 32]),
+			expected_outbound_amount_msat: 200_000,
+		};
+		assert_eq!(jit_channel.htlc_intercepted(htlc).unwrap(), None);
+		peer_state.insert_outbound_channel(42, jit_channel);
+		peer_state.pending_requests.insert(
+			RequestId("xyz123".to_string()),
+			LSPS2Request::GetInfo(GetInfoRequest { version: 1, token: None }),
+		);
+
+		let encoded = peer_state.encode();
+		let decoded = PeerState::read(&mut io::Cursor::new(&encoded)).unwrap();
+
+		// Pending requests are not persisted.
+		assert!(decoded.pending_requests.is_empty());
+		assert_eq!(decoded.outbound_channels_by_scid.len(), 1);
+		let decoded_channel = decoded.outbound_channels_by_scid.get(&42).unwrap();
+		assert_eq!(decoded_channel.scid, 42);
+		assert_eq!(decoded_channel.cltv_expiry_delta, 144);
+		assert!(!decoded_channel.client_trusts_lsp);
+		assert_eq!(
+			decoded_channel.state,
+			OutboundJITChannelState::AwaitingPayment {
+				opening_fee_params: test_opening_fee_params("2035-05-20T08:30:45Z"),
+				htlcs: vec![htlc],
+				payment_size_msat: Some(500_000),
+			}
+		);
+	}
+
+	#[test]
+	fn open_jit_channels_include_pending_buy_requests() {
+		let mut peer_state = PeerState::new();
+		let jit_channel = test_jit_channel(false, None);
+		peer_state.insert_outbound_channel(42, jit_channel);
+		peer_state.pending_requests.insert(
+			RequestId("get_info".to_string()),
+			LSPS2Request::GetInfo(GetInfoRequest { version: 1, token: None }),
+		);
+		peer_state.pending_requests.insert(
+			RequestId("buy".to_string()),
+			LSPS2Request::Buy(BuyRequest {
+				version: 1,
+				opening_fee_params: test_opening_fee_params("2035-05-20T08:30:45Z"),
+				payment_size_msat: None,
+			}),
+		);
+		assert_eq!(peer_state.open_jit_channel_count(), 2);
+
+		// Completed channels don't count towards the limit.
+		peer_state.outbound_channels_by_scid.get_mut(&42).unwrap().state =
+			OutboundJITChannelState::Completed {
+				channel_id: ChannelId([0; 32]),
+				skimmed_fee_msat: 100,
+			};
+		peer_state.archive_completed_channel(42);
+		assert_eq!(peer_state.open_jit_channel_count(), 1);
+	}
+
+	#[test]
+	fn held_htlcs_time_out() {
+		let mut jit_channel = test_jit_channel(false, Some(500_000));
+
+		// We only start counting once we hold an HTLC.
+		assert!(jit_channel.timer_tick_occurred(2).is_empty());
+		assert_eq!(jit_channel.htlc_hold_ticks, 0);
+
+		let htlc = InterceptedHTLC {
+			intercept_id: InterceptId([7; 32]),
+			expected_outbound_amount_msat: 200_000,
+		};
+		assert_eq!(jit_channel.htlc_intercepted(htlc).unwrap(), None);
+		assert!(jit_channel.timer_tick_occurred(2).is_empty());
+		assert_eq!(jit_channel.timer_tick_occurred(2), vec![htlc]);
+		assert_eq!(
+			jit_channel.state,
+			OutboundJITChannelState::AwaitingPayment {
+				opening_fee_params: test_opening_fee_params("2035-05-20T08:30:45Z"),
+				htlcs: vec![],
+				payment_size_msat: Some(500_000),
+			}
+		);
+
+		// The payer may retry after the HTLCs were failed back.
+		assert_eq!(jit_channel.htlc_intercepted(htlc).unwrap(), None);
+		assert_eq!(
+			jit_channel
+				.htlc_intercepted(InterceptedHTLC {
+					intercept_id: InterceptId([8; 32]),
+					expected_outbound_amount_msat: 300_000,
+				})
+				.unwrap(),
+			Some(HTLCInterceptedAction::OpenChannel {
+				opening_fee_msat: 100,
+				amt_to_forward_msat: 499_900
+			})
+		);
+		assert!(jit_channel.timer_tick_occurred(1).is_empty());
+	}
+
+	#[test]
+	#[cfg(feature = "std")]
+	fn expired_opening_fee_params_are_rejected() {
+		let mut jit_channel = OutboundJITChannel::new(
+			42,
+			144,
+			false,
+			Some(500_000),
+			test_opening_fee_params("2023-05-20T08:30:45Z"),
+		);
+		assert!(jit_channel.is_expired_offer());
+
+		let htlc = InterceptedHTLC {
+			intercept_id: InterceptId([7; 32]),
+			expected_outbound_amount_msat: 200_000,
+		};
+		assert!(jit_channel.htlc_intercepted(htlc).is_err());
+
+		// Parts of a payment whose first part arrived in time are still accepted after expiry.
+		let mut jit_channel = test_jit_channel(false, Some(500_000));
+		assert_eq!(jit_channel.htlc_intercepted(htlc).unwrap(), None);
+		if let OutboundJITChannelState::AwaitingPayment { opening_fee_params, .. } =
+			&mut jit_channel.state
+		{
+			*opening_fee_params = test_opening_fee_params("2023-05-20T08:30:45Z");
+		}
+		assert!(!jit_channel.is_expired_offer());
+		assert_eq!(
+			jit_channel
+				.htlc_intercepted(InterceptedHTLC {
+					intercept_id: InterceptId([8; 32]),
+					expected_outbound_amount_msat: 300_000,
+				})
+				.unwrap(),
+			Some(HTLCInterceptedAction::OpenChannel {
+				opening_fee_msat: 100,
+				amt_to_forward_msat: 499_900
+			})
+		);
+	}
+
+	#[test]
+	#[cfg(feature = "std")]
+	fn expired_offers_are_forgotten() {
+		let counterparty_node_id = test_counterparty_node_id();
+		let handler = create_handler(test_config()).unwrap();
+		let new_jit_channel = |scid, valid_until| {
+			OutboundJITChannel::new(
+				scid,
+				144,
+				false,
+				Some(500_000),
+				test_opening_fee_params(valid_until),
+			)
+		};
+		insert_jit_channel(
+			&handler,
+			counterparty_node_id,
+			new_jit_channel(42, "2023-05-20T08:30:45Z"),
+		);
+		insert_jit_channel(
+			&handler,
+			counterparty_node_id,
+			new_jit_channel(43, "2023-05-20T08:30:45Z"),
+		);
+		insert_jit_channel(
+			&handler,
+			counterparty_node_id,
+			new_jit_channel(44, "2035-05-20T08:30:45Z"),
+		);
+
+		// Payments for expired offers are rejected, and the offer is forgotten right away.
+		assert!(handler.htlc_intercepted(42, InterceptId([7; 32]), 500_000).is_err());
+		assert!(!handler.peer_by_scid.read().unwrap().contains_key(&42));
+
+		handler.timer_tick_occurred();
+		assert!(!handler.peer_by_scid.read().unwrap().contains_key(&43));
+		assert!(handler.peer_by_scid.read().unwrap().contains_key(&44));
+		let outer_state_lock = handler.per_peer_state.read().unwrap();
+		let peer_state = outer_state_lock.get(&counterparty_node_id).unwrap().lock().unwrap();
+		assert_eq!(peer_state.outbound_channels_by_scid.keys().collect::<Vec<_>>(), vec![&44]);
+	}
+
+	#[test]
+	fn zero_htlc_hold_timeout_is_rejected() {
+		assert!(create_handler(test_config()).is_ok());
+		let config = LSPS2ServiceConfig { htlc_hold_timeout_ticks: 0, ..test_config() };
+		assert_eq!(create_handler(config).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+	}
+
+	#[test]
+	fn failed_channel_open_allows_retry() {
+		let mut jit_channel = test_jit_channel(false, Some(500_000));
+
+		let htlcs = [
+			InterceptedHTLC {
+				intercept_id: InterceptId([7; 32]),
+				expected_outbound_amount_msat: 200_000,
+			},
+			InterceptedHTLC {
+				intercept_id: InterceptId([8; 32]),
+				expected_outbound_amount_msat: 300_000,
+			},
+		];
+		assert!(jit_channel.channel_open_failed().is_err());
+		assert_eq!(jit_channel.htlc_intercepted(htlcs[0]).unwrap(), None);
+		assert_eq!(
+			jit_channel.htlc_intercepted(htlcs[1]).unwrap(),
+			Some(HTLCInterceptedAction::OpenChannel {
+				opening_fee_msat: 100,
+				amt_to_forward_msat: 499_900
+			})
+		);
+
+		assert_eq!(jit_channel.channel_open_failed().unwrap(), htlcs.to_vec());
+		assert_eq!(
+			jit_channel.state,
+			OutboundJITChannelState::AwaitingPayment {
+				opening_fee_params: test_opening_fee_params("2035-05-20T08:30:45Z"),
+				htlcs: vec![],
+				payment_size_msat: Some(500_000),
+			}
+		);
+
+		// A retried payment opens the channel again.
+		assert_eq!(jit_channel.htlc_intercepted(htlcs[0]).unwrap(), None);
+		assert_eq!(
+			jit_channel.htlc_intercepted(htlcs[1]).unwrap(),
+			Some(HTLCInterceptedAction::OpenChannel {
+				opening_fee_msat: 100,
+				amt_to_forward_msat: 499_900
+			})
+		);
+		assert_eq!(
+			jit_channel.channel_ready(ChannelId([1; 32])).unwrap(),
+			vec![(htlcs[0].intercept_id, 199_960), (htlcs[1].intercept_id, 299_940)]
+		);
+	}
+
+	#[test]
+	fn funding_tx_is_withheld_until_payment_is_claimed() {
+		let funding_tx = Transaction {
+			version: 2,
+			lock_time: bitcoin::PackedLockTime::ZERO,
+			input: vec![],
+			output: vec![],
+		};
+
+		let mut jit_channel = test_jit_channel(false, None);
+		let htlc = InterceptedHTLC {
+			intercept_id: InterceptId([7; 32]),
+			expected_outbound_amount_msat: 200_000,
+		};
+		assert_eq!(
+			jit_channel.htlc_intercepted(htlc).unwrap(),
+			Some(HTLCInterceptedAction::OpenChannel {
+				opening_fee_msat: 100,
+				amt_to_forward_msat: 199_900
+			})
+		);
+		assert_eq!(jit_channel.store_funding_transaction(funding_tx.clone()), None);
+		assert_eq!(
+			jit_channel.channel_ready(ChannelId([1; 32])).unwrap(),
+			vec![(htlc.intercept_id, 199_900)]
+		);
+		assert_eq!(jit_channel.channel_id(), Some(ChannelId([1; 32])));
+
+		let encoded = jit_channel.encode();
+		let mut jit_channel = OutboundJITChannel::read(&mut io::Cursor::new(&encoded)).unwrap();
+		assert_eq!(jit_channel.payment_claimed(), Some(funding_tx.clone()));
+		assert_eq!(jit_channel.payment_claimed(), None);
+
+		// If the client trusts us, the funding transaction may be broadcast right away.
+		let mut jit_channel = test_jit_channel(true, None);
+		assert_eq!(jit_channel.store_funding_transaction(funding_tx.clone()), Some(funding_tx));
+	}
+
+	#[test]
+	fn skimmed_fee_is_tracked_until_all_htlcs_are_claimed() {
+		let mut jit_channel = test_jit_channel(true, Some(500_000));
+		let htlcs = [
+			InterceptedHTLC {
+				intercept_id: InterceptId([7; 32]),
+				expected_outbound_amount_msat: 200_000,
+			},
+			InterceptedHTLC {
+				intercept_id: InterceptId([8; 32]),
+				expected_outbound_amount_msat: 300_000,
+			},
+		];
+		assert!(jit_channel.payment_forwarded(Some(200_000)).is_err());
+		assert_eq!(jit_channel.htlc_intercepted(htlcs[0]).unwrap(), None);
+		assert_eq!(
+			jit_channel.htlc_intercepted(htlcs[1]).unwrap(),
+			Some(HTLCInterceptedAction::OpenChannel {
+				opening_fee_msat: 100,
+				amt_to_forward_msat: 499_900
+			})
+		);
+		assert_eq!(
+			jit_channel.channel_ready(ChannelId([1; 32])).unwrap(),
+			vec![(htlcs[0].intercept_id, 199_960), (htlcs[1].intercept_id, 299_940)]
+		);
+
+		// The second HTLC is claimed first.
+		assert_eq!(jit_channel.payment_forwarded(Some(299_940)).unwrap(), None);
+		assert!(!jit_channel.is_completed());
+
+		let encoded = jit_channel.encode();
+		let mut jit_channel = OutboundJITChannel::read(&mut io::Cursor::new(&encoded)).unwrap();
+		assert_eq!(jit_channel.payment_forwarded(None).unwrap(), Some((100, vec![])));
+		assert!(jit_channel.is_completed());
+		assert_eq!(jit_channel.channel_id(), Some(ChannelId([1; 32])));
+		assert!(jit_channel.payment_forwarded(None).is_err());
+	}
+
+	#[test]
+	fn late_htlcs_are_forwarded_without_fee() {
+		let mut jit_channel = test_jit_channel(true, Some(500_000));
+		let htlcs = [
+			InterceptedHTLC {
+				intercept_id: InterceptId([7; 32]),
+				expected_outbound_amount_msat: 500_000,
+			},
+			InterceptedHTLC {
+				intercept_id: InterceptId([8; 32]),
+				expected_outbound_amount_msat: 100_000,
+			},
+			InterceptedHTLC {
+				intercept_id: InterceptId([9; 32]),
+				expected_outbound_amount_msat: 50_000,
+			},
+			InterceptedHTLC {
+				intercept_id: InterceptId([10; 32]),
+				expected_outbound_amount_msat: 20_000,
+			},
+		];
+		assert_eq!(
+			jit_channel.htlc_intercepted(htlcs[0]).unwrap(),
+			Some(HTLCInterceptedAction::OpenChannel {
+				opening_fee_msat: 100,
+				amt_to_forward_msat: 499_900
+			})
+		);
+
+		// HTLCs arriving after the payment was complete are held until its HTLCs were claimed, as
+		// we couldn't tell the claims apart otherwise.
+		assert_eq!(jit_channel.htlc_intercepted(htlcs[1]).unwrap(), None);
+		assert_eq!(
+			jit_channel.channel_ready(ChannelId([1; 32])).unwrap(),
+			vec![(htlcs[0].intercept_id, 499_900)]
+		);
+		assert_eq!(jit_channel.htlc_intercepted(htlcs[2]).unwrap(), None);
+
+		assert_eq!(
+			jit_channel.payment_forwarded(Some(499_900)).unwrap(),
+			Some((100, vec![htlcs[1], htlcs[2]]))
+		);
+		assert!(jit_channel.is_completed());
+
+		assert_eq!(
+			jit_channel.htlc_intercepted(htlcs[3]).unwrap(),
+			Some(HTLCInterceptedAction::ForwardHTLC {
+				channel_id: ChannelId([1; 32]),
+				amt_to_forward_msat: 20_000
+			})
+		);
+	}
+
+	#[test]
+	fn late_htlcs_are_failed_with_the_payment() {
+		let mut jit_channel = test_jit_channel(true, None);
+		let htlcs = [
+			InterceptedHTLC {
+				intercept_id: InterceptId([7; 32]),
+				expected_outbound_amount_msat: 200_000,
+			},
+			InterceptedHTLC {
+				intercept_id: InterceptId([8; 32]),
+				expected_outbound_amount_msat: 300_000,
+			},
+		];
+		assert!(jit_channel.htlc_intercepted(htlcs[0]).unwrap().is_some());
+		assert_eq!(jit_channel.htlc_intercepted(htlcs[1]).unwrap(), None);
+		assert_eq!(jit_channel.channel_open_failed().unwrap(), htlcs.to_vec());
+	}
+
+	#[test]
+	fn completed_channels_are_archived_until_closed() {
+		let counterparty_node_id = test_counterparty_node_id();
+		let handler = create_handler(test_config()).unwrap();
+		let channel_id = ChannelId([1; 32]);
+		let mut jit_channel = test_jit_channel(true, None);
+		let htlc = InterceptedHTLC {
+			intercept_id: InterceptId([7; 32]),
+			expected_outbound_amount_msat: 200_000,
+		};
+		assert!(jit_channel.htlc_intercepted(htlc).unwrap().is_some());
+		assert!(jit_channel.channel_ready(channel_id).is_ok());
+		insert_jit_channel(&handler, counterparty_node_id, jit_channel);
+
+		handler.payment_forwarded(channel_id, Some(199_900)).unwrap();
+		assert_eq!(
+			handler.pending_events.get_and_clear_pending_events(),
+			vec![Event::LSPS2Service(LSPS2ServiceEvent::OpeningFeeCollected {
+				counterparty_node_id,
+				user_channel_id: 42,
+				skimmed_fee_msat: 100,
+			})]
+		);
+		{
+			let outer_state_lock = handler.per_peer_state.read().unwrap();
+			let peer_state = outer_state_lock.get(&counterparty_node_id).unwrap().lock().unwrap();
+			assert!(peer_state.outbound_channels_by_scid.is_empty());
+			assert_eq!(peer_state.completed_channels_by_scid.get(&42), Some(&channel_id));
+		}
+
+		// Later payments forwarded over the channel don't collect another fee.
+		handler.payment_forwarded(channel_id, Some(50_000)).unwrap();
+		assert!(handler.pending_events.get_and_clear_pending_events().is_empty());
+
+		handler.channel_closed(&channel_id).unwrap();
+		assert!(!handler.peer_by_channel_id.read().unwrap().contains_key(&channel_id));
+		let outer_state_lock = handler.per_peer_state.read().unwrap();
+		let peer_state = outer_state_lock.get(&counterparty_node_id).unwrap().lock().unwrap();
+		assert!(peer_state.completed_channels_by_scid.is_empty());
+		assert!(!peer_state.is_persistable());
+	}
+
+	#[test]
+	fn ready_channels_are_forgotten_once_closed() {
+		let counterparty_node_id = test_counterparty_node_id();
+		let handler = create_handler(test_config()).unwrap();
+		let channel_id = ChannelId([1; 32]);
+		let mut jit_channel = test_jit_channel(true, None);
+		let htlc = InterceptedHTLC {
+			intercept_id: InterceptId([7; 32]),
+			expected_outbound_amount_msat: 200_000,
+		};
+		assert!(jit_channel.htlc_intercepted(htlc).unwrap().is_some());
+		assert!(jit_channel.channel_ready(channel_id).is_ok());
+		insert_jit_channel(&handler, counterparty_node_id, jit_channel);
+
+		// The channel closes before the payment was claimed.
+		handler.channel_closed(&channel_id).unwrap();
+		assert!(handler.peer_by_scid.read().unwrap().is_empty());
+		assert!(handler.peer_by_channel_id.read().unwrap().is_empty());
+		{
+			let outer_state_lock = handler.per_peer_state.read().unwrap();
+			let peer_state = outer_state_lock.get(&counterparty_node_id).unwrap().lock().unwrap();
+			assert!(!peer_state.is_persistable());
+		}
+		let persisted_peer_states: HashMap<PublicKey, PeerState> =
+			read_peer_states(&handler.kv_store).unwrap();
+		assert!(persisted_peer_states.is_empty());
+	}
+
+	#[test]
+	fn channel_ready_is_recorded_even_if_forwards_fail() {
+		let counterparty_node_id = test_counterparty_node_id();
+		let handler = create_handler(test_config()).unwrap();
+		connect_peer(&handler.channel_manager, &counterparty_node_id);
+		let channel_id = ChannelId([1; 32]);
+		let mut jit_channel = test_jit_channel(true, None);
+		for (id, amount_msat) in [(7, 200_000), (8, 300_000)] {
+			let htlc = InterceptedHTLC {
+				intercept_id: InterceptId([id; 32]),
+				expected_outbound_amount_msat: amount_msat,
+			};
+			jit_channel.htlc_intercepted(htlc).unwrap();
+		}
+		insert_jit_channel(&handler, counterparty_node_id, jit_channel);
+
+		// The channel manager doesn't know the channel, so forwarding the HTLCs fails.
+		match handler.channel_ready(42, &channel_id, &counterparty_node_id) {
+			Err(LiquidityError::APIError(APIError::ChannelUnavailable { err })) => {
+				assert!(err.contains(&channel_id.to_string()));
+			}
+			_ => panic!("Unexpected result"),
+		}
+
+		assert_eq!(
+			handler.peer_by_channel_id.read().unwrap().get(&channel_id),
+			Some(&counterparty_node_id)
+		);
+		let persisted_peer_states: HashMap<PublicKey, PeerState> =
+			read_peer_states(&handler.kv_store).unwrap();
+		let persisted_channel = persisted_peer_states
+			.get(&counterparty_node_id)
+			.unwrap()
+			.outbound_channels_by_scid
+			.get(&42)
+			.unwrap();
+		assert!(matches!(
+			persisted_channel.state,
+			OutboundJITChannelState::ChannelReady { channel_id: ready_channel_id, .. }
+				if ready_channel_id == channel_id
+		));
+	}
+
+	#[test]
+	fn payments_after_completion_are_forwarded() {
+		let counterparty_node_id = test_counterparty_node_id();
+		let handler = create_handler(test_config()).unwrap();
+		connect_peer(&handler.channel_manager, &counterparty_node_id);
+		let channel_id = ChannelId([1; 32]);
+		let mut jit_channel = test_jit_channel(true, None);
+		let htlc = InterceptedHTLC {
+			intercept_id: InterceptId([7; 32]),
+			expected_outbound_amount_msat: 200_000,
+		};
+		assert!(jit_channel.htlc_intercepted(htlc).unwrap().is_some());
+		assert!(jit_channel.channel_ready(channel_id).is_ok());
+		insert_jit_channel(&handler, counterparty_node_id, jit_channel);
+		handler.payment_forwarded(channel_id, Some(199_900)).unwrap();
+		assert_eq!(handler.pending_events.get_and_clear_pending_events().len(), 1);
+
+		// The second payment is forwarded over the channel, which the channel manager doesn't know.
+		match handler.htlc_intercepted(42, InterceptId([8; 32]), 50_000) {
+			Err(LiquidityError::APIError(APIError::ChannelUnavailable { err })) => {
+				assert!(err.contains(&channel_id.to_string()));
+			}
+			_ => panic!("Unexpected result"),
+		}
+		assert!(handler.pending_events.get_and_clear_pending_events().is_empty());
+
+		// Once the channel closed, the scid is unknown and its HTLCs are left alone.
+		handler.channel_closed(&channel_id).unwrap();
+		for scid in [42, 43] {
+			assert!(handler.htlc_intercepted(scid, InterceptId([9; 32]), 50_000).is_ok());
+		}
+		assert!(handler.pending_events.get_and_clear_pending_events().is_empty());
+	}
 }
@@ -12,21 +12,8 @@ use std::time::{SystemTime, UNIX_EPOCH};
 pub fn is_valid_opening_fee_params(
 	fee_params: &OpeningFeeParams, promise_secret: &[u8; 32],
 ) -> bool {
-	#[cfg(feature = "std")]
-	{
-		// TODO: We need to find a way to check expiry times in no-std builds.
-		let seconds_since_epoch = SystemTime::now()
-			.duration_since(UNIX_EPOCH)
-			.expect("system clock to be ahead of the unix epoch")
-			.as_secs();
-		let valid_until_seconds_since_epoch = fee_params
-			.valid_until
-			.timestamp()
-			.try_into()
-			.expect("expiration to be ahead of unix epoch");
-		if seconds_since_epoch > valid_until_seconds_since_epoch {
-			return false;
-		}
+	if is_expired_opening_fee_params(fee_params) {
+		return false;
 	}
 
 	let mut hmac = HmacEngine::<Sha256>::new(promise_secret);
@@ -40,6 +27,33 @@ pub fn is_valid_opening_fee_params(
 	promise == fee_params.promise
 }
 
+/// Determines if the given parameters are expired, i.e., their `valid_until` has passed.
+///
+/// Parameters valid until a time before the unix epoch are always considered expired.
+///
+/// Note that expiry is not checked in no-std builds, where this always returns `false`.
+pub fn is_expired_opening_fee_params(fee_params: &OpeningFeeParams) -> bool {
+	#[cfg(feature = "std")]
+	{
+		// TODO: We need to find a way to check expiry times in no-std builds.
+		let seconds_since_epoch = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.expect("system clock to be ahead of the unix epoch")
+			.as_secs();
+		match u64::try_from(fee_params.valid_until.timestamp()) {
+			Ok(valid_until_seconds_since_epoch) => {
+				seconds_since_epoch > valid_until_seconds_since_epoch
+			}
+			Err(_) => true,
+		}
+	}
+	#[cfg(not(feature = "std"))]
+	{
+		let _ = fee_params;
+		false
+	}
+}
+
 /// Computes the opening fee given a payment size and the fee parameters.
 ///
 /// Returns [`Option::None`] when the computation overflows.
@@ -1,25 +1,28 @@
+use crate::errors::LiquidityError;
 use crate::events::{Event, EventQueue};
 use crate::lsps0::client::LSPS0ClientHandler;
 use crate::lsps0::msgs::{
-	LSPS0Message, LSPSMessage, ProtocolMessageHandler, RawLSPSMessage, LSPS_MESSAGE_TYPE_ID,
+	LSPS0Message, LSPSMessage, ProtocolMessageHandler, RawLSPSMessage, ResponseError,
+	JSONRPC_RATE_LIMITED_ERROR_CODE, LSPS_MESSAGE_TYPE_ID,
 };
 use crate::lsps0::service::LSPS0ServiceHandler;
 use crate::message_queue::{DefaultMessageQueue, MessageQueue};
 
-#[cfg(lsps1)]
+#[cfg(feature = "lsps1")]
 use crate::lsps1::client::{LSPS1ClientConfig, LSPS1ClientHandler};
-#[cfg(lsps1)]
+#[cfg(feature = "lsps1")]
 use crate::lsps1::msgs::LSPS1Message;
-#[cfg(lsps1)]
+#[cfg(feature = "lsps1")]
 use crate::lsps1::service::{LSPS1ServiceConfig, LSPS1ServiceHandler};
 
 use crate::lsps2::client::{LSPS2ClientConfig, LSPS2ClientHandler};
 use crate::lsps2::msgs::LSPS2Message;
 use crate::lsps2::service::{LSPS2ServiceConfig, LSPS2ServiceHandler};
-use crate::prelude::{HashMap, String, Vec};
+use crate::prelude::{HashMap, String, ToString, Vec};
 use crate::sync::{Arc, Mutex, RwLock};
 
 use lightning::chain::{self, BestBlock, Confirm, Filter, Listen};
+use lightning::io;
 use lightning::ln::channelmanager::{AChannelManager, ChainParameters};
 use lightning::ln::features::{InitFeatures, NodeFeatures};
 use lightning::ln::msgs::{ErrorAction, LightningError};
@@ -27,6 +30,7 @@ use lightning::ln::peer_handler::{APeerManager, CustomMessageHandler};
 use lightning::ln::wire::CustomMessageReader;
 use lightning::sign::EntropySource;
 use lightning::util::logger::Level;
+use lightning::util::persist::KVStore;
 use lightning::util::ser::Readable;
 
 use bitcoin::secp256k1::PublicKey;
@@ -34,26 +38,125 @@ use bitcoin::secp256k1::PublicKey;
 use core::ops::Deref;
 const LSPS_FEATURE_BIT: usize = 729;
 
+/// The default value for [`LiquidityClientConfig::request_timeout_ticks`].
+pub const DEFAULT_REQUEST_TIMEOUT_TICKS: u32 = 2;
+
+/// The default value for [`LiquidityServiceConfig::max_requests_per_peer_per_tick`].
+pub const DEFAULT_MAX_REQUESTS_PER_PEER_PER_TICK: u32 = 60;
+
+/// The maximum number of unanswered requests we keep track of per counterparty. If exceeded, we
+/// forget about the oldest request, i.e., its response will be rejected.
+const MAX_PENDING_REQUESTS_PER_PEER: usize = 100;
+
+/// The requests we sent to a counterparty, which we need to parse their responses.
+#[derive(Default)]
+struct PeerRequestIdMap {
+	request_id_to_method: HashMap<String, String>,
+	// The number of timer ticks each request in `request_id_to_method` is awaiting a response.
+	request_ticks: HashMap<String, u32>,
+}
+
+impl PeerRequestIdMap {
+	fn insert(&mut self, request_id: String, method: String) {
+		if self.request_id_to_method.len() >= MAX_PENDING_REQUESTS_PER_PEER
+			&& !self.request_id_to_method.contains_key(&request_id)
+		{
+			let oldest_request_id = self
+				.request_ticks
+				.iter()
+				.max_by_key(|(_, ticks)| **ticks)
+				.map(|(request_id, _)| request_id.clone());
+			if let Some(oldest_request_id) = oldest_request_id {
+				self.request_id_to_method.remove(&oldest_request_id);
+				self.request_ticks.remove(&oldest_request_id);
+			}
+		}
+
+		self.request_ticks.insert(request_id.clone(), 0);
+		self.request_id_to_method.insert(request_id, method);
+	}
+
+	/// Forgets about the requests whose response was parsed.
+	fn prune_answered_requests(&mut self) {
+		let request_id_to_method = &self.request_id_to_method;
+		self.request_ticks.retain(|request_id, _| request_id_to_method.contains_key(request_id));
+	}
+
+	/// Forgets about the requests that weren't answered within `request_timeout_ticks` timer ticks.
+	fn timer_tick_occurred(&mut self, request_timeout_ticks: u32) {
+		let request_id_to_method = &mut self.request_id_to_method;
+		self.request_ticks.retain(|request_id, ticks| {
+			*ticks += 1;
+			if *ticks > request_timeout_ticks {
+				request_id_to_method.remove(request_id);
+				false
+			} else {
+				true
+			}
+		});
+	}
+
+	fn is_empty(&self) -> bool {
+		self.request_id_to_method.is_empty()
+	}
+}
+
+/// The [`LSPS1ServiceHandler`] as instantiated by the [`LiquidityManager`].
+#[cfg(feature = "lsps1")]
+type LSPS1ServiceHandlerFor<ES, CM, PM, C, K> =
+	LSPS1ServiceHandler<ES, CM, Arc<DefaultMessageQueue<PM>>, C, K>;
+
+/// The [`LSPS1ClientHandler`] as instantiated by the [`LiquidityManager`].
+#[cfg(feature = "lsps1")]
+type LSPS1ClientHandlerFor<ES, PM, K> = LSPS1ClientHandler<ES, Arc<DefaultMessageQueue<PM>>, K>;
+
 /// A server-side configuration for [`LiquidityManager`].
 ///
 /// Allows end-users to configure options when using the [`LiquidityManager`]
 /// to provide liquidity services to clients.
 pub struct LiquidityServiceConfig {
+	/// The maximum number of requests a single peer may send us between two calls to
+	/// [`LiquidityManager::timer_tick_occurred`].
+	///
+	/// Further requests are rejected with an error response, without being passed on to the
+	/// respective service handler.
+	///
+	/// Defaults to [`DEFAULT_MAX_REQUESTS_PER_PEER_PER_TICK`].
+	pub max_requests_per_peer_per_tick: u32,
 	/// Optional server-side configuration for LSPS1 channel requests.
-	#[cfg(lsps1)]
+	#[cfg(feature = "lsps1")]
 	pub lsps1_service_config: Option<LSPS1ServiceConfig>,
 	/// Optional server-side configuration for JIT channels
 	/// should you want to support them.
 	pub lsps2_service_config: Option<LSPS2ServiceConfig>,
 }
 
+impl Default for LiquidityServiceConfig {
+	fn default() -> Self {
+		Self {
+			max_requests_per_peer_per_tick: DEFAULT_MAX_REQUESTS_PER_PEER_PER_TICK,
+			#[cfg(feature = "lsps1")]
+			lsps1_service_config: None,
+			lsps2_service_config: None,
+		}
+	}
+}
+
 /// A client-side configuration for [`LiquidityManager`].
 ///
 /// Allows end-user to configure options when using the [`LiquidityManager`]
 /// to access liquidity services from a provider.
 pub struct LiquidityClientConfig {
+	/// The number of calls to [`LiquidityManager::timer_tick_occurred`] after which a request we
+	/// sent to an LSP is considered unanswered.
+	///
+	/// Timed out requests are dropped along with the state they were tracking, and are surfaced
+	/// via a `RequestTimedOut` event of the respective protocol. Requests sent via the LSPS0
+	/// client, which is always available, use [`DEFAULT_REQUEST_TIMEOUT_TICKS`] if no
+	/// [`LiquidityClientConfig`] is given.
+	pub request_timeout_ticks: u32,
 	/// Optional client-side configuration for LSPS1 channel requests.
-	#[cfg(lsps1)]
+	#[cfg(feature = "lsps1")]
 	pub lsps1_client_config: Option<LSPS1ClientConfig>,
 	/// Optional client-side configuration for JIT channels.
 	pub lsps2_client_config: Option<LSPS2ClientConfig>,
@@ -73,64 +176,96 @@ pub struct LiquidityClientConfig {
 ///
 /// If configured, users must forward the [`Event::HTLCIntercepted`] event parameters to [`LSPS2ServiceHandler::htlc_intercepted`]
 /// and the [`Event::ChannelReady`] event parameters to [`LSPS2ServiceHandler::channel_ready`].
+/// The [`Event::PaymentForwarded`] and [`Event::ChannelClosed`] event parameters should also be
+/// forwarded to [`LSPS2ServiceHandler::payment_forwarded`] and
+/// [`LSPS2ServiceHandler::channel_closed`], so that the collected opening fees are reported and
+/// the state of closed JIT channels is cleaned up.
+///
+/// Users should also call [`LiquidityManager::timer_tick_occurred`] regularly, so that requests
+/// which are never answered are timed out.
 ///
 /// [`PeerManager`]: lightning::ln::peer_handler::PeerManager
 /// [`MessageHandler`]: lightning::ln::peer_handler::MessageHandler
 /// [`Event::HTLCIntercepted`]: lightning::events::Event::HTLCIntercepted
 /// [`Event::ChannelReady`]: lightning::events::Event::ChannelReady
+/// [`Event::PaymentForwarded`]: lightning::events::Event::PaymentForwarded
+/// [`Event::ChannelClosed`]: lightning::events::Event::ChannelClosed
 pub struct LiquidityManager<
 	ES: Deref + Clone,
 	CM: Deref + Clone,
 	PM: Deref + Clone,
 	C: Deref + Clone,
+	K: Deref + Clone,
 > where
 	ES::Target: EntropySource,
 	CM::Target: AChannelManager,
 	PM::Target: APeerManager,
 	C::Target: Filter,
+	K::Target: KVStore,
 {
 	pending_messages: Arc<DefaultMessageQueue<PM>>,
 	pending_events: Arc<EventQueue>,
-	request_id_to_method_map: Mutex<HashMap<String, String>>,
+	request_id_to_method_map: Mutex<HashMap<PublicKey, PeerRequestIdMap>>,
+	// The number of requests each peer sent us since the last timer tick.
+	request_counts: Mutex<HashMap<PublicKey, u32>>,
 	lsps0_client_handler: LSPS0ClientHandler<ES, Arc<DefaultMessageQueue<PM>>>,
 	lsps0_service_handler: Option<LSPS0ServiceHandler<Arc<DefaultMessageQueue<PM>>>>,
-	#[cfg(lsps1)]
-	lsps1_service_handler: Option<LSPS1ServiceHandler<ES, CM, Arc<DefaultMessageQueue<PM>>, C>>,
-	#[cfg(lsps1)]
-	lsps1_client_handler: Option<LSPS1ClientHandler<ES, CM, Arc<DefaultMessageQueue<PM>>, C>>,
-	lsps2_service_handler: Option<LSPS2ServiceHandler<CM, Arc<DefaultMessageQueue<PM>>>>,
-	lsps2_client_handler: Option<LSPS2ClientHandler<ES, Arc<DefaultMessageQueue<PM>>>>,
+	#[cfg(feature = "lsps1")]
+	lsps1_service_handler: Option<LSPS1ServiceHandlerFor<ES, CM, PM, C, K>>,
+	#[cfg(feature = "lsps1")]
+	lsps1_client_handler: Option<LSPS1ClientHandlerFor<ES, PM, K>>,
+	lsps2_service_handler: Option<LSPS2ServiceHandler<CM, Arc<DefaultMessageQueue<PM>>, K>>,
+	lsps2_client_handler: Option<LSPS2ClientHandler<ES, Arc<DefaultMessageQueue<PM>>, K>>,
 	service_config: Option<LiquidityServiceConfig>,
 	_client_config: Option<LiquidityClientConfig>,
+	request_timeout_ticks: u32,
 	best_block: Option<RwLock<BestBlock>>,
 	_chain_source: Option<C>,
 }
 
-impl<ES: Deref + Clone, CM: Deref + Clone, PM: Deref + Clone, C: Deref + Clone>
-	LiquidityManager<ES, CM, PM, C>
+impl<
+		ES: Deref + Clone,
+		CM: Deref + Clone,
+		PM: Deref + Clone,
+		C: Deref + Clone,
+		K: Deref + Clone,
+	> LiquidityManager<ES, CM, PM, C, K>
 where
 	ES::Target: EntropySource,
 	CM::Target: AChannelManager,
 	PM::Target: APeerManager,
 	C::Target: Filter,
+	K::Target: KVStore,
 {
 	/// Constructor for the [`LiquidityManager`].
 	///
 	/// Sets up the required protocol message handlers based on the given
 	/// [`LiquidityClientConfig`] and [`LiquidityServiceConfig`].
+	///
+	/// All protocol handlers persist their state to the given `kv_store`, and any state persisted
+	/// previously will be reloaded. Returns an error if the persisted state could not be read, or
+	/// if the given configuration is invalid. See the [`persist`] module for the storage backends
+	/// shipped with this crate.
+	///
+	/// [`persist`]: crate::persist
 	pub fn new(
 		entropy_source: ES, channel_manager: CM, chain_source: Option<C>,
-		chain_params: Option<ChainParameters>, service_config: Option<LiquidityServiceConfig>,
+		chain_params: Option<ChainParameters>, kv_store: K,
+		service_config: Option<LiquidityServiceConfig>,
 		client_config: Option<LiquidityClientConfig>,
-	) -> Self
+	) -> Result<Self, io::Error>
 where {
 		let pending_messages = Arc::new(DefaultMessageQueue::new());
 		let pending_events = Arc::new(EventQueue::new());
+		let request_timeout_ticks = client_config
+			.as_ref()
+			.map_or(DEFAULT_REQUEST_TIMEOUT_TICKS, |config| config.request_timeout_ticks);
 
 		let lsps0_client_handler = LSPS0ClientHandler::new(
 			entropy_source.clone(),
 			Arc::clone(&pending_messages),
 			Arc::clone(&pending_events),
+			request_timeout_ticks,
 		);
 
 		let lsps0_service_handler = if service_config.is_some() {
@@ -139,72 +274,91 @@ where {
 			None
 		};
 
-		let lsps2_client_handler = client_config.as_ref().and_then(|config| {
-			config.lsps2_client_config.map(|config| {
-				LSPS2ClientHandler::new(
-					entropy_source.clone(),
-					Arc::clone(&pending_messages),
-					Arc::clone(&pending_events),
-					config.clone(),
-				)
+		let lsps2_client_handler = client_config
+			.as_ref()
+			.and_then(|config| {
+				config.lsps2_client_config.map(|config| {
+					LSPS2ClientHandler::new(
+						entropy_source.clone(),
+						Arc::clone(&pending_messages),
+						Arc::clone(&pending_events),
+						kv_store.clone(),
+						config,
+						request_timeout_ticks,
+					)
+				})
 			})
-		});
-		let lsps2_service_handler = service_config.as_ref().and_then(|config| {
-			config.lsps2_service_config.as_ref().map(|config| {
-				LSPS2ServiceHandler::new(
-					Arc::clone(&pending_messages),
-					Arc::clone(&pending_events),
-					channel_manager.clone(),
-					config.clone(),
-				)
+			.transpose()?;
+		let lsps2_service_handler = service_config
+			.as_ref()
+			.and_then(|config| {
+				config.lsps2_service_config.as_ref().map(|config| {
+					LSPS2ServiceHandler::new(
+						Arc::clone(&pending_messages),
+						Arc::clone(&pending_events),
+						channel_manager.clone(),
+						kv_store.clone(),
+						config.clone(),
+					)
+				})
 			})
-		});
-
-		#[cfg(lsps1)]
-		let lsps1_client_handler = client_config.as_ref().and_then(|config| {
-			config.lsps1_client_config.as_ref().map(|config| {
-				LSPS1ClientHandler::new(
-					entropy_source.clone(),
-					Arc::clone(&pending_messages),
-					Arc::clone(&pending_events),
-					channel_manager.clone(),
-					chain_source.clone(),
-					config.clone(),
-				)
+			.transpose()?;
+
+		#[cfg(feature = "lsps1")]
+		let lsps1_client_handler = client_config
+			.as_ref()
+			.and_then(|config| {
+				config.lsps1_client_config.as_ref().map(|config| {
+					LSPS1ClientHandler::new(
+						entropy_source.clone(),
+						Arc::clone(&pending_messages),
+						Arc::clone(&pending_events),
+						kv_store.clone(),
+						config.clone(),
+						request_timeout_ticks,
+					)
+				})
 			})
-		});
-
-		#[cfg(lsps1)]
-		let lsps1_service_handler = service_config.as_ref().and_then(|config| {
-			config.lsps1_service_config.as_ref().map(|config| {
-				LSPS1ServiceHandler::new(
-					entropy_source.clone(),
-					Arc::clone(&pending_messages),
-					Arc::clone(&pending_events),
-					channel_manager.clone(),
-					chain_source.clone(),
-					config.clone(),
-				)
+			.transpose()?;
+
+		#[cfg(feature = "lsps1")]
+		let lsps1_service_handler = service_config
+			.as_ref()
+			.and_then(|config| {
+				config.lsps1_service_config.as_ref().map(|config| {
+					LSPS1ServiceHandler::new(
+						entropy_source.clone(),
+						Arc::clone(&pending_messages),
+						Arc::clone(&pending_events),
+						channel_manager.clone(),
+						chain_source.clone(),
+						chain_params.as_ref().map(|params| params.best_block.height()),
+						kv_store.clone(),
+						config.clone(),
+					)
+				})
 			})
-		});
+			.transpose()?;
 
-		Self {
+		Ok(Self {
 			pending_messages,
 			pending_events,
 			request_id_to_method_map: Mutex::new(HashMap::new()),
+			request_counts: Mutex::new(HashMap::new()),
 			lsps0_client_handler,
 			lsps0_service_handler,
-			#[cfg(lsps1)]
+			#[cfg(feature = "lsps1")]
 			lsps1_client_handler,
-			#[cfg(lsps1)]
+			#[cfg(feature = "lsps1")]
 			lsps1_service_handler,
 			lsps2_client_handler,
 			lsps2_service_handler,
 			service_config,
 			_client_config: client_config,
+			request_timeout_ticks,
 			best_block: chain_params.map(|chain_params| RwLock::new(chain_params.best_block)),
 			_chain_source: chain_source,
-		}
+		})
 	}
 
 	/// Returns a reference to the LSPS0 client-side handler.
@@ -220,32 +374,28 @@ where {
 	}
 
 	/// Returns a reference to the LSPS1 client-side handler.
-	#[cfg(lsps1)]
-	pub fn lsps1_client_handler(
-		&self,
-	) -> Option<&LSPS1ClientHandler<ES, CM, Arc<DefaultMessageQueue<PM>>, C>> {
+	#[cfg(feature = "lsps1")]
+	pub fn lsps1_client_handler(&self) -> Option<&LSPS1ClientHandlerFor<ES, PM, K>> {
 		self.lsps1_client_handler.as_ref()
 	}
 
 	/// Returns a reference to the LSPS1 server-side handler.
-	#[cfg(lsps1)]
-	pub fn lsps1_service_handler(
-		&self,
-	) -> Option<&LSPS1ServiceHandler<ES, CM, Arc<DefaultMessageQueue<PM>>, C>> {
+	#[cfg(feature = "lsps1")]
+	pub fn lsps1_service_handler(&self) -> Option<&LSPS1ServiceHandlerFor<ES, CM, PM, C, K>> {
 		self.lsps1_service_handler.as_ref()
 	}
 
 	/// Returns a reference to the LSPS2 client-side handler.
 	pub fn lsps2_client_handler(
 		&self,
-	) -> Option<&LSPS2ClientHandler<ES, Arc<DefaultMessageQueue<PM>>>> {
+	) -> Option<&LSPS2ClientHandler<ES, Arc<DefaultMessageQueue<PM>>, K>> {
 		self.lsps2_client_handler.as_ref()
 	}
 
 	/// Returns a reference to the LSPS2 server-side handler.
 	pub fn lsps2_service_handler(
 		&self,
-	) -> Option<&LSPS2ServiceHandler<CM, Arc<DefaultMessageQueue<PM>>>> {
+	) -> Option<&LSPS2ServiceHandler<CM, Arc<DefaultMessageQueue<PM>>, K>> {
 		self.lsps2_service_handler.as_ref()
 	}
 
@@ -271,6 +421,86 @@ where {
 		self.pending_events.get_and_clear_pending_events()
 	}
 
+	/// Times out requests that weren't answered by the respective LSP within
+	/// [`LiquidityClientConfig::request_timeout_ticks`], resets the per-peer request budget of
+	/// [`LiquidityServiceConfig::max_requests_per_peer_per_tick`], and calls
+	/// [`LSPS2ServiceHandler::timer_tick_occurred`] if an LSPS2 service handler is configured.
+	///
+	/// Should be called roughly once a minute, e.g., alongside
+	/// [`ChannelManager::timer_tick_occurred`].
+	///
+	/// [`ChannelManager::timer_tick_occurred`]: lightning::ln::channelmanager::ChannelManager::timer_tick_occurred
+	pub fn timer_tick_occurred(&self) {
+		self.request_counts.lock().unwrap().clear();
+		self.request_id_to_method_map.lock().unwrap().retain(|_, peer_request_id_map| {
+			peer_request_id_map.timer_tick_occurred(self.request_timeout_ticks);
+			!peer_request_id_map.is_empty()
+		});
+
+		self.lsps0_client_handler.timer_tick_occurred();
+
+		#[cfg(feature = "lsps1")]
+		if let Some(lsps1_client_handler) = self.lsps1_client_handler.as_ref() {
+			lsps1_client_handler.timer_tick_occurred();
+		}
+
+		if let Some(lsps2_client_handler) = self.lsps2_client_handler.as_ref() {
+			lsps2_client_handler.timer_tick_occurred();
+		}
+
+		if let Some(lsps2_service_handler) = self.lsps2_service_handler.as_ref() {
+			lsps2_service_handler.timer_tick_occurred();
+		}
+	}
+
+	/// Resumes the JIT channel flows with the given counterparty that were interrupted by a
+	/// disconnection or a restart, re-sending the requests that weren't answered yet.
+	///
+	/// Should be called whenever a connection to a peer was established.
+	pub fn peer_connected(&self, counterparty_node_id: &PublicKey) -> Result<(), LiquidityError> {
+		if let Some(lsps2_client_handler) = self.lsps2_client_handler.as_ref() {
+			lsps2_client_handler.resume_pending_flows(counterparty_node_id)?;
+		}
+
+		Ok(())
+	}
+
+	/// Forgets about the requests exchanged with the given counterparty that weren't answered yet,
+	/// as their responses can't be delivered anymore, and drops the messages queued for it.
+	///
+	/// Client requests to the counterparty are reported via `PeerDisconnected` events, such as
+	/// [`LSPS0ClientEvent::PeerDisconnected`], so that they may be repeated once the connection was
+	/// re-established. Pending LSPS2 JIT channel flows are instead resumed automatically by
+	/// [`LiquidityManager::peer_connected`].
+	///
+	/// Should be called whenever the connection to a peer was closed.
+	///
+	/// [`LSPS0ClientEvent::PeerDisconnected`]: crate::lsps0::event::LSPS0ClientEvent::PeerDisconnected
+	pub fn peer_disconnected(&self, counterparty_node_id: &PublicKey) {
+		self.request_id_to_method_map.lock().unwrap().remove(counterparty_node_id);
+		self.pending_messages.clear_pending_msgs(counterparty_node_id);
+
+		self.lsps0_client_handler.peer_disconnected(counterparty_node_id);
+
+		#[cfg(feature = "lsps1")]
+		if let Some(lsps1_client_handler) = self.lsps1_client_handler.as_ref() {
+			lsps1_client_handler.peer_disconnected(counterparty_node_id);
+		}
+
+		#[cfg(feature = "lsps1")]
+		if let Some(lsps1_service_handler) = self.lsps1_service_handler.as_ref() {
+			lsps1_service_handler.peer_disconnected(counterparty_node_id);
+		}
+
+		if let Some(lsps2_client_handler) = self.lsps2_client_handler.as_ref() {
+			lsps2_client_handler.peer_disconnected(counterparty_node_id);
+		}
+
+		if let Some(lsps2_service_handler) = self.lsps2_service_handler.as_ref() {
+			lsps2_service_handler.peer_disconnected(counterparty_node_id);
+		}
+	}
+
 	/// Set a [`PeerManager`] reference for all configured message handlers.
 	///
 	/// This allows the message handlers to wake the [`PeerManager`] by calling
@@ -285,6 +515,19 @@ where {
 		self.pending_messages.set_peer_manager(peer_manager);
 	}
 
+	/// Counts a request from the given peer, returning whether it exceeds the peer's budget.
+	fn is_rate_limited(&self, sender_node_id: &PublicKey) -> bool {
+		let max_requests = match self.service_config.as_ref() {
+			Some(service_config) => service_config.max_requests_per_peer_per_tick,
+			None => return false,
+		};
+
+		let mut request_counts = self.request_counts.lock().unwrap();
+		let request_count = request_counts.entry(*sender_node_id).or_insert(0);
+		*request_count = request_count.saturating_add(1);
+		*request_count > max_requests
+	}
+
 	fn handle_lsps_message(
 		&self, msg: LSPSMessage, sender_node_id: &PublicKey,
 	) -> Result<(), lightning::ln::msgs::LightningError> {
@@ -305,7 +548,7 @@ where {
 					}
 				}
 			}
-			#[cfg(lsps1)]
+			#[cfg(feature = "lsps1")]
 			LSPSMessage::LSPS1(msg @ LSPS1Message::Response(..)) => match &self.lsps1_client_handler {
 				Some(lsps1_client_handler) => {
 					lsps1_client_handler.handle_message(msg, sender_node_id)?;
@@ -314,7 +557,7 @@ where {
 					return Err(LightningError { err: format!("Received LSPS1 response message without LSPS1 client handler configured. From node = {:?}", sender_node_id), action: ErrorAction::IgnoreAndLog(Level::Info)});
 				}
 			},
-			#[cfg(lsps1)]
+			#[cfg(feature = "lsps1")]
 			LSPSMessage::LSPS1(msg @ LSPS1Message::Request(..)) => match &self.lsps1_service_handler {
 				Some(lsps1_service_handler) => {
 					lsps1_service_handler.handle_message(msg, sender_node_id)?;
@@ -348,13 +591,19 @@ where {
 	}
 }
 
-impl<ES: Deref + Clone + Clone, CM: Deref + Clone, PM: Deref + Clone, C: Deref + Clone>
-	CustomMessageReader for LiquidityManager<ES, CM, PM, C>
+impl<
+		ES: Deref + Clone + Clone,
+		CM: Deref + Clone,
+		PM: Deref + Clone,
+		C: Deref + Clone,
+		K: Deref + Clone,
+	> CustomMessageReader for LiquidityManager<ES, CM, PM, C, K>
 where
 	ES::Target: EntropySource,
 	CM::Target: AChannelManager,
 	PM::Target: APeerManager,
 	C::Target: Filter,
+	K::Target: KVStore,
 {
 	type CustomMessage = RawLSPSMessage;
 
@@ -368,24 +617,59 @@ where
 	}
 }
 
-impl<ES: Deref + Clone, CM: Deref + Clone, PM: Deref + Clone, C: Deref + Clone> CustomMessageHandler
-	for LiquidityManager<ES, CM, PM, C>
+impl<
+		ES: Deref + Clone,
+		CM: Deref + Clone,
+		PM: Deref + Clone,
+		C: Deref + Clone,
+		K: Deref + Clone,
+	> CustomMessageHandler for LiquidityManager<ES, CM, PM, C, K>
 where
 	ES::Target: EntropySource,
 	CM::Target: AChannelManager,
 	PM::Target: APeerManager,
 	C::Target: Filter,
+	K::Target: KVStore,
 {
 	fn handle_custom_message(
 		&self, msg: Self::CustomMessage, sender_node_id: &PublicKey,
 	) -> Result<(), lightning::ln::msgs::LightningError> {
 		let message = {
+			// We only consider the requests we sent to the sender, so that responses to requests
+			// sent to other peers are rejected.
 			let mut request_id_to_method_map = self.request_id_to_method_map.lock().unwrap();
-			LSPSMessage::from_str_with_id_map(&msg.payload, &mut request_id_to_method_map)
+			let peer_request_id_map = request_id_to_method_map.entry(*sender_node_id).or_default();
+			let message = LSPSMessage::from_str_with_id_map(
+				&msg.payload,
+				&mut peer_request_id_map.request_id_to_method,
+			);
+			peer_request_id_map.prune_answered_requests();
+			if peer_request_id_map.is_empty() {
+				request_id_to_method_map.remove(sender_node_id);
+			}
+			message
 		};
 
 		match message {
-			Ok(msg) => self.handle_lsps_message(msg, sender_node_id),
+			Ok(msg) => {
+				if msg.get_request_id_and_method().is_some() && self.is_rate_limited(sender_node_id)
+				{
+					let error = ResponseError {
+						code: JSONRPC_RATE_LIMITED_ERROR_CODE,
+						message: "too many requests".to_string(),
+						data: None,
+					};
+					if let Some(response) = msg.error_response(error) {
+						self.pending_messages.enqueue(sender_node_id, response);
+					}
+					return Err(LightningError {
+						err: format!("Rejected request from rate-limited peer {}", sender_node_id),
+						action: ErrorAction::IgnoreAndLog(Level::Info),
+					});
+				}
+
+				self.handle_lsps_message(msg, sender_node_id)
+			}
 			Err(_) => {
 				self.pending_messages.enqueue(sender_node_id, LSPSMessage::Invalid);
 				Ok(())
@@ -400,7 +684,10 @@ where
 			.iter()
 			.map(|(public_key, lsps_message)| {
 				if let Some((request_id, method_name)) = lsps_message.get_request_id_and_method() {
-					request_id_to_method_map.insert(request_id, method_name);
+					request_id_to_method_map
+						.entry(*public_key)
+						.or_default()
+						.insert(request_id, method_name);
 				}
 				(
 					*public_key,
@@ -431,13 +718,19 @@ where
 	}
 }
 
-impl<ES: Deref + Clone, CM: Deref + Clone, PM: Deref + Clone, C: Deref + Clone> Listen
-	for LiquidityManager<ES, CM, PM, C>
+impl<
+		ES: Deref + Clone,
+		CM: Deref + Clone,
+		PM: Deref + Clone,
+		C: Deref + Clone,
+		K: Deref + Clone,
+	> Listen for LiquidityManager<ES, CM, PM, C, K>
 where
 	ES::Target: EntropySource,
 	CM::Target: AChannelManager,
 	PM::Target: APeerManager,
 	C::Target: Filter,
+	K::Target: KVStore,
 {
 	fn filtered_block_connected(
 		&self, header: &bitcoin::BlockHeader, txdata: &chain::transaction::TransactionData,
@@ -466,39 +759,112 @@ where
 			*best_block = BestBlock::new(header.prev_blockhash, new_height)
 		}
 
-		// TODO: Call block_disconnected on all sub-modules that require it, e.g., LSPS1MessageHandler.
-		// Internally this should call transaction_unconfirmed for all transactions that were
-		// confirmed at a height <= the one we now disconnected.
+		#[cfg(feature = "lsps1")]
+		if let Some(lsps1_service_handler) = self.lsps1_service_handler.as_ref() {
+			lsps1_service_handler.block_disconnected(height);
+		}
 	}
 }
 
-impl<ES: Deref + Clone, CM: Deref + Clone, PM: Deref + Clone, C: Deref + Clone> Confirm
-	for LiquidityManager<ES, CM, PM, C>
+impl<
+		ES: Deref + Clone,
+		CM: Deref + Clone,
+		PM: Deref + Clone,
+		C: Deref + Clone,
+		K: Deref + Clone,
+	> Confirm for LiquidityManager<ES, CM, PM, C, K>
 where
 	ES::Target: EntropySource,
 	CM::Target: AChannelManager,
 	PM::Target: APeerManager,
 	C::Target: Filter,
+	K::Target: KVStore,
 {
+	#[cfg_attr(not(feature = "lsps1"), allow(unused_variables))]
 	fn transactions_confirmed(
 		&self, header: &bitcoin::BlockHeader, txdata: &chain::transaction::TransactionData,
 		height: u32,
 	) {
-		// TODO: Call transactions_confirmed on all sub-modules that require it, e.g., LSPS1MessageHandler.
+		#[cfg(feature = "lsps1")]
+		if let Some(lsps1_service_handler) = self.lsps1_service_handler.as_ref() {
+			lsps1_service_handler.transactions_confirmed(header, txdata, height);
+		}
 	}
 
+	#[cfg_attr(not(feature = "lsps1"), allow(unused_variables))]
 	fn transaction_unconfirmed(&self, txid: &bitcoin::Txid) {
-		// TODO: Call transaction_unconfirmed on all sub-modules that require it, e.g., LSPS1MessageHandler.
-		// Internally this should call transaction_unconfirmed for all transactions that were
-		// confirmed at a height <= the one we now unconfirmed.
+		#[cfg(feature = "lsps1")]
+		if let Some(lsps1_service_handler) = self.lsps1_service_handler.as_ref() {
+			lsps1_service_handler.transaction_unconfirmed(txid);
+		}
 	}
 
 	fn best_block_updated(&self, header: &bitcoin::BlockHeader, height: u32) {
-		// TODO: Call best_block_updated on all sub-modules that require it, e.g., LSPS1MessageHandler.
+		if let Some(best_block) = &self.best_block {
+			*best_block.write().unwrap() = BestBlock::new(header.block_hash(), height);
+		}
+
+		#[cfg(feature = "lsps1")]
+		if let Some(lsps1_service_handler) = self.lsps1_service_handler.as_ref() {
+			lsps1_service_handler.best_block_updated(header, height);
+		}
 	}
 
+	#[cfg_attr(not(feature = "lsps1"), allow(unused_mut))]
 	fn get_relevant_txids(&self) -> Vec<(bitcoin::Txid, Option<bitcoin::BlockHash>)> {
-		// TODO: Collect relevant txids from all sub-modules that, e.g., LSPS1MessageHandler.
-		Vec::new()
+		let mut relevant_txids = Vec::new();
+
+		#[cfg(feature = "lsps1")]
+		if let Some(lsps1_service_handler) = self.lsps1_service_handler.as_ref() {
+			relevant_txids.append(&mut lsps1_service_handler.get_relevant_txids());
+		}
+
+		relevant_txids
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::prelude::ToString;
+
+	#[test]
+	fn peer_request_id_map_is_bounded() {
+		let mut peer_request_id_map = PeerRequestIdMap::default();
+		peer_request_id_map.insert("oldest".to_string(), "lsps2.get_versions".to_string());
+		peer_request_id_map.timer_tick_occurred(2);
+		for i in 1..MAX_PENDING_REQUESTS_PER_PEER {
+			peer_request_id_map.insert(i.to_string(), "lsps2.get_info".to_string());
+		}
+		assert_eq!(peer_request_id_map.request_id_to_method.len(), MAX_PENDING_REQUESTS_PER_PEER);
+
+		// The oldest request is evicted to make room for a new one.
+		peer_request_id_map.insert("newest".to_string(), "lsps2.buy".to_string());
+		assert_eq!(peer_request_id_map.request_id_to_method.len(), MAX_PENDING_REQUESTS_PER_PEER);
+		assert!(!peer_request_id_map.request_id_to_method.contains_key("oldest"));
+		assert_eq!(peer_request_id_map.request_ticks.len(), MAX_PENDING_REQUESTS_PER_PEER);
+	}
+
+	#[test]
+	fn peer_request_id_map_forgets_answered_and_timed_out_requests() {
+		let mut peer_request_id_map = PeerRequestIdMap::default();
+		peer_request_id_map.insert("a".to_string(), "lsps2.get_versions".to_string());
+		peer_request_id_map.timer_tick_occurred(2);
+		peer_request_id_map.insert("b".to_string(), "lsps2.get_versions".to_string());
+		peer_request_id_map.insert("c".to_string(), "lsps2.get_versions".to_string());
+
+		peer_request_id_map.request_id_to_method.remove("c");
+		peer_request_id_map.prune_answered_requests();
+		assert!(!peer_request_id_map.request_ticks.contains_key("c"));
+
+		peer_request_id_map.timer_tick_occurred(2);
+		assert!(peer_request_id_map.request_id_to_method.contains_key("a"));
+		peer_request_id_map.timer_tick_occurred(2);
+		assert!(!peer_request_id_map.request_id_to_method.contains_key("a"));
+		assert!(peer_request_id_map.request_id_to_method.contains_key("b"));
+		peer_request_id_map.timer_tick_occurred(2);
+		assert!(peer_request_id_map.is_empty());
+		assert!(peer_request_id_map.request_ticks.is_empty());
 	}
 }
@@ -44,6 +44,11 @@ where
 		self.queue.lock().unwrap().drain(..).collect()
 	}
 
+	/// Drops the messages queued for the given counterparty, e.g., as we disconnected from it.
+	pub(crate) fn clear_pending_msgs(&self, counterparty_node_id: &PublicKey) {
+		self.queue.lock().unwrap().retain(|(node_id, _)| node_id != counterparty_node_id);
+	}
+
 	pub(crate) fn set_peer_manager(&self, peer_manager: PM) {
 		*self.peer_manager.lock().unwrap() = Some(peer_manager);
 	}
@@ -0,0 +1,232 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Contains [`FilesystemStore`], a [`KVStore`] implementation persisting data to the filesystem.
+
+use super::check_namespace_key_validity;
+use crate::prelude::{String, Vec};
+
+use lightning::util::persist::KVStore;
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A [`KVStore`] implementation that persists data to the filesystem.
+///
+/// Each key is stored as a separate file at `data_dir/primary_namespace/secondary_namespace/key`.
+/// Writes are atomic and durable, i.e., data is first written and synced to a temporary file which
+/// is then moved into place, before the directory is synced as well. Temporary files left behind
+/// by interrupted writes are removed upon construction.
+pub struct FilesystemStore {
+	data_dir: PathBuf,
+	tmp_file_counter: AtomicUsize,
+}
+
+impl FilesystemStore {
+	/// Constructs a `FilesystemStore` persisting data under the given `data_dir`.
+	pub fn new(data_dir: PathBuf) -> Self {
+		remove_tmp_files(&data_dir);
+		Self { data_dir, tmp_file_counter: AtomicUsize::new(0) }
+	}
+
+	/// Returns the directory under which data is persisted.
+	pub fn data_dir(&self) -> PathBuf {
+		self.data_dir.clone()
+	}
+
+	fn namespace_path(&self, primary_namespace: &str, secondary_namespace: &str) -> PathBuf {
+		let mut path = self.data_dir.clone();
+		if !primary_namespace.is_empty() {
+			path.push(primary_namespace);
+		}
+		if !secondary_namespace.is_empty() {
+			path.push(secondary_namespace);
+		}
+		path
+	}
+}
+
+impl KVStore for FilesystemStore {
+	fn read(
+		&self, primary_namespace: &str, secondary_namespace: &str, key: &str,
+	) -> Result<Vec<u8>, io::Error> {
+		check_namespace_key_validity(primary_namespace, secondary_namespace, Some(key))?;
+
+		let mut path = self.namespace_path(primary_namespace, secondary_namespace);
+		path.push(key);
+		fs::read(path)
+	}
+
+	fn write(
+		&self, primary_namespace: &str, secondary_namespace: &str, key: &str, buf: &[u8],
+	) -> Result<(), io::Error> {
+		check_namespace_key_validity(primary_namespace, secondary_namespace, Some(key))?;
+
+		let dir_path = self.namespace_path(primary_namespace, secondary_namespace);
+		fs::create_dir_all(&dir_path)?;
+
+		// The `.` isn't part of the allowed key alphabet, so temporary files never collide with
+		// actual keys and are skipped when listing.
+		let tmp_file_id = self.tmp_file_counter.fetch_add(1, Ordering::AcqRel);
+		let tmp_file_path = dir_path.join(format!("{}.{}.tmp", key, tmp_file_id));
+		{
+			let mut tmp_file = fs::File::create(&tmp_file_path)?;
+			tmp_file.write_all(buf)?;
+			tmp_file.sync_all()?;
+		}
+
+		if let Err(e) = fs::rename(&tmp_file_path, dir_path.join(key)) {
+			let _ = fs::remove_file(&tmp_file_path);
+			return Err(e);
+		}
+		sync_dir(&dir_path)
+	}
+
+	fn remove(
+		&self, primary_namespace: &str, secondary_namespace: &str, key: &str, _lazy: bool,
+	) -> Result<(), io::Error> {
+		check_namespace_key_validity(primary_namespace, secondary_namespace, Some(key))?;
+
+		let dir_path = self.namespace_path(primary_namespace, secondary_namespace);
+		match fs::remove_file(dir_path.join(key)) {
+			Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+			Err(e) => Err(e),
+			Ok(()) => sync_dir(&dir_path),
+		}
+	}
+
+	fn list(
+		&self, primary_namespace: &str, secondary_namespace: &str,
+	) -> Result<Vec<String>, io::Error> {
+		check_namespace_key_validity(primary_namespace, secondary_namespace, None)?;
+
+		let dir_path = self.namespace_path(primary_namespace, secondary_namespace);
+		let entries = match fs::read_dir(dir_path) {
+			Ok(entries) => entries,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+			Err(e) => return Err(e),
+		};
+
+		let mut keys = Vec::new();
+		for entry in entries {
+			let entry = entry?;
+			if !entry.file_type()?.is_file() {
+				continue;
+			}
+
+			// Temporary files of writes in progress are skipped, as their names are no valid keys.
+			if let Some(key) = entry.file_name().to_str() {
+				if check_namespace_key_validity(primary_namespace, secondary_namespace, Some(key))
+					.is_ok()
+				{
+					keys.push(key.to_string());
+				}
+			}
+		}
+		Ok(keys)
+	}
+}
+
+/// Syncs the given directory, making sure that renames and removals of its entries are durable.
+fn sync_dir(dir_path: &Path) -> Result<(), io::Error> {
+	// Directories can't be opened, and don't need to be synced, on Windows.
+	#[cfg(not(target_os = "windows"))]
+	fs::File::open(dir_path)?.sync_all()?;
+	#[cfg(target_os = "windows")]
+	let _ = dir_path;
+	Ok(())
+}
+
+/// Recursively removes the temporary files that were left behind by interrupted writes.
+fn remove_tmp_files(dir_path: &Path) {
+	let entries = match fs::read_dir(dir_path) {
+		Ok(entries) => entries,
+		Err(_) => return,
+	};
+
+	for entry in entries.flatten() {
+		let path = entry.path();
+		match entry.file_type() {
+			Ok(file_type) if file_type.is_dir() => remove_tmp_files(&path),
+			Ok(file_type) if file_type.is_file() && path.extension() == Some(OsStr::new("tmp")) => {
+				let _ = fs::remove_file(&path);
+			}
+			_ => {}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	struct TempDir(PathBuf);
+
+	impl TempDir {
+		fn new(name: &str) -> Self {
+			let mut path = std::env::temp_dir();
+			path.push(format!("lightning_liquidity_{}_{}", name, std::process::id()));
+			let _ = fs::remove_dir_all(&path);
+			Self(path)
+		}
+	}
+
+	impl Drop for TempDir {
+		fn drop(&mut self) {
+			let _ = fs::remove_dir_all(&self.0);
+		}
+	}
+
+	#[test]
+	fn read_write_remove_list() {
+		let temp_dir = TempDir::new("read_write_remove_list");
+		let store = FilesystemStore::new(temp_dir.0.clone());
+		assert!(store.list("primary", "secondary").unwrap().is_empty());
+		assert_eq!(
+			store.read("primary", "secondary", "key").unwrap_err().kind(),
+			io::ErrorKind::NotFound
+		);
+
+		store.write("primary", "secondary", "key", &[42u8; 32]).unwrap();
+		store.write("primary", "", "other_key", &[1u8; 32]).unwrap();
+		assert_eq!(store.read("primary", "secondary", "key").unwrap(), vec![42u8; 32]);
+		assert_eq!(store.list("primary", "secondary").unwrap(), vec!["key".to_string()]);
+		// Secondary namespaces are not listed as keys of the primary namespace.
+		assert_eq!(store.list("primary", "").unwrap(), vec!["other_key".to_string()]);
+
+		// Data survives reinitializing the store.
+		let store = FilesystemStore::new(temp_dir.0.clone());
+		store.write("primary", "secondary", "key", &[7u8; 16]).unwrap();
+		assert_eq!(store.read("primary", "secondary", "key").unwrap(), vec![7u8; 16]);
+
+		store.remove("primary", "secondary", "key", false).unwrap();
+		store.remove("primary", "secondary", "key", false).unwrap();
+		assert!(store.list("primary", "secondary").unwrap().is_empty());
+	}
+
+	#[test]
+	fn stale_tmp_files_are_ignored_and_removed() {
+		let temp_dir = TempDir::new("stale_tmp_files_are_ignored_and_removed");
+		let store = FilesystemStore::new(temp_dir.0.clone());
+		store.write("primary", "secondary", "key", &[42u8; 32]).unwrap();
+
+		// Leave a temporary file behind, as if we crashed while writing.
+		let tmp_file_path = temp_dir.0.join("primary").join("secondary").join("key.7.tmp");
+		fs::write(&tmp_file_path, [1u8; 32]).unwrap();
+		assert_eq!(store.list("primary", "secondary").unwrap(), vec!["key".to_string()]);
+
+		let store = FilesystemStore::new(temp_dir.0.clone());
+		assert!(!tmp_file_path.exists());
+		assert_eq!(store.list("primary", "secondary").unwrap(), vec!["key".to_string()]);
+		assert_eq!(store.read("primary", "secondary", "key").unwrap(), vec![42u8; 32]);
+	}
+}
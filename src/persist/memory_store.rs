@@ -0,0 +1,131 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Contains [`MemoryStore`], a [`KVStore`] implementation keeping all data in memory.
+
+use super::check_namespace_key_validity;
+use crate::prelude::{HashMap, String, ToString, Vec};
+use crate::sync::Mutex;
+
+use lightning::io;
+use lightning::util::persist::KVStore;
+
+/// A [`KVStore`] implementation that keeps all data in memory.
+///
+/// Data stored in a `MemoryStore` does not survive a restart, which makes it mostly useful for
+/// testing or for nodes that don't need to keep any state across restarts.
+pub struct MemoryStore {
+	persisted_bytes: Mutex<HashMap<String, HashMap<String, Vec<u8>>>>,
+}
+
+impl MemoryStore {
+	/// Constructs an empty `MemoryStore`.
+	pub fn new() -> Self {
+		Self { persisted_bytes: Mutex::new(HashMap::new()) }
+	}
+}
+
+fn namespace_prefix(primary_namespace: &str, secondary_namespace: &str) -> String {
+	format!("{}/{}", primary_namespace, secondary_namespace)
+}
+
+impl Default for MemoryStore {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl KVStore for MemoryStore {
+	fn read(
+		&self, primary_namespace: &str, secondary_namespace: &str, key: &str,
+	) -> Result<Vec<u8>, io::Error> {
+		check_namespace_key_validity(primary_namespace, secondary_namespace, Some(key))?;
+
+		let persisted_bytes = self.persisted_bytes.lock().unwrap();
+		persisted_bytes
+			.get(&namespace_prefix(primary_namespace, secondary_namespace))
+			.and_then(|entries| entries.get(key))
+			.cloned()
+			.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Key not found"))
+	}
+
+	fn write(
+		&self, primary_namespace: &str, secondary_namespace: &str, key: &str, buf: &[u8],
+	) -> Result<(), io::Error> {
+		check_namespace_key_validity(primary_namespace, secondary_namespace, Some(key))?;
+
+		let mut persisted_bytes = self.persisted_bytes.lock().unwrap();
+		persisted_bytes
+			.entry(namespace_prefix(primary_namespace, secondary_namespace))
+			.or_default()
+			.insert(key.to_string(), buf.to_vec());
+		Ok(())
+	}
+
+	fn remove(
+		&self, primary_namespace: &str, secondary_namespace: &str, key: &str, _lazy: bool,
+	) -> Result<(), io::Error> {
+		check_namespace_key_validity(primary_namespace, secondary_namespace, Some(key))?;
+
+		let mut persisted_bytes = self.persisted_bytes.lock().unwrap();
+		if let Some(entries) =
+			persisted_bytes.get_mut(&namespace_prefix(primary_namespace, secondary_namespace))
+		{
+			entries.remove(key);
+		}
+		Ok(())
+	}
+
+	fn list(
+		&self, primary_namespace: &str, secondary_namespace: &str,
+	) -> Result<Vec<String>, io::Error> {
+		check_namespace_key_validity(primary_namespace, secondary_namespace, None)?;
+
+		let persisted_bytes = self.persisted_bytes.lock().unwrap();
+		Ok(persisted_bytes
+			.get(&namespace_prefix(primary_namespace, secondary_namespace))
+			.map(|entries| entries.keys().cloned().collect())
+			.unwrap_or_default())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn read_write_remove_list() {
+		let store = MemoryStore::new();
+		assert!(store.list("primary", "secondary").unwrap().is_empty());
+		assert_eq!(
+			store.read("primary", "secondary", "key").unwrap_err().kind(),
+			io::ErrorKind::NotFound
+		);
+
+		store.write("primary", "secondary", "key", &[42u8; 32]).unwrap();
+		store.write("primary", "", "other_key", &[1u8; 32]).unwrap();
+		assert_eq!(store.read("primary", "secondary", "key").unwrap(), vec![42u8; 32]);
+		assert_eq!(store.list("primary", "secondary").unwrap(), vec!["key".to_string()]);
+		assert_eq!(store.list("primary", "").unwrap(), vec!["other_key".to_string()]);
+
+		store.remove("primary", "secondary", "key", false).unwrap();
+		assert!(store.list("primary", "secondary").unwrap().is_empty());
+		assert_eq!(store.list("primary", "").unwrap(), vec!["other_key".to_string()]);
+	}
+
+	#[test]
+	fn rejects_invalid_namespaces_and_keys() {
+		let store = MemoryStore::new();
+		assert!(store.write("", "secondary", "key", &[]).is_err());
+		assert!(store.write("primary", "", "", &[]).is_err());
+		assert!(store.write("primary", "", "in/valid", &[]).is_err());
+		assert!(store.write("primary", "", &"a".repeat(121), &[]).is_err());
+		assert!(store.list("in.valid", "").is_err());
+	}
+}
@@ -0,0 +1,158 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Types and utilities used to persist the state of the LSPS protocol handlers via a [`KVStore`].
+//!
+//! Each handler persists its per-peer state under its own primary namespace, keyed by the hex
+//! representation of the counterparty's node id.
+//!
+//! Any [`KVStore`] implementation may be used as a storage backend. For convenience, an in-memory
+//! [`MemoryStore`] and, with the `std` feature enabled, a [`FilesystemStore`] are provided.
+//!
+//! [`MemoryStore`]: memory_store::MemoryStore
+//! [`FilesystemStore`]: fs_store::FilesystemStore
+
+// The `fs_store` module only exists with the `std` feature enabled.
+#![cfg_attr(not(feature = "std"), allow(rustdoc::broken_intra_doc_links))]
+
+#[cfg(feature = "std")]
+pub mod fs_store;
+pub mod memory_store;
+
+use crate::errors::LiquidityError;
+use crate::prelude::{HashMap, ToString};
+use crate::utils;
+
+use lightning::io;
+pub use lightning::util::persist::KVStore;
+use lightning::util::persist::{KVSTORE_NAMESPACE_KEY_ALPHABET, KVSTORE_NAMESPACE_KEY_MAX_LEN};
+use lightning::util::ser::{Readable, Writeable};
+
+use bitcoin::secp256k1::PublicKey;
+
+use core::ops::Deref;
+
+/// The primary namespace under which the [`LSPS2ServiceHandler`] state will be persisted.
+///
+/// [`LSPS2ServiceHandler`]: crate::lsps2::service::LSPS2ServiceHandler
+pub const LSPS2_SERVICE_PERSISTENCE_PRIMARY_NAMESPACE: &str = "lsps2_service";
+/// The secondary namespace under which the [`LSPS2ServiceHandler`] state will be persisted.
+///
+/// [`LSPS2ServiceHandler`]: crate::lsps2::service::LSPS2ServiceHandler
+pub const LSPS2_SERVICE_PERSISTENCE_SECONDARY_NAMESPACE: &str = "";
+
+/// The primary namespace under which the [`LSPS2ClientHandler`] state will be persisted.
+///
+/// [`LSPS2ClientHandler`]: crate::lsps2::client::LSPS2ClientHandler
+pub const LSPS2_CLIENT_PERSISTENCE_PRIMARY_NAMESPACE: &str = "lsps2_client";
+/// The secondary namespace under which the [`LSPS2ClientHandler`] state will be persisted.
+///
+/// [`LSPS2ClientHandler`]: crate::lsps2::client::LSPS2ClientHandler
+pub const LSPS2_CLIENT_PERSISTENCE_SECONDARY_NAMESPACE: &str = "";
+
+/// The primary namespace under which the [`LSPS1ClientHandler`] state will be persisted.
+///
+/// [`LSPS1ClientHandler`]: crate::lsps1::client::LSPS1ClientHandler
+#[cfg(feature = "lsps1")]
+pub const LSPS1_CLIENT_PERSISTENCE_PRIMARY_NAMESPACE: &str = "lsps1_client";
+/// The secondary namespace under which the [`LSPS1ClientHandler`] state will be persisted.
+///
+/// [`LSPS1ClientHandler`]: crate::lsps1::client::LSPS1ClientHandler
+#[cfg(feature = "lsps1")]
+pub const LSPS1_CLIENT_PERSISTENCE_SECONDARY_NAMESPACE: &str = "";
+/// The primary namespace under which the [`LSPS1ServiceHandler`] state will be persisted.
+///
+/// [`LSPS1ServiceHandler`]: crate::lsps1::service::LSPS1ServiceHandler
+#[cfg(feature = "lsps1")]
+pub const LSPS1_SERVICE_PERSISTENCE_PRIMARY_NAMESPACE: &str = "lsps1_service";
+/// The secondary namespace under which the [`LSPS1ServiceHandler`] state will be persisted.
+///
+/// [`LSPS1ServiceHandler`]: crate::lsps1::service::LSPS1ServiceHandler
+#[cfg(feature = "lsps1")]
+pub const LSPS1_SERVICE_PERSISTENCE_SECONDARY_NAMESPACE: &str = "";
+
+/// The per-peer state of a protocol handler, persisted under the handler's namespaces.
+pub(crate) trait PersistedPeerState: Readable + Writeable {
+	/// The primary namespace under which the state is persisted.
+	const PRIMARY_NAMESPACE: &'static str;
+	/// The secondary namespace under which the state is persisted.
+	const SECONDARY_NAMESPACE: &'static str;
+
+	/// Returns whether there is anything to persist, as the state is removed from the store
+	/// otherwise.
+	fn is_persistable(&self) -> bool;
+}
+
+/// Reads all per-peer states previously persisted under the namespaces of `S`.
+pub(crate) fn read_peer_states<K: Deref, S: PersistedPeerState>(
+	kv_store: &K,
+) -> Result<HashMap<PublicKey, S>, io::Error>
+where
+	K::Target: KVStore,
+{
+	let primary_namespace = S::PRIMARY_NAMESPACE;
+	let secondary_namespace = S::SECONDARY_NAMESPACE;
+	let mut peer_states = HashMap::new();
+	for stored_key in kv_store.list(primary_namespace, secondary_namespace)? {
+		let counterparty_node_id = utils::parse_pubkey(&stored_key)?;
+		let mut reader =
+			io::Cursor::new(kv_store.read(primary_namespace, secondary_namespace, &stored_key)?);
+		let peer_state = S::read(&mut reader).map_err(|_| {
+			io::Error::new(io::ErrorKind::InvalidData, "Failed to deserialize peer state")
+		})?;
+		peer_states.insert(counterparty_node_id, peer_state);
+	}
+	Ok(peer_states)
+}
+
+/// Persists the given per-peer state, or removes it from the store if it isn't persistable.
+pub(crate) fn persist_peer_state<K: Deref, S: PersistedPeerState>(
+	kv_store: &K, counterparty_node_id: &PublicKey, peer_state: &S,
+) -> Result<(), LiquidityError>
+where
+	K::Target: KVStore,
+{
+	let key = counterparty_node_id.to_string();
+	if peer_state.is_persistable() {
+		kv_store.write(S::PRIMARY_NAMESPACE, S::SECONDARY_NAMESPACE, &key, &peer_state.encode())
+	} else {
+		kv_store.remove(S::PRIMARY_NAMESPACE, S::SECONDARY_NAMESPACE, &key, false)
+	}
+	.map_err(LiquidityError::PersistenceError)
+}
+
+fn is_valid_kvstore_str(s: &str) -> bool {
+	s.len() <= KVSTORE_NAMESPACE_KEY_MAX_LEN
+		&& s.chars().all(|c| KVSTORE_NAMESPACE_KEY_ALPHABET.contains(c))
+}
+
+/// Checks that the given namespaces and, if given, key adhere to the requirements of the
+/// [`KVStore`] interface.
+pub(crate) fn check_namespace_key_validity(
+	primary_namespace: &str, secondary_namespace: &str, key: Option<&str>,
+) -> Result<(), io::Error> {
+	if primary_namespace.is_empty() && !secondary_namespace.is_empty() {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			"Secondary namespace may only be set if the primary namespace is set",
+		));
+	}
+
+	if !is_valid_kvstore_str(primary_namespace) || !is_valid_kvstore_str(secondary_namespace) {
+		return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid namespace"));
+	}
+
+	if let Some(key) = key {
+		if key.is_empty() || !is_valid_kvstore_str(key) {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid key"));
+		}
+	}
+
+	Ok(())
+}
@@ -1,11 +1,21 @@
 use crate::lsps0::msgs::LSPSMessage;
 use crate::message_queue::MessageQueue;
-use crate::prelude::{Vec, VecDeque};
-use crate::sync::Mutex;
+use crate::prelude::{ToString, Vec, VecDeque};
+use crate::sync::{Arc, Mutex};
 
-use lightning::sign::EntropySource;
+use lightning::chain::chaininterface::{BroadcasterInterface, ConfirmationTarget, FeeEstimator};
+use lightning::chain::channelmonitor::{ChannelMonitor, ChannelMonitorUpdate, MonitorEvent};
+use lightning::chain::transaction::OutPoint;
+use lightning::chain::{BestBlock, ChannelMonitorUpdateStatus, Watch};
+use lightning::ln::channelmanager::{provided_init_features, ChainParameters, ChannelManager};
+use lightning::ln::msgs::{ChannelMessageHandler, ErrorAction, Init, LightningError};
+use lightning::routing::router::{InFlightHtlcs, Route, RouteParameters, Router};
+use lightning::sign::{EntropySource, InMemorySigner, KeysManager};
+use lightning::util::config::UserConfig;
+use lightning::util::logger::{Logger, Record};
 
 use bitcoin::secp256k1::PublicKey;
+use bitcoin::{Network, Transaction};
 
 pub(crate) struct TestMessageQueue {
 	queue: Mutex<VecDeque<(PublicKey, LSPSMessage)>>,
@@ -37,3 +47,98 @@ impl EntropySource for TestEntropy {
 		[0; 32]
 	}
 }
+
+pub(crate) struct TestChainMonitor {}
+impl Watch<InMemorySigner> for TestChainMonitor {
+	fn watch_channel(
+		&self, _funding_txo: OutPoint, _monitor: ChannelMonitor<InMemorySigner>,
+	) -> Result<ChannelMonitorUpdateStatus, ()> {
+		Ok(ChannelMonitorUpdateStatus::Completed)
+	}
+
+	fn update_channel(
+		&self, _funding_txo: OutPoint, _update: &ChannelMonitorUpdate,
+	) -> ChannelMonitorUpdateStatus {
+		ChannelMonitorUpdateStatus::Completed
+	}
+
+	fn release_pending_monitor_events(
+		&self,
+	) -> Vec<(OutPoint, Vec<MonitorEvent>, Option<PublicKey>)> {
+		Vec::new()
+	}
+}
+
+pub(crate) struct TestBroadcaster {}
+impl BroadcasterInterface for TestBroadcaster {
+	fn broadcast_transactions(&self, _txs: &[&Transaction]) {}
+}
+
+pub(crate) struct TestFeeEstimator {}
+impl FeeEstimator for TestFeeEstimator {
+	fn get_est_sat_per_1000_weight(&self, _confirmation_target: ConfirmationTarget) -> u32 {
+		253
+	}
+}
+
+pub(crate) struct TestRouter {}
+impl Router for TestRouter {
+	fn find_route(
+		&self, _payer: &PublicKey, _route_params: &RouteParameters,
+		_first_hops: Option<&[&lightning::ln::channelmanager::ChannelDetails]>,
+		_inflight_htlcs: InFlightHtlcs,
+	) -> Result<Route, LightningError> {
+		Err(LightningError {
+			err: "Routing is not supported in tests".to_string(),
+			action: ErrorAction::IgnoreError,
+		})
+	}
+}
+
+pub(crate) struct TestLogger {}
+impl Logger for TestLogger {
+	fn log(&self, _record: &Record) {}
+}
+
+pub(crate) type TestChannelManager = ChannelManager<
+	Arc<TestChainMonitor>,
+	Arc<TestBroadcaster>,
+	Arc<KeysManager>,
+	Arc<KeysManager>,
+	Arc<KeysManager>,
+	Arc<TestFeeEstimator>,
+	Arc<TestRouter>,
+	Arc<TestLogger>,
+>;
+
+/// Creates a [`ChannelManager`] which doesn't persist or broadcast anything.
+pub(crate) fn create_channel_manager() -> Arc<TestChannelManager> {
+	let keys_manager = Arc::new(KeysManager::new(&[42; 32], 42, 42));
+	let params = ChainParameters {
+		network: Network::Bitcoin,
+		best_block: BestBlock::from_network(Network::Bitcoin),
+	};
+	Arc::new(ChannelManager::new(
+		Arc::new(TestFeeEstimator {}),
+		Arc::new(TestChainMonitor {}),
+		Arc::new(TestBroadcaster {}),
+		Arc::new(TestRouter {}),
+		Arc::new(TestLogger {}),
+		Arc::clone(&keys_manager),
+		Arc::clone(&keys_manager),
+		keys_manager,
+		UserConfig::default(),
+		params,
+		0,
+	))
+}
+
+/// Lets the given [`ChannelManager`] know that it is connected to the given counterparty.
+pub(crate) fn connect_peer(channel_manager: &TestChannelManager, counterparty_node_id: &PublicKey) {
+	let init = Init {
+		features: provided_init_features(&UserConfig::default()),
+		networks: None,
+		remote_network_address: None,
+	};
+	channel_manager.peer_connected(counterparty_node_id, &init, true).unwrap();
+}
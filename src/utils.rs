@@ -29,6 +29,16 @@ pub fn vout_from_scid(short_channel_id: &u64) -> u16 {
 	((short_channel_id) & MAX_SCID_VOUT_INDEX) as u16
 }
 
+/// Formats the `short_channel_id` in the human readable format of BBBxTTTx000.
+pub fn scid_to_human_readable_string(short_channel_id: u64) -> String {
+	format!(
+		"{}x{}x{}",
+		block_from_scid(&short_channel_id),
+		tx_index_from_scid(&short_channel_id),
+		vout_from_scid(&short_channel_id)
+	)
+}
+
 pub fn scid_from_human_readable_string(human_readable_scid: &str) -> Result<u64, ()> {
 	let mut parts = human_readable_scid.split('x');
 
@@ -120,5 +130,6 @@ mod tests {
 		assert_eq!(block_from_scid(&scid), block);
 		assert_eq!(tx_index_from_scid(&scid), tx_index);
 		assert_eq!(vout_from_scid(&scid), vout);
+		assert_eq!(scid_to_human_readable_string(scid), human_readable_scid);
 	}
 }